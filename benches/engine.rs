@@ -0,0 +1,63 @@
+//! Compare `MicroArch::exec` with `Engine::run` on the reference samples.
+//!
+//! `cargo bench --bench engine`. Only the run itself is timed, not copying the project.
+use micro_programming::engine::Engine;
+use micro_programming::reference::{project, SAMPLES};
+use micro_programming::vm::MicroArch;
+use std::time::{Duration, Instant};
+
+/// how many times each sample is run from wakeup to halt.
+const ROUNDS: u32 = 2000;
+
+fn prepare(vm: &MicroArch) -> MicroArch {
+    let mut vm = vm.clone();
+    vm.sw1 = 20;
+    vm.sw2 = 13;
+    vm.start();
+    vm
+}
+
+fn report(label: &str, cycles: u64, elapsed: Duration) {
+    println!(
+        "  {:<10} {:>12} micro codes {:>10.3} ms {:>8.1} M micro codes/s",
+        label,
+        cycles,
+        elapsed.as_secs_f64() * 1000.0,
+        cycles as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    for sample in SAMPLES.iter() {
+        let base = project(sample);
+        println!("{}", sample.name);
+
+        let mut cycles = 0;
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..ROUNDS {
+            let mut vm = prepare(&base);
+            let start = Instant::now();
+            loop {
+                cycles += 1;
+                if vm.exec() {
+                    break;
+                }
+            }
+            elapsed += start.elapsed();
+        }
+        report("exec", cycles, elapsed);
+
+        let mut engine = Engine::new();
+        let mut cycles = 0;
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..ROUNDS {
+            let mut vm = prepare(&base);
+            let start = Instant::now();
+            let result = engine.run(&mut vm, u64::MAX);
+            elapsed += start.elapsed();
+            assert!(result.halted);
+            cycles += result.cycles;
+        }
+        report("engine", cycles, elapsed);
+    }
+}
@@ -0,0 +1,435 @@
+use crate::vm::{AluOp, Assemble, Branch, MemOp, MicroCode, Register, RegisterOrSwitch, ShiftOp};
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Description of the machine the VM, micro code editor and encoder work on.
+///
+/// Loaded from a TOML file so one build can serve several textbook machines.
+/// `Architecture::default()` is the machine this tool was originally written for.
+///
+/// The register file itself is fixed: R0~R6, PC, IR, MDR, MAR, STR and CNT, one
+/// byte each except the 16 bit CNT. A description can rename, encode, hide or
+/// restrict these registers but cannot add any or change their width.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Architecture {
+    pub name: String,
+    /// micro instruction field widths in bits.
+    pub fields: FieldWidths,
+    /// STR bit positions of each flag.
+    pub flags: FlagBits,
+    /// registers in display order, a subset of the fixed register file. a register
+    /// absent here does not exist in the machine.
+    pub registers: Vec<RegisterSpec>,
+    /// switches selectable on X and Y bus.
+    pub switches: Vec<SwitchSpec>,
+    pub alu_ops: Vec<OpSpec<AluOp>>,
+    pub shift_ops: Vec<OpSpec<ShiftOp>>,
+    pub mem_ops: Vec<OpSpec<MemOp>>,
+    pub branches: Vec<OpSpec<Branch>>,
+}
+
+/// Field widths. fields are packed high to low in declaration order.
+///
+/// The default architecture packs them into a 43 bit word:
+///
+/// ```text
+/// 42..39 38..35 34..32 31..29  28  27  26..23 22..21 20..17  16  15..0
+/// x_bus  y_bus   alu    sft   sin  fl  z_bus   mem   branch hlt  addr
+/// ```
+///
+/// Before LDCT and RPCT the branch field was 3 bits and the word 42 bits, so every
+/// field above `branch` sat one bit lower. Control store ROMs built for that layout
+/// have to be exported again.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FieldWidths {
+    pub x_bus: u32,
+    pub y_bus: u32,
+    pub alu: u32,
+    pub sft: u32,
+    pub sin: u32,
+    pub fl: u32,
+    pub z_bus: u32,
+    pub mem: u32,
+    pub branch: u32,
+    pub hlt: u32,
+    pub addr: u32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FlagBits {
+    pub minus: u8,
+    pub zero: u8,
+    pub carry: u8,
+    pub overflow: u8,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegisterSpec {
+    pub register: Register,
+    pub name: String,
+    /// encoding in X, Y and Z bus field.
+    pub code: u64,
+    /// readable from X and Y bus.
+    pub source: bool,
+    /// writable from Z bus.
+    pub destination: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SwitchSpec {
+    pub switch: RegisterOrSwitch,
+    pub name: String,
+    /// encoding in X and Y bus field.
+    pub code: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OpSpec<T> {
+    pub op: T,
+    pub name: String,
+    pub code: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// value not provided by this architecture.
+    Unsupported { field: &'static str, value: String },
+    /// value does not fit into the field.
+    Overflow {
+        field: &'static str,
+        value: u64,
+        width: u32,
+    },
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::Unsupported { field, value } => {
+                write!(f, "{} is not available in {} field", value, field)
+            }
+            EncodeError::Overflow {
+                field,
+                value,
+                width,
+            } => write!(f, "{:X}H does not fit {} bit {} field", value, width, field),
+        }
+    }
+}
+
+impl Default for Architecture {
+    fn default() -> Self {
+        const REGISTERS: [Register; 14] = [
+            Register::Nop,
+            Register::R0,
+            Register::R1,
+            Register::R2,
+            Register::R3,
+            Register::R4,
+            Register::R5,
+            Register::R6,
+            Register::Pc,
+            Register::Ir,
+            Register::Mdr,
+            Register::Mar,
+            Register::Str,
+            Register::Cnt,
+        ];
+        fn ops<T: Assemble + ToString + Copy>(ops: &[T]) -> Vec<OpSpec<T>> {
+            ops.iter()
+                .map(|op| OpSpec {
+                    op: *op,
+                    name: op.to_string(),
+                    code: op.assemble(),
+                })
+                .collect()
+        }
+        Self {
+            name: "default".to_owned(),
+            fields: FieldWidths {
+                x_bus: 4,
+                y_bus: 4,
+                alu: 3,
+                sft: 3,
+                sin: 1,
+                fl: 1,
+                z_bus: 4,
+                mem: 2,
+                branch: 4,
+                hlt: 1,
+                addr: 16,
+            },
+            flags: FlagBits {
+                minus: 0,
+                zero: 1,
+                carry: 2,
+                overflow: 3,
+            },
+            registers: REGISTERS
+                .iter()
+                .map(|register| RegisterSpec {
+                    register: *register,
+                    name: register.to_string(),
+                    code: register.assemble(),
+                    source: true,
+                    destination: true,
+                })
+                .collect(),
+            switches: [RegisterOrSwitch::Sw1, RegisterOrSwitch::Sw2]
+                .iter()
+                .map(|switch| SwitchSpec {
+                    switch: *switch,
+                    name: switch.to_string(),
+                    code: switch.assemble(),
+                })
+                .collect(),
+            alu_ops: ops(&[
+                AluOp::XPlusY,
+                AluOp::XMinusY,
+                AluOp::XAndY,
+                AluOp::XorY,
+                AluOp::XxorY,
+                AluOp::XPlus1,
+                AluOp::XMinus1,
+            ]),
+            shift_ops: ops(&[
+                ShiftOp::Nop,
+                ShiftOp::RRwC,
+                ShiftOp::RlwC,
+                ShiftOp::Srl,
+                ShiftOp::Sll,
+                ShiftOp::Sra,
+                ShiftOp::Sla,
+            ]),
+            mem_ops: ops(&[MemOp::Nop, MemOp::R, MemOp::W]),
+            branches: ops(&[
+                Branch::Plus1,
+                Branch::J,
+                Branch::JM,
+                Branch::JZ,
+                Branch::JC,
+                Branch::JV,
+                Branch::JI,
+                Branch::Ldct,
+                Branch::Rpct,
+            ]),
+        }
+    }
+}
+
+impl Architecture {
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let architecture: Self = toml::from_str(text).map_err(|e| e.to_string())?;
+        architecture.validate()?;
+        Ok(architecture)
+    }
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_default()
+    }
+    /// check that every encoding fits and is unique.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.word_width() > 64 {
+            return Err(format!(
+                "{} bit micro code is wider than 64 bit",
+                self.word_width()
+            ));
+        }
+        let flags = [
+            ("minus", self.flags.minus),
+            ("zero", self.flags.zero),
+            ("carry", self.flags.carry),
+            ("overflow", self.flags.overflow),
+        ];
+        for (i, (name, bit)) in flags.iter().enumerate() {
+            if *bit > 7 {
+                return Err(format!("{} flag bit {} is outside STR", name, bit));
+            }
+            if let Some((other, _)) = flags[..i].iter().find(|(_, b)| b == bit) {
+                return Err(format!("{} and {} flag share bit {}", other, name, bit));
+            }
+        }
+        if !self.registers.iter().any(|r| r.register == Register::Nop) {
+            return Err("Nop register is required".to_owned());
+        }
+        let mut bus_codes: Vec<(u64, &str)> = self
+            .registers
+            .iter()
+            .map(|r| (r.code, r.name.as_str()))
+            .collect();
+        for switch in &self.switches {
+            if !matches!(switch.switch, RegisterOrSwitch::Sw1 | RegisterOrSwitch::Sw2) {
+                return Err(format!("{} is not a switch", switch.name));
+            }
+            bus_codes.push((switch.code, switch.name.as_str()));
+        }
+        let bus_width = self.fields.x_bus.min(self.fields.y_bus);
+        check_codes("register", &bus_codes, bus_width)?;
+        check_codes(
+            "register",
+            &self
+                .registers
+                .iter()
+                .map(|r| (r.code, r.name.as_str()))
+                .collect::<Vec<_>>(),
+            self.fields.z_bus,
+        )?;
+        check_ops("ALU", &self.alu_ops, self.fields.alu)?;
+        check_ops("shift", &self.shift_ops, self.fields.sft)?;
+        check_ops("memory", &self.mem_ops, self.fields.mem)?;
+        check_ops("branch", &self.branches, self.fields.branch)?;
+        Ok(())
+    }
+    /// total micro code width in bits.
+    pub fn word_width(&self) -> u32 {
+        let f = &self.fields;
+        f.x_bus
+            + f.y_bus
+            + f.alu
+            + f.sft
+            + f.sin
+            + f.fl
+            + f.z_bus
+            + f.mem
+            + f.branch
+            + f.hlt
+            + f.addr
+    }
+    pub fn register(&self, register: Register) -> Option<&RegisterSpec> {
+        self.registers.iter().find(|r| r.register == register)
+    }
+    pub fn switch(&self, switch: RegisterOrSwitch) -> Option<&SwitchSpec> {
+        self.switches.iter().find(|s| s.switch == switch)
+    }
+    pub fn is_source(&self, register: Register) -> bool {
+        matches!(self.register(register), Some(r) if r.source)
+    }
+    pub fn is_destination(&self, register: Register) -> bool {
+        matches!(self.register(register), Some(r) if r.destination)
+    }
+    /// general purpose registers present in this machine.
+    pub fn gprs(&self) -> impl Iterator<Item = &RegisterSpec> {
+        self.registers
+            .iter()
+            .filter(|r| r.register.gpr_index().is_some())
+    }
+    pub fn register_name(&self, register: Register) -> String {
+        self.register(register)
+            .map_or_else(|| format!("{}?", register.to_string()), |r| r.name.clone())
+    }
+    pub fn source_name(&self, source: RegisterOrSwitch) -> String {
+        match source {
+            RegisterOrSwitch::Register(register) => self.register_name(register),
+            switch => self
+                .switch(switch)
+                .map_or_else(|| format!("{}?", switch.to_string()), |s| s.name.clone()),
+        }
+    }
+    /// error for the first ALU, shift, memory or branch op of `micro_code` this
+    /// machine lacks.
+    pub fn ops_available(&self, micro_code: &MicroCode) -> Result<(), EncodeError> {
+        op_code("ALU", &self.alu_ops, micro_code.alu)?;
+        op_code("SFT", &self.shift_ops, micro_code.sft)?;
+        op_code("Mem", &self.mem_ops, micro_code.mem)?;
+        op_code("Branch", &self.branches, micro_code.branch)?;
+        Ok(())
+    }
+    /// encode micro code with this architecture's field widths and encodings.
+    pub fn encode(&self, micro_code: &MicroCode) -> Result<u64, EncodeError> {
+        let f = &self.fields;
+        let source = |field, source: RegisterOrSwitch| match source {
+            RegisterOrSwitch::Register(register) => self
+                .register(register)
+                .filter(|r| r.source)
+                .map(|r| r.code)
+                .ok_or_else(|| unsupported(field, self.source_name(source))),
+            switch => self
+                .switch(switch)
+                .map(|s| s.code)
+                .ok_or_else(|| unsupported(field, self.source_name(source))),
+        };
+        let destination = self
+            .register(micro_code.z_bus)
+            .filter(|r| r.destination)
+            .map(|r| r.code)
+            .ok_or_else(|| unsupported("Z-Bus", self.register_name(micro_code.z_bus)))?;
+        // low to high
+        let fields = [
+            ("B.Addr", micro_code.addr as u64, f.addr),
+            ("Halt", micro_code.hlt as u64, f.hlt),
+            (
+                "Branch",
+                op_code("Branch", &self.branches, micro_code.branch)?,
+                f.branch,
+            ),
+            ("Mem", op_code("Mem", &self.mem_ops, micro_code.mem)?, f.mem),
+            ("Z-Bus", destination, f.z_bus),
+            ("FL", micro_code.fl as u64, f.fl),
+            ("Sin", micro_code.sin as u64, f.sin),
+            (
+                "SFT",
+                op_code("SFT", &self.shift_ops, micro_code.sft)?,
+                f.sft,
+            ),
+            ("ALU", op_code("ALU", &self.alu_ops, micro_code.alu)?, f.alu),
+            ("Y-Bus", source("Y-Bus", micro_code.y_bus)?, f.y_bus),
+            ("X-Bus", source("X-Bus", micro_code.x_bus)?, f.x_bus),
+        ];
+        let mut word = 0;
+        let mut offset = 0;
+        for (field, value, width) in fields {
+            if width < 64 && value >> width != 0 {
+                return Err(EncodeError::Overflow {
+                    field,
+                    value,
+                    width,
+                });
+            }
+            word |= value << offset;
+            offset += width;
+        }
+        Ok(word)
+    }
+}
+
+fn unsupported(field: &'static str, value: String) -> EncodeError {
+    EncodeError::Unsupported { field, value }
+}
+
+fn op_code<T: PartialEq + ToString>(
+    field: &'static str,
+    ops: &[OpSpec<T>],
+    op: T,
+) -> Result<u64, EncodeError> {
+    ops.iter()
+        .find(|spec| spec.op == op)
+        .map(|spec| spec.code)
+        .ok_or_else(|| unsupported(field, op.to_string()))
+}
+
+fn check_ops<T>(kind: &str, ops: &[OpSpec<T>], width: u32) -> Result<(), String> {
+    check_codes(
+        kind,
+        &ops.iter()
+            .map(|op| (op.code, op.name.as_str()))
+            .collect::<Vec<_>>(),
+        width,
+    )
+}
+
+fn check_codes(kind: &str, codes: &[(u64, &str)], width: u32) -> Result<(), String> {
+    for (i, (code, name)) in codes.iter().enumerate() {
+        if width < 64 && code >> width != 0 {
+            return Err(format!(
+                "{} {} code {} does not fit {} bit",
+                kind, name, code, width
+            ));
+        }
+        if let Some((_, other)) = codes[..i].iter().find(|(c, _)| c == code) {
+            return Err(format!(
+                "{} {} and {} share code {}",
+                kind, other, name, code
+            ));
+        }
+    }
+    Ok(())
+}
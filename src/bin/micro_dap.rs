@@ -0,0 +1,11 @@
+//! Debug Adapter Protocol server over stdio, launched by the editor.
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+
+fn main() {
+    let input = std::io::BufReader::new(std::io::stdin());
+    if let Err(e) = micro_programming::dap::serve(input, std::io::stdout()) {
+        eprintln!("micro_dap: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,86 @@
+//! Serve the macro machine of a project to GDB over TCP.
+//!
+//! ```text
+//! micro_gdb sample.cpu_memory --port 1234
+//! gdb -ex "target remote localhost:1234"
+//! ```
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+use micro_programming::gdb::{self, Target};
+use micro_programming::{project, reference};
+use std::net::TcpListener;
+
+const USAGE: &str = "\
+usage: micro_gdb PROJECT [options]
+
+  --port N          TCP port on localhost (default 1234)
+  --fetch ADDR      micro address of the instruction fetch, hex (default 0000)
+
+the machine keeps its state between connections. monitor commands: reset, start, upc.";
+
+const DEFAULT_PORT: u16 = 1234;
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(String, u16, u16), String> {
+    let mut project = None;
+    let mut port = DEFAULT_PORT;
+    let mut fetch = reference::FETCH;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--port" => {
+                let value = value()?;
+                port = value.parse().map_err(|_| format!("bad port : {}", value))?;
+            }
+            "--fetch" => {
+                let value = value()?;
+                fetch = u16::from_str_radix(value.trim_end_matches(['h', 'H']), 16)
+                    .map_err(|_| format!("bad micro address : {}", value))?;
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option : {}", arg)),
+            _ if project.is_none() => project = Some(arg),
+            _ => return Err(format!("unexpected argument : {}", arg)),
+        }
+    }
+    Ok((project.ok_or("no project file given")?, port, fetch))
+}
+
+fn run(path: &str, port: u16, fetch: u16) -> Result<(), String> {
+    let vm = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| project::load(&bytes))
+        .map_err(|e| format!("{} : {}", path, e))?;
+    let mut target = Target::new(vm, fetch);
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    eprintln!("micro_gdb: listening on 127.0.0.1:{}", port);
+    for stream in listener.incoming() {
+        let stream = stream.map_err(|e| e.to_string())?;
+        eprintln!("micro_gdb: debugger connected");
+        if let Err(e) = gdb::serve(stream, &mut target) {
+            eprintln!("micro_gdb: {}", e);
+        }
+        eprintln!("micro_gdb: debugger detached");
+    }
+    Ok(())
+}
+
+fn main() {
+    let code = match parse_args(std::env::args().skip(1)) {
+        Err(e) if e.is_empty() => {
+            println!("{}", USAGE);
+            0
+        }
+        Err(e) => {
+            eprintln!("micro_gdb: {}\n{}", e, USAGE);
+            1
+        }
+        Ok((path, port, fetch)) => match run(&path, port, fetch) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("micro_gdb: {}", e);
+                1
+            }
+        },
+    };
+    std::process::exit(code);
+}
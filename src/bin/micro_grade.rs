@@ -0,0 +1,215 @@
+//! Grade a project, or a directory of projects, against a test specification.
+//!
+//! Exit status: 0 for full marks, 1 for bad arguments or files and 2 when any case
+//! failed.
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+use micro_programming::grader::{self, batch, GradeReport, Spec};
+use micro_programming::project;
+use std::path::Path;
+use std::time::Duration;
+
+const USAGE: &str = "\
+usage: micro_grade PROJECT SPEC [options]
+       micro_grade --batch DIR SPEC --output REPORTS [options]
+
+  --format text|json    report format (default text)
+  --output FILE         write the report to FILE instead of stdout
+  --show-hidden         include names and diffs of hidden cases
+
+batch mode grades every .cpu_memory file in DIR and writes summary.csv,
+summary.json and one report per student into REPORTS.
+
+  --jobs N              submissions graded at once (default: every core)
+  --timeout SECONDS     give up on a submission after this long (default 30)";
+
+const DEFAULT_TIMEOUT: u64 = 30;
+
+struct Args {
+    /// project file, or submission directory in batch mode.
+    project: String,
+    spec: String,
+    json: bool,
+    output: Option<String>,
+    show_hidden: bool,
+    batch: bool,
+    jobs: Option<usize>,
+    timeout: u64,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        project: String::new(),
+        spec: String::new(),
+        json: false,
+        output: None,
+        show_hidden: false,
+        batch: false,
+        jobs: None,
+        timeout: DEFAULT_TIMEOUT,
+    };
+    let mut files = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--format" => {
+                parsed.json = match value()?.as_str() {
+                    "text" => false,
+                    "json" => true,
+                    other => return Err(format!("unknown format : {}", other)),
+                }
+            }
+            "--output" => parsed.output = Some(value()?),
+            "--show-hidden" => parsed.show_hidden = true,
+            "--batch" => parsed.batch = true,
+            "--jobs" => {
+                let jobs = value()?;
+                parsed.jobs = Some(
+                    jobs.parse()
+                        .map_err(|_| format!("not a number : {}", jobs))?,
+                )
+            }
+            "--timeout" => {
+                let timeout = value()?;
+                parsed.timeout = timeout
+                    .parse()
+                    .map_err(|_| format!("not a number : {}", timeout))?
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option : {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+    match <[String; 2]>::try_from(files) {
+        Ok([project, spec]) => {
+            parsed.project = project;
+            parsed.spec = spec;
+        }
+        Err(_) if parsed.batch => return Err("expected a directory and a spec file".to_owned()),
+        Err(_) => return Err("expected a project and a spec file".to_owned()),
+    }
+    if parsed.batch && parsed.output.is_none() {
+        return Err("--batch needs --output REPORTS".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn write(path: &Path, contents: String) -> Result<(), String> {
+    std::fs::write(path, contents).map_err(|e| format!("{} : {}", path.display(), e))
+}
+
+fn render(report: &GradeReport, args: &Args) -> String {
+    let report = if args.show_hidden {
+        report.clone()
+    } else {
+        report.redacted()
+    };
+    if args.json {
+        report.to_json() + "\n"
+    } else {
+        report.to_text()
+    }
+}
+
+fn grade_one(spec: &Spec, args: &Args) -> Result<i32, String> {
+    let student = std::fs::read(&args.project)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| project::load(&bytes))
+        .map_err(|e| format!("{} : {}", args.project, e))?;
+    let report = grader::grade(&student, spec);
+    let text = render(&report, args);
+    match &args.output {
+        Some(path) => write(Path::new(path), text)?,
+        None => print!("{}", text),
+    }
+    Ok(if report.passed() { 0 } else { 2 })
+}
+
+fn grade_directory(spec: &Spec, args: &Args) -> Result<i32, String> {
+    let directory = Path::new(&args.project);
+    let mut paths: Vec<_> = std::fs::read_dir(directory)
+        .map_err(|e| format!("{} : {}", directory.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == "cpu_memory"))
+        .collect();
+    paths.sort();
+    let mut submissions = vec![];
+    for path in &paths {
+        let student = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let bytes = std::fs::read(path).map_err(|e| format!("{} : {}", path.display(), e))?;
+        submissions.push((student, bytes));
+    }
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |jobs| jobs.get()));
+    let results = batch::grade_all(submissions, spec, jobs, Duration::from_secs(args.timeout));
+
+    let reports = Path::new(args.output.as_deref().unwrap_or_default());
+    std::fs::create_dir_all(reports).map_err(|e| format!("{} : {}", reports.display(), e))?;
+    write(
+        &reports.join("summary.csv"),
+        batch::summary_csv(&results, spec),
+    )?;
+    write(
+        &reports.join("summary.json"),
+        batch::summary_json(&results, spec),
+    )?;
+    let extension = if args.json { "json" } else { "txt" };
+    let mut all_passed = true;
+    for submission in &results {
+        let text = match &submission.result {
+            Ok(report) => {
+                all_passed &= report.passed();
+                render(report, args)
+            }
+            Err(e) => {
+                all_passed = false;
+                eprintln!("micro_grade: {} : {}", submission.student, e);
+                format!("not graded : {}\n", e)
+            }
+        };
+        write(
+            &reports.join(format!("{}.{}", submission.student, extension)),
+            text,
+        )?;
+    }
+    println!(
+        "graded {} submissions into {}",
+        results.len(),
+        reports.display()
+    );
+    Ok(if all_passed { 0 } else { 2 })
+}
+
+fn run(args: &Args) -> Result<i32, String> {
+    let spec = std::fs::read_to_string(&args.spec)
+        .map_err(|e| e.to_string())
+        .and_then(|text| Spec::from_toml(&text))
+        .map_err(|e| format!("{} : {}", args.spec, e))?;
+    if args.batch {
+        grade_directory(&spec, args)
+    } else {
+        grade_one(&spec, args)
+    }
+}
+
+fn main() {
+    let code = match parse_args(std::env::args().skip(1)) {
+        Err(e) if e.is_empty() => {
+            println!("{}", USAGE);
+            0
+        }
+        Err(e) => {
+            eprintln!("micro_grade: {}\n{}", e, USAGE);
+            1
+        }
+        Ok(args) => run(&args).unwrap_or_else(|e| {
+            eprintln!("micro_grade: {}", e);
+            1
+        }),
+    };
+    std::process::exit(code);
+}
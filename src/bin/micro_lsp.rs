@@ -0,0 +1,14 @@
+//! Language server for microassembly over stdio, launched by the editor.
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+
+fn main() {
+    let input = std::io::BufReader::new(std::io::stdin());
+    match micro_programming::lsp::serve(input, std::io::stdout()) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("micro_lsp: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Run a project without the GUI and print the final machine state.
+//!
+//! Exit status: 0 after HLT, 1 for bad arguments or files, 2 on a fault and 3 when
+//! the cycle limit is reached.
+//!
+//! Build it without GTK or a display with `cargo build --no-default-features --bin micro_run`.
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+use micro_programming::memory_image::{self, ImageFormat};
+use micro_programming::{project, runner};
+use std::path::Path;
+
+const USAGE: &str = "\
+usage: micro_run PROJECT [options]
+
+  --sw1 N               set SW1 before running
+  --sw2 N               set SW2 before running
+  --memory FILE[@ADDR]  load a memory image (hex, srec, bin, xxd), raw binary at ADDR
+  --reset               reset the registers first, as \"Reset registers\" in the GUI
+  --cycles N            stop after N micro codes (default 1000000)
+  --format text|json    output format (default text)
+  --output FILE         write the report to FILE instead of stdout
+
+numbers are decimal or 0x prefixed hex.";
+
+const DEFAULT_CYCLES: u64 = 1_000_000;
+
+struct Args {
+    project: String,
+    sw1: Option<u8>,
+    sw2: Option<u8>,
+    memory: Vec<(String, usize)>,
+    reset: bool,
+    cycles: u64,
+    json: bool,
+    output: Option<String>,
+}
+
+fn number<T: TryFrom<u64>>(text: &str) -> Result<T, String> {
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| format!("not a number : {}", text))?;
+    T::try_from(value).map_err(|_| format!("out of range : {}", text))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        project: String::new(),
+        sw1: None,
+        sw2: None,
+        memory: vec![],
+        reset: false,
+        cycles: DEFAULT_CYCLES,
+        json: false,
+        output: None,
+    };
+    let mut project = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--sw1" => parsed.sw1 = Some(number(&value()?)?),
+            "--sw2" => parsed.sw2 = Some(number(&value()?)?),
+            "--memory" => {
+                let value = value()?;
+                parsed.memory.push(match value.rsplit_once('@') {
+                    Some((path, addr)) => (path.to_owned(), number(addr)?),
+                    None => (value, 0),
+                });
+            }
+            "--reset" => parsed.reset = true,
+            "--cycles" => parsed.cycles = number(&value()?)?,
+            "--format" => {
+                parsed.json = match value()?.as_str() {
+                    "text" => false,
+                    "json" => true,
+                    other => return Err(format!("unknown format : {}", other)),
+                }
+            }
+            "--output" => parsed.output = Some(value()?),
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option : {}", arg)),
+            _ if project.is_none() => project = Some(arg),
+            _ => return Err(format!("unexpected argument : {}", arg)),
+        }
+    }
+    parsed.project = project.ok_or("no project file given")?;
+    Ok(parsed)
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("{} : {}", path, e))
+}
+
+fn run(args: &Args) -> Result<i32, String> {
+    let mut vm =
+        project::load(&read(&args.project)?).map_err(|e| format!("{} : {}", args.project, e))?;
+    if args.reset {
+        vm.reset_register();
+    }
+    if let Some(sw1) = args.sw1 {
+        vm.sw1 = sw1;
+    }
+    if let Some(sw2) = args.sw2 {
+        vm.sw2 = sw2;
+    }
+    for (path, addr) in &args.memory {
+        let format = Path::new(path)
+            .extension()
+            .and_then(|extension| ImageFormat::from_extension(&extension.to_string_lossy()))
+            .unwrap_or(ImageFormat::Binary);
+        let loaded = memory_image::parse(&read(path)?, format).and_then(|segments| {
+            // only raw binary has no addresses of its own.
+            let offset = if format == ImageFormat::Binary {
+                *addr
+            } else {
+                0
+            };
+            memory_image::load(&mut vm.memory, &segments, offset)
+        });
+        loaded.map_err(|e| format!("{} : {}", path, e))?;
+    }
+
+    let outcome = runner::run(&mut vm, args.cycles);
+    let report = runner::Report::new(&vm, &outcome);
+    let mut text = if args.json {
+        report.to_json()
+    } else {
+        report.to_text()
+    };
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    match &args.output {
+        Some(path) => std::fs::write(path, text).map_err(|e| format!("{} : {}", path, e))?,
+        None => print!("{}", text),
+    }
+    Ok(outcome.stop.exit_code())
+}
+
+fn main() {
+    let code = match parse_args(std::env::args().skip(1)) {
+        Err(e) if e.is_empty() => {
+            println!("{}", USAGE);
+            0
+        }
+        Err(e) => {
+            eprintln!("micro_run: {}\n{}", e, USAGE);
+            1
+        }
+        Ok(args) => run(&args).unwrap_or_else(|e| {
+            eprintln!("micro_run: {}", e);
+            1
+        }),
+    };
+    std::process::exit(code);
+}
@@ -0,0 +1,508 @@
+//! Debug Adapter Protocol server for micro programs.
+//!
+//! `launch` loads a project file. The control store is shown as a listing with one
+//! line per programmed micro address, served through a `sourceReference`, and the
+//! micro PC is the current line. Steps execute one micro code; `continue` runs until
+//! a breakpoint line, HLT (stop reason `halt`) or a fault (stop reason `exception`).
+//! Registers, flags and memory are the variable scopes.
+//!
+//! `serve` speaks the protocol over any byte streams, `micro_dap` over stdio.
+use crate::engine::Engine;
+use crate::vm::{MicroArch, MicroCode};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::sync::mpsc;
+
+const THREAD_ID: i64 = 1;
+const LISTING_REFERENCE: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+const MEMORY_REFERENCE: i64 = 3;
+/// micro codes run between looks at incoming requests while continuing.
+pub const RUN_CHUNK: u64 = 1 << 16;
+
+struct Debuggee {
+    vm: MicroArch,
+    engine: Engine,
+    /// listing name shown by the editor.
+    name: String,
+    /// micro address of every listing line.
+    addresses: Vec<u16>,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl Debuggee {
+    fn new(vm: MicroArch, name: String) -> Self {
+        let default = MicroCode::default();
+        let addresses = (0..vm.micro_program.len())
+            .filter(|addr| vm.micro_program[*addr] != default)
+            .map(|addr| addr as u16)
+            .collect();
+        Self {
+            vm,
+            engine: Engine::new(),
+            name,
+            addresses,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    fn listing(&self) -> String {
+        self.addresses
+            .iter()
+            .map(|addr| {
+                format!(
+                    "{:04X}H  {}\n",
+                    addr,
+                    crate::microasm::disassemble(&self.vm.micro_program[*addr as usize])
+                )
+            })
+            .collect()
+    }
+
+    /// 0 based listing line of a micro address.
+    fn line_of(&self, addr: u16) -> Option<usize> {
+        self.addresses.binary_search(&addr).ok()
+    }
+
+    /// execute at most `cycles` micro codes, stopping at micro breakpoints when
+    /// `breakpoints` is set. the stop reason and description if it stops the run.
+    fn run(&mut self, cycles: u64, breakpoints: bool) -> Option<(&'static str, Option<String>)> {
+        let stops = &self.breakpoints;
+        let result = self.engine.run_until(&mut self.vm, cycles, |upc| {
+            breakpoints && stops.contains(&upc)
+        });
+        if result.halted {
+            return Some(("halt", Some("HLT".to_owned())));
+        }
+        if let Some(fault) = self.vm.fault().filter(|_| result.faulted) {
+            return Some(("exception", Some(fault)));
+        }
+        (breakpoints && result.cycles > 0 && stops.contains(&self.vm.micro_program_counter))
+            .then_some(("breakpoint", None))
+    }
+}
+
+#[derive(Default)]
+pub struct Session {
+    seq: i64,
+    output: Vec<Value>,
+    /// lines count from 0 unless the client says 1, the default.
+    lines_start_at_0: bool,
+    stop_on_entry: bool,
+    debuggee: Option<Debuggee>,
+    /// continuing; call `run_for` until it stops.
+    pub running: bool,
+    /// disconnected, nothing more to do.
+    pub finished: bool,
+}
+
+fn hex(value: u16, digits: usize) -> String {
+    format!("{:0digits$X}H", value, digits = digits)
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// messages to send, in order.
+    pub fn take_output(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        self.output.push(message);
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        self.running = false;
+        let mut body =
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(description) = description {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": format!("{}\n", description) }),
+            );
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+        self.event("stopped", body);
+    }
+
+    fn line(&self, line: usize) -> usize {
+        if self.lines_start_at_0 {
+            line
+        } else {
+            line + 1
+        }
+    }
+
+    fn debuggee(&mut self) -> Result<&mut Debuggee, String> {
+        self.debuggee
+            .as_mut()
+            .ok_or_else(|| "no program launched".to_owned())
+    }
+
+    /// handle one incoming message. Only requests are answered.
+    pub fn handle(&mut self, message: &Value) {
+        if message["type"] != "request" {
+            return;
+        }
+        let args = &message["arguments"];
+        let command = message["command"].as_str().unwrap_or_default();
+        let result = match command {
+            "initialize" => {
+                self.lines_start_at_0 = args["linesStartAt1"] == false;
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsTerminateRequest": true,
+                }))
+            }
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "sequencer" }] })),
+            "stackTrace" => self.stack_trace(),
+            "source" => self.debuggee().map(
+                |debuggee| json!({ "content": debuggee.listing(), "mimeType": "text/x-microasm" }),
+            ),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY_REFERENCE, "expensive": false },
+            ] })),
+            "variables" => self.variables(args["variablesReference"].as_i64().unwrap_or_default()),
+            "continue" => self
+                .debuggee()
+                .map(|_| json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "pause" => self.debuggee().map(|_| json!({})),
+            "disconnect" | "terminate" => Ok(json!({})),
+            _ => Err(format!("unsupported request {}", command)),
+        };
+        let launched = command == "launch" && result.is_ok();
+        self.respond(message, result);
+
+        // events that follow the response.
+        match command {
+            "launch" if launched => self.event("initialized", json!({})),
+            "configurationDone" if self.debuggee.is_some() => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.running = true;
+                }
+            }
+            "continue" if self.debuggee.is_some() => self.running = true,
+            "next" | "stepIn" | "stepOut" if self.debuggee.is_some() => {
+                self.running = false;
+                match self
+                    .debuggee()
+                    .ok()
+                    .and_then(|debuggee| debuggee.run(1, false))
+                {
+                    Some((reason, description)) => self.stopped(reason, description),
+                    None => self.stopped("step", None),
+                }
+            }
+            "pause" if self.running => self.stopped("pause", None),
+            "disconnect" | "terminate" => {
+                self.running = false;
+                self.finished = true;
+                self.event("terminated", json!({}));
+            }
+            _ => {}
+        }
+    }
+
+    /// continue for at most `cycles` micro codes.
+    pub fn run_for(&mut self, cycles: u64) {
+        if !self.running {
+            return;
+        }
+        let debuggee = match self.debuggee.as_mut() {
+            Some(debuggee) => debuggee,
+            None => return,
+        };
+        if let Some((reason, description)) = debuggee.run(cycles, true) {
+            self.stopped(reason, description);
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or_else(|| "launch needs \"program\", a project file".to_owned())?;
+        let mut vm = std::fs::read(program)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| crate::project::load(&bytes))
+            .map_err(|e| format!("{} : {}", program, e))?;
+        if args["reset"] == true {
+            vm.reset_register();
+        }
+        for (key, switch) in [("sw1", &mut vm.sw1), ("sw2", &mut vm.sw2)] {
+            if let Some(value) = args[key].as_u64() {
+                *switch = u8::try_from(value).map_err(|_| format!("{} out of range", key))?;
+            }
+        }
+        let name = std::path::Path::new(program)
+            .file_stem()
+            .map_or_else(|| "control store".into(), |stem| stem.to_string_lossy())
+            .into_owned()
+            + ".micro";
+        self.stop_on_entry = args["stopOnEntry"] != false;
+        self.debuggee = Some(Debuggee::new(vm, name));
+        Ok(json!({}))
+    }
+
+    fn source(debuggee: &Debuggee) -> Value {
+        json!({ "name": debuggee.name, "sourceReference": LISTING_REFERENCE })
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let first_line = self.line(0);
+        let debuggee = self.debuggee()?;
+        debuggee.breakpoints.clear();
+        let lines: Vec<i64> = args["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_i64())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut verified = vec![];
+        for line in lines {
+            let addr = usize::try_from(line - first_line as i64)
+                .ok()
+                .and_then(|index| debuggee.addresses.get(index).copied());
+            if let Some(addr) = addr {
+                debuggee.breakpoints.insert(addr);
+            }
+            verified.push(json!({
+                "verified": addr.is_some(),
+                "line": line,
+                "message": if addr.is_some() { Value::Null } else { json!("no micro code on this line") },
+            }));
+        }
+        Ok(json!({ "breakpoints": verified }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let first_line = self.line(0);
+        let debuggee = self.debuggee()?;
+        let upc = debuggee.vm.micro_program_counter;
+        let micro_code = debuggee
+            .vm
+            .micro_program
+            .get(upc as usize)
+            .copied()
+            .unwrap_or_default();
+        let mut frame = json!({
+            "id": 0,
+            "name": format!("{:04X}H {}", upc, crate::microasm::disassemble(&micro_code)),
+            "line": 0,
+            "column": 0,
+        });
+        // an unprogrammed row has no line of its own.
+        if let Some(line) = debuggee.line_of(upc) {
+            frame["source"] = Self::source(debuggee);
+            frame["line"] = json!(line + first_line);
+            frame["column"] = json!(1);
+        }
+        Ok(json!({ "stackFrames": [frame], "totalFrames": 1 }))
+    }
+
+    fn variables(&mut self, reference: i64) -> Result<Value, String> {
+        let vm = &self.debuggee()?.vm;
+        let variables = match reference {
+            REGISTERS_REFERENCE => {
+                let mut variables: Vec<Value> = (0..7)
+                    .map(|i| variable(&format!("R{}", i), hex(vm.gpr[i] as u16, 2)))
+                    .collect();
+                variables.extend([
+                    variable("PC", hex(vm.pc as u16, 2)),
+                    variable("IR", hex(vm.ir as u16, 2)),
+                    variable("MDR", hex(vm.mdr as u16, 2)),
+                    variable("MAR", hex(vm.mar as u16, 2)),
+                    variable("STR", hex(vm.str as u16, 2)),
+                    variable("CNT", hex(vm.counter, 4)),
+                    variable("uPC", hex(vm.micro_program_counter, 4)),
+                    variable("SW1", hex(vm.sw1 as u16, 2)),
+                    variable("SW2", hex(vm.sw2 as u16, 2)),
+                    variable("HLT", (vm.hlt as u8).to_string()),
+                ]);
+                variables
+            }
+            FLAGS_REFERENCE => {
+                let flags = vm.arch.flags;
+                [
+                    ("M", flags.minus),
+                    ("Z", flags.zero),
+                    ("C", flags.carry),
+                    ("V", flags.overflow),
+                ]
+                .iter()
+                .map(|(name, bit)| variable(name, (vm.flag(*bit) as u8).to_string()))
+                .collect()
+            }
+            MEMORY_REFERENCE => crate::project::dump_memory(&vm.memory)
+                .into_iter()
+                .filter_map(|line| {
+                    let (addr, bytes) = line.split_once(": ")?;
+                    Some(variable(&format!("{}H", addr), bytes.to_owned()))
+                })
+                .collect(),
+            _ => return Err(format!("no variables {}", reference)),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+}
+
+/// next `Content-Length` framed message. None at end of input.
+pub fn read_message(input: &mut impl BufRead) -> std::io::Result<Option<Value>> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = Some(
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .map_err(|e| invalid(e.to_string()))?,
+                );
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> std::io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// answer requests from `input` until disconnect or end of input.
+pub fn serve(input: impl BufRead + Send + 'static, mut output: impl Write) -> std::io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let mut session = Session::new();
+    while !session.finished {
+        if session.running {
+            match receiver.try_recv() {
+                Ok(message) => session.handle(&message),
+                Err(mpsc::TryRecvError::Empty) => session.run_for(RUN_CHUNK),
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(message) => session.handle(&message),
+                Err(_) => break,
+            }
+        }
+        for message in session.take_output() {
+            write_message(&mut output, &message)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let mut bytes = vec![];
+        write_message(&mut bytes, &json!({ "a": 1 })).unwrap();
+        write_message(&mut bytes, &json!({ "b": "two" })).unwrap();
+        assert!(bytes.starts_with(b"Content-Length: 7\r\n\r\n{\"a\":1}"));
+        let mut input = std::io::Cursor::new(bytes);
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "a": 1 })));
+        assert_eq!(
+            read_message(&mut input).unwrap(),
+            Some(json!({ "b": "two" }))
+        );
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn requests_before_launch_fail() {
+        let mut session = Session::new();
+        session.handle(&json!({ "seq": 1, "type": "request", "command": "stackTrace" }));
+        session
+            .handle(&json!({ "seq": 2, "type": "request", "command": "launch", "arguments": {} }));
+        session.handle(&json!({ "seq": 3, "type": "request", "command": "readMemory" }));
+        let output = session.take_output();
+        assert_eq!(output.len(), 3);
+        assert!(output.iter().all(|message| message["success"] == false));
+        assert_eq!(output[0]["message"], "no program launched");
+        assert_eq!(output[2]["request_seq"], 3);
+        assert_eq!(output[2]["seq"], 3);
+    }
+
+    #[test]
+    fn listing_and_breakpoint_lines() {
+        let mut vm = crate::reference::project(&crate::reference::SAMPLES[1]);
+        vm.micro_program.truncate(0x300);
+        let debuggee = Debuggee::new(vm, "x.micro".to_owned());
+        let listing = debuggee.listing();
+        assert_eq!(listing.lines().count(), debuggee.addresses.len());
+        assert_eq!(listing.lines().next().unwrap(), "0000H  X=PC Z=MAR");
+        assert_eq!(debuggee.line_of(0), Some(0));
+        assert_eq!(
+            debuggee.line_of(0x100),
+            debuggee.addresses.iter().position(|a| *a == 0x100)
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! What one micro code does on the datapath.
+//!
+//! `trace` works out the bus values, the ALU and shifter outputs, the register
+//! written, the memory access and the sequencer of the micro code at the micro
+//! program counter without executing it. `MicroArch::step` and `exec` execute it
+//! by applying the same `Transfer`, which the GUI draws as the last transfer.
+use crate::vm::{AluOp, Branch, MemOp, MicroArch, MicroCode, Register, RegisterOrSwitch, ShiftOp};
+
+/// one micro code's values, as `MicroArch::exec` applies them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    /// micro code address.
+    pub addr: u16,
+    pub micro_code: MicroCode,
+    /// driver of the X bus, None when nothing drives it and it reads 0.
+    pub x_source: Option<RegisterOrSwitch>,
+    pub y_source: Option<RegisterOrSwitch>,
+    pub x: u8,
+    pub y: u8,
+    /// ALU output before the shifter.
+    pub alu: u8,
+    /// shifter output on the Z bus.
+    pub z: u8,
+    /// register written from the Z bus.
+    pub destination: Option<Register>,
+    /// STR after flags and shift carry, before the Z bus write.
+    pub flags: u8,
+    pub memory: Option<Access>,
+    /// CNT after the Z bus write and LDCT or RPCT.
+    pub counter: u16,
+    /// micro code address chosen by the sequencer.
+    pub next: u16,
+}
+
+/// main memory read or write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub op: MemOp,
+    /// MAR after the Z bus write.
+    pub addr: u8,
+    /// byte read into MDR or written from it, 0 for a read past main memory.
+    pub data: u8,
+}
+
+/// values of the micro code at the micro program counter, None outside the control store.
+pub fn trace(vm: &MicroArch) -> Option<Transfer> {
+    let addr = vm.micro_program_counter;
+    let micro_code = *vm.micro_program.get(addr as usize)?;
+    let arch = &vm.arch;
+    let source = |source: RegisterOrSwitch| match source {
+        RegisterOrSwitch::Register(Register::Nop) => None,
+        RegisterOrSwitch::Register(register) => arch.is_source(register).then_some(source),
+        switch => arch.switch(switch).map(|_| switch),
+    };
+    let load = |source: Option<RegisterOrSwitch>| match source {
+        Some(RegisterOrSwitch::Register(register)) => vm.load_register(register),
+        Some(RegisterOrSwitch::Sw1) => vm.sw1,
+        Some(RegisterOrSwitch::Sw2) => vm.sw2,
+        None => 0,
+    };
+    let (x_source, y_source) = (source(micro_code.x_bus), source(micro_code.y_bus));
+    let (x, y) = (load(x_source), load(y_source));
+    let alu = match micro_code.alu {
+        AluOp::XPlusY => x.wrapping_add(y),
+        AluOp::XMinusY => x.wrapping_sub(y),
+        AluOp::XAndY => x & y,
+        AluOp::XorY => x | y,
+        AluOp::XxorY => x ^ y,
+        AluOp::XPlus1 => x.wrapping_add(1),
+        AluOp::XMinus1 => x.wrapping_sub(1),
+    };
+
+    let bits = arch.flags;
+    let (minus, zero, carry, overflow) = (
+        1u8 << bits.minus,
+        1u8 << bits.zero,
+        1u8 << bits.carry,
+        1u8 << bits.overflow,
+    );
+    let mut flags = vm.str;
+    if micro_code.fl {
+        let sum = x as u16 + y as u16;
+        flags &= !(minus | zero | carry | overflow);
+        for (flag, set) in [
+            (minus, alu & 0x80 != 0),
+            (zero, alu == 0),
+            (carry, sum > 0xff),
+            (overflow, sum > 0x7f),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+    }
+    let carry_in = flags & carry != 0;
+    let (z, carry_out) = match micro_code.sft {
+        ShiftOp::Nop => (alu, false),
+        ShiftOp::RRwC => (alu >> 1 | (carry_in as u8) << 7, alu & 1 != 0),
+        ShiftOp::RlwC => (alu << 1 | carry_in as u8, alu & 0x80 != 0),
+        ShiftOp::Srl => (alu >> 1 | (micro_code.sin as u8) << 7, alu & 1 != 0),
+        ShiftOp::Sll | ShiftOp::Sla => (alu << 1 | micro_code.sin as u8, alu & 0x80 != 0),
+        ShiftOp::Sra => (alu & 0x80 | alu >> 1, alu & 1 != 0),
+    };
+    if micro_code.sft != ShiftOp::Nop {
+        flags &= !carry;
+    }
+    if carry_out {
+        flags |= carry;
+    }
+
+    let destination = Some(micro_code.z_bus)
+        .filter(|register| *register != Register::Nop && arch.is_destination(*register));
+    // registers as the memory and the sequencer see them, after the Z bus write.
+    let after = |register: Register| match destination {
+        Some(written) if written == register => z,
+        _ if register == Register::Str => flags,
+        _ => vm.load_register(register),
+    };
+    let memory = match micro_code.mem {
+        MemOp::Nop => None,
+        op => {
+            let addr = after(Register::Mar);
+            let data = match op {
+                MemOp::R => vm.memory.get(addr as usize).copied().unwrap_or(0),
+                _ => after(Register::Mdr),
+            };
+            Some(Access { op, addr, data })
+        }
+    };
+    let mut counter = match destination {
+        Some(Register::Cnt) => z as u16,
+        _ => vm.counter,
+    };
+    let str = after(Register::Str);
+    let jump = addr.wrapping_add(1);
+    let taken = |flag: u8| {
+        if str & flag != 0 {
+            micro_code.addr
+        } else {
+            jump
+        }
+    };
+    let next = match micro_code.branch {
+        Branch::Plus1 => jump,
+        Branch::J => micro_code.addr,
+        Branch::JM => taken(minus),
+        Branch::JZ => taken(zero),
+        Branch::JC => taken(carry),
+        Branch::JV => taken(overflow),
+        Branch::JI => micro_code.addr.wrapping_add(after(Register::Ir) as u16),
+        Branch::Ldct => {
+            counter = micro_code.addr;
+            jump
+        }
+        Branch::Rpct if counter != 0 => {
+            counter -= 1;
+            micro_code.addr
+        }
+        Branch::Rpct => jump,
+    };
+    Some(Transfer {
+        addr,
+        micro_code,
+        x_source,
+        y_source,
+        x,
+        y,
+        alu,
+        z,
+        destination,
+        flags,
+        memory,
+        counter,
+        next,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_engine_on_samples() {
+        // the engine decodes micro codes on its own, `exec` applies `trace`.
+        let mut engine = crate::engine::Engine::new();
+        for sample in &crate::reference::SAMPLES {
+            let mut vm = crate::reference::project(sample);
+            vm.sw1 = 5;
+            vm.sw2 = 3;
+            engine.invalidate_all();
+            let mut cycles = 0;
+            while !vm.hlt && cycles < 10_000 {
+                let transfer = trace(&vm).unwrap();
+                let before = vm.clone();
+                engine.run(&mut vm, 1);
+                cycles += 1;
+                let context = format!("{} at {:04X}H", sample.name, transfer.addr);
+                assert_eq!(vm.micro_program_counter, transfer.next, "{}", context);
+                assert_eq!(vm.counter, transfer.counter, "{}", context);
+                let reads_mdr = matches!(transfer.memory, Some(Access { op: MemOp::R, .. }));
+                match transfer.destination {
+                    Some(Register::Mdr) if reads_mdr => {}
+                    Some(register) => {
+                        assert_eq!(vm.load_register(register), transfer.z, "{}", context)
+                    }
+                    None => assert_eq!(vm.str, transfer.flags, "{}", context),
+                }
+                match transfer.memory {
+                    Some(Access {
+                        op: MemOp::R, data, ..
+                    }) => assert_eq!(vm.mdr, data),
+                    Some(Access { addr, data, .. }) => {
+                        assert_eq!(vm.memory[addr as usize], data, "{}", context)
+                    }
+                    None => assert_eq!(vm.memory, before.memory, "{}", context),
+                }
+                let mut stepped = before;
+                assert_eq!(stepped.step(), Some(transfer), "{}", context);
+            }
+            assert!(vm.hlt, "{} did not halt", sample.name);
+        }
+    }
+
+    #[test]
+    fn shifter_and_unwired_sources() {
+        let micro_code = MicroCode {
+            x_bus: RegisterOrSwitch::Register(Register::R0),
+            y_bus: RegisterOrSwitch::Register(Register::Nop),
+            alu: AluOp::XPlus1,
+            sft: ShiftOp::Srl,
+            sin: true,
+            z_bus: Register::R1,
+            mem: MemOp::W,
+            ..MicroCode::default()
+        };
+        let mut vm = MicroArch::construct(vec![micro_code]);
+        vm.gpr[0] = 0x04;
+        vm.mar = 0x80;
+        vm.mdr = 0x2a;
+        let transfer = trace(&vm).unwrap();
+        assert_eq!(transfer.x_source, Some(micro_code.x_bus));
+        assert_eq!(transfer.y_source, None);
+        assert_eq!((transfer.x, transfer.y, transfer.alu), (0x04, 0, 0x05));
+        assert_eq!(transfer.z, 0x82);
+        assert_eq!(transfer.destination, Some(Register::R1));
+        assert_ne!(transfer.flags & 1 << vm.arch.flags.carry, 0);
+        let write = Access {
+            op: MemOp::W,
+            addr: 0x80,
+            data: 0x2a,
+        };
+        assert_eq!(transfer.memory, Some(write));
+        assert_eq!(transfer.next, 1);
+        vm.micro_program_counter = 1;
+        assert_eq!(trace(&vm), None);
+    }
+}
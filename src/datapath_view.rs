@@ -0,0 +1,399 @@
+use crate::arch::OpSpec;
+use crate::datapath::Transfer;
+use crate::vm::{MemOp, MicroArch, Register, RegisterOrSwitch, ShiftOp};
+use eframe::egui::{
+    Align2, Color32, Painter, Pos2, Rect, Sense, Shape, Stroke, TextStyle, Ui, Vec2,
+};
+
+const X_COLOR: Color32 = Color32::LIGHT_BLUE;
+const Y_COLOR: Color32 = Color32::LIGHT_GREEN;
+const Z_COLOR: Color32 = Color32::GOLD;
+const MEMORY_COLOR: Color32 = Color32::LIGHT_RED;
+
+// layout in points from the top left corner of the diagram.
+const WIDTH: f32 = 600.0;
+const ROW: f32 = 18.0;
+const TOP: f32 = 24.0;
+const MEMORY: (f32, f32) = (10.0, 90.0);
+const FILE: (f32, f32) = (120.0, 220.0);
+const Z_BUS: f32 = 235.0;
+const X_BUS: f32 = 255.0;
+const Y_BUS: f32 = 275.0;
+const ALU: (f32, f32) = (340.0, 410.0);
+const SHIFTER: (f32, f32) = (450.0, 510.0);
+const Z_RETURN: f32 = 560.0;
+
+/// Datapath diagram of `last`, or of the micro code at the micro program counter
+/// when nothing was stepped since the machine last changed.
+pub fn datapath_view(ui: &mut Ui, vm: &MicroArch, last: Option<&Transfer>) {
+    let (transfer, executed) = match last.copied() {
+        Some(transfer) => (transfer, true),
+        None => match crate::datapath::trace(vm) {
+            Some(transfer) => (transfer, false),
+            None => {
+                ui.label("micro code address is outside the control store");
+                return;
+            }
+        },
+    };
+    if executed {
+        ui.label(format!(
+            "executed {:04X}H, next {:04X}H",
+            transfer.addr, transfer.next
+        ));
+    } else {
+        ui.label(format!(
+            "{:04X}H not executed yet, values from the current registers",
+            transfer.addr
+        ));
+    }
+    ui.label(crate::microasm::describe(&transfer.micro_code));
+
+    let arch = &vm.arch;
+    let hex = |value: u8| format!("{:02X}H", value);
+    let mut rows: Vec<(RegisterOrSwitch, String, String)> = arch
+        .registers
+        .iter()
+        .filter(|spec| spec.register != Register::Nop)
+        .map(|spec| {
+            let value = match spec.register {
+                Register::Cnt => format!("{:04X}H", vm.counter),
+                register => hex(vm.load_register(register)),
+            };
+            (
+                RegisterOrSwitch::Register(spec.register),
+                spec.name.clone(),
+                value,
+            )
+        })
+        .collect();
+    rows.extend(arch.switches.iter().map(|spec| {
+        let value = match spec.switch {
+            RegisterOrSwitch::Sw1 => vm.sw1,
+            _ => vm.sw2,
+        };
+        (spec.switch, spec.name.clone(), hex(value))
+    }));
+
+    let bottom = TOP + rows.len().max(10) as f32 * ROW;
+    let (response, painter) =
+        ui.allocate_painter(Vec2::new(WIDTH, bottom + 2.0 * ROW), Sense::hover());
+    let origin = response.rect.min;
+    let at = |x: f32, y: f32| origin + Vec2::new(x, y);
+    let x = |x: f32| origin.x + x;
+    let text = ui.visuals().text_color();
+    let weak = ui.visuals().weak_text_color();
+    let idle = Stroke::new(1.0, weak);
+    let active = |color| Stroke::new(2.5, color);
+    let label = |pos: Pos2, anchor: Align2, text: &str, color: Color32| {
+        painter.text(pos, anchor, text, TextStyle::Monospace, color);
+    };
+    let row_y = |index: usize| TOP + (index as f32 + 0.5) * ROW;
+    let row_of = |key: RegisterOrSwitch| rows.iter().position(|row| row.0 == key);
+    let destination = transfer.destination.map(RegisterOrSwitch::Register);
+
+    // register file
+    for (index, (key, name, value)) in rows.iter().enumerate() {
+        let rect = Rect::from_min_max(
+            at(FILE.0, TOP + index as f32 * ROW),
+            at(FILE.1, TOP + (index + 1) as f32 * ROW),
+        );
+        if Some(*key) == transfer.x_source {
+            painter.rect_filled(rect, 0.0, X_COLOR.linear_multiply(0.3));
+        } else if Some(*key) == transfer.y_source {
+            painter.rect_filled(rect, 0.0, Y_COLOR.linear_multiply(0.3));
+        }
+        let stroke = if Some(*key) == destination {
+            active(Z_COLOR)
+        } else {
+            idle
+        };
+        painter.rect_stroke(rect, 0.0, stroke);
+        label(
+            rect.left_center() + Vec2::new(4.0, 0.0),
+            Align2::LEFT_CENTER,
+            name,
+            text,
+        );
+        label(
+            rect.right_center() - Vec2::new(4.0, 0.0),
+            Align2::RIGHT_CENTER,
+            value,
+            text,
+        );
+    }
+
+    // X and Y bus into the ALU
+    let alu = Rect::from_min_max(at(ALU.0, TOP), at(ALU.1, TOP + 5.0 * ROW));
+    let buses = [
+        (
+            "X",
+            X_BUS,
+            alu.top() + ROW,
+            transfer.x_source,
+            transfer.x,
+            X_COLOR,
+        ),
+        (
+            "Y",
+            Y_BUS,
+            alu.bottom() - ROW,
+            transfer.y_source,
+            transfer.y,
+            Y_COLOR,
+        ),
+    ];
+    for (name, bus, input, source, value, color) in buses {
+        path(&painter, &[at(bus, TOP), at(bus, bottom)], idle, false);
+        let input = Pos2::new(alu.left(), input);
+        match source.and_then(&row_of) {
+            Some(row) => {
+                let from = at(FILE.1, row_y(row));
+                let points = [
+                    from,
+                    Pos2::new(x(bus), from.y),
+                    Pos2::new(x(bus), input.y),
+                    input,
+                ];
+                path(&painter, &points, active(color), true);
+            }
+            None => path(&painter, &[Pos2::new(x(bus), input.y), input], idle, true),
+        }
+        let color = if source.is_some() { color } else { weak };
+        label(
+            input - Vec2::new(4.0, 2.0),
+            Align2::RIGHT_BOTTOM,
+            &format!("{}={}", name, hex(value)),
+            color,
+        );
+    }
+
+    // ALU, flags and shifter
+    painter.rect_stroke(alu, 4.0, active(text));
+    label(
+        alu.center_top() + Vec2::new(0.0, 4.0),
+        Align2::CENTER_TOP,
+        "ALU",
+        weak,
+    );
+    label(
+        alu.center(),
+        Align2::CENTER_CENTER,
+        &op_name(&arch.alu_ops, transfer.micro_code.alu),
+        text,
+    );
+
+    let fl = transfer.micro_code.fl;
+    let flags = Rect::from_min_max(
+        Pos2::new(alu.left() + 15.0, alu.bottom() + ROW),
+        Pos2::new(alu.right() - 15.0, alu.bottom() + 2.0 * ROW),
+    );
+    let flag_stroke = if fl { active(text) } else { idle };
+    path(
+        &painter,
+        &[alu.center_bottom(), flags.center_top()],
+        flag_stroke,
+        true,
+    );
+    painter.rect_stroke(flags, 2.0, flag_stroke);
+    label(
+        flags.center(),
+        Align2::CENTER_CENTER,
+        "FL",
+        if fl { text } else { weak },
+    );
+    let bits = arch.flags;
+    let set = |bit: u8| transfer.flags >> bit & 1;
+    label(
+        flags.center_bottom() + Vec2::new(0.0, 4.0),
+        Align2::CENTER_TOP,
+        &format!(
+            "M{} Z{} C{} V{}",
+            set(bits.minus),
+            set(bits.zero),
+            set(bits.carry),
+            set(bits.overflow)
+        ),
+        if fl { text } else { weak },
+    );
+
+    let middle = alu.center().y;
+    let shifter = Rect::from_min_max(
+        Pos2::new(x(SHIFTER.0), middle - ROW),
+        Pos2::new(x(SHIFTER.1), middle + ROW),
+    );
+    path(
+        &painter,
+        &[alu.right_center(), shifter.left_center()],
+        active(text),
+        true,
+    );
+    label(
+        Pos2::new((alu.right() + shifter.left()) / 2.0, middle - 2.0),
+        Align2::CENTER_BOTTOM,
+        &hex(transfer.alu),
+        text,
+    );
+    let shifting = transfer.micro_code.sft != ShiftOp::Nop;
+    painter.rect_stroke(shifter, 4.0, if shifting { active(text) } else { idle });
+    label(
+        shifter.center(),
+        Align2::CENTER_CENTER,
+        &op_name(&arch.shift_ops, transfer.micro_code.sft),
+        if shifting { text } else { weak },
+    );
+    if transfer.micro_code.sin {
+        label(
+            shifter.center_bottom() + Vec2::new(0.0, 4.0),
+            Align2::CENTER_TOP,
+            "SIN=1",
+            text,
+        );
+    }
+
+    // Z bus back into the register file
+    let z_out = Pos2::new(x(Z_RETURN), middle);
+    let z_bottom = origin.y + bottom + ROW;
+    path(
+        &painter,
+        &[at(Z_BUS, TOP), at(Z_BUS, bottom + ROW)],
+        idle,
+        false,
+    );
+    match destination.and_then(&row_of) {
+        Some(row) => {
+            let to = at(FILE.1, row_y(row));
+            let points = [
+                shifter.right_center(),
+                z_out,
+                Pos2::new(z_out.x, z_bottom),
+                Pos2::new(x(Z_BUS), z_bottom),
+                Pos2::new(x(Z_BUS), to.y),
+                to,
+            ];
+            path(&painter, &points, active(Z_COLOR), true);
+            label(
+                z_out + Vec2::new(4.0, 0.0),
+                Align2::LEFT_CENTER,
+                &format!("Z={}", hex(transfer.z)),
+                Z_COLOR,
+            );
+        }
+        None => {
+            let points = [
+                shifter.right_center(),
+                z_out,
+                Pos2::new(z_out.x, z_bottom),
+                Pos2::new(x(Z_BUS), z_bottom),
+            ];
+            path(&painter, &points, idle, false);
+            label(
+                z_out + Vec2::new(4.0, 0.0),
+                Align2::LEFT_CENTER,
+                &format!("Z={}", hex(transfer.z)),
+                weak,
+            );
+        }
+    }
+
+    // main memory on MAR and MDR
+    let memory = Rect::from_min_max(at(MEMORY.0, TOP), at(MEMORY.1, bottom));
+    let access = transfer.memory;
+    painter.rect_stroke(
+        memory,
+        4.0,
+        if access.is_some() {
+            active(MEMORY_COLOR)
+        } else {
+            idle
+        },
+    );
+    label(
+        memory.center_top() + Vec2::new(0.0, 4.0),
+        Align2::CENTER_TOP,
+        "Memory",
+        text,
+    );
+    let mar = row_of(RegisterOrSwitch::Register(Register::Mar)).map(|row| origin.y + row_y(row));
+    let mdr = row_of(RegisterOrSwitch::Register(Register::Mdr)).map(|row| origin.y + row_y(row));
+    let (file, port) = (x(FILE.0), memory.right());
+    if let Some(mar) = mar {
+        let stroke = if access.is_some() {
+            active(MEMORY_COLOR)
+        } else {
+            idle
+        };
+        path(
+            &painter,
+            &[Pos2::new(file, mar), Pos2::new(port, mar)],
+            stroke,
+            true,
+        );
+    }
+    if let Some(mdr) = mdr {
+        let (from, to) = (Pos2::new(file, mdr), Pos2::new(port, mdr));
+        match access {
+            Some(access) if access.op == MemOp::R => {
+                path(&painter, &[to, from], active(MEMORY_COLOR), true)
+            }
+            Some(_) => path(&painter, &[from, to], active(MEMORY_COLOR), true),
+            None => path(&painter, &[from, to], idle, false),
+        }
+    }
+    if let Some(access) = access {
+        let operation = if access.op == MemOp::R {
+            "read"
+        } else {
+            "write"
+        };
+        label(
+            memory.center(),
+            Align2::CENTER_BOTTOM,
+            operation,
+            MEMORY_COLOR,
+        );
+        label(
+            memory.center(),
+            Align2::CENTER_TOP,
+            &format!("[{}]={}", hex(access.addr), hex(access.data)),
+            MEMORY_COLOR,
+        );
+    }
+
+    // sequencer
+    let mut sequencer = format!(
+        "µPC {:04X}H → {:04X}H  {}",
+        transfer.addr,
+        transfer.next,
+        op_name(&arch.branches, transfer.micro_code.branch)
+    );
+    if transfer.micro_code.hlt {
+        sequencer.push_str("  HLT");
+    }
+    label(
+        at(Y_BUS + 15.0, TOP + 9.0 * ROW),
+        Align2::LEFT_CENTER,
+        &sequencer,
+        text,
+    );
+}
+
+/// name of `op` in the architecture.
+fn op_name<T: Copy + PartialEq + ToString>(ops: &[OpSpec<T>], op: T) -> String {
+    ops.iter()
+        .find(|spec| spec.op == op)
+        .map_or_else(|| format!("{}?", op.to_string()), |spec| spec.name.clone())
+}
+
+/// wire through `points`, with an arrow head at the end.
+fn path(painter: &Painter, points: &[Pos2], stroke: Stroke, arrow: bool) {
+    painter.add(Shape::line(points.to_vec(), stroke));
+    if let [.., from, to] = points {
+        if arrow && from != to {
+            let direction = (*to - *from).normalized() * 6.0;
+            let side = direction.rot90() * 0.6;
+            let head = vec![*to, *to - direction + side, *to - direction - side];
+            painter.add(Shape::convex_polygon(head, stroke.color, Stroke::none()));
+        }
+    }
+}
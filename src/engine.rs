@@ -0,0 +1,657 @@
+//! Pre-decoded execution engine.
+//!
+//! `MicroArch::exec` matches on every field and consults the architecture each cycle,
+//! which is fine for stepping. `Engine` decodes each micro code once into register file
+//! indices with the bus wiring already resolved and then runs a tight loop with a cycle
+//! budget. Decoded rows are cached until invalidated, so callers editing the micro
+//! program must call `invalidate` for the changed address and callers changing the
+//! architecture must call `invalidate_all`.
+use crate::arch::Architecture;
+use crate::vm::{AluOp, Branch, MemOp, MicroArch, MicroCode, Register, RegisterOrSwitch, ShiftOp};
+
+// register file slots. ZERO is never written, SINK absorbs writes to nothing.
+const ZERO: u8 = 0;
+const PC: usize = 8;
+const IR: usize = 9;
+const MDR: usize = 10;
+const MAR: usize = 11;
+const STR: usize = 12;
+const SW1: usize = 13;
+const SW2: usize = 14;
+const CNT: u8 = 15;
+const SINK: u8 = 16;
+const SLOTS: usize = 17;
+
+#[derive(Copy, Clone)]
+struct Decoded {
+    x: u8,
+    y: u8,
+    alu: AluOp,
+    sft: ShiftOp,
+    sin: bool,
+    fl: bool,
+    z: u8,
+    mem: MemOp,
+    branch: Branch,
+    hlt: bool,
+    addr: u16,
+    /// uses an op the architecture lacks.
+    fault: bool,
+}
+
+const UNDECODED: Decoded = Decoded {
+    x: ZERO,
+    y: ZERO,
+    alu: AluOp::XPlusY,
+    sft: ShiftOp::Nop,
+    sin: false,
+    fl: false,
+    z: SINK,
+    mem: MemOp::Nop,
+    branch: Branch::Plus1,
+    hlt: false,
+    addr: 0,
+    fault: false,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RunResult {
+    /// micro codes executed.
+    pub cycles: u64,
+    /// stopped by a HLT bit rather than by the budget.
+    pub halted: bool,
+    /// stopped in front of a micro code that cannot execute, `MicroArch::fault`
+    /// says why.
+    pub faulted: bool,
+}
+
+#[derive(Default)]
+pub struct Engine {
+    decoded: Vec<Decoded>,
+    valid: Vec<bool>,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// drop the decoded form of one micro code.
+    pub fn invalidate(&mut self, addr: usize) {
+        if let Some(valid) = self.valid.get_mut(addr) {
+            *valid = false;
+        }
+    }
+    /// drop every decoded micro code.
+    pub fn invalidate_all(&mut self) {
+        self.valid.iter_mut().for_each(|valid| *valid = false);
+    }
+    /// execute until HLT, a fault or until `budget` micro codes ran.
+    ///
+    /// the resulting machine state is identical to calling `MicroArch::exec` the same
+    /// number of times.
+    pub fn run(&mut self, vm: &mut MicroArch, budget: u64) -> RunResult {
+        self.run_until(vm, budget, |_| false)
+    }
+    /// `run`, also stopping once the sequencer moves to a micro address `stop`
+    /// holds for.
+    pub fn run_until(
+        &mut self,
+        vm: &mut MicroArch,
+        budget: u64,
+        mut stop: impl FnMut(u16) -> bool,
+    ) -> RunResult {
+        if vm.hlt {
+            return RunResult {
+                cycles: 0,
+                halted: true,
+                faulted: false,
+            };
+        }
+        if self.decoded.len() != vm.micro_program.len() {
+            self.decoded = vec![UNDECODED; vm.micro_program.len()];
+            self.valid = vec![false; vm.micro_program.len()];
+        }
+        let flags = vm.arch.flags;
+        let (minus, zero, carry, overflow) = (
+            1u8 << flags.minus,
+            1u8 << flags.zero,
+            1u8 << flags.carry,
+            1u8 << flags.overflow,
+        );
+
+        let mut r = [0u8; SLOTS];
+        r[1..8].copy_from_slice(&vm.gpr);
+        r[PC] = vm.pc;
+        r[IR] = vm.ir;
+        r[MDR] = vm.mdr;
+        r[MAR] = vm.mar;
+        r[STR] = vm.str;
+        r[SW1] = vm.sw1;
+        r[SW2] = vm.sw2;
+        r[CNT as usize] = vm.counter as u8;
+        let mut counter = vm.counter;
+        let mut upc = vm.micro_program_counter;
+        let mut cycles = 0;
+        let mut halted = false;
+        let mut faulted = false;
+
+        const MSB: u8 = 0b10000000;
+        const LSB: u8 = 0b00000001;
+        while cycles < budget {
+            let index = upc as usize;
+            match self.valid.get_mut(index) {
+                Some(true) => {}
+                Some(valid) => {
+                    self.decoded[index] = decode(&vm.arch, &vm.micro_program[index]);
+                    *valid = true;
+                }
+                None => {
+                    faulted = true;
+                    break;
+                }
+            }
+            let d = self.decoded[index];
+            if d.fault {
+                faulted = true;
+                break;
+            }
+
+            let x = r[d.x as usize];
+            let y = r[d.y as usize];
+            let alu_out = match d.alu {
+                AluOp::XPlusY => x.wrapping_add(y),
+                AluOp::XMinusY => x.wrapping_sub(y),
+                AluOp::XAndY => x & y,
+                AluOp::XorY => x | y,
+                AluOp::XxorY => x ^ y,
+                AluOp::XPlus1 => x.wrapping_add(1),
+                AluOp::XMinus1 => x.wrapping_sub(1),
+            };
+            let mut str = r[STR];
+            if d.fl {
+                let sum = x as u16 + y as u16;
+                str &= !(minus | zero | carry | overflow);
+                if alu_out & MSB != 0 {
+                    str |= minus;
+                }
+                if alu_out == 0 {
+                    str |= zero;
+                }
+                if sum > 255 {
+                    str |= carry;
+                }
+                if sum > 127 {
+                    str |= overflow;
+                }
+            }
+            let cf = str & carry != 0;
+            if d.sft != ShiftOp::Nop {
+                str &= !carry;
+            }
+            let z = match d.sft {
+                ShiftOp::Nop => alu_out,
+                ShiftOp::RRwC => {
+                    if alu_out & LSB != 0 {
+                        str |= carry;
+                    }
+                    (alu_out >> 1) | if cf { MSB } else { 0 }
+                }
+                ShiftOp::RlwC => {
+                    if alu_out & MSB != 0 {
+                        str |= carry;
+                    }
+                    (alu_out << 1) | cf as u8
+                }
+                ShiftOp::Srl => {
+                    if alu_out & LSB != 0 {
+                        str |= carry;
+                    }
+                    (alu_out >> 1) | if d.sin { MSB } else { 0 }
+                }
+                ShiftOp::Sll | ShiftOp::Sla => {
+                    if alu_out & MSB != 0 {
+                        str |= carry;
+                    }
+                    (alu_out << 1) | d.sin as u8
+                }
+                ShiftOp::Sra => {
+                    if alu_out & LSB != 0 {
+                        str |= carry;
+                    }
+                    (alu_out & MSB) | (alu_out >> 1)
+                }
+            };
+            let mar = if d.z == MAR as u8 { z } else { r[MAR] };
+            if d.mem != MemOp::Nop && mar as usize >= vm.memory.len() {
+                faulted = true;
+                break;
+            }
+            cycles += 1;
+            r[STR] = str;
+            r[d.z as usize] = z;
+            if d.z == CNT {
+                counter = z as u16;
+            }
+            match d.mem {
+                MemOp::Nop => {}
+                MemOp::R => r[MDR] = vm.memory[r[MAR] as usize],
+                MemOp::W => vm.memory[r[MAR] as usize] = r[MDR],
+            }
+            let next = upc.wrapping_add(1);
+            let taken = |flag: u8| if r[STR] & flag != 0 { d.addr } else { next };
+            upc = match d.branch {
+                Branch::Plus1 => next,
+                Branch::J => d.addr,
+                Branch::JM => taken(minus),
+                Branch::JZ => taken(zero),
+                Branch::JC => taken(carry),
+                Branch::JV => taken(overflow),
+                Branch::JI => d.addr.wrapping_add(r[IR] as u16),
+                Branch::Ldct => {
+                    counter = d.addr;
+                    r[CNT as usize] = counter as u8;
+                    next
+                }
+                Branch::Rpct => {
+                    if counter != 0 {
+                        counter -= 1;
+                        r[CNT as usize] = counter as u8;
+                        d.addr
+                    } else {
+                        next
+                    }
+                }
+            };
+            if d.hlt {
+                halted = true;
+                break;
+            }
+            if stop(upc) {
+                break;
+            }
+        }
+
+        vm.gpr.copy_from_slice(&r[1..8]);
+        vm.pc = r[PC];
+        vm.ir = r[IR];
+        vm.mdr = r[MDR];
+        vm.mar = r[MAR];
+        vm.str = r[STR];
+        vm.counter = counter;
+        vm.micro_program_counter = upc;
+        vm.hlt = halted;
+        RunResult {
+            cycles,
+            halted,
+            faulted,
+        }
+    }
+}
+
+fn decode(arch: &Architecture, micro_code: &MicroCode) -> Decoded {
+    let source = |source: RegisterOrSwitch| match source {
+        RegisterOrSwitch::Register(register) if arch.is_source(register) => slot(register),
+        RegisterOrSwitch::Register(_) => ZERO,
+        switch if arch.switch(switch).is_none() => ZERO,
+        RegisterOrSwitch::Sw1 => SW1 as u8,
+        RegisterOrSwitch::Sw2 => SW2 as u8,
+    };
+    Decoded {
+        x: source(micro_code.x_bus),
+        y: source(micro_code.y_bus),
+        alu: micro_code.alu,
+        sft: micro_code.sft,
+        sin: micro_code.sin,
+        fl: micro_code.fl,
+        z: match micro_code.z_bus {
+            Register::Nop => SINK,
+            register if arch.is_destination(register) => slot(register),
+            _ => SINK,
+        },
+        mem: micro_code.mem,
+        branch: micro_code.branch,
+        hlt: micro_code.hlt,
+        addr: micro_code.addr,
+        fault: arch.ops_available(micro_code).is_err(),
+    }
+}
+
+fn slot(register: Register) -> u8 {
+    match register {
+        Register::Nop => ZERO,
+        Register::Pc => PC as u8,
+        Register::Ir => IR as u8,
+        Register::Mdr => MDR as u8,
+        Register::Mar => MAR as u8,
+        Register::Str => STR as u8,
+        Register::Cnt => CNT,
+        gpr => gpr.gpr_index().map_or(ZERO, |index| index as u8 + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::MicroCode;
+
+    /// xorshift, enough to scatter micro code fields.
+    struct Random(u64);
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.next() as usize % items.len()]
+        }
+    }
+
+    fn random_micro_code(random: &mut Random, arch: &Architecture) -> MicroCode {
+        let sources: Vec<RegisterOrSwitch> = arch
+            .switches
+            .iter()
+            .map(|s| s.switch)
+            .chain(
+                arch.registers
+                    .iter()
+                    .map(|r| RegisterOrSwitch::Register(r.register)),
+            )
+            .collect();
+        let registers: Vec<Register> = arch.registers.iter().map(|r| r.register).collect();
+        let ops = |random: &mut Random, len: usize| random.next() as usize % len;
+        MicroCode {
+            x_bus: random.pick(&sources),
+            y_bus: random.pick(&sources),
+            alu: arch.alu_ops[ops(random, arch.alu_ops.len())].op,
+            sft: arch.shift_ops[ops(random, arch.shift_ops.len())].op,
+            sin: random.next() % 2 == 0,
+            fl: random.next() % 2 == 0,
+            z_bus: random.pick(&registers),
+            mem: arch.mem_ops[ops(random, arch.mem_ops.len())].op,
+            branch: arch.branches[ops(random, arch.branches.len())].op,
+            hlt: random.next() % 64 == 0,
+            addr: (random.next() % 0x100) as u16,
+        }
+    }
+
+    fn random_vm(random: &mut Random) -> MicroArch {
+        let mut arch = Architecture::default();
+        // wire some machines differently so resolution of the bus is covered.
+        match random.next() % 3 {
+            0 => {}
+            1 => {
+                arch.registers.retain(|r| r.register != Register::R3);
+                arch.switches.pop();
+                arch.flags.zero = 6;
+                arch.flags.carry = 5;
+            }
+            _ => {
+                for register in arch.registers.iter_mut().skip(4).step_by(2) {
+                    register.source = false;
+                }
+                for register in arch.registers.iter_mut().skip(3).step_by(3) {
+                    register.destination = false;
+                }
+            }
+        }
+        let mut micro_program = vec![MicroCode::default(); 1 << 16];
+        for micro_code in micro_program.iter_mut().take(0x100) {
+            *micro_code = random_micro_code(random, &arch);
+        }
+        let mut vm = MicroArch::construct(micro_program);
+        vm.arch = arch;
+        for byte in vm.memory.iter_mut() {
+            *byte = random.next() as u8;
+        }
+        for gpr in vm.gpr.iter_mut() {
+            *gpr = random.next() as u8;
+        }
+        vm.pc = random.next() as u8;
+        vm.ir = random.next() as u8;
+        vm.mdr = random.next() as u8;
+        vm.mar = random.next() as u8;
+        vm.str = random.next() as u8;
+        vm.sw1 = random.next() as u8;
+        vm.sw2 = random.next() as u8;
+        vm.counter = random.next() as u16 % 8;
+        vm
+    }
+
+    fn assert_same_state(reference: &MicroArch, fast: &MicroArch, context: &str) {
+        assert_eq!(reference.gpr, fast.gpr, "gpr {}", context);
+        assert_eq!(
+            (
+                reference.pc,
+                reference.ir,
+                reference.mdr,
+                reference.mar,
+                reference.str
+            ),
+            (fast.pc, fast.ir, fast.mdr, fast.mar, fast.str),
+            "PC IR MDR MAR STR {}",
+            context
+        );
+        assert_eq!(reference.counter, fast.counter, "counter {}", context);
+        assert_eq!(
+            reference.micro_program_counter, fast.micro_program_counter,
+            "micro program counter {}",
+            context
+        );
+        assert_eq!(reference.hlt, fast.hlt, "hlt {}", context);
+        assert!(reference.memory == fast.memory, "memory {}", context);
+    }
+
+    /// run reference interpreter the way `Engine::run` counts cycles.
+    fn reference_run(vm: &mut MicroArch, budget: u64) -> RunResult {
+        let result = |cycles, halted, faulted| RunResult {
+            cycles,
+            halted,
+            faulted,
+        };
+        if vm.hlt {
+            return result(0, true, false);
+        }
+        let mut cycles = 0;
+        while cycles < budget {
+            if vm.fault().is_some() {
+                return result(cycles, false, true);
+            }
+            cycles += 1;
+            if vm.exec() {
+                return result(cycles, true, false);
+            }
+        }
+        result(cycles, false, false)
+    }
+
+    #[test]
+    fn matches_reference_interpreter_on_random_programs() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for case in 0..200 {
+            let mut reference = random_vm(&mut random);
+            let mut fast = reference.clone();
+            let mut engine = Engine::new();
+            // resume a few times to cover halts in the middle of a run.
+            for round in 0..4 {
+                let budget = 1 + random.next() % 500;
+                let expected = reference_run(&mut reference, budget);
+                let result = engine.run(&mut fast, budget);
+                let context = format!("case {} round {}", case, round);
+                assert_eq!(expected, result, "{}", context);
+                assert_same_state(&reference, &fast, &context);
+                reference.start();
+                fast.start();
+            }
+        }
+    }
+
+    #[test]
+    fn matches_reference_interpreter_on_samples() {
+        for sample in &crate::reference::SAMPLES {
+            let mut reference = crate::reference::project(sample);
+            reference.sw1 = 13;
+            reference.sw2 = 11;
+            let mut fast = reference.clone();
+            let expected = reference_run(&mut reference, 1_000_000);
+            let result = Engine::new().run(&mut fast, 1_000_000);
+            assert!(result.halted, "{}", sample.name);
+            assert_eq!(expected, result, "{}", sample.name);
+            assert_same_state(&reference, &fast, sample.name);
+        }
+    }
+
+    #[test]
+    fn budget_limits_cycles() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 0x100]);
+        let result = Engine::new().run(&mut vm, 10);
+        assert_eq!(
+            result,
+            RunResult {
+                cycles: 10,
+                halted: false,
+                faulted: false
+            }
+        );
+        assert_eq!(vm.micro_program_counter, 10);
+    }
+
+    #[test]
+    fn invalidate_picks_up_edits() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 0x100]);
+        let mut engine = Engine::new();
+        engine.run(&mut vm, 4);
+        vm.micro_program_counter = 0;
+        vm.micro_program[2].hlt = true;
+        engine.invalidate(2);
+        let result = engine.run(&mut vm, 10);
+        assert_eq!(
+            result,
+            RunResult {
+                cycles: 3,
+                halted: true,
+                faulted: false
+            }
+        );
+    }
+
+    #[test]
+    fn architecture_change_redecodes() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 0x100]);
+        vm.micro_program[0] = MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::R0,
+            hlt: true,
+            ..MicroCode::default()
+        };
+        vm.sw1 = 0x42;
+        let mut engine = Engine::new();
+        engine.run(&mut vm, 1);
+        assert_eq!(vm.gpr[0], 0x42);
+        vm.reset_register();
+        vm.sw1 = 0x42;
+        vm.arch.switches.clear();
+        engine.invalidate_all();
+        engine.run(&mut vm, 1);
+        assert_eq!(vm.gpr[0], 0);
+    }
+
+    #[test]
+    fn run_until_stops_at_address() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 0x100]);
+        let result = Engine::new().run_until(&mut vm, 100, |upc| upc == 5);
+        assert_eq!(
+            result,
+            RunResult {
+                cycles: 5,
+                halted: false,
+                faulted: false
+            }
+        );
+        assert_eq!(vm.micro_program_counter, 5);
+    }
+
+    #[test]
+    fn truncated_machine_faults() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 4]);
+        let mut reference = vm.clone();
+        let result = Engine::new().run(&mut vm, 100);
+        assert_eq!(reference_run(&mut reference, 100), result);
+        assert!(result.faulted);
+        assert_eq!(vm.micro_program_counter, 4);
+
+        // the read sees MAR as the same micro code writes it.
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 0x100]);
+        vm.memory.truncate(0x10);
+        vm.sw1 = 0x20;
+        vm.micro_program[1] = MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::Mar,
+            mem: MemOp::R,
+            ..MicroCode::default()
+        };
+        let mut reference = vm.clone();
+        let result = Engine::new().run(&mut vm, 100);
+        assert_eq!(reference_run(&mut reference, 100), result);
+        assert_same_state(&reference, &vm, "truncated memory");
+        assert_eq!((result.cycles, result.faulted), (1, true));
+        assert_eq!(vm.mar, 0);
+        assert!(vm.fault().unwrap().contains("MAR 20H"));
+    }
+
+    #[test]
+    fn disabled_op_faults() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 0x100]);
+        vm.micro_program[1] = MicroCode {
+            alu: AluOp::XxorY,
+            ..MicroCode::default()
+        };
+        vm.arch.alu_ops.retain(|spec| spec.op != AluOp::XxorY);
+        let mut reference = vm.clone();
+        let result = Engine::new().run(&mut vm, 10);
+        assert_eq!(
+            result,
+            RunResult {
+                cycles: 1,
+                halted: false,
+                faulted: true
+            }
+        );
+        assert_eq!(reference_run(&mut reference, 10), result);
+        assert_same_state(&reference, &vm, "disabled op");
+        assert!(vm.fault().unwrap().starts_with("0001H"));
+        assert!(vm.exec());
+        assert_eq!(vm.micro_program_counter, 1);
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 1 << 16]);
+        vm.gpr[0] = 0xff;
+        vm.ir = 0x02;
+        let code = |alu, branch, addr| MicroCode {
+            x_bus: RegisterOrSwitch::Register(Register::R0),
+            alu,
+            z_bus: Register::R0,
+            branch,
+            addr,
+            ..MicroCode::default()
+        };
+        // R0 + 1, R0 - 1 at the end of the control store, then JI past FFFFH.
+        vm.micro_program_counter = 0xfffe;
+        vm.micro_program[0xfffe] = code(AluOp::XPlus1, Branch::Plus1, 0);
+        vm.micro_program[0xffff] = code(AluOp::XMinus1, Branch::Plus1, 0);
+        vm.micro_program[0] = code(AluOp::XPlus1, Branch::JI, 0xffff);
+        vm.micro_program[1] = MicroCode {
+            hlt: true,
+            ..MicroCode::default()
+        };
+        let mut reference = vm.clone();
+        let result = Engine::new().run(&mut vm, 10);
+        assert_eq!(reference_run(&mut reference, 10), result);
+        assert_same_state(&reference, &vm, "wrapping");
+        assert_eq!((result.cycles, result.halted), (4, true));
+        assert_eq!(vm.gpr[0], 0x00);
+    }
+}
@@ -0,0 +1,192 @@
+//! File access behind the GUI.
+//!
+//! Natively files come from `rfd` dialogs and `std::fs`. In the browser `open`
+//! uploads through a file input, `save` downloads, and the project is kept in local
+//! storage between visits. Uploads finish after the frame that asked for them, so
+//! opened files come back through `take` on both targets.
+use crate::vm::MicroArch;
+use eframe::epi::{Frame, Storage};
+use std::sync::{Arc, Mutex};
+
+/// what an opened file is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    Project,
+    MemoryImage,
+    Architecture,
+}
+
+pub struct Opened {
+    pub purpose: Purpose,
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+/// file type offered by dialogs.
+pub struct Filter {
+    pub name: String,
+    pub extensions: Vec<&'static str>,
+}
+
+impl Filter {
+    pub fn new(name: impl Into<String>, extensions: &[&'static str]) -> Self {
+        Self {
+            name: name.into(),
+            extensions: extensions.to_vec(),
+        }
+    }
+}
+
+/// named file contents to write.
+pub type Files = Vec<(String, Vec<u8>)>;
+
+#[derive(Default)]
+pub struct FileIo {
+    opened: Arc<Mutex<Vec<Result<Opened, String>>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileIo {
+    /// ask for a file, it arrives through `take`.
+    pub fn open(&self, _frame: &Frame, purpose: Purpose, filters: &[Filter]) {
+        let dialog = filters
+            .iter()
+            .fold(rfd::FileDialog::new(), |dialog, filter| {
+                dialog.add_filter(&filter.name, &filter.extensions)
+            });
+        if let Some(path) = dialog.pick_file() {
+            let opened = std::fs::read(&path)
+                .map(|bytes| Opened {
+                    purpose,
+                    name: path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    bytes,
+                })
+                .map_err(|e| format!("{} : {}", path.display(), e));
+            self.opened.lock().unwrap().push(opened);
+        }
+    }
+
+    /// offer `name` for saving. `files` gets the chosen file name and returns every
+    /// file to write next to it, several for a ROM split into chips.
+    pub fn save(
+        &self,
+        name: &str,
+        filters: &[Filter],
+        files: impl FnOnce(&str) -> Result<Files, String>,
+    ) -> Result<(), String> {
+        let dialog = filters.iter().fold(
+            rfd::FileDialog::new().set_file_name(name),
+            |dialog, filter| dialog.add_filter(&filter.name, &filter.extensions),
+        );
+        let path = match dialog.save_file() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for (name, contents) in files(&name)? {
+            let path = path.with_file_name(name);
+            std::fs::write(&path, contents).map_err(|e| format!("{} : {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// the project kept from the last visit. Only the browser keeps one.
+    pub fn restore(_storage: Option<&dyn Storage>) -> Option<MicroArch> {
+        None
+    }
+
+    pub fn store(_storage: &mut dyn Storage, _vm: &MicroArch) {}
+}
+
+#[cfg(target_arch = "wasm32")]
+const STORAGE_KEY: &str = "project";
+
+#[cfg(target_arch = "wasm32")]
+impl FileIo {
+    /// ask for a file, it arrives through `take`.
+    pub fn open(&self, frame: &Frame, purpose: Purpose, filters: &[Filter]) {
+        let dialog = filters
+            .iter()
+            .fold(rfd::AsyncFileDialog::new(), |dialog, filter| {
+                dialog.add_filter(&filter.name, &filter.extensions)
+            });
+        let opened = self.opened.clone();
+        let frame = frame.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Some(file) = dialog.pick_file().await {
+                let bytes = file.read().await;
+                opened.lock().unwrap().push(Ok(Opened {
+                    purpose,
+                    name: file.file_name(),
+                    bytes,
+                }));
+                frame.request_repaint();
+            }
+        });
+    }
+
+    /// download `name`. `files` gets the file name and returns every file to
+    /// download, several for a ROM split into chips.
+    pub fn save(
+        &self,
+        name: &str,
+        _filters: &[Filter],
+        files: impl FnOnce(&str) -> Result<Files, String>,
+    ) -> Result<(), String> {
+        for (name, contents) in files(name)? {
+            download(&name, &contents)?;
+        }
+        Ok(())
+    }
+
+    /// the project kept from the last visit.
+    pub fn restore(storage: Option<&dyn Storage>) -> Option<MicroArch> {
+        let text = storage?.get_string(STORAGE_KEY)?;
+        let bytes = (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        crate::project::load(&bytes).ok()
+    }
+
+    /// keep the project, deflated and in hex since storage holds strings.
+    pub fn store(storage: &mut dyn Storage, vm: &MicroArch) {
+        if let Ok(bytes) = crate::project::save(vm, crate::project::Compression::Deflate) {
+            let text = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            storage.set_string(STORAGE_KEY, text);
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn download(name: &str, contents: &[u8]) -> Result<(), String> {
+    use wasm_bindgen::JsCast;
+    let error = |e: wasm_bindgen::JsValue| format!("{} : {:?}", name, e);
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(contents));
+    let blob = web_sys::Blob::new_with_u8_array_sequence(&parts).map_err(error)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob).map_err(error)?;
+    let anchor = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or_else(|| "no document to download from".to_owned())?
+        .create_element("a")
+        .map_err(error)?
+        .dyn_into::<web_sys::HtmlAnchorElement>()
+        .map_err(|_| "no anchor to download with".to_owned())?;
+    anchor.set_href(&url);
+    anchor.set_download(name);
+    anchor.click();
+    web_sys::Url::revoke_object_url(&url).map_err(error)
+}
+
+impl FileIo {
+    /// files opened since the last call.
+    pub fn take(&self) -> Vec<Result<Opened, String>> {
+        std::mem::take(&mut *self.opened.lock().unwrap())
+    }
+}
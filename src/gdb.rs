@@ -0,0 +1,508 @@
+//! GDB remote serial protocol stub for the macro machine.
+//!
+//! The micro program implements a macro CPU; this exposes that CPU to a debugger.
+//! An instruction boundary is the micro PC reaching the fetch routine (`FETCH` of the
+//! reference micro program unless told otherwise), so a single step runs micro codes
+//! until the next fetch and breakpoints are checked against PC there.
+//!
+//! Registers, in `g` packet order: R0-R6, PC, IR, STR, all 8 bits. They are
+//! described to GDB by `target.xml`.
+use crate::engine::Engine;
+use crate::vm::MicroArch;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// micro codes one macro instruction may take before it counts as stuck.
+const MAX_INSTRUCTION_CYCLES: u64 = 1 << 20;
+/// instructions run between checks for a GDB interrupt.
+const INTERRUPT_POLL: u32 = 4096;
+const REGISTER_COUNT: usize = 10;
+const INTERRUPT: u8 = 0x03;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.micro_programming.cpu">
+    <reg name="r0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="r1" bitsize="8" type="uint8"/>
+    <reg name="r2" bitsize="8" type="uint8"/>
+    <reg name="r3" bitsize="8" type="uint8"/>
+    <reg name="r4" bitsize="8" type="uint8"/>
+    <reg name="r5" bitsize="8" type="uint8"/>
+    <reg name="r6" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="8" type="code_ptr"/>
+    <reg name="ir" bitsize="8" type="uint8"/>
+    <reg name="str" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// reached the next instruction.
+    Step,
+    Breakpoint,
+    Halted,
+    Fault(String),
+    Interrupted,
+}
+
+pub struct Target {
+    pub vm: MicroArch,
+    /// micro address of the instruction fetch routine.
+    pub fetch: u16,
+    engine: Engine,
+    /// macro PC values.
+    breakpoints: BTreeSet<u8>,
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// "addr,len" of m and M packets as (start, end), None if the end overflows.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = parse_hex(addr)?;
+    Some((addr, addr.checked_add(parse_hex(len)?)?))
+}
+
+impl Target {
+    pub fn new(vm: MicroArch, fetch: u16) -> Self {
+        Self {
+            vm,
+            fetch,
+            engine: Engine::new(),
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    fn registers(&self) -> [u8; REGISTER_COUNT] {
+        let vm = &self.vm;
+        let g = vm.gpr;
+        [
+            g[0], g[1], g[2], g[3], g[4], g[5], g[6], vm.pc, vm.ir, vm.str,
+        ]
+    }
+
+    fn set_register(&mut self, index: usize, value: u8) -> bool {
+        match index {
+            0..=6 => self.vm.gpr[index] = value,
+            7 => self.vm.pc = value,
+            8 => self.vm.ir = value,
+            9 => self.vm.str = value,
+            _ => return false,
+        }
+        true
+    }
+
+    /// run micro codes until the next instruction fetch.
+    pub fn step(&mut self) -> StopReason {
+        let fetch = self.fetch;
+        let result = self
+            .engine
+            .run_until(&mut self.vm, MAX_INSTRUCTION_CYCLES, |upc| upc == fetch);
+        if result.halted {
+            return StopReason::Halted;
+        }
+        if let Some(fault) = self.vm.fault().filter(|_| result.faulted) {
+            return StopReason::Fault(fault);
+        }
+        if self.vm.micro_program_counter == fetch {
+            return StopReason::Step;
+        }
+        StopReason::Fault(format!(
+            "no instruction fetch at {:04X}H within {} micro codes",
+            fetch, MAX_INSTRUCTION_CYCLES
+        ))
+    }
+
+    /// step until a breakpoint, HLT, a fault or `interrupted` says so.
+    pub fn resume(&mut self, interrupted: &mut dyn FnMut() -> bool) -> StopReason {
+        let mut instructions = 0u32;
+        loop {
+            let reason = self.step();
+            if reason != StopReason::Step {
+                return reason;
+            }
+            if self.breakpoints.contains(&self.vm.pc) {
+                return StopReason::Breakpoint;
+            }
+            instructions = instructions.wrapping_add(1);
+            if instructions % INTERRUPT_POLL == 0 && interrupted() {
+                return StopReason::Interrupted;
+            }
+        }
+    }
+
+    /// console output, if any, and the stop reply packet.
+    fn stop_reply(reason: StopReason) -> Vec<String> {
+        let console = |text: &str| format!("O{}", hex_bytes(text.as_bytes()));
+        match reason {
+            StopReason::Step => vec!["S05".to_owned()],
+            StopReason::Breakpoint => vec!["T05swbreak:;".to_owned()],
+            StopReason::Interrupted => vec!["S02".to_owned()],
+            StopReason::Halted => vec![console("HLT\n"), "S05".to_owned()],
+            StopReason::Fault(fault) => {
+                vec![console(&format!("fault : {}\n", fault)), "S0b".to_owned()]
+            }
+        }
+    }
+
+    fn monitor(&mut self, command: &str) -> String {
+        let output = match command.trim() {
+            "reset" => {
+                let (sw1, sw2) = (self.vm.sw1, self.vm.sw2);
+                self.vm.reset_register();
+                self.vm.sw1 = sw1;
+                self.vm.sw2 = sw2;
+                "registers reset\n".to_owned()
+            }
+            "start" => {
+                self.vm.start();
+                "HLT cleared\n".to_owned()
+            }
+            "upc" => format!("micro PC {:04X}H\n", self.vm.micro_program_counter),
+            _ => "monitor commands: reset, start, upc\n".to_owned(),
+        };
+        hex_bytes(output.as_bytes())
+    }
+
+    /// reply packets for one request packet, in order. An empty reply means
+    /// unsupported.
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Vec<String> {
+        let reply = |text: &str| vec![text.to_owned()];
+        let error = || reply("E01");
+        let (command, args) = match packet.get(..1) {
+            Some(command) => (command, &packet[1..]),
+            None => ("", packet),
+        };
+        match command {
+            "?" => reply("S05"),
+            "g" => vec![hex_bytes(&self.registers())],
+            "G" => match parse_hex_bytes(args) {
+                Some(values) if values.len() == REGISTER_COUNT => {
+                    for (index, value) in values.into_iter().enumerate() {
+                        self.set_register(index, value);
+                    }
+                    reply("OK")
+                }
+                _ => error(),
+            },
+            "p" => match parse_hex(args).and_then(|index| self.registers().get(index).copied()) {
+                Some(value) => vec![hex_bytes(&[value])],
+                None => error(),
+            },
+            "P" => {
+                let written = args.split_once('=').and_then(|(index, value)| {
+                    let value = parse_hex_bytes(value).filter(|value| value.len() == 1)?;
+                    Some(self.set_register(parse_hex(index)?, value[0]))
+                });
+                if written == Some(true) {
+                    reply("OK")
+                } else {
+                    error()
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, end)) if addr < self.vm.memory.len() => {
+                    let end = end.min(self.vm.memory.len());
+                    vec![hex_bytes(&self.vm.memory[addr..end])]
+                }
+                _ => error(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, end) = parse_range(range)?;
+                    let data = parse_hex_bytes(data).filter(|data| data.len() == end - addr)?;
+                    self.vm.memory.get_mut(addr..end)?.copy_from_slice(&data);
+                    Some(())
+                });
+                match write {
+                    Some(()) => reply("OK"),
+                    None => error(),
+                }
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next();
+                let addr = fields.next().and_then(parse_hex);
+                match (kind, addr) {
+                    (Some("0") | Some("1"), Some(addr)) if addr <= u8::MAX as usize => {
+                        if command == "Z" {
+                            self.breakpoints.insert(addr as u8);
+                        } else {
+                            self.breakpoints.remove(&(addr as u8));
+                        }
+                        reply("OK")
+                    }
+                    _ => reply(""),
+                }
+            }
+            "s" => Self::stop_reply(self.step()),
+            "c" => Self::stop_reply(self.resume(interrupted)),
+            "H" => reply("OK"),
+            "D" => reply("OK"),
+            "k" => vec![],
+            _ => self.query(packet),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> Vec<String> {
+        let reply = |text: &str| vec![text.to_owned()];
+        if packet.starts_with("qSupported") {
+            return reply("PacketSize=1000;qXfer:features:read+;swbreak+");
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((start, end)) => {
+                    let start = start.min(TARGET_XML.len());
+                    let end = end.min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    vec![format!("{}{}", more, &TARGET_XML[start..end])]
+                }
+                None => reply("E01"),
+            };
+        }
+        if let Some(command) = packet.strip_prefix("qRcmd,") {
+            return match parse_hex_bytes(command).and_then(|bytes| String::from_utf8(bytes).ok()) {
+                Some(command) => vec![format!("O{}", self.monitor(&command)), "OK".to_owned()],
+                None => reply("E01"),
+            };
+        }
+        match packet {
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "qOffsets" => reply("Text=0;Data=0;Bss=0"),
+            _ => reply(""),
+        }
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn send(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+fn read_byte(stream: &mut TcpStream) -> std::io::Result<Option<u8>> {
+    let mut byte = [0];
+    Ok(match stream.read(&mut byte)? {
+        0 => None,
+        _ => Some(byte[0]),
+    })
+}
+
+/// next well formed packet, acknowledging it. None when the connection closes.
+fn receive(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    loop {
+        match read_byte(stream)? {
+            None => return Ok(None),
+            Some(b'$') => {}
+            // acks and interrupts while stopped.
+            Some(_) => continue,
+        }
+        let mut data = vec![];
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+            }
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let valid = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+            == Some(checksum(&data));
+        stream.write_all(if valid { b"+" } else { b"-" })?;
+        if valid {
+            return Ok(Some(data));
+        }
+    }
+}
+
+/// a pending ^C from GDB, without blocking.
+fn interrupt_pending(stream: &TcpStream) -> bool {
+    let mut byte = [0];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let pending = matches!(stream.peek(&mut byte), Ok(1) if byte[0] == INTERRUPT);
+    if pending {
+        let _ = (&*stream).read(&mut byte);
+    }
+    let _ = stream.set_nonblocking(false);
+    pending
+}
+
+/// answer one debugger connection until it detaches, kills or disconnects.
+pub fn serve(mut stream: TcpStream, target: &mut Target) -> std::io::Result<()> {
+    let poll = stream.try_clone()?;
+    while let Some(packet) = receive(&mut stream)? {
+        for reply in target.handle(&packet, &mut || interrupt_pending(&poll)) {
+            send(&mut stream, &reply)?;
+        }
+        if packet == "D" || packet == "k" {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::{project, FETCH, SAMPLES};
+    use std::net::TcpListener;
+
+    fn target() -> Target {
+        let mut vm = project(&SAMPLES[1]);
+        vm.sw1 = 6;
+        vm.sw2 = 7;
+        Target::new(vm, FETCH)
+    }
+
+    fn handle(target: &mut Target, packet: &str) -> Vec<String> {
+        target.handle(packet, &mut || false)
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut target = target();
+        assert_eq!(handle(&mut target, "g"), ["00000000000000000000"]);
+        assert_eq!(handle(&mut target, "P7=05"), ["OK"]);
+        assert_eq!(target.vm.pc, 5);
+        assert_eq!(handle(&mut target, "p7"), ["05"]);
+        assert_eq!(handle(&mut target, "pa"), ["E01"]);
+        assert_eq!(handle(&mut target, "G0102030405060708090a"), ["OK"]);
+        assert_eq!(target.vm.gpr, [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(target.vm.str, 0x0a);
+
+        assert_eq!(handle(&mut target, "m0,3"), ["024180"]);
+        assert_eq!(handle(&mut target, "mfe,4"), ["0000"]);
+        assert_eq!(handle(&mut target, "m100,1"), ["E01"]);
+        assert_eq!(handle(&mut target, "M90,2:abcd"), ["OK"]);
+        assert_eq!(target.vm.memory[0x90..0x92], [0xab, 0xcd]);
+        assert_eq!(handle(&mut target, "Mff,2:abcd"), ["E01"]);
+        // addr + len past usize::MAX.
+        assert_eq!(handle(&mut target, "m1,ffffffffffffffff"), ["E01"]);
+        assert_eq!(handle(&mut target, "M1,ffffffffffffffff:ab"), ["E01"]);
+        assert_eq!(
+            handle(
+                &mut target,
+                "qXfer:features:read:target.xml:1,ffffffffffffffff"
+            ),
+            ["E01"]
+        );
+    }
+
+    #[test]
+    fn step_runs_one_instruction() {
+        let mut target = target();
+        // IN1
+        assert_eq!(handle(&mut target, "s"), ["S05"]);
+        assert_eq!(target.vm.micro_program_counter, FETCH);
+        assert_eq!(target.vm.pc, 1);
+        assert_eq!(target.vm.gpr[0], 6);
+        // ST ACC,[80]
+        assert_eq!(handle(&mut target, "s"), ["S05"]);
+        assert_eq!(target.vm.pc, 3);
+        assert_eq!(target.vm.memory[0x80], 6);
+    }
+
+    #[test]
+    fn breakpoints_and_halt() {
+        let mut target = target();
+        assert_eq!(handle(&mut target, "Z0,22,1"), ["OK"]);
+        assert_eq!(handle(&mut target, "c"), ["T05swbreak:;"]);
+        assert_eq!(target.vm.pc, 0x22);
+        assert_eq!(handle(&mut target, "z0,22,1"), ["OK"]);
+        let reply = handle(&mut target, "c");
+        assert_eq!(reply[0], format!("O{}", hex_bytes(b"HLT\n")));
+        assert_eq!(reply[1], "S05");
+        assert_eq!(target.vm.gpr[6], 42);
+        assert_eq!(handle(&mut target, "s")[1], "S05");
+        assert_eq!(handle(&mut target, "Z2,22,1"), [""]);
+    }
+
+    #[test]
+    fn interrupt_and_fault() {
+        let mut target = target();
+        // BA 00 forever.
+        target.vm.memory[..2].copy_from_slice(&[0x20, 0x00]);
+        assert_eq!(target.handle("c", &mut || true), ["S02"]);
+        target.vm.micro_program.truncate(0x10);
+        let reply = handle(&mut target, "s");
+        assert_eq!(reply[1], "S0b");
+    }
+
+    #[test]
+    fn queries() {
+        let mut target = target();
+        assert!(handle(&mut target, "qSupported:multiprocess+")[0].contains("qXfer:features:read+"));
+        let first = &handle(&mut target, "qXfer:features:read:target.xml:0,10")[0];
+        assert_eq!(first, &format!("m{}", &TARGET_XML[..0x10]));
+        let rest = &handle(&mut target, "qXfer:features:read:target.xml:10,1000")[0];
+        assert!(rest.starts_with('l') && rest.ends_with("</target>\n"));
+        assert_eq!(handle(&mut target, "vMustReplyEmpty"), [""]);
+        let monitor = handle(&mut target, &format!("qRcmd,{}", hex_bytes(b"upc")));
+        assert_eq!(monitor[0], format!("O{}", hex_bytes(b"micro PC 0000H\n")));
+        assert_eq!(monitor[1], "OK");
+    }
+
+    fn packet(stream: &mut TcpStream, data: &str) -> String {
+        send(stream, data).unwrap();
+        let mut ack = [0];
+        stream.read_exact(&mut ack).unwrap();
+        assert_eq!(&ack, b"+");
+        receive(stream).unwrap().unwrap()
+    }
+
+    #[test]
+    fn over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut target = target();
+            serve(stream, &mut target).unwrap();
+            target.vm
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(packet(&mut client, "?"), "S05");
+        assert_eq!(packet(&mut client, "Z0,1b,1"), "OK");
+        assert_eq!(packet(&mut client, "c"), "T05swbreak:;");
+        assert_eq!(packet(&mut client, "p7"), "1b");
+        // a corrupted packet is refused and not executed.
+        client.write_all(b"$g#00").unwrap();
+        let mut ack = [0];
+        client.read_exact(&mut ack).unwrap();
+        assert_eq!(&ack, b"-");
+        assert_eq!(packet(&mut client, "D"), "OK");
+        let vm = server.join().unwrap();
+        assert_eq!(vm.pc, 0x1b);
+    }
+}
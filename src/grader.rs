@@ -0,0 +1,445 @@
+//! Autograding against declarative test specifications.
+//!
+//! A spec is a TOML file of cases. Every case runs the student's micro program on a
+//! fresh machine: registers and memory start at zero, the spec's common `memory` is
+//! loaded (usually the macro program under test), then the case's `initial` state.
+//! The case passes when the final state matches every value in `expect` and earns
+//! its `weight`. `hidden` cases are scored as usual but their name and diffs are left
+//! out of the report students see. See `testdata/grading/multiply.toml`. `batch` grades
+//! a whole class.
+use crate::runner::{self, Stop};
+use crate::vm::{MicroArch, Register, REGISTER_FILE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicBool;
+
+pub mod batch;
+
+const DEFAULT_CYCLES: u64 = 100_000;
+const FLAGS: [&str; 4] = ["minus", "zero", "carry", "overflow"];
+
+fn default_cycles() -> u64 {
+    DEFAULT_CYCLES
+}
+fn one() -> u32 {
+    1
+}
+fn yes() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    #[serde(default)]
+    pub name: String,
+    /// cycle limit of cases without their own.
+    #[serde(default = "default_cycles")]
+    pub cycles: u64,
+    /// loaded before every case.
+    #[serde(default)]
+    pub memory: Vec<Bytes>,
+    #[serde(rename = "case")]
+    pub cases: Vec<Case>,
+}
+
+/// consecutive main memory bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bytes {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    pub name: String,
+    #[serde(default = "one")]
+    pub weight: u32,
+    #[serde(default)]
+    pub hidden: bool,
+    pub cycles: Option<u64>,
+    #[serde(default)]
+    pub initial: Initial,
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Initial {
+    #[serde(default)]
+    pub sw1: u8,
+    #[serde(default)]
+    pub sw2: u8,
+    /// keyed "r0".."r6", "pc", "ir", "mdr", "mar", "str", "cnt".
+    #[serde(default)]
+    pub registers: BTreeMap<String, u16>,
+    #[serde(default)]
+    pub memory: Vec<Bytes>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// the run must end with HLT within the cycle limit.
+    #[serde(default = "yes")]
+    pub halt: bool,
+    #[serde(default)]
+    pub registers: BTreeMap<String, u16>,
+    /// keyed "minus", "zero", "carry", "overflow".
+    #[serde(default)]
+    pub flags: BTreeMap<String, bool>,
+    #[serde(default)]
+    pub memory: Vec<Bytes>,
+}
+
+impl Default for Expect {
+    fn default() -> Self {
+        Self {
+            halt: true,
+            registers: BTreeMap::new(),
+            flags: BTreeMap::new(),
+            memory: vec![],
+        }
+    }
+}
+
+fn register(name: &str) -> Option<(Register, u32)> {
+    REGISTER_FILE
+        .iter()
+        .find(|(_, register_name, _)| *register_name == name)
+        .map(|(register, _, bits)| (*register, *bits))
+}
+
+impl Spec {
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let spec: Spec = toml::from_str(text).map_err(|e| e.to_string())?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.cases.is_empty() {
+            return Err("no test cases".to_owned());
+        }
+        let check_memory = |memory: &[Bytes]| {
+            memory.iter().try_for_each(|bytes| {
+                if bytes.addr + bytes.bytes.len() > 0x100 {
+                    Err(format!(
+                        "{} bytes at {:02X}H run past main memory",
+                        bytes.bytes.len(),
+                        bytes.addr
+                    ))
+                } else {
+                    Ok(())
+                }
+            })
+        };
+        let check_registers = |registers: &BTreeMap<String, u16>| {
+            registers.iter().try_for_each(|(name, value)| {
+                let (_, bits) =
+                    register(name).ok_or_else(|| format!("unknown register {}", name))?;
+                if u32::from(*value) >> bits != 0 {
+                    return Err(format!(
+                        "{} = {:X}H does not fit {} bits",
+                        name, value, bits
+                    ));
+                }
+                Ok(())
+            })
+        };
+        check_memory(&self.memory)?;
+        for case in &self.cases {
+            let context = |e: String| format!("case \"{}\" : {}", case.name, e);
+            check_memory(&case.initial.memory).map_err(context)?;
+            check_memory(&case.expect.memory).map_err(context)?;
+            check_registers(&case.initial.registers).map_err(context)?;
+            check_registers(&case.expect.registers).map_err(context)?;
+            if let Some(flag) = case
+                .expect
+                .flags
+                .keys()
+                .find(|flag| !FLAGS.contains(&flag.as_str()))
+            {
+                return Err(context(format!("unknown flag {}", flag)));
+            }
+        }
+        Ok(())
+    }
+
+    /// total weight of every case.
+    pub fn max_score(&self) -> u32 {
+        self.cases.iter().map(|case| case.weight).sum()
+    }
+}
+
+/// one value that differs from the expectation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diff {
+    pub what: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CaseResult {
+    pub name: String,
+    pub hidden: bool,
+    pub weight: u32,
+    pub passed: bool,
+    /// `runner::Report::status` of the run.
+    pub status: String,
+    pub cycles: u64,
+    pub diffs: Vec<Diff>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GradeReport {
+    pub spec: String,
+    pub score: u32,
+    pub max_score: u32,
+    pub cases: Vec<CaseResult>,
+}
+
+fn load(memory: &mut [u8], list: &[Bytes]) {
+    for bytes in list {
+        memory[bytes.addr..bytes.addr + bytes.bytes.len()].copy_from_slice(&bytes.bytes);
+    }
+}
+
+fn run_case(student: &MicroArch, spec: &Spec, case: &Case, cancel: &AtomicBool) -> CaseResult {
+    let mut vm = MicroArch::construct(student.micro_program.clone());
+    vm.arch = student.arch.clone();
+    load(&mut vm.memory, &spec.memory);
+    load(&mut vm.memory, &case.initial.memory);
+    vm.sw1 = case.initial.sw1;
+    vm.sw2 = case.initial.sw2;
+    for (name, value) in &case.initial.registers {
+        match register(name) {
+            Some((Register::Cnt, _)) => vm.counter = *value,
+            Some((register, _)) => vm.store_register(register, *value as u8),
+            None => {}
+        }
+    }
+
+    let outcome = runner::run_cancellable(&mut vm, case.cycles.unwrap_or(spec.cycles), cancel);
+    let report = runner::Report::new(&vm, &outcome);
+    let mut diffs = vec![];
+    if case.expect.halt && outcome.stop != Stop::Halted {
+        diffs.push(Diff {
+            what: "status".to_owned(),
+            expected: "halted".to_owned(),
+            actual: match &outcome.stop {
+                Stop::Fault(fault) => format!("fault : {}", fault),
+                _ => report.status.clone(),
+            },
+        });
+    }
+    for (name, expected) in &case.expect.registers {
+        let actual = match register(name) {
+            Some((Register::Cnt, _)) => vm.counter,
+            Some((register, _)) => vm.load_register(register) as u16,
+            None => continue,
+        };
+        if actual != *expected {
+            diffs.push(Diff {
+                what: name.clone(),
+                expected: format!("{:02X}H", expected),
+                actual: format!("{:02X}H", actual),
+            });
+        }
+    }
+    let flag_bits = vm.arch.flags;
+    for (name, expected) in &case.expect.flags {
+        let bit = match name.as_str() {
+            "minus" => flag_bits.minus,
+            "zero" => flag_bits.zero,
+            "carry" => flag_bits.carry,
+            _ => flag_bits.overflow,
+        };
+        let actual = vm.flag(bit);
+        if actual != *expected {
+            diffs.push(Diff {
+                what: format!("{} flag", name),
+                expected: (*expected as u8).to_string(),
+                actual: (actual as u8).to_string(),
+            });
+        }
+    }
+    for bytes in &case.expect.memory {
+        for (i, expected) in bytes.bytes.iter().enumerate() {
+            let addr = bytes.addr + i;
+            let actual = vm.memory[addr];
+            if actual != *expected {
+                diffs.push(Diff {
+                    what: format!("[{:02X}]", addr),
+                    expected: format!("{:02X}H", expected),
+                    actual: format!("{:02X}H", actual),
+                });
+            }
+        }
+    }
+    CaseResult {
+        name: case.name.clone(),
+        hidden: case.hidden,
+        weight: case.weight,
+        passed: diffs.is_empty(),
+        status: report.status,
+        cycles: outcome.cycles,
+        diffs,
+    }
+}
+
+/// run every case of `spec` against the micro program and architecture of `student`.
+pub fn grade(student: &MicroArch, spec: &Spec) -> GradeReport {
+    grade_cancellable(student, spec, &AtomicBool::new(false))
+}
+
+/// `grade`, cutting the runs short as at their cycle limit once `cancel` is set.
+pub fn grade_cancellable(student: &MicroArch, spec: &Spec, cancel: &AtomicBool) -> GradeReport {
+    let cases: Vec<CaseResult> = spec
+        .cases
+        .iter()
+        .map(|case| run_case(student, spec, case, cancel))
+        .collect();
+    GradeReport {
+        spec: spec.name.clone(),
+        score: cases
+            .iter()
+            .filter(|case| case.passed)
+            .map(|case| case.weight)
+            .sum(),
+        max_score: spec.max_score(),
+        cases,
+    }
+}
+
+impl GradeReport {
+    /// the report for students: hidden cases keep their score only.
+    pub fn redacted(&self) -> Self {
+        let mut report = self.clone();
+        let mut hidden = 0;
+        for case in report.cases.iter_mut().filter(|case| case.hidden) {
+            hidden += 1;
+            case.name = format!("hidden case {}", hidden);
+            case.status.clear();
+            case.cycles = 0;
+            case.diffs.clear();
+        }
+        report
+    }
+
+    pub fn passed(&self) -> bool {
+        self.score == self.max_score
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report serializes")
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if !self.spec.is_empty() {
+            text += &format!("{}\n", self.spec);
+        }
+        text += &format!("score {} / {}\n", self.score, self.max_score);
+        for case in &self.cases {
+            text += &format!(
+                "{} {} ({} / {})",
+                if case.passed { "PASS" } else { "FAIL" },
+                case.name,
+                if case.passed { case.weight } else { 0 },
+                case.weight
+            );
+            if !case.status.is_empty() {
+                text += &format!(" {} after {} cycles", case.status, case.cycles);
+            }
+            text += "\n";
+            for diff in &case.diffs {
+                text += &format!(
+                    "    {}: expected {}, got {}\n",
+                    diff.what, diff.expected, diff.actual
+                );
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::{project, SAMPLES};
+    use crate::vm::MicroCode;
+
+    const MULTIPLY: &str = include_str!("../testdata/grading/multiply.toml");
+
+    #[test]
+    fn reference_solution_gets_full_marks() {
+        let spec = Spec::from_toml(MULTIPLY).unwrap();
+        assert_eq!(spec.max_score(), 7);
+        // the student's memory is ignored, cases start from the spec.
+        let mut student = project(&SAMPLES[0]);
+        student.gpr[6] = 0x12;
+        let report = grade(&student, &spec);
+        assert!(report.passed(), "{}", report.to_text());
+        assert_eq!(report.score, 7);
+        assert!(report.cases.iter().all(|case| case.status == "halted"));
+    }
+
+    #[test]
+    fn failures_are_diffed() {
+        let spec = Spec::from_toml(MULTIPLY).unwrap();
+        let mut student = project(&SAMPLES[1]);
+        // never halts.
+        student.micro_program = vec![MicroCode::default(); 1 << 16];
+        let report = grade(&student, &spec);
+        assert_eq!(report.score, 0);
+        let first = &report.cases[0];
+        assert_eq!(first.status, "cycle limit");
+        assert_eq!(first.cycles, 20_000);
+        assert_eq!(
+            first.diffs[0],
+            Diff {
+                what: "status".to_owned(),
+                expected: "halted".to_owned(),
+                actual: "cycle limit".to_owned(),
+            }
+        );
+        assert!(first.diffs.iter().any(|diff| diff.what == "[82]"));
+        assert!(report.to_text().contains("    r0: expected 2AH, got 00H\n"));
+    }
+
+    #[test]
+    fn hidden_cases_are_redacted() {
+        let spec = Spec::from_toml(MULTIPLY).unwrap();
+        let mut student = project(&SAMPLES[1]);
+        student.micro_program = vec![MicroCode::default(); 1 << 16];
+        let report = grade(&student, &spec);
+        assert_eq!(report.cases[3].name, "13 x 11");
+        let redacted = report.redacted();
+        assert_eq!(redacted.score, report.score);
+        assert_eq!(redacted.cases[3].name, "hidden case 1");
+        assert!(redacted.cases[3].diffs.is_empty());
+        assert!(!redacted.to_text().contains("13 x 11"));
+        assert!(!redacted.to_json().contains("8FH"));
+        assert_eq!(redacted.cases[0], report.cases[0]);
+    }
+
+    #[test]
+    fn bad_specs_are_rejected() {
+        assert!(Spec::from_toml("name = \"empty\"").is_err());
+        let case = |body: &str| Spec::from_toml(&format!("[[case]]\nname = \"c\"\n{}", body));
+        assert!(case("").is_ok());
+        assert!(case("expect = { registers = { r9 = 1 } }")
+            .unwrap_err()
+            .contains("unknown register r9"));
+        assert!(case("initial = { registers = { r0 = 0x100 } }").is_err());
+        assert!(case("initial = { registers = { cnt = 0x100 } }").is_ok());
+        assert!(case("expect = { flags = { parity = true } }").is_err());
+        assert!(case("initial = { memory = [{ addr = 0xFF, bytes = [1, 2] }] }").is_err());
+        assert!(case("weigth = 2").is_err());
+    }
+}
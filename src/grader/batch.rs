@@ -0,0 +1,232 @@
+//! Grading many submissions at once.
+//!
+//! Each submission is loaded and graded on its own thread so a file that panics the
+//! loader or the VM only fails that student. A submission still running after the
+//! timeout is reported as timed out and cancelled, its thread stops within a slice
+//! of `runner::run_cancellable` so `jobs` bounds the busy threads.
+use super::{grade_cancellable, GradeReport, Spec};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// the graded report of one student or why there is none.
+#[derive(Debug, Clone)]
+pub struct Submission {
+    pub student: String,
+    pub result: Result<GradeReport, String>,
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    }
+}
+
+fn grade_one(bytes: Vec<u8>, spec: Arc<Spec>, timeout: Duration) -> Result<GradeReport, String> {
+    let (sender, receiver) = mpsc::channel();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancelled = cancel.clone();
+    let handle = std::thread::spawn(move || {
+        let result = crate::project::load(&bytes)
+            .map(|student| grade_cancellable(&student, &spec, &cancelled));
+        let _ = sender.send(result);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            cancel.store(true, Ordering::Relaxed);
+            Err(format!("timed out after {:.1} s", timeout.as_secs_f64()))
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(format!(
+            "crashed : {}",
+            handle.join().err().map_or_else(String::new, panic_message)
+        )),
+    }
+}
+
+/// grade (student, project file bytes) pairs on `jobs` threads, in input order.
+pub fn grade_all(
+    submissions: Vec<(String, Vec<u8>)>,
+    spec: &Spec,
+    jobs: usize,
+    timeout: Duration,
+) -> Vec<Submission> {
+    let spec = Arc::new(spec.clone());
+    let count = submissions.len();
+    let queue = Mutex::new(submissions.into_iter().enumerate());
+    let results = Mutex::new(vec![None; count]);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let Some((index, (student, bytes))) = next else {
+                    break;
+                };
+                let result = grade_one(bytes, spec.clone(), timeout);
+                results.lock().unwrap()[index] = Some(Submission { student, result });
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|submission| submission.expect("every submission graded"))
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    student: &'a str,
+    score: Option<u32>,
+    max_score: u32,
+    /// pass or fail per spec case, empty on error.
+    passed: Vec<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    spec: &'a str,
+    cases: Vec<&'a str>,
+    students: Vec<Row<'a>>,
+}
+
+fn rows<'a>(submissions: &'a [Submission], spec: &Spec) -> Vec<Row<'a>> {
+    submissions
+        .iter()
+        .map(|submission| match &submission.result {
+            Ok(report) => Row {
+                student: &submission.student,
+                score: Some(report.score),
+                max_score: report.max_score,
+                passed: report.cases.iter().map(|case| case.passed).collect(),
+                error: None,
+            },
+            Err(e) => Row {
+                student: &submission.student,
+                score: None,
+                max_score: spec.max_score(),
+                passed: vec![],
+                error: Some(e),
+            },
+        })
+        .collect()
+}
+
+/// one object with the case names and a row per student.
+pub fn summary_json(submissions: &[Submission], spec: &Spec) -> String {
+    let summary = Summary {
+        spec: &spec.name,
+        cases: spec.cases.iter().map(|case| case.name.as_str()).collect(),
+        students: rows(submissions, spec),
+    };
+    serde_json::to_string_pretty(&summary).expect("summary serializes") + "\n"
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// student, score, max_score, error, then 1 or 0 per case.
+pub fn summary_csv(submissions: &[Submission], spec: &Spec) -> String {
+    let mut header = vec!["student", "score", "max_score", "error"];
+    header.extend(spec.cases.iter().map(|case| case.name.as_str()));
+    let header: Vec<String> = header.into_iter().map(csv_field).collect();
+    let mut csv = header.join(",") + "\n";
+    for row in rows(submissions, spec) {
+        let mut fields = vec![
+            csv_field(row.student),
+            row.score
+                .map_or_else(String::new, |score| score.to_string()),
+            row.max_score.to_string(),
+            csv_field(row.error.unwrap_or_default()),
+        ];
+        fields.extend(row.passed.iter().map(|passed| (*passed as u8).to_string()));
+        csv += &fields.join(",");
+        csv += "\n";
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{save, Compression};
+    use crate::reference::{project, SAMPLES};
+    use crate::vm::MicroCode;
+
+    const MULTIPLY: &str = include_str!("../../testdata/grading/multiply.toml");
+
+    fn submissions() -> Vec<(String, Vec<u8>)> {
+        let solved = project(&SAMPLES[1]);
+        let mut looping = solved.clone();
+        looping.micro_program = vec![MicroCode::default(); 1 << 16];
+        vec![
+            (
+                "alice".to_owned(),
+                save(&solved, Compression::Deflate).unwrap(),
+            ),
+            ("bob".to_owned(), b"not a project".to_vec()),
+            (
+                "carol, c.".to_owned(),
+                save(&looping, Compression::None).unwrap(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn grades_in_order_and_isolates_failures() {
+        let spec = Spec::from_toml(MULTIPLY).unwrap();
+        let results = grade_all(submissions(), &spec, 2, Duration::from_secs(60));
+        let students: Vec<&str> = results.iter().map(|s| s.student.as_str()).collect();
+        assert_eq!(students, ["alice", "bob", "carol, c."]);
+        assert_eq!(results[0].result.as_ref().unwrap().score, 7);
+        assert!(results[1].result.is_err());
+        assert_eq!(results[2].result.as_ref().unwrap().score, 0);
+
+        let csv = summary_csv(&results, &spec);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "student,score,max_score,error,6 x 7,anything x 0,15 x 17,13 x 11"
+        );
+        assert_eq!(lines[1], "alice,7,7,,1,1,1,1");
+        assert!(lines[2].starts_with("bob,,7,") && !lines[2].ends_with(",7,"));
+        assert_eq!(lines[3], "\"carol, c.\",0,7,,0,0,0,0");
+
+        let json: serde_json::Value = serde_json::from_str(&summary_json(&results, &spec)).unwrap();
+        assert_eq!(json["cases"][3], "13 x 11");
+        assert_eq!(json["students"][0]["passed"][0], true);
+        assert!(json["students"][1]["score"].is_null());
+    }
+
+    #[test]
+    fn infinite_loops_time_out() {
+        let mut spec = Spec::from_toml(MULTIPLY).unwrap();
+        spec.cycles = u64::MAX;
+        let submissions = submissions().split_off(2);
+        let carol = crate::project::load(&submissions[0].1).unwrap();
+        let results = grade_all(submissions, &spec, 1, Duration::from_millis(50));
+        assert!(results[0]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .starts_with("timed out"));
+
+        // the timed out thread gives up instead of spinning through u64::MAX cycles.
+        let report = grade_cancellable(&carol, &spec, &AtomicBool::new(true));
+        assert_eq!(report.score, 0);
+        assert!(report.cases.iter().all(|case| case.status == "cycle limit"));
+    }
+}
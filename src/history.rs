@@ -0,0 +1,448 @@
+//! Undo and redo of edits to the machine.
+//!
+//! The views describe what they edit as an `Edit`: the micro codes, main memory
+//! bytes, registers, switches and the labels and comments of micro code addresses
+//! it changed, with the values before and after. `History::record` keeps it as one
+//! entry, and entries touching the same places within `GROUP_SECONDS` merge so
+//! typing a value or dragging a field undoes in one step. Execution is not an edit
+//! and is never recorded.
+use crate::vm::{Annotation, MicroArch, MicroCode};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// edits of the same places closer than this merge into one entry.
+pub const GROUP_SECONDS: f64 = 1.0;
+/// oldest entries are dropped beyond this.
+pub const MAX_ENTRIES: usize = 1000;
+
+/// registers in `Registers` order.
+const REGISTERS: usize = 17;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    MicroCode {
+        addr: usize,
+        before: MicroCode,
+        after: MicroCode,
+    },
+    Memory {
+        addr: usize,
+        before: u8,
+        after: u8,
+    },
+    /// index into `Registers`.
+    Register {
+        index: usize,
+        before: u16,
+        after: u16,
+    },
+    /// None when the address has no label or comment.
+    Annotation {
+        addr: u16,
+        before: Option<Annotation>,
+        after: Option<Annotation>,
+    },
+}
+
+impl Change {
+    /// what the change writes to, for grouping.
+    fn place(&self) -> (u8, usize) {
+        match *self {
+            Change::MicroCode { addr, .. } => (0, addr),
+            Change::Memory { addr, .. } => (1, addr),
+            Change::Register { index, .. } => (2, index),
+            Change::Annotation { addr, .. } => (3, addr as usize),
+        }
+    }
+
+    fn apply(&self, vm: &mut MicroArch, undo: bool) {
+        match self.clone() {
+            Change::MicroCode {
+                addr,
+                before,
+                after,
+            } => vm.micro_program[addr] = if undo { before } else { after },
+            Change::Memory {
+                addr,
+                before,
+                after,
+            } => vm.memory[addr] = if undo { before } else { after },
+            Change::Register {
+                index,
+                before,
+                after,
+            } => set_register(vm, index, if undo { before } else { after }),
+            Change::Annotation {
+                addr,
+                before,
+                after,
+            } => match if undo { before } else { after } {
+                Some(annotation) => {
+                    vm.annotations.insert(addr, annotation);
+                }
+                None => {
+                    vm.annotations.remove(&addr);
+                }
+            },
+        }
+    }
+
+    /// `self` followed by `later` on the same place.
+    fn merge(&mut self, later: &Change) {
+        match (self, later) {
+            (Change::MicroCode { after, .. }, Change::MicroCode { after: later, .. }) => {
+                *after = *later
+            }
+            (Change::Memory { after, .. }, Change::Memory { after: later, .. }) => *after = *later,
+            (Change::Register { after, .. }, Change::Register { after: later, .. }) => {
+                *after = *later
+            }
+            (Change::Annotation { after, .. }, Change::Annotation { after: later, .. }) => {
+                *after = later.clone()
+            }
+            _ => unreachable!("merged changes of different places"),
+        }
+    }
+}
+
+struct Entry {
+    changes: Vec<Change>,
+    /// time of the last change merged into the entry.
+    time: f64,
+}
+
+/// user visible registers, switches and sequencer state, taken before an edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers([u16; REGISTERS]);
+
+impl Registers {
+    pub fn of(vm: &MicroArch) -> Self {
+        let mut registers = [0; REGISTERS];
+        for (register, gpr) in registers.iter_mut().zip(vm.gpr) {
+            *register = gpr as u16;
+        }
+        registers[7..].copy_from_slice(&[
+            vm.pc as u16,
+            vm.ir as u16,
+            vm.mdr as u16,
+            vm.mar as u16,
+            vm.str as u16,
+            vm.sw1 as u16,
+            vm.sw2 as u16,
+            vm.counter,
+            vm.micro_program_counter,
+            vm.hlt as u16,
+        ]);
+        Self(registers)
+    }
+}
+
+fn set_register(vm: &mut MicroArch, index: usize, value: u16) {
+    match index {
+        0..=6 => vm.gpr[index] = value as u8,
+        7 => vm.pc = value as u8,
+        8 => vm.ir = value as u8,
+        9 => vm.mdr = value as u8,
+        10 => vm.mar = value as u8,
+        11 => vm.str = value as u8,
+        12 => vm.sw1 = value as u8,
+        13 => vm.sw2 = value as u8,
+        14 => vm.counter = value,
+        15 => vm.micro_program_counter = value,
+        _ => vm.hlt = value != 0,
+    }
+}
+
+/// what one edit changed. values that stayed the same are left out.
+#[derive(Debug, Default)]
+pub struct Edit {
+    changes: Vec<Change>,
+}
+
+impl Edit {
+    pub fn micro_code(&mut self, addr: usize, before: MicroCode, after: MicroCode) {
+        if before != after {
+            self.changes.push(Change::MicroCode {
+                addr,
+                before,
+                after,
+            });
+        }
+    }
+
+    pub fn memory(&mut self, addr: usize, before: u8, after: u8) {
+        if before != after {
+            self.changes.push(Change::Memory {
+                addr,
+                before,
+                after,
+            });
+        }
+    }
+
+    pub fn registers(&mut self, before: Registers, after: Registers) {
+        for (index, (before, after)) in before.0.into_iter().zip(after.0).enumerate() {
+            if before != after {
+                self.changes.push(Change::Register {
+                    index,
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+
+    /// label and comment of `addr`, None when it has neither.
+    pub fn annotation(&mut self, addr: u16, before: Option<Annotation>, after: Option<Annotation>) {
+        if before != after {
+            self.changes.push(Change::Annotation {
+                addr,
+                before,
+                after,
+            });
+        }
+    }
+
+    /// every address whose label or comment differs between the two.
+    pub fn annotations(
+        &mut self,
+        before: &BTreeMap<u16, Annotation>,
+        after: &BTreeMap<u16, Annotation>,
+    ) {
+        let addrs: BTreeSet<u16> = before.keys().chain(after.keys()).copied().collect();
+        for addr in addrs {
+            self.annotation(addr, before.get(&addr).cloned(), after.get(&addr).cloned());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: VecDeque<Entry>,
+    redo: Vec<Entry>,
+}
+
+impl History {
+    /// forget everything, for a newly loaded machine.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// keep `edit`, made at `time` seconds, as the latest entry.
+    pub fn record(&mut self, edit: Edit, time: f64) {
+        let changes = edit.changes;
+        if changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        if let Some(last) = self.undo.back_mut() {
+            let same_places = last.changes.len() == changes.len()
+                && last
+                    .changes
+                    .iter()
+                    .zip(&changes)
+                    .all(|(last, change)| last.place() == change.place());
+            if same_places && time - last.time < GROUP_SECONDS {
+                for (last, change) in last.changes.iter_mut().zip(&changes) {
+                    last.merge(change);
+                }
+                last.time = time;
+                return;
+            }
+        }
+        if self.undo.len() == MAX_ENTRIES {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(Entry { changes, time });
+    }
+
+    /// revert the last entry, false when there is none.
+    pub fn undo(&mut self, vm: &mut MicroArch) -> bool {
+        match self.undo.pop_back() {
+            Some(entry) => {
+                entry
+                    .changes
+                    .iter()
+                    .rev()
+                    .for_each(|change| change.apply(vm, true));
+                self.redo.push(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// apply the last undone entry again, false when there is none.
+    pub fn redo(&mut self, vm: &mut MicroArch) -> bool {
+        match self.redo.pop() {
+            Some(mut entry) => {
+                entry
+                    .changes
+                    .iter()
+                    .for_each(|change| change.apply(vm, false));
+                // a redone entry never merges with a following edit.
+                entry.time = f64::NEG_INFINITY;
+                self.undo.push_back(entry);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Branch;
+
+    fn vm() -> MicroArch {
+        MicroArch::construct(vec![MicroCode::default(); 16])
+    }
+
+    /// set micro code `addr`, the way the micro code view records it.
+    fn edit_micro_code(vm: &mut MicroArch, addr: usize, micro_code: MicroCode) -> Edit {
+        let mut edit = Edit::default();
+        edit.micro_code(addr, vm.micro_program[addr], micro_code);
+        vm.micro_program[addr] = micro_code;
+        edit
+    }
+
+    #[test]
+    fn undo_and_redo_edits() {
+        let mut vm = vm();
+        let mut history = History::default();
+        let jump = MicroCode {
+            branch: Branch::J,
+            ..MicroCode::default()
+        };
+        let mut edit = edit_micro_code(&mut vm, 3, jump);
+        edit.memory(0x80, vm.memory[0x80], 0x2a);
+        vm.memory[0x80] = 0x2a;
+        history.record(edit, 0.0);
+        let before = Registers::of(&vm);
+        vm.gpr[0] = 7;
+        vm.sw1 = 1;
+        let mut edit = Edit::default();
+        edit.registers(before, Registers::of(&vm));
+        history.record(edit, 5.0);
+        assert!(history.can_undo());
+
+        assert!(history.undo(&mut vm));
+        assert_eq!((vm.gpr[0], vm.sw1), (0, 0));
+        assert_eq!(vm.memory[0x80], 0x2a);
+        assert!(history.undo(&mut vm));
+        assert_eq!(vm.micro_program[3], MicroCode::default());
+        assert_eq!(vm.memory[0x80], 0);
+        assert!(!history.undo(&mut vm));
+
+        assert!(history.redo(&mut vm));
+        assert_eq!(vm.micro_program[3].branch, Branch::J);
+        assert!(history.redo(&mut vm));
+        assert_eq!((vm.gpr[0], vm.sw1), (7, 1));
+        assert!(!history.can_redo());
+
+        // a new edit drops what was undone.
+        history.undo(&mut vm);
+        let before = Registers::of(&vm);
+        vm.pc = 1;
+        let mut edit = Edit::default();
+        edit.registers(before, Registers::of(&vm));
+        history.record(edit, 12.0);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn unchanged_values_are_not_recorded() {
+        let mut vm = vm();
+        let mut history = History::default();
+        let mut edit = edit_micro_code(&mut vm, 0, MicroCode::default());
+        edit.memory(1, 2, 2);
+        edit.registers(Registers::of(&vm), Registers::of(&vm));
+        edit.annotation(4, None, None);
+        assert!(edit.is_empty());
+        history.record(edit, 0.0);
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn groups_rapid_edits_of_the_same_place() {
+        let mut vm = vm();
+        let mut history = History::default();
+        let addr = |addr| MicroCode {
+            addr,
+            ..MicroCode::default()
+        };
+        for (time, value) in [(0.0, 1), (0.5, 0x12), (1.2, 0x123)] {
+            let edit = edit_micro_code(&mut vm, 0, addr(value));
+            history.record(edit, time);
+        }
+        let edit = edit_micro_code(&mut vm, 1, addr(5));
+        history.record(edit, 1.3);
+        let edit = edit_micro_code(&mut vm, 0, addr(0x1234));
+        history.record(edit, 10.0);
+
+        history.undo(&mut vm);
+        assert_eq!(vm.micro_program[0].addr, 0x123);
+        history.undo(&mut vm);
+        assert_eq!(vm.micro_program[1].addr, 0);
+        history.undo(&mut vm);
+        assert_eq!(vm.micro_program[0].addr, 0);
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn undo_labels_and_comments() {
+        let mut vm = vm();
+        let mut history = History::default();
+        let mut annotation = Annotation::default();
+        for (time, label) in [(0.0, "l"), (0.3, "lo"), (0.6, "loop")] {
+            let before = vm.annotations.get(&4).cloned();
+            annotation.label = label.to_owned();
+            vm.annotations.insert(4, annotation.clone());
+            let mut edit = Edit::default();
+            edit.annotation(4, before, Some(annotation.clone()));
+            history.record(edit, time);
+        }
+        let before = vm.annotations.clone();
+        vm.annotations.remove(&4);
+        let mut edit = Edit::default();
+        edit.annotations(&before, &vm.annotations);
+        history.record(edit, 5.0);
+        history.undo(&mut vm);
+        assert_eq!(vm.annotations[&4].label, "loop");
+        history.undo(&mut vm);
+        assert!(vm.annotations.is_empty());
+        history.redo(&mut vm);
+        assert_eq!(vm.annotations[&4].label, "loop");
+    }
+
+    #[test]
+    fn execution_between_edits_is_kept() {
+        let mut vm = crate::reference::project(&crate::reference::SAMPLES[0]);
+        let mut history = History::default();
+        let before = Registers::of(&vm);
+        vm.sw1 = 3;
+        let mut edit = Edit::default();
+        edit.registers(before, Registers::of(&vm));
+        history.record(edit, 0.0);
+        while !vm.exec() {}
+        let result = vm.memory[0x81];
+        assert_eq!(result, 6);
+        assert!(history.undo(&mut vm));
+        assert_eq!(vm.sw1, 0);
+        assert_eq!(vm.memory[0x81], result);
+        assert!(vm.hlt);
+        assert!(!history.can_undo());
+    }
+}
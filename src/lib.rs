@@ -0,0 +1,22 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+// `% n == 0` builds on toolchains older than `is_multiple_of` (1.87).
+#![allow(clippy::manual_is_multiple_of)]
+pub mod arch;
+pub mod dap;
+pub mod datapath;
+pub mod engine;
+pub mod gdb;
+pub mod grader;
+pub mod history;
+pub mod lsp;
+pub mod memory_image;
+pub mod microasm;
+pub mod project;
+pub mod reference;
+pub mod relocate;
+pub mod rom_image;
+pub mod runner;
+pub mod testbench;
+pub mod verilog;
+pub mod vm;
@@ -0,0 +1,547 @@
+//! Language server for microassembly, the text format of `microasm`.
+//!
+//! Documents are synced whole and reassembled on every change, publishing the
+//! diagnostics. Completion offers the fields and, after `KEY=`, the values the
+//! architecture provides or the labels; hover shows what a micro code does and its
+//! encoding; go to definition jumps from `ADDR=label` to the label.
+//!
+//! The architecture is the default one unless `initializationOptions.architecture`
+//! names a TOML file. `micro_lsp` serves over stdio with the debug adapter's framing.
+use crate::arch::Architecture;
+use crate::dap::{read_message, write_message};
+use crate::microasm::{self, Program, Severity, Span};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INVALID_REQUEST: i64 = -32600;
+
+struct Document {
+    text: String,
+    program: Program,
+}
+
+impl Document {
+    fn line(&self, line: usize) -> &str {
+        self.text.lines().nth(line).unwrap_or_default()
+    }
+
+    /// LSP range of a span, columns in UTF-16 code units.
+    fn range(&self, span: Span) -> Value {
+        let line = self.line(span.line);
+        json!({
+            "start": { "line": span.line, "character": utf16(line, span.start) },
+            "end": { "line": span.line, "character": utf16(line, span.end) },
+        })
+    }
+}
+
+fn utf16(line: &str, column: usize) -> usize {
+    line.get(..column).unwrap_or(line).encode_utf16().count()
+}
+
+/// byte column of a UTF-16 column.
+fn byte(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+#[derive(Default)]
+pub struct Server {
+    arch: Architecture,
+    documents: BTreeMap<String, Document>,
+    output: Vec<Value>,
+    shutdown: bool,
+    /// exit code, set by the `exit` notification.
+    pub exit: Option<i32>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// messages to send, in order.
+    pub fn take_output(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.output
+            .push(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /// handle one incoming message, requests are answered.
+    pub fn handle(&mut self, message: &Value) {
+        let method = match message["method"].as_str() {
+            Some(method) => method,
+            // a response, the server sends no requests.
+            None => return,
+        };
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id,
+            None => return self.notification(method, params),
+        };
+        let result = if self.shutdown {
+            Err((INVALID_REQUEST, "shut down".to_owned()))
+        } else {
+            match method {
+                "initialize" => self.initialize(params),
+                "shutdown" => {
+                    self.shutdown = true;
+                    Ok(Value::Null)
+                }
+                "textDocument/completion" => self.completion(params),
+                "textDocument/hover" => self.hover(params),
+                "textDocument/definition" => self.definition(params),
+                _ => Err((METHOD_NOT_FOUND, format!("unsupported method {}", method))),
+            }
+        };
+        let mut response = json!({ "jsonrpc": "2.0", "id": id });
+        match result {
+            Ok(result) => response["result"] = result,
+            Err((code, message)) => response["error"] = json!({ "code": code, "message": message }),
+        }
+        self.output.push(response);
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.update(uri, text.to_owned());
+            }
+            "textDocument/didChange" => {
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let Some(text) = text {
+                    self.update(uri, text.to_owned());
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.notify(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                );
+            }
+            "exit" => self.exit = Some(if self.shutdown { 0 } else { 1 }),
+            _ => {}
+        }
+    }
+
+    fn initialize(&mut self, params: &Value) -> Result<Value, (i64, String)> {
+        if let Some(path) = params["initializationOptions"]["architecture"].as_str() {
+            self.arch = std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| Architecture::from_toml(&text))
+                .map_err(|e| (INVALID_PARAMS, format!("{} : {}", path, e)))?;
+        }
+        Ok(json!({
+            "capabilities": {
+                "textDocumentSync": 1,
+                "completionProvider": { "triggerCharacters": ["="] },
+                "hoverProvider": true,
+                "definitionProvider": true,
+            },
+            "serverInfo": { "name": "micro_lsp", "version": env!("CARGO_PKG_VERSION") },
+        }))
+    }
+
+    fn update(&mut self, uri: String, text: String) {
+        let document = Document {
+            program: microasm::parse(&text, &self.arch),
+            text,
+        };
+        let diagnostics: Vec<Value> = document
+            .program
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                json!({
+                    "range": document.range(diagnostic.span),
+                    "severity": match diagnostic.severity {
+                        Severity::Error => 1,
+                        Severity::Warning => 2,
+                    },
+                    "source": "microasm",
+                    "message": diagnostic.message,
+                })
+            })
+            .collect();
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        );
+        self.documents.insert(uri, document);
+    }
+
+    /// the document and 0 based line and byte column of a position.
+    fn at(&self, params: &Value) -> Result<(&Document, usize, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let document = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("{} is not open", uri)))?;
+        let position = &params["position"];
+        let line = position["line"].as_u64().unwrap_or_default() as usize;
+        let character = position["character"].as_u64().unwrap_or_default() as usize;
+        Ok((document, line, byte(document.line(line), character)))
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (document, line, column) = self.at(params)?;
+        let before = &document.line(line)[..column];
+        if before.contains(';') {
+            return Ok(json!([]));
+        }
+        let start = before
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let token = &before[start..];
+        let item = |label: String, kind: u32, detail: String, from: usize| {
+            let span = Span {
+                line,
+                start: from,
+                end: column,
+            };
+            json!({
+                "label": label,
+                "kind": kind,
+                "detail": detail,
+                "textEdit": { "range": document.range(span), "newText": label },
+            })
+        };
+        // kinds: 5 field, 12 value, 14 keyword, 18 reference.
+        let items: Vec<Value> = match token.split_once('=') {
+            Some((key, _)) if key.eq_ignore_ascii_case("ADDR") => document
+                .program
+                .labels
+                .iter()
+                .map(|label| {
+                    let detail = format!("{:04X}H", label.addr);
+                    item(label.name.clone(), 18, detail, start + key.len() + 1)
+                })
+                .collect(),
+            Some((key, _)) => microasm::values(&self.arch, key)
+                .into_iter()
+                .map(|(value, code)| {
+                    let detail = format!("{}={} encodes as {:b}", key, value, code);
+                    item(value, 12, detail, start + key.len() + 1)
+                })
+                .collect(),
+            None => microasm::FIELDS
+                .iter()
+                .map(|(field, takes_value, description)| {
+                    let label = format!("{}{}", field, if *takes_value { "=" } else { "" });
+                    item(label, 5, description.to_string(), start)
+                })
+                .chain(std::iter::once(item(
+                    "ORG".to_owned(),
+                    14,
+                    "place the following micro codes from an address".to_owned(),
+                    start,
+                )))
+                .collect(),
+        };
+        Ok(json!(items))
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (document, line, column) = self.at(params)?;
+        let program = &document.program;
+        let label = |name: &str, span: Span| {
+            let value = match program.label(name) {
+                Some(label) => format!("`{}` = {:04X}H", name, label.addr),
+                None => format!("`{}` is not defined", name),
+            };
+            json!({ "contents": { "kind": "markdown", "value": value }, "range": document.range(span) })
+        };
+        if let Some(reference) = program
+            .references
+            .iter()
+            .find(|reference| reference.span.contains(line, column))
+        {
+            return Ok(label(&reference.name, reference.span));
+        }
+        if let Some(definition) = program
+            .labels
+            .iter()
+            .find(|label| label.span.contains(line, column))
+        {
+            return Ok(label(&definition.name, definition.span));
+        }
+        let row = match program.rows.iter().find(|row| row.span.line == line) {
+            Some(row) => row,
+            None => return Ok(Value::Null),
+        };
+        let encoding = microasm::encoding(&self.arch, &row.micro_code)
+            .unwrap_or_else(|e| format!("can not encode: {}", e));
+        let value = format!(
+            "**{:04X}H** `{}`\n\n{}\n\n```text\n{}\n```",
+            row.addr,
+            microasm::disassemble(&row.micro_code),
+            microasm::describe(&row.micro_code),
+            encoding
+        );
+        Ok(json!({
+            "contents": { "kind": "markdown", "value": value },
+            "range": document.range(row.span),
+        }))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (document, line, column) = self.at(params)?;
+        let program = &document.program;
+        let name = program
+            .references
+            .iter()
+            .find(|reference| reference.span.contains(line, column))
+            .map(|reference| &reference.name)
+            .or_else(|| {
+                program
+                    .labels
+                    .iter()
+                    .find(|label| label.span.contains(line, column))
+                    .map(|label| &label.name)
+            });
+        Ok(match name.and_then(|name| program.label(name)) {
+            Some(label) => json!({
+                "uri": params["textDocument"]["uri"],
+                "range": document.range(label.span),
+            }),
+            None => Value::Null,
+        })
+    }
+}
+
+/// answer messages from `input` until `exit`, the exit code.
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> std::io::Result<i32> {
+    let mut server = Server::new();
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            // a body that is not JSON, the framing itself is intact.
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                let error = json!({ "code": PARSE_ERROR, "message": e.to_string() });
+                let response = json!({ "jsonrpc": "2.0", "id": null, "error": error });
+                write_message(&mut output, &response)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        server.handle(&message);
+        for message in server.take_output() {
+            write_message(&mut output, &message)?;
+        }
+        if let Some(code) = server.exit {
+            return Ok(code);
+        }
+    }
+    // the client went away without shutting down.
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///fetch.micro";
+    const TEXT: &str = "\
+fetch:  X=PC Z=MAR
+        X=PC ALU=X+1 Z=PC MEM=R
+        X=MDR Z=IR BR=JI ADDR=dispatch
+        ORG 100H
+dispatch:
+        BR=J ADDR=fetch
+        X=R9 BR=J ADDR=nowhere
+";
+
+    fn server() -> Server {
+        let mut server = Server::new();
+        server.handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }));
+        server.handle(&json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }));
+        server.handle(
+            &json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": { "uri": URI, "languageId": "microasm", "version": 1, "text": TEXT },
+        } }),
+        );
+        server
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        server.handle(
+            &json!({ "jsonrpc": "2.0", "id": 2, "method": method, "params": {
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        } }),
+        );
+        let mut output = server.take_output();
+        assert_eq!(output.len(), 1);
+        output.remove(0)["result"].take()
+    }
+
+    #[test]
+    fn diagnostics_on_open_and_change() {
+        let mut server = server();
+        let output = server.take_output();
+        assert_eq!(
+            output[0]["result"]["capabilities"]["definitionProvider"],
+            true
+        );
+        let params = &output[1]["params"];
+        assert_eq!(output[1]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(params["uri"], URI);
+        let diagnostics = params["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0]["message"], "unknown register R9");
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({ "start": { "line": 6, "character": 10 }, "end": { "line": 6, "character": 12 } })
+        );
+        assert_eq!(diagnostics[1]["message"], "undefined label nowhere");
+        assert_eq!(diagnostics[1]["severity"], 1);
+
+        server.handle(
+            &json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": "ｘ: X=R1\n" }],
+        } }),
+        );
+        let output = server.take_output();
+        assert_eq!(
+            output[0]["params"]["diagnostics"][0]["message"],
+            "invalid label name \"ｘ\""
+        );
+        assert_eq!(
+            output[0]["params"]["diagnostics"][0]["range"]["end"]["character"],
+            1
+        );
+    }
+
+    #[test]
+    fn completion() {
+        let mut server = server();
+        server.take_output();
+        let labels = |items: &Value| -> Vec<String> {
+            items
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_owned())
+                .collect()
+        };
+        // after "X=PC ALU=X"
+        let items = request(&mut server, "textDocument/completion", 1, 18);
+        assert_eq!(
+            labels(&items),
+            ["X+Y", "X-Y", "X&Y", "X|Y", "X^Y", "X+1", "X-1"]
+        );
+        assert_eq!(
+            items[0]["textEdit"]["range"]["start"],
+            json!({ "line": 1, "character": 17 })
+        );
+        let items = request(&mut server, "textDocument/completion", 0, 15);
+        assert!(labels(&items).contains(&"MAR".to_owned()));
+        assert!(!labels(&items).contains(&"Sw1".to_owned()));
+        let items = request(&mut server, "textDocument/completion", 2, 30);
+        assert_eq!(labels(&items), ["fetch", "dispatch"]);
+        assert_eq!(items[1]["detail"], "0100H");
+        let items = request(&mut server, "textDocument/completion", 3, 8);
+        assert_eq!(labels(&items)[0], "X=");
+        assert!(labels(&items).contains(&"HLT".to_owned()));
+        assert!(labels(&items).contains(&"ORG".to_owned()));
+    }
+
+    #[test]
+    fn hover_and_definition() {
+        let mut server = server();
+        server.take_output();
+        let hover = request(&mut server, "textDocument/hover", 1, 0);
+        let value = hover["contents"]["value"].as_str().unwrap();
+        assert!(
+            value.starts_with("**0001H** `X=PC ALU=X+1 Z=PC MEM=R`\n\nPC ← PC + 1; MDR ← [MAR]")
+        );
+        assert!(value.contains("X    Y    ALU"));
+        let hover = request(&mut server, "textDocument/hover", 2, 32);
+        assert_eq!(hover["contents"]["value"], "`dispatch` = 0100H");
+        assert_eq!(
+            request(&mut server, "textDocument/hover", 3, 10),
+            Value::Null
+        );
+
+        let location = request(&mut server, "textDocument/definition", 5, 20);
+        assert_eq!(location["uri"], URI);
+        assert_eq!(
+            location["range"],
+            json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 5 } })
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/definition", 6, 26),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn shutdown_and_exit() {
+        let mut input = vec![];
+        for message in [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/formatting", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ] {
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut output = vec![];
+        assert_eq!(serve(std::io::Cursor::new(input), &mut output).unwrap(), 0);
+        let mut output = std::io::Cursor::new(output);
+        read_message(&mut output).unwrap();
+        let unsupported = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(unsupported["error"]["code"], METHOD_NOT_FOUND);
+        let shutdown = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(
+            shutdown,
+            json!({ "jsonrpc": "2.0", "id": 3, "result": null })
+        );
+        assert_eq!(read_message(&mut output).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_error_keeps_serving() {
+        let mut input = b"Content-Length: 8\r\n\r\n{\"id\": 1".to_vec();
+        for message in [
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ] {
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut output = vec![];
+        assert_eq!(serve(std::io::Cursor::new(input), &mut output).unwrap(), 0);
+        let mut output = std::io::Cursor::new(output);
+        let error = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(error["id"], Value::Null);
+        assert_eq!(error["error"]["code"], PARSE_ERROR);
+        let shutdown = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(shutdown["id"], 2);
+    }
+}
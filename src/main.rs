@@ -1,12 +1,19 @@
 #![forbid(unsafe_code)]
 //#![cfg_attr(not(debug_assertions), deny(warnings))] // Forbid warnings in release builds
 #![warn(clippy::all, rust_2018_idioms)]
+mod datapath_view;
+mod file_io;
 mod hex_input;
 mod micro_code_view;
+mod number_input;
 mod ram_view;
 mod register_view;
 mod view;
-mod vm;
+
+use micro_programming::{
+    arch, datapath, engine, history, memory_image, microasm, project, reference, relocate,
+    rom_image, testbench, verilog, vm,
+};
 
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
@@ -15,3 +22,10 @@ fn main() {
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(Box::new(app), native_options);
 }
+
+// When compiling to web, `trunk serve` or `trunk build --release` with index.html:
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    let app = crate::view::VMView::init();
+    eframe::start_web("the_canvas_id", Box::new(app)).expect("failed to start eframe");
+}
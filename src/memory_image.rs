@@ -0,0 +1,452 @@
+//! Main memory images.
+//!
+//! Import and export of `MicroArch::memory`, or any range of it, as Intel HEX,
+//! Motorola S-record, raw binary or an `xxd` style hex dump, so macro programs
+//! assembled elsewhere can be loaded without touching the micro program.
+use std::ops::Range;
+
+/// bytes per Intel HEX / S-record data record and per dump line.
+const BYTES_PER_RECORD: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat {
+    IntelHex,
+    SRecord,
+    Binary,
+    Xxd,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 4] = [
+        ImageFormat::IntelHex,
+        ImageFormat::SRecord,
+        ImageFormat::Binary,
+        ImageFormat::Xxd,
+    ];
+
+    /// file extensions, the first one is used when saving.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ImageFormat::IntelHex => &["hex", "ihx"],
+            ImageFormat::SRecord => &["srec", "s19", "mot"],
+            ImageFormat::Binary => &["bin"],
+            ImageFormat::Xxd => &["xxd", "txt"],
+        }
+    }
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|format| {
+            format
+                .extensions()
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(extension))
+        })
+    }
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ImageFormat::IntelHex => "Intel HEX",
+            ImageFormat::SRecord => "Motorola S-record",
+            ImageFormat::Binary => "raw binary",
+            ImageFormat::Xxd => "xxd hex dump",
+        })
+    }
+}
+
+/// bytes stored from `addr` on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// write `memory[range]` in the format.
+pub fn export(memory: &[u8], range: Range<usize>, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let bytes = memory.get(range.clone()).ok_or_else(|| {
+        format!(
+            "range {:X}H..{:X}H is outside memory of {:X}H bytes",
+            range.start,
+            range.end,
+            memory.len()
+        )
+    })?;
+    Ok(match format {
+        ImageFormat::IntelHex => intel_hex(range.start, bytes).into_bytes(),
+        ImageFormat::SRecord => s_record(range.start, bytes).into_bytes(),
+        ImageFormat::Binary => bytes.to_vec(),
+        ImageFormat::Xxd => xxd(range.start, bytes).into_bytes(),
+    })
+}
+
+/// read an image. raw binary has no addresses and comes back as one segment at 0.
+pub fn parse(image: &[u8], format: ImageFormat) -> Result<Vec<Segment>, String> {
+    if format == ImageFormat::Binary {
+        return Ok(vec![Segment {
+            addr: 0,
+            bytes: image.to_vec(),
+        }]);
+    }
+    let text = std::str::from_utf8(image).map_err(|_| format!("{} is not text", format))?;
+    match format {
+        ImageFormat::IntelHex => parse_intel_hex(text),
+        ImageFormat::SRecord => parse_s_record(text),
+        ImageFormat::Xxd => parse_xxd(text),
+        ImageFormat::Binary => unreachable!(),
+    }
+}
+
+/// store segments moved by `offset`. nothing is written unless every byte fits.
+pub fn load(memory: &mut [u8], segments: &[Segment], offset: usize) -> Result<(), String> {
+    for segment in segments {
+        let start = segment.addr + offset;
+        if start + segment.bytes.len() > memory.len() {
+            return Err(format!(
+                "{:X}H bytes at {:X}H do not fit in memory of {:X}H bytes",
+                segment.bytes.len(),
+                start,
+                memory.len()
+            ));
+        }
+    }
+    for segment in segments {
+        let start = segment.addr + offset;
+        memory[start..start + segment.bytes.len()].copy_from_slice(&segment.bytes);
+    }
+    Ok(())
+}
+
+/// append to the last segment when contiguous.
+fn push(segments: &mut Vec<Segment>, addr: usize, bytes: &[u8]) {
+    match segments.last_mut() {
+        Some(last) if last.addr + last.bytes.len() == addr => last.bytes.extend_from_slice(bytes),
+        _ => segments.push(Segment {
+            addr,
+            bytes: bytes.to_vec(),
+        }),
+    }
+}
+
+fn hex_bytes(digits: &str, line: usize) -> Result<Vec<u8>, String> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return Err(format!("line {} : odd hex digits", line));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("line {} : bad hex {}", line, &digits[i..i + 2]))
+        })
+        .collect()
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// two's complement of the byte sum.
+fn intel_hex_checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+fn intel_hex_record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&addr.to_be_bytes());
+    record.push(kind);
+    record.extend_from_slice(data);
+    record.push(intel_hex_checksum(&record));
+    format!(":{}\n", hex_string(&record))
+}
+
+fn intel_hex(start: usize, bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let mut upper = 0;
+    for (i, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+        let addr = start + i * BYTES_PER_RECORD;
+        if addr >> 16 != upper {
+            upper = addr >> 16;
+            text += &intel_hex_record(4, 0, &(upper as u16).to_be_bytes());
+        }
+        text += &intel_hex_record(0, addr as u16, chunk);
+    }
+    text + &intel_hex_record(1, 0, &[])
+}
+
+fn parse_intel_hex(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = vec![];
+    let mut base = 0;
+    for (number, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| format!("line {} : record does not start with ':'", number))?;
+        let record = hex_bytes(digits, number)?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(format!("line {} : bad record length", number));
+        }
+        if intel_hex_checksum(&record) != 0 {
+            return Err(format!("line {} : checksum mismatch", number));
+        }
+        let addr = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0 => push(&mut segments, base + addr, data),
+            1 => return Ok(segments),
+            2 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4,
+            4 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16,
+            // start addresses mean nothing here.
+            3 | 5 => {}
+            kind => {
+                return Err(format!(
+                    "line {} : unknown record type {:02X}",
+                    number, kind
+                ))
+            }
+        }
+    }
+    Err("missing end of file record".to_string())
+}
+
+/// ones' complement of the byte sum.
+fn s_record_checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn s_record_line(kind: u8, addr: usize, addr_len: usize, data: &[u8]) -> String {
+    let mut record = vec![(addr_len + data.len() + 1) as u8];
+    record.extend_from_slice(&(addr as u32).to_be_bytes()[4 - addr_len..]);
+    record.extend_from_slice(data);
+    record.push(s_record_checksum(&record));
+    format!("S{}{}\n", kind, hex_string(&record))
+}
+
+fn s_record(start: usize, bytes: &[u8]) -> String {
+    // S1/S9 for 16 bit addresses, S2/S8 for 24 bit, S3/S7 for 32 bit.
+    let end = start + bytes.len();
+    let (data, termination, addr_len) = if end <= 1 << 16 {
+        (1, 9, 2)
+    } else if end <= 1 << 24 {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+    let mut text = s_record_line(0, 0, 2, b"micro_programming");
+    let records = bytes.chunks(BYTES_PER_RECORD).len();
+    for (i, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+        text += &s_record_line(data, start + i * BYTES_PER_RECORD, addr_len, chunk);
+    }
+    if records <= 0xffff {
+        text += &s_record_line(5, records, 2, &[]);
+    }
+    text + &s_record_line(termination, 0, addr_len, &[])
+}
+
+fn parse_s_record(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = vec![];
+    for (number, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+        let mut chars = line.chars();
+        if chars.next() != Some('S') {
+            return Err(format!("line {} : record does not start with 'S'", number));
+        }
+        let kind = chars
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .ok_or_else(|| format!("line {} : bad record type", number))?;
+        let record = hex_bytes(&line[2..], number)?;
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            return Err(format!("line {} : bad record length", number));
+        }
+        if s_record_checksum(&record[..record.len() - 1]) != record[record.len() - 1] {
+            return Err(format!("line {} : checksum mismatch", number));
+        }
+        let addr_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(format!("line {} : unknown record type S{}", number, kind)),
+        };
+        if record.len() < addr_len + 2 {
+            return Err(format!("line {} : record too short", number));
+        }
+        let addr = record[1..=addr_len]
+            .iter()
+            .fold(0usize, |addr, byte| addr << 8 | *byte as usize);
+        let data = &record[addr_len + 1..record.len() - 1];
+        match kind {
+            1..=3 => push(&mut segments, addr, data),
+            7..=9 => return Ok(segments),
+            _ => {}
+        }
+    }
+    // the termination record is optional in practice.
+    Ok(segments)
+}
+
+fn xxd(start: usize, bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for (i, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+        let groups: Vec<String> = chunk.chunks(2).map(hex_string).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|byte| {
+                if byte.is_ascii_graphic() || *byte == b' ' {
+                    *byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        text += &format!(
+            "{:08x}: {:<39}  {}\n",
+            start + i * BYTES_PER_RECORD,
+            groups.join(" ").to_lowercase(),
+            ascii
+        );
+    }
+    text
+}
+
+fn parse_xxd(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = vec![];
+    for (number, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (offset, rest) = line
+            .split_once(':')
+            .ok_or_else(|| format!("line {} : missing offset", number))?;
+        let addr = usize::from_str_radix(offset.trim(), 16)
+            .map_err(|_| format!("line {} : bad offset {}", number, offset))?;
+        // hex groups end where the two spaces before the text column start.
+        let hex = rest.split("  ").find(|part| !part.is_empty()).unwrap_or("");
+        let digits: String = hex.split_whitespace().collect();
+        push(&mut segments, addr, &hex_bytes(&digits, number)?);
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> Vec<u8> {
+        (0..=255u8).map(|x| x.wrapping_mul(37)).collect()
+    }
+
+    #[test]
+    fn round_trip_every_format() {
+        let memory = memory();
+        for format in ImageFormat::ALL {
+            for range in [0..256, 0x10..0x35, 0xff..0x100, 0x40..0x40] {
+                let image = export(&memory, range.clone(), format).unwrap();
+                let segments = parse(&image, format).unwrap();
+                let offset = if format == ImageFormat::Binary {
+                    range.start
+                } else {
+                    0
+                };
+                let mut loaded = vec![0; 256];
+                load(&mut loaded, &segments, offset).unwrap();
+                assert_eq!(loaded[range.clone()], memory[range.clone()], "{}", format);
+                assert!(loaded[..range.start].iter().all(|x| *x == 0));
+                assert!(loaded[range.end..].iter().all(|x| *x == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn intel_hex_records() {
+        let image = export(&[0x02, 0x41, 0x80], 0..3, ImageFormat::IntelHex).unwrap();
+        assert_eq!(
+            String::from_utf8(image).unwrap(),
+            ":030000000241803A\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn intel_hex_extended_address() {
+        let text = ":020000040001F9\n:020010001122BB\n:00000001FF\n";
+        assert_eq!(
+            parse(text.as_bytes(), ImageFormat::IntelHex).unwrap(),
+            [Segment {
+                addr: 0x10010,
+                bytes: vec![0x11, 0x22]
+            }]
+        );
+        let long: Vec<u8> = vec![0xaa; 0x10020];
+        let image = export(&long, 0xfff0..0x10010, ImageFormat::IntelHex).unwrap();
+        assert!(String::from_utf8_lossy(&image).contains(":020000040001F9"));
+        assert_eq!(
+            parse(&image, ImageFormat::IntelHex).unwrap(),
+            [Segment {
+                addr: 0xfff0,
+                bytes: vec![0xaa; 0x20]
+            }]
+        );
+    }
+
+    #[test]
+    fn intel_hex_errors() {
+        let bad = [
+            "030000000241803A\n:00000001FF\n",
+            ":030000000241803B\n:00000001FF\n",
+            ":0300000002418\n",
+            ":030000000241803A\n",
+            ":00000007F9\n",
+        ];
+        for text in bad {
+            assert!(
+                parse(text.as_bytes(), ImageFormat::IntelHex).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn s_record_lines() {
+        let image = export(&[0x02, 0x41, 0x80], 0..3, ImageFormat::SRecord).unwrap();
+        let text = String::from_utf8(image).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("S0"));
+        assert_eq!(lines[1..], ["S106000002418036", "S5030001FB", "S9030000FC"]);
+        assert!(parse(b"S106000002418037\n", ImageFormat::SRecord).is_err());
+        assert!(parse(b"S4030000FC\n", ImageFormat::SRecord).is_err());
+    }
+
+    #[test]
+    fn xxd_dump() {
+        let image = export(b"Hi\x00\x01there, memory!!!", 0..20, ImageFormat::Xxd).unwrap();
+        assert_eq!(
+            String::from_utf8(image).unwrap(),
+            "00000000: 4869 0001 7468 6572 652c 206d 656d 6f72  Hi..there, memor\n\
+             00000010: 7921 2121                                y!!!\n"
+        );
+    }
+
+    #[test]
+    fn load_checks_bounds() {
+        let mut memory = vec![0; 16];
+        let segments = [
+            Segment {
+                addr: 0,
+                bytes: vec![1],
+            },
+            Segment {
+                addr: 15,
+                bytes: vec![2, 3],
+            },
+        ];
+        assert!(load(&mut memory, &segments, 0).is_err());
+        assert_eq!(memory, [0; 16]);
+        assert!(export(&memory, 8..17, ImageFormat::Binary).is_err());
+    }
+}
@@ -1,255 +1,646 @@
-pub fn micro_code_view(
-    ui: &mut eframe::egui::Ui,
-    micro_code_base_addr: usize,
-    micro_code_addr: usize,
-    micro_codes: &mut [crate::vm::MicroCode],
-) {
-    const TABLE_NAME: [&str; 12] = [
-        "Address", "X-Bus", "Y-Bus", "ALU", "SFT", "Sin", "FL", "Z-Bus", "Mem", "Branch", "Halt",
-        "B.Addr",
+use crate::relocate::{Preview, RowOp};
+use crate::vm::{Annotation, MicroCode};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+// column titles and widths.
+const COLUMNS: [(&str, f32); 13] = [
+    ("Address", 130.0),
+    ("X-Bus", 70.0),
+    ("Y-Bus", 70.0),
+    ("ALU", 70.0),
+    ("SFT", 60.0),
+    ("Sin", 30.0),
+    ("FL", 30.0),
+    ("Z-Bus", 60.0),
+    ("Mem", 50.0),
+    ("Branch", 60.0),
+    ("Halt", 30.0),
+    ("B.Addr", 110.0),
+    ("Comment", 200.0),
+];
+
+/// Micro code table over the whole control store.
+///
+/// Only rows in view are laid out, so scrolling through all 64K rows costs the same
+/// as showing a screenful. Addresses can carry a label, shown next to the address
+/// and in place of branch addresses pointing at it, and a comment.
+pub struct MicroCodeView {
+    /// address typed into the go to box.
+    goto: u16,
+    /// keep the micro program counter in view while it moves.
+    follow: bool,
+    /// row to bring to the top on the next frame.
+    scroll_to: Option<usize>,
+    /// rows laid out by the last frame.
+    visible: Range<usize>,
+    /// micro program counter of the last frame, scrolling away from it stays put.
+    followed: usize,
+    row_op: RowOpKind,
+    /// first selected row, where Insert inserts.
+    start: u16,
+    count: u16,
+    /// destination of Move and Duplicate.
+    to: u16,
+    /// preview of the last operation, kept until it or the control store changes.
+    preview: Option<(RowOp, Result<Preview, String>)>,
+}
+
+/// what the table changed this frame.
+#[derive(Default)]
+pub struct Changes {
+    /// micro codes edited in place or moved by a row operation, with what they
+    /// held before.
+    pub edited: Vec<(usize, MicroCode)>,
+    /// labels and comments edited in place, with what they were before.
+    pub annotated: Vec<(u16, Option<Annotation>)>,
+    /// row operation applied to the whole control store.
+    pub row_op: Option<RowOp>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RowOpKind {
+    Insert,
+    Delete,
+    Move,
+    Duplicate,
+}
+
+impl RowOpKind {
+    const ALL: [RowOpKind; 4] = [
+        RowOpKind::Insert,
+        RowOpKind::Delete,
+        RowOpKind::Move,
+        RowOpKind::Duplicate,
     ];
-    eframe::egui::ScrollArea::both().show(ui, |ui| {
-        ui.columns(12, |columns| {
-            for (x, name) in TABLE_NAME.iter().enumerate() {
-                columns[x].label(*name);
+}
+
+impl std::fmt::Display for RowOpKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RowOpKind::Insert => "insert",
+            RowOpKind::Delete => "delete",
+            RowOpKind::Move => "move",
+            RowOpKind::Duplicate => "duplicate",
+        })
+    }
+}
+
+impl Default for MicroCodeView {
+    fn default() -> Self {
+        Self {
+            goto: 0,
+            follow: true,
+            scroll_to: None,
+            visible: 0..0,
+            followed: usize::MAX,
+            row_op: RowOpKind::Insert,
+            start: 0,
+            count: 1,
+            to: 0,
+            preview: None,
+        }
+    }
+}
+
+impl MicroCodeView {
+    /// forget the row operation preview once the control store changed elsewhere.
+    pub fn invalidate(&mut self) {
+        self.preview = None;
+    }
+
+    pub fn show(
+        &mut self,
+        ui: &mut Ui,
+        micro_code_addr: usize,
+        micro_codes: &mut [crate::vm::MicroCode],
+        annotations: &mut BTreeMap<u16, Annotation>,
+        arch: &Architecture,
+    ) -> Changes {
+        let mut changes = Changes::default();
+        ui.horizontal(|ui| {
+            ui.label("go to");
+            let goto = ui.add(NumberInput::new(
+                &mut self.goto,
+                16,
+                NumberFormat::Hex,
+                "go to micro address",
+            ));
+            let entered = goto.lost_focus() && ui.input().key_pressed(Key::Enter);
+            if ui.button("Go").clicked() || entered {
+                self.scroll_to = Some(self.goto as usize);
             }
-            for (x, micro_code) in micro_codes.iter_mut().enumerate() {
-                columns[0].add_sized([40.0, 18.0], {
-                    let addr = micro_code_base_addr + x;
-                    Label::new(
-                        RichText::new(format!("{:04X}H", addr))
-                            .color(if addr == micro_code_addr {
-                                Color32::RED
-                            } else {
-                                Color32::WHITE
-                            })
-                            .monospace(),
-                    )
-                });
-                columns[1].register_or_switch(&mut micro_code.x_bus, x * 10).on_hover_ui(|ui|{
-                    ui.heading("X bus.");
-                    ui.label("select which value inputted to ALU. if no one selected 0 inputted to ALU.");
-                });
-                columns[2].register_or_switch(&mut micro_code.y_bus, x * 10 + 1).on_hover_ui(|ui|{
-                    ui.heading("Y bus.");
-                    ui.label("select which value inputted to ALU. if no one selected 0 inputted to ALU.");
-                });
-                columns[3].alu(&mut micro_code.alu, x * 10 + 2).on_hover_ui(|ui|{
-                    ui.heading("ALU operation.");
-                    ui.label("calculated value submitted to shifter.");
-                });
-                columns[4].sft(&mut micro_code.sft, x * 10 + 3).on_hover_ui(|ui|{
-                    ui.heading("Shift operation.");
-                    ui.label("even if FL = 0 carry flag updated after shift operation.");
-                    ui.columns(2,|columns|{
-                        columns[0].label("Nop");
-                        columns[1].label("don't shift");
-                        columns[0].label("RRwC");
-                        columns[1].label("rotate right with carry flag");
-                        columns[0].label("RLwC");
-                        columns[1].label("rotate left with carry flag");
-                        columns[0].label("SRL");
-                        columns[1].label("logical right shift. overflowed bit stored to carry.");
-                        columns[0].label("SLL");
-                        columns[1].label("logical left shift. overflowed bit stored to carry.");
-                        columns[0].label("SRA");
-                        columns[1].label("arithmetic right shift. overflowed bit stored to carry.");
-                        columns[0].label("SLA");
-                        columns[1].label("arithmetic left shift. overflowed bit stored to carry.");
-                    });
-                });
-                columns[5].bool(&mut micro_code.sin, x * 10 + 4).on_hover_ui(|ui|{
-                    ui.heading("Shifter input bit");
-                    ui.columns(2,|columns|{
-                        columns[0].label("SRL");
-                        columns[1].label("put to bit 7 after shift op.");
-                        columns[0].label("SLL");
-                        columns[1].label("put to bit 0 after shift op.");
-                        columns[0].label("SLA");
-                        columns[1].label("put to bit 7 after shift op.");
-                    });
-                });
-                columns[6].bool(&mut micro_code.fl, x * 10 + 5).on_hover_ui(|ui|{
-                    ui.heading("Flag update.");
-                    ui.columns(2,|ui|{
-                        ui[0].label("0");
-                        ui[1].label(" don't update STR");
-                        ui[0].label("1");
-                        ui[1].label("update STR value by ALU result.");
-                    })
-                });
-                columns[7].register(&mut micro_code.z_bus, x * 10 + 6).on_hover_ui(|ui|{
-                    ui.heading("Z bus.");
-                    ui.label("if Nop selected don't update register.");
-                });
-                columns[8].mem(&mut micro_code.mem, x * 10 + 7).on_hover_ui(|ui|{
-                    ui.heading("Memory operation.");
-                    ui.columns(2,|columns|{
-                        columns[0].label("Nop");
-                        columns[1].label("no memory operation.");
-                        columns[0].label("R");
-                        columns[1].label("memory read from (MAR). read value stored to MDR.");
-                        columns[0].label("W");
-                        columns[1].label("memory write to (MAR) with MDR data");
-                    })
-                });
-                columns[9].branch(&mut micro_code.branch, x * 10 + 8).on_hover_ui(|ui|{
-                    ui.heading("Branch operation.");
-                    ui.label("");
-                    ui.horizontal_wrapped(|ui|{
-                        ui.label("+1");
-                        ui.label("Increment micro code address after execute this micro code.");
-                    });
-                    ui.horizontal_wrapped(|ui|{
-                        ui.label("J");
-                        ui.label("set micro code address specified by B.Addr after execute");
-                    });
-                    ui.horizontal_wrapped(|ui|{
-                        ui.label("JM");
-                        ui.label("if STR[0] = 1 then set micro code address specified by B.Addr else Increment. after execute");
-                    });
-                    ui.horizontal_wrapped(|ui|{
-                        ui.label("JZ");
-                        ui.label("if STR[1] = 1 then set micro code address specified by B.Addr else Increment. after execute");
-                    });
-                    ui.horizontal_wrapped(|ui|{
-                        ui.label("JC");
-                        ui.label("if STR[2] = 1 then set micro code address specified by B.Addr else Increment. after execute");
-                    });
-                    ui.horizontal_wrapped(|ui|{
-                        ui.label("JV");
-                        ui.label("if STR[3] = 1 then set micro code address specified by B.Addr else Increment. after execute");
-                    });
-                    ui.horizontal_wrapped(|ui|{
-                        ui.label("JI");
-                        ui.label("set micro code address to IR+B.addr after execute.");
-                    });
-                });
-                columns[10].bool(&mut micro_code.hlt, x * 10 + 9).on_hover_ui(|ui|{
-                    ui.heading("Halt bit");
-                    ui.label("if 1 then stop execution.");
+            if ui.button("Current").clicked() {
+                self.scroll_to = Some(micro_code_addr);
+            }
+            ui.checkbox(&mut self.follow, "follow micro code address");
+        });
+        eframe::egui::CollapsingHeader::new("Row operations").show(ui, |ui| {
+            changes.row_op = self.row_operations(ui, micro_codes, &mut changes.edited);
+        });
+        // keep a couple of rows above the micro program counter when following it.
+        let in_view = self.visible.start..self.visible.end.saturating_sub(2);
+        let moved = std::mem::replace(&mut self.followed, micro_code_addr) != micro_code_addr;
+        if self.follow && moved && self.scroll_to.is_none() && !in_view.contains(&micro_code_addr) {
+            self.scroll_to = Some(micro_code_addr.saturating_sub(2));
+        }
+
+        let labels: Vec<(u16, String)> = annotations
+            .iter()
+            .filter(|(_, annotation)| !annotation.label.is_empty())
+            .map(|(addr, annotation)| (*addr, annotation.label.clone()))
+            .collect();
+        let row_height = ui.spacing().interact_size.y;
+        let table = Table {
+            arch,
+            labels: &labels,
+            row_height,
+        };
+        let row_spacing = ui.spacing().item_spacing.y;
+        ScrollArea::horizontal()
+            .id_source("micro code columns")
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    for (name, width) in COLUMNS {
+                        ui.add_sized([width, row_height], Label::new(name));
+                    }
                 });
-                columns[11].add(eframe::egui::widgets::DragValue::new(&mut micro_code.addr)).on_hover_ui(|ui|{
-                    ui.label("Micro code address");
+                let mut rows = ScrollArea::vertical()
+                    .id_source("micro code rows")
+                    .auto_shrink([true, false]);
+                if let Some(row) = self.scroll_to.take() {
+                    rows = rows.vertical_scroll_offset(row as f32 * (row_height + row_spacing));
+                }
+                rows.show_rows(ui, row_height, micro_codes.len(), |ui, range| {
+                    self.visible = range.clone();
+                    let selected = self.start as usize..self.start as usize + self.count as usize;
+                    for addr in range {
+                        let micro_code = &mut micro_codes[addr];
+                        let before = *micro_code;
+                        let key = addr as u16;
+                        let previous = annotations.get(&key).cloned();
+                        let mut annotation = previous.clone().unwrap_or_default();
+                        let address = ui
+                            .horizontal(|ui| {
+                                let color = if addr == micro_code_addr {
+                                    Color32::RED
+                                } else if selected.contains(&addr) {
+                                    Color32::LIGHT_BLUE
+                                } else {
+                                    Color32::WHITE
+                                };
+                                row(ui, &table, addr, color, micro_code, &mut annotation)
+                            })
+                            .inner;
+                        if address.clicked() {
+                            self.select(addr, ui.input().modifiers.shift);
+                        }
+                        if *micro_code != before {
+                            changes.edited.push((addr, before));
+                        }
+                        let annotation = (!annotation.is_empty()).then_some(annotation);
+                        if annotation != previous {
+                            changes.annotated.push((key, previous));
+                            match annotation {
+                                Some(annotation) => annotations.insert(key, annotation),
+                                None => annotations.remove(&key),
+                            };
+                        }
+                    }
                 });
+            });
+        if !changes.edited.is_empty() {
+            self.invalidate();
+        }
+        changes
+    }
+
+    /// click selects a row, shift click extends the selection to it.
+    fn select(&mut self, addr: usize, extend: bool) {
+        let addr = addr as u16;
+        if extend && addr >= self.start {
+            self.count = (addr - self.start).saturating_add(1);
+        } else {
+            self.start = addr;
+            self.count = 1;
+        }
+    }
+
+    /// operation controls with a preview, returns the operation once applied and
+    /// adds the rows it changed to `edited`.
+    fn row_operations(
+        &mut self,
+        ui: &mut Ui,
+        micro_codes: &mut [MicroCode],
+        edited: &mut Vec<(usize, MicroCode)>,
+    ) -> Option<RowOp> {
+        ui.horizontal(|ui| {
+            for kind in RowOpKind::ALL {
+                ui.radio_value(&mut self.row_op, kind, kind.to_string());
             }
         });
-    });
+        ui.horizontal(|ui| {
+            let start = if self.row_op == RowOpKind::Insert {
+                "before"
+            } else {
+                "rows from"
+            };
+            ui.label(start);
+            ui.add(NumberInput::new(
+                &mut self.start,
+                16,
+                NumberFormat::Hex,
+                "row operation start",
+            ));
+            ui.label("count");
+            ui.add(NumberInput::new(
+                &mut self.count,
+                16,
+                NumberFormat::Unsigned,
+                "row operation count",
+            ));
+            match self.row_op {
+                RowOpKind::Move => {
+                    ui.label("to start at");
+                }
+                RowOpKind::Duplicate => {
+                    ui.label("before");
+                }
+                _ => return,
+            }
+            ui.add(NumberInput::new(
+                &mut self.to,
+                16,
+                NumberFormat::Hex,
+                "row operation destination",
+            ));
+        })
+        .response
+        .on_hover_text("click an address to select rows, shift click to extend");
+        ui.label("labels and comments move with their rows, duplicated rows get none.");
+        let (start, count, to) = (self.start as usize, self.count as usize, self.to as usize);
+        let op = match self.row_op {
+            RowOpKind::Insert => RowOp::Insert { at: start, count },
+            RowOpKind::Delete => RowOp::Delete { start, count },
+            RowOpKind::Move => RowOp::Move { start, count, to },
+            RowOpKind::Duplicate => RowOp::Duplicate { start, count, to },
+        };
+        if !matches!(&self.preview, Some((cached, _)) if *cached == op) {
+            self.preview = Some((op, crate::relocate::preview(micro_codes, op)));
+        }
+        let preview = match &self.preview {
+            Some((_, Ok(preview))) => preview,
+            Some((_, Err(e))) => {
+                ui.colored_label(Color32::RED, e);
+                return None;
+            }
+            None => return None,
+        };
+        for warning in &preview.warnings {
+            ui.colored_label(Color32::YELLOW, warning);
+        }
+        ui.label(format!(
+            "{} branch addresses relocated",
+            preview.relocations.len()
+        ));
+        ScrollArea::vertical()
+            .id_source("relocations")
+            .max_height(100.0)
+            .show(ui, |ui| {
+                for relocation in &preview.relocations {
+                    ui.monospace(format!(
+                        "{:04X}H {:>4} {:04X}H → {:04X}H",
+                        relocation.addr,
+                        relocation.branch.to_string(),
+                        relocation.from,
+                        relocation.to
+                    ));
+                }
+            });
+        if ui.button(format!("Apply {}", self.row_op)).clicked() {
+            for (addr, (micro_code, after)) in
+                micro_codes.iter_mut().zip(&preview.program).enumerate()
+            {
+                if micro_code != after {
+                    edited.push((addr, *micro_code));
+                    *micro_code = *after;
+                }
+            }
+            self.invalidate();
+            Some(op)
+        } else {
+            None
+        }
+    }
 }
 
-use crate::vm::{AluOp, Branch, MemOp, Register, RegisterOrSwitch, ShiftOp};
-use eframe::egui::{Color32, Label, Response, RichText, Ui};
-
-const REGISTER_OR_SWITCH_SELECTABLE: [RegisterOrSwitch; 2] =
-    [RegisterOrSwitch::Sw1, RegisterOrSwitch::Sw2];
-
-const REGISTER_SELECTABLE: [Register; 13] = [
-    Register::Nop,
-    Register::R0,
-    Register::R1,
-    Register::R2,
-    Register::R3,
-    Register::R4,
-    Register::R5,
-    Register::R6,
-    Register::Pc,
-    Register::Ir,
-    Register::Mdr,
-    Register::Mar,
-    Register::Str,
-];
+/// what every row of one frame shares.
+struct Table<'a> {
+    arch: &'a Architecture,
+    /// (address, label) of every labelled address in address order.
+    labels: &'a [(u16, String)],
+    row_height: f32,
+}
 
-const ALU_OP_SELECTABLE: [AluOp; 7] = [
-    AluOp::XPlusY,
-    AluOp::XMinusY,
-    AluOp::XAndY,
-    AluOp::XorY,
-    AluOp::XxorY,
-    AluOp::XPlus1,
-    AluOp::XMinus1,
-];
+impl Table<'_> {
+    fn label(&self, addr: u16) -> Option<&str> {
+        let index = self
+            .labels
+            .binary_search_by_key(&addr, |(labelled, _)| *labelled)
+            .ok()?;
+        Some(&self.labels[index].1)
+    }
 
-const SHIFT_OP_SELECTABLE: [ShiftOp; 7] = [
-    ShiftOp::Nop,
-    ShiftOp::RRwC,
-    ShiftOp::RlwC,
-    ShiftOp::Srl,
-    ShiftOp::Sll,
-    ShiftOp::Sra,
-    ShiftOp::Sla,
-];
+    /// why branching to `label` at `addr` by name is ambiguous or impossible.
+    fn label_problem(&self, addr: u16, label: &str) -> Option<String> {
+        if label.is_empty() {
+            None
+        } else if !crate::microasm::is_identifier(label) {
+            Some(format!("{:?} is not a valid label name", label))
+        } else {
+            self.labels
+                .iter()
+                .find(|(other, name)| *other != addr && name == label)
+                .map(|(other, _)| format!("{} also labels {:04X}H", label, other))
+        }
+    }
+}
 
-const MEM_OP_SELECTABLE: [MemOp; 3] = [MemOp::Nop, MemOp::R, MemOp::W];
+/// cells of one micro code, returns the address cell.
+fn row(
+    ui: &mut Ui,
+    table: &Table<'_>,
+    addr: usize,
+    color: Color32,
+    micro_code: &mut crate::vm::MicroCode,
+    annotation: &mut Annotation,
+) -> Response {
+    let (arch, row_height) = (table.arch, table.row_height);
+    // combo boxes take the slider width as their minimum.
+    let width = |ui: &mut Ui, column: usize| ui.spacing_mut().slider_width = COLUMNS[column].1;
+    let address = RichText::new(format!("{:04X}H {}", addr, annotation.label))
+        .color(color)
+        .monospace();
+    let address = ui
+        .add_sized(
+            [COLUMNS[0].1, row_height],
+            Label::new(address).wrap(false).sense(Sense::click()),
+        )
+        .on_hover_ui(|ui| {
+            match arch.encode(micro_code) {
+                Ok(word) => ui.label(format!(
+                    "{:0width$X}H",
+                    word,
+                    width = (arch.word_width() as usize).div_ceil(4)
+                )),
+                Err(e) => ui.colored_label(Color32::RED, e.to_string()),
+            };
+            if !annotation.comment.is_empty() {
+                ui.label(&annotation.comment);
+            }
+            ui.label("right click to edit the label and comment.");
+        })
+        .context_menu(|ui| {
+            ui.label("label");
+            ui.text_edit_singleline(&mut annotation.label);
+            if let Some(problem) = table.label_problem(addr as u16, &annotation.label) {
+                ui.colored_label(Color32::RED, problem);
+            }
+            ui.label("comment");
+            ui.text_edit_singleline(&mut annotation.comment);
+        });
+    width(ui, 1);
+    ui.register_or_switch(arch, &mut micro_code.x_bus, addr * 16)
+        .on_hover_ui(|ui| {
+            ui.heading("X bus.");
+            ui.label("select which value inputted to ALU. if no one selected 0 inputted to ALU.");
+        });
+    width(ui, 2);
+    ui.register_or_switch(arch, &mut micro_code.y_bus, addr * 16 + 1)
+        .on_hover_ui(|ui| {
+            ui.heading("Y bus.");
+            ui.label("select which value inputted to ALU. if no one selected 0 inputted to ALU.");
+        });
+    width(ui, 3);
+    ui.alu(arch, &mut micro_code.alu, addr * 16 + 2)
+        .on_hover_ui(|ui| {
+            ui.heading("ALU operation.");
+            ui.label("calculated value submitted to shifter.");
+        });
+    width(ui, 4);
+    ui.sft(arch, &mut micro_code.sft, addr * 16 + 3)
+        .on_hover_ui(|ui| {
+            ui.heading("Shift operation.");
+            ui.label("even if FL = 0 carry flag updated after shift operation.");
+            ui.columns(2, |columns| {
+                columns[0].label("Nop");
+                columns[1].label("don't shift");
+                columns[0].label("RRwC");
+                columns[1].label("rotate right with carry flag");
+                columns[0].label("RLwC");
+                columns[1].label("rotate left with carry flag");
+                columns[0].label("SRL");
+                columns[1].label("logical right shift. overflowed bit stored to carry.");
+                columns[0].label("SLL");
+                columns[1].label("logical left shift. overflowed bit stored to carry.");
+                columns[0].label("SRA");
+                columns[1].label("arithmetic right shift. overflowed bit stored to carry.");
+                columns[0].label("SLA");
+                columns[1].label("arithmetic left shift. overflowed bit stored to carry.");
+            });
+        });
+    width(ui, 5);
+    ui.bool(&mut micro_code.sin, addr * 16 + 4)
+        .on_hover_ui(|ui| {
+            ui.heading("Shifter input bit");
+            ui.columns(2, |columns| {
+                columns[0].label("SRL");
+                columns[1].label("put to bit 7 after shift op.");
+                columns[0].label("SLL");
+                columns[1].label("put to bit 0 after shift op.");
+                columns[0].label("SLA");
+                columns[1].label("put to bit 7 after shift op.");
+            });
+        });
+    width(ui, 6);
+    ui.bool(&mut micro_code.fl, addr * 16 + 5)
+        .on_hover_ui(|ui| {
+            ui.heading("Flag update.");
+            ui.columns(2, |ui| {
+                ui[0].label("0");
+                ui[1].label(" don't update STR");
+                ui[0].label("1");
+                ui[1].label("update STR value by ALU result.");
+            })
+        });
+    width(ui, 7);
+    ui.register(arch, &mut micro_code.z_bus, addr * 16 + 6)
+        .on_hover_ui(|ui| {
+            ui.heading("Z bus.");
+            ui.label(
+                "if Nop selected don't update register. CNT takes the value as sequencer counter.",
+            );
+        });
+    width(ui, 8);
+    ui.mem(arch, &mut micro_code.mem, addr * 16 + 7)
+        .on_hover_ui(|ui| {
+            ui.heading("Memory operation.");
+            ui.columns(2, |columns| {
+                columns[0].label("Nop");
+                columns[1].label("no memory operation.");
+                columns[0].label("R");
+                columns[1].label("memory read from (MAR). read value stored to MDR.");
+                columns[0].label("W");
+                columns[1].label("memory write to (MAR) with MDR data");
+            })
+        });
+    width(ui, 9);
+    ui.branch(arch, &mut micro_code.branch, addr * 16 + 8).on_hover_ui(|ui|{
+        ui.heading("Branch operation.");
+        ui.label("");
+        ui.horizontal_wrapped(|ui|{
+            ui.label("+1");
+            ui.label("Increment micro code address after execute this micro code.");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("J");
+            ui.label("set micro code address specified by B.Addr after execute");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("JM");
+            ui.label("if STR[0] = 1 then set micro code address specified by B.Addr else Increment. after execute");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("JZ");
+            ui.label("if STR[1] = 1 then set micro code address specified by B.Addr else Increment. after execute");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("JC");
+            ui.label("if STR[2] = 1 then set micro code address specified by B.Addr else Increment. after execute");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("JV");
+            ui.label("if STR[3] = 1 then set micro code address specified by B.Addr else Increment. after execute");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("JI");
+            ui.label("set micro code address to IR+B.addr after execute.");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("LDCT");
+            ui.label("load sequencer counter with B.Addr then Increment. after execute");
+        });
+        ui.horizontal_wrapped(|ui|{
+            ui.label("RPCT");
+            ui.label("if CNT != 0 then decrement CNT and set micro code address specified by B.Addr else Increment. after execute");
+        });
+    });
+    width(ui, 10);
+    ui.bool(&mut micro_code.hlt, addr * 16 + 9)
+        .on_hover_ui(|ui| {
+            ui.heading("Halt bit");
+            ui.label("if 1 then stop execution.");
+        });
+    let target = micro_code.addr;
+    let branch_address = if crate::relocate::branches_to_address(micro_code.branch) {
+        width(ui, 11);
+        let selected_text = table
+            .label(target)
+            .map_or_else(|| format!("{:04X}H", target), str::to_owned);
+        eframe::egui::ComboBox::from_id_source(addr * 16 + 10)
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                ui.add(NumberInput::new(
+                    &mut micro_code.addr,
+                    16,
+                    NumberFormat::Hex,
+                    "branch address",
+                ));
+                for (labelled, label) in table.labels {
+                    ui.selectable_value(&mut micro_code.addr, *labelled, label)
+                        .on_hover_text(format!("{:04X}H", labelled));
+                }
+            })
+            .response
+    } else {
+        ui.add_sized(
+            [COLUMNS[11].1, row_height],
+            eframe::egui::widgets::DragValue::new(&mut micro_code.addr),
+        )
+    };
+    branch_address.on_hover_ui(|ui| {
+        ui.label("Micro code address");
+        if let Some(label) = table.label(target) {
+            ui.label(format!("{} = {:04X}H", label, target));
+        }
+    });
+    ui.add_sized(
+        [COLUMNS[12].1, row_height],
+        TextEdit::singleline(&mut annotation.comment),
+    );
+    address
+}
 
-const BRANCH_OP_SELECTABLE: [Branch; 7] = [
-    Branch::Plus1,
-    Branch::J,
-    Branch::JM,
-    Branch::JZ,
-    Branch::JC,
-    Branch::JV,
-    Branch::JI,
-];
+use crate::arch::{Architecture, OpSpec};
+use crate::number_input::{NumberFormat, NumberInput};
+use crate::vm::{AluOp, Branch, MemOp, Register, RegisterOrSwitch, ShiftOp};
+use eframe::egui::{Color32, Key, Label, Response, RichText, ScrollArea, Sense, TextEdit, Ui};
 
 // Ui に追加実装.
 trait AdditionalWidget {
-    fn register_or_switch(&mut self, register: &mut RegisterOrSwitch, id: usize) -> Response;
-    fn register(&mut self, register: &mut Register, id: usize) -> Response;
-    fn alu(&mut self, alu_op: &mut AluOp, id: usize) -> Response;
-    fn sft(&mut self, sft_op: &mut ShiftOp, id: usize) -> Response;
+    fn register_or_switch(
+        &mut self,
+        arch: &Architecture,
+        register: &mut RegisterOrSwitch,
+        id: usize,
+    ) -> Response;
+    fn register(&mut self, arch: &Architecture, register: &mut Register, id: usize) -> Response;
+    fn alu(&mut self, arch: &Architecture, alu_op: &mut AluOp, id: usize) -> Response;
+    fn sft(&mut self, arch: &Architecture, sft_op: &mut ShiftOp, id: usize) -> Response;
     fn bool(&mut self, x: &mut bool, id: usize) -> Response;
-    fn mem(&mut self, mem_op: &mut MemOp, id: usize) -> Response;
-    fn branch(&mut self, branch_op: &mut Branch, id: usize) -> Response;
+    fn mem(&mut self, arch: &Architecture, mem_op: &mut MemOp, id: usize) -> Response;
+    fn branch(&mut self, arch: &Architecture, branch_op: &mut Branch, id: usize) -> Response;
 }
 impl AdditionalWidget for Ui {
-    fn register_or_switch(&mut self, register: &mut RegisterOrSwitch, id: usize) -> Response {
+    fn register_or_switch(
+        &mut self,
+        arch: &Architecture,
+        register: &mut RegisterOrSwitch,
+        id: usize,
+    ) -> Response {
         eframe::egui::ComboBox::from_id_source(id)
-            .selected_text(register.to_string())
+            .selected_text(arch.source_name(*register))
             .show_ui(self, |ui| {
-                for selectable in REGISTER_OR_SWITCH_SELECTABLE {
-                    ui.selectable_value(register, selectable, selectable.to_string());
+                for selectable in &arch.switches {
+                    ui.selectable_value(register, selectable.switch, &selectable.name);
                 }
-                for selectable in REGISTER_SELECTABLE {
+                for selectable in arch.registers.iter().filter(|r| r.source) {
                     ui.selectable_value(
                         register,
-                        RegisterOrSwitch::Register(selectable),
-                        selectable.to_string(),
+                        RegisterOrSwitch::Register(selectable.register),
+                        &selectable.name,
                     );
                 }
             })
             .response
     }
 
-    fn register(&mut self, register: &mut Register, id: usize) -> Response {
+    fn register(&mut self, arch: &Architecture, register: &mut Register, id: usize) -> Response {
         eframe::egui::ComboBox::from_id_source(id)
-            .selected_text(register.to_string())
+            .selected_text(arch.register_name(*register))
             .show_ui(self, |ui| {
-                for selectable in REGISTER_SELECTABLE {
-                    ui.selectable_value(register, selectable, selectable.to_string());
+                for selectable in arch.registers.iter().filter(|r| r.destination) {
+                    ui.selectable_value(register, selectable.register, &selectable.name);
                 }
             })
             .response
     }
 
-    fn alu(&mut self, alu_op: &mut AluOp, id: usize) -> Response {
-        eframe::egui::ComboBox::from_id_source(id)
-            .selected_text(alu_op.to_string())
-            .show_ui(self, |ui| {
-                for selectable in ALU_OP_SELECTABLE {
-                    ui.selectable_value(alu_op, selectable, selectable.to_string());
-                }
-            })
-            .response
+    fn alu(&mut self, arch: &Architecture, alu_op: &mut AluOp, id: usize) -> Response {
+        op_combo_box(self, &arch.alu_ops, alu_op, id)
     }
-    fn sft(&mut self, shift_op: &mut ShiftOp, id: usize) -> Response {
-        eframe::egui::ComboBox::from_id_source(id)
-            .selected_text(shift_op.to_string())
-            .show_ui(self, |ui| {
-                for selectable in SHIFT_OP_SELECTABLE {
-                    ui.selectable_value(shift_op, selectable, selectable.to_string());
-                }
-            })
-            .response
+    fn sft(&mut self, arch: &Architecture, shift_op: &mut ShiftOp, id: usize) -> Response {
+        op_combo_box(self, &arch.shift_ops, shift_op, id)
     }
 
     fn bool(&mut self, register: &mut bool, id: usize) -> Response {
@@ -261,25 +652,32 @@ impl AdditionalWidget for Ui {
             })
             .response
     }
-    fn mem(&mut self, mem_op: &mut MemOp, id: usize) -> Response {
-        eframe::egui::ComboBox::from_id_source(id)
-            .selected_text(mem_op.to_string())
-            .show_ui(self, |ui| {
-                for selectable in MEM_OP_SELECTABLE {
-                    ui.selectable_value(mem_op, selectable, selectable.to_string());
-                }
-            })
-            .response
+    fn mem(&mut self, arch: &Architecture, mem_op: &mut MemOp, id: usize) -> Response {
+        op_combo_box(self, &arch.mem_ops, mem_op, id)
     }
 
-    fn branch(&mut self, branch: &mut Branch, id: usize) -> Response {
-        eframe::egui::ComboBox::from_id_source(id)
-            .selected_text(branch.to_string())
-            .show_ui(self, |ui| {
-                for selectable in BRANCH_OP_SELECTABLE {
-                    ui.selectable_value(branch, selectable, selectable.to_string());
-                }
-            })
-            .response
+    fn branch(&mut self, arch: &Architecture, branch: &mut Branch, id: usize) -> Response {
+        op_combo_box(self, &arch.branches, branch, id)
     }
 }
+
+/// combo box listing operations provided by the architecture.
+fn op_combo_box<T: Copy + PartialEq + ToString>(
+    ui: &mut Ui,
+    ops: &[OpSpec<T>],
+    op: &mut T,
+    id: usize,
+) -> Response {
+    let selected_text = ops
+        .iter()
+        .find(|spec| spec.op == *op)
+        .map_or_else(|| format!("{}?", op.to_string()), |spec| spec.name.clone());
+    eframe::egui::ComboBox::from_id_source(id)
+        .selected_text(selected_text)
+        .show_ui(ui, |ui| {
+            for selectable in ops {
+                ui.selectable_value(op, selectable.op, &selectable.name);
+            }
+        })
+        .response
+}
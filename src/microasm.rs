@@ -0,0 +1,971 @@
+//! Micro codes as text.
+//!
+//! One micro code per line, fields as `KEY=VALUE` in any order and omitted when
+//! they hold the `MicroCode::default()` value; values are the `ToString`
+//! mnemonics, matched regardless of case:
+//!
+//! ```text
+//! ; fetch
+//! fetch:  X=PC Z=MAR
+//!         X=PC ALU=X+1 Z=PC MEM=R
+//!         X=MDR Z=IR BR=JI ADDR=dispatch
+//!
+//!         ORG 0100H
+//! dispatch:
+//!         BR=J ADDR=fetch
+//! ```
+//!
+//! `SIN`, `FL` and `HLT` are bare flags and a row of nothing but defaults is `NOP`.
+//! Micro codes take consecutive micro addresses from 0000H and `ORG` moves the ones
+//! that follow. `name:` labels the address of the next micro code. `ADDR` is a label
+//! or a number, `0100H`, `0x100` or decimal. `;` starts a comment.
+//!
+//! `parse` keeps going after errors and reports everything it finds as diagnostics
+//! for the language server; `assemble` wants a clean program.
+use crate::arch::{Architecture, OpSpec};
+use crate::vm::{AluOp, Branch, MemOp, MicroCode, Register, RegisterOrSwitch, ShiftOp};
+use std::collections::BTreeMap;
+
+/// words in the control store.
+pub const CONTROL_STORE: usize = 1 << 16;
+
+/// field name, whether it takes a value and what it does.
+pub const FIELDS: [(&str, bool, &str); 12] = [
+    ("X", true, "X bus source"),
+    ("Y", true, "Y bus source"),
+    ("ALU", true, "ALU operation on X and Y"),
+    ("SFT", true, "shift of the ALU result"),
+    ("SIN", false, "bit shifted in by SRL, SLL and SLA"),
+    ("FL", false, "update the flags in STR"),
+    ("Z", true, "Z bus destination of the shifted result"),
+    ("MEM", true, "memory access at MAR through MDR"),
+    ("BR", true, "next micro address"),
+    ("ADDR", true, "branch address, label or number"),
+    ("HLT", false, "halt after this micro code"),
+    ("NOP", false, "a row of nothing but defaults"),
+];
+
+/// 0 based line and byte columns of a piece of source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn contains(&self, line: usize, column: usize) -> bool {
+        self.line == line && self.start <= column && column <= self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// an assembled micro code and the statement it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub addr: u16,
+    pub span: Span,
+    pub micro_code: MicroCode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub name: String,
+    pub addr: u16,
+    pub span: Span,
+}
+
+/// `ADDR=name` of `rows[row]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub name: String,
+    pub span: Span,
+    pub row: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub rows: Vec<Row>,
+    pub labels: Vec<Label>,
+    pub references: Vec<Reference>,
+    /// sorted by position.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Program {
+    pub fn label(&self, name: &str) -> Option<&Label> {
+        self.labels.iter().find(|label| label.name == name)
+    }
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+    /// the whole control store, unassembled rows are `MicroCode::default()`.
+    pub fn micro_program(&self) -> Vec<MicroCode> {
+        let mut micro_program = vec![MicroCode::default(); CONTROL_STORE];
+        for row in &self.rows {
+            micro_program[row.addr as usize] = row.micro_code;
+        }
+        micro_program
+    }
+}
+
+/// assemble `text` for `arch`, diagnostics and all.
+pub fn parse(text: &str, arch: &Architecture) -> Program {
+    let mut parser = Parser {
+        arch,
+        known: Architecture::default(),
+        program: Program::default(),
+        next: 0,
+        pending: vec![],
+        owners: BTreeMap::new(),
+    };
+    for (line, text) in text.lines().enumerate() {
+        parser.line(line, text);
+    }
+    parser.finish()
+}
+
+/// the control store of `text`, or its first error as `line: message`.
+pub fn assemble(text: &str, arch: &Architecture) -> Result<Vec<MicroCode>, String> {
+    let program = parse(text, arch);
+    if let Some(error) = program.errors().next() {
+        return Err(format!("line {}: {}", error.span.line + 1, error.message));
+    }
+    Ok(program.micro_program())
+}
+
+struct Parser<'a> {
+    arch: &'a Architecture,
+    /// every value the mnemonics can name, `arch` may lack some.
+    known: Architecture,
+    program: Program,
+    /// micro address of the next micro code, may run past the control store.
+    next: usize,
+    /// labels waiting for the next micro code.
+    pending: Vec<(String, Span)>,
+    /// line of the micro code at each address.
+    owners: BTreeMap<u16, usize>,
+}
+
+fn tokens(line: &str) -> Vec<(usize, &str)> {
+    let code = line.split(';').next().unwrap_or_default();
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, c) in code.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                tokens.push((s, &code[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s, &code[s..]));
+    }
+    tokens
+}
+
+/// whether `text` can name a label.
+pub fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `0100H`, `0x100` or decimal. Numbers start with a digit so `ADDH` stays a label.
+fn number(text: &str) -> Option<u64> {
+    if !text.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_suffix(['H', 'h']) {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn op<T: Copy + PartialEq + ToString>(
+    known: &[OpSpec<T>],
+    available: &[OpSpec<T>],
+    kind: &str,
+    value: &str,
+    machine: &str,
+) -> Result<T, String> {
+    let op = known
+        .iter()
+        .map(|spec| spec.op)
+        .find(|op| op.to_string().eq_ignore_ascii_case(value))
+        .ok_or_else(|| format!("unknown {} {}", kind, value))?;
+    if available.iter().any(|spec| spec.op == op) {
+        Ok(op)
+    } else {
+        Err(format!("{} is not available in {}", value, machine))
+    }
+}
+
+impl Parser<'_> {
+    fn report(&mut self, span: Span, severity: Severity, message: String) {
+        self.program.diagnostics.push(Diagnostic {
+            span,
+            severity,
+            message,
+        });
+    }
+    fn error(&mut self, span: Span, message: String) {
+        self.report(span, Severity::Error, message);
+    }
+
+    fn line(&mut self, line: usize, text: &str) {
+        let tokens = tokens(text);
+        let mut rest = &tokens[..];
+        if let Some((start, name)) = rest
+            .first()
+            .and_then(|(start, token)| Some((*start, token.strip_suffix(':')?)))
+        {
+            let span = Span {
+                line,
+                start,
+                end: start + name.len(),
+            };
+            self.define(name, span);
+            rest = &rest[1..];
+        }
+        match rest {
+            [] => {}
+            [(start, org), operands @ ..] if org.eq_ignore_ascii_case("ORG") => {
+                let span = Span {
+                    line,
+                    start: *start,
+                    end: start + org.len(),
+                };
+                self.org(span, operands);
+            }
+            _ => self.micro_code(line, rest),
+        }
+    }
+
+    fn define(&mut self, name: &str, span: Span) {
+        if !is_identifier(name) {
+            return self.error(span, format!("invalid label name {:?}", name));
+        }
+        let defined = self
+            .program
+            .label(name)
+            .map(|label| label.span)
+            .or_else(|| {
+                self.pending
+                    .iter()
+                    .find(|(pending, _)| pending == name)
+                    .map(|(_, span)| *span)
+            });
+        match defined {
+            Some(defined) => self.error(
+                span,
+                format!(
+                    "label {} is already defined on line {}",
+                    name,
+                    defined.line + 1
+                ),
+            ),
+            None => self.pending.push((name.to_owned(), span)),
+        }
+    }
+
+    fn org(&mut self, span: Span, operands: &[(usize, &str)]) {
+        let (start, operand) = match operands {
+            [operand] => *operand,
+            _ => return self.error(span, "ORG takes one micro address".to_owned()),
+        };
+        let span = Span {
+            start,
+            end: start + operand.len(),
+            ..span
+        };
+        match number(operand) {
+            Some(addr) if addr < CONTROL_STORE as u64 => self.next = addr as usize,
+            Some(addr) => self.error(span, outside(addr)),
+            None => self.error(span, format!("invalid micro address {}", operand)),
+        }
+    }
+
+    /// a branch address fitting both the control store and the address field.
+    fn address(&self, value: u64) -> Result<u16, String> {
+        let width = self.arch.fields.addr;
+        if value >= CONTROL_STORE as u64 {
+            Err(outside(value))
+        } else if width < 64 && value >> width != 0 {
+            Err(format!(
+                "{:04X}H does not fit the {} bit address field",
+                value, width
+            ))
+        } else {
+            Ok(value as u16)
+        }
+    }
+
+    fn source(&self, value: &str, bus: &str) -> Result<RegisterOrSwitch, String> {
+        let source = self
+            .known
+            .switches
+            .iter()
+            .map(|switch| switch.switch)
+            .chain(
+                self.known
+                    .registers
+                    .iter()
+                    .map(|register| RegisterOrSwitch::Register(register.register)),
+            )
+            .find(|source| source.to_string().eq_ignore_ascii_case(value))
+            .ok_or_else(|| format!("unknown register {}", value))?;
+        let available = match source {
+            RegisterOrSwitch::Register(register) => self.arch.is_source(register),
+            switch => self.arch.switch(switch).is_some(),
+        };
+        if available {
+            Ok(source)
+        } else {
+            Err(format!(
+                "{} can not drive the {} bus in {}",
+                value, bus, self.arch.name
+            ))
+        }
+    }
+
+    fn destination(&self, value: &str) -> Result<Register, String> {
+        let is_switch = self
+            .known
+            .switches
+            .iter()
+            .any(|switch| switch.switch.to_string().eq_ignore_ascii_case(value));
+        if is_switch {
+            return Err(format!("{} is a switch, it can not be written", value));
+        }
+        let register = self
+            .known
+            .registers
+            .iter()
+            .map(|register| register.register)
+            .find(|register| register.to_string().eq_ignore_ascii_case(value))
+            .ok_or_else(|| format!("unknown register {}", value))?;
+        if self.arch.is_destination(register) {
+            Ok(register)
+        } else {
+            Err(format!(
+                "{} can not be written from the Z bus in {}",
+                value, self.arch.name
+            ))
+        }
+    }
+
+    fn micro_code(&mut self, line: usize, tokens: &[(usize, &str)]) {
+        let (first, last) = (tokens[0], tokens[tokens.len() - 1]);
+        let span = Span {
+            line,
+            start: first.0,
+            end: last.0 + last.1.len(),
+        };
+        let mut micro_code = MicroCode::default();
+        let mut seen: Vec<(&str, Span)> = vec![];
+        let mut reference = None;
+        for (start, token) in tokens {
+            let token_span = Span {
+                line,
+                start: *start,
+                end: start + token.len(),
+            };
+            let (key, value) = match token.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (*token, None),
+            };
+            let value_span = Span {
+                start: start + key.len() + 1,
+                ..token_span
+            };
+            let (field, takes_value) = match FIELDS
+                .iter()
+                .find(|(field, _, _)| field.eq_ignore_ascii_case(key))
+            {
+                Some((field, takes_value, _)) => (*field, *takes_value),
+                None => {
+                    self.error(token_span, format!("unknown field {}", key));
+                    continue;
+                }
+            };
+            if seen.iter().any(|(seen, _)| *seen == field) {
+                self.error(token_span, format!("{} is given twice", field));
+                continue;
+            }
+            seen.push((field, token_span));
+            let value = match (takes_value, value) {
+                (true, Some(value)) => value,
+                (false, None) => "",
+                (true, None) => {
+                    self.error(
+                        token_span,
+                        format!("{} needs a value, {}=...", field, field),
+                    );
+                    continue;
+                }
+                (false, Some(_)) => {
+                    self.error(token_span, format!("{} takes no value", field));
+                    continue;
+                }
+            };
+            let (known, arch) = (&self.known, self.arch);
+            let machine = &arch.name;
+            let result = match field {
+                "X" => self.source(value, "X").map(|x| micro_code.x_bus = x),
+                "Y" => self.source(value, "Y").map(|y| micro_code.y_bus = y),
+                "Z" => self.destination(value).map(|z| micro_code.z_bus = z),
+                "ALU" => op(
+                    &known.alu_ops,
+                    &arch.alu_ops,
+                    "ALU operation",
+                    value,
+                    machine,
+                )
+                .map(|alu| micro_code.alu = alu),
+                "SFT" => op(&known.shift_ops, &arch.shift_ops, "shift", value, machine)
+                    .map(|sft| micro_code.sft = sft),
+                "MEM" => op(
+                    &known.mem_ops,
+                    &arch.mem_ops,
+                    "memory operation",
+                    value,
+                    machine,
+                )
+                .map(|mem| micro_code.mem = mem),
+                "BR" => op(&known.branches, &arch.branches, "branch", value, machine)
+                    .map(|branch| micro_code.branch = branch),
+                "ADDR" => match number(value) {
+                    Some(addr) => self.address(addr).map(|addr| micro_code.addr = addr),
+                    None if is_identifier(value) => {
+                        reference = Some((value.to_owned(), value_span));
+                        Ok(())
+                    }
+                    None => Err(format!("invalid address {}", value)),
+                },
+                "SIN" => {
+                    micro_code.sin = true;
+                    Ok(())
+                }
+                "FL" => {
+                    micro_code.fl = true;
+                    Ok(())
+                }
+                "HLT" => {
+                    micro_code.hlt = true;
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(message) = result {
+                self.error(value_span, message);
+            }
+        }
+        self.check(&micro_code, &seen);
+
+        let addr = self.next;
+        self.next += 1;
+        if addr >= CONTROL_STORE {
+            return self.error(span, outside(addr as u64));
+        }
+        let addr = addr as u16;
+        if let Some(owner) = self.owners.insert(addr, line) {
+            self.error(
+                span,
+                format!(
+                    "{:04X}H already holds the micro code on line {}",
+                    addr,
+                    owner + 1
+                ),
+            );
+        }
+        for (name, span) in std::mem::take(&mut self.pending) {
+            self.program.labels.push(Label { name, addr, span });
+        }
+        if let Some((name, span)) = reference {
+            self.program.references.push(Reference {
+                name,
+                span,
+                row: self.program.rows.len(),
+            });
+        }
+        self.program.rows.push(Row {
+            addr,
+            span,
+            micro_code,
+        });
+    }
+
+    /// fields which are fine on their own but not together.
+    fn check(&mut self, micro_code: &MicroCode, seen: &[(&str, Span)]) {
+        let span_of = |field| {
+            seen.iter()
+                .find(|(seen, _)| *seen == field)
+                .map(|(_, span)| *span)
+        };
+        if let (Some(nop), true) = (span_of("NOP"), seen.len() > 1) {
+            self.error(nop, "NOP can not be combined with other fields".to_owned());
+        }
+        let branch = micro_code.branch.to_string();
+        if let (Some(br), None) = (span_of("BR"), span_of("ADDR")) {
+            if micro_code.branch != Branch::Plus1 {
+                self.error(br, format!("BR={} needs ADDR", branch));
+            }
+        }
+        if let Some(z) = span_of("Z") {
+            if micro_code.mem == MemOp::R && micro_code.z_bus == Register::Mdr {
+                self.error(z, "Z=MDR is overwritten by MEM=R".to_owned());
+            }
+            if micro_code.z_bus == Register::Cnt
+                && matches!(micro_code.branch, Branch::Ldct | Branch::Rpct)
+            {
+                self.error(
+                    z,
+                    format!("Z=CNT conflicts with BR={}, both write CNT", branch),
+                );
+            }
+        }
+        if let Some(sin) = span_of("SIN") {
+            if !matches!(micro_code.sft, ShiftOp::Srl | ShiftOp::Sll | ShiftOp::Sla) {
+                self.report(
+                    sin,
+                    Severity::Warning,
+                    "SIN only affects SFT=SRL, SLL and SLA".to_owned(),
+                );
+            }
+        }
+        if let Some(y) = span_of("Y") {
+            if matches!(micro_code.alu, AluOp::XPlus1 | AluOp::XMinus1) {
+                self.report(
+                    y,
+                    Severity::Warning,
+                    format!("Y is not used by ALU={}", micro_code.alu.to_string()),
+                );
+            }
+        }
+    }
+
+    fn finish(mut self) -> Program {
+        let end = self.next.min(CONTROL_STORE - 1) as u16;
+        for (name, span) in std::mem::take(&mut self.pending) {
+            self.program.labels.push(Label {
+                name,
+                addr: end,
+                span,
+            });
+        }
+        for i in 0..self.program.references.len() {
+            let reference = &self.program.references[i];
+            let (row, span) = (reference.row, reference.span);
+            let result = match self.program.label(&reference.name) {
+                Some(label) => self.address(label.addr as u64),
+                None => Err(format!("undefined label {}", reference.name)),
+            };
+            match result {
+                Ok(addr) => self.program.rows[row].micro_code.addr = addr,
+                Err(message) => self.error(span, message),
+            }
+        }
+        self.program
+            .diagnostics
+            .sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.start));
+        self.program
+    }
+}
+
+fn outside(addr: u64) -> String {
+    format!(
+        "{:04X}H is outside the {:X}H word control store",
+        addr, CONTROL_STORE
+    )
+}
+
+/// values `field` takes in `arch` with their encoding, for completion.
+pub fn values(arch: &Architecture, field: &str) -> Vec<(String, u64)> {
+    fn ops<T: ToString>(ops: &[OpSpec<T>]) -> Vec<(String, u64)> {
+        ops.iter()
+            .map(|spec| (spec.op.to_string(), spec.code))
+            .collect()
+    }
+    let registers = |filter: fn(&crate::arch::RegisterSpec) -> bool| {
+        arch.registers
+            .iter()
+            .filter(|register| filter(register))
+            .map(|register| (register.register.to_string(), register.code))
+            .collect::<Vec<_>>()
+    };
+    match field.to_ascii_uppercase().as_str() {
+        "X" | "Y" => arch
+            .switches
+            .iter()
+            .map(|switch| (switch.switch.to_string(), switch.code))
+            .chain(registers(|register| register.source))
+            .collect(),
+        "Z" => registers(|register| register.destination),
+        "ALU" => ops(&arch.alu_ops),
+        "SFT" => ops(&arch.shift_ops),
+        "MEM" => ops(&arch.mem_ops),
+        "BR" => ops(&arch.branches),
+        _ => vec![],
+    }
+}
+
+/// what one micro code does, in register transfer notation.
+pub fn describe(micro_code: &MicroCode) -> String {
+    let source = |source: RegisterOrSwitch| match source {
+        RegisterOrSwitch::Register(Register::Nop) => "0".to_owned(),
+        source => source.to_string(),
+    };
+    let (x, y) = (source(micro_code.x_bus), source(micro_code.y_bus));
+    let mut result = match micro_code.alu {
+        AluOp::XPlusY => format!("{} + {}", x, y),
+        AluOp::XMinusY => format!("{} - {}", x, y),
+        AluOp::XAndY => format!("{} & {}", x, y),
+        AluOp::XorY => format!("{} | {}", x, y),
+        AluOp::XxorY => format!("{} ^ {}", x, y),
+        AluOp::XPlus1 => format!("{} + 1", x),
+        AluOp::XMinus1 => format!("{} - 1", x),
+    };
+    if micro_code.sft != ShiftOp::Nop {
+        result = format!("{}({})", micro_code.sft.to_string(), result);
+    }
+    let mut effects = vec![];
+    if micro_code.z_bus != Register::Nop {
+        effects.push(format!("{} ← {}", micro_code.z_bus.to_string(), result));
+    }
+    if micro_code.sin {
+        effects.push("shift in 1".to_owned());
+    }
+    if micro_code.fl {
+        effects.push("update flags".to_owned());
+    }
+    match micro_code.mem {
+        MemOp::Nop => {}
+        MemOp::R => effects.push("MDR ← [MAR]".to_owned()),
+        MemOp::W => effects.push("[MAR] ← MDR".to_owned()),
+    }
+    let target = format!("{:04X}H", micro_code.addr);
+    let jump_if = |flag| format!("jump to {} if {}", target, flag);
+    match micro_code.branch {
+        Branch::Plus1 => {}
+        Branch::J => effects.push(format!("jump to {}", target)),
+        Branch::JM => effects.push(jump_if("minus")),
+        Branch::JZ => effects.push(jump_if("zero")),
+        Branch::JC => effects.push(jump_if("carry")),
+        Branch::JV => effects.push(jump_if("overflow")),
+        Branch::JI => effects.push(format!("jump to {} + IR", target)),
+        Branch::Ldct => effects.push(format!("CNT ← {}", target)),
+        Branch::Rpct => effects.push(format!(
+            "jump to {} while CNT ≠ 0, counting CNT down",
+            target
+        )),
+    }
+    if micro_code.hlt {
+        effects.push("halt".to_owned());
+    }
+    if effects.is_empty() {
+        "nothing".to_owned()
+    } else {
+        effects.join("; ")
+    }
+}
+
+/// the encoded word in hex, then field names over their bits.
+pub fn encoding(arch: &Architecture, micro_code: &MicroCode) -> Result<String, String> {
+    let word = arch.encode(micro_code).map_err(|e| e.to_string())?;
+    let f = &arch.fields;
+    let fields = [
+        ("X", f.x_bus),
+        ("Y", f.y_bus),
+        ("ALU", f.alu),
+        ("SFT", f.sft),
+        ("SIN", f.sin),
+        ("FL", f.fl),
+        ("Z", f.z_bus),
+        ("MEM", f.mem),
+        ("BR", f.branch),
+        ("HLT", f.hlt),
+        ("ADDR", f.addr),
+    ];
+    let mut offset = arch.word_width();
+    let (mut names, mut bits) = (String::new(), String::new());
+    for (name, width) in fields.iter().filter(|(_, width)| *width != 0) {
+        offset -= width;
+        let mask = if *width < 64 {
+            (1 << width) - 1
+        } else {
+            u64::MAX
+        };
+        let value = format!(
+            "{:0width$b}",
+            word >> offset & mask,
+            width = *width as usize
+        );
+        let column = value.len().max(name.len()) + 1;
+        names += &format!("{:column$}", name, column = column);
+        bits += &format!("{:column$}", value, column = column);
+    }
+    Ok(format!(
+        "{:0digits$X}H\n{}\n{}",
+        word,
+        names.trim_end(),
+        bits.trim_end(),
+        digits = arch.word_width().div_ceil(4) as usize
+    ))
+}
+
+/// the text of one micro code.
+pub fn disassemble(micro_code: &MicroCode) -> String {
+    let default = MicroCode::default();
+    let mut fields = vec![];
+    if micro_code.x_bus != default.x_bus {
+        fields.push(format!("X={}", micro_code.x_bus.to_string()));
+    }
+    if micro_code.y_bus != default.y_bus {
+        fields.push(format!("Y={}", micro_code.y_bus.to_string()));
+    }
+    if micro_code.alu != default.alu {
+        fields.push(format!("ALU={}", micro_code.alu.to_string()));
+    }
+    if micro_code.sft != default.sft {
+        fields.push(format!("SFT={}", micro_code.sft.to_string()));
+    }
+    if micro_code.sin {
+        fields.push("SIN".to_owned());
+    }
+    if micro_code.fl {
+        fields.push("FL".to_owned());
+    }
+    if micro_code.z_bus != default.z_bus {
+        fields.push(format!("Z={}", micro_code.z_bus.to_string()));
+    }
+    if micro_code.mem != default.mem {
+        fields.push(format!("MEM={}", micro_code.mem.to_string()));
+    }
+    if micro_code.branch != default.branch {
+        fields.push(format!("BR={}", micro_code.branch.to_string()));
+    }
+    if micro_code.addr != default.addr || micro_code.branch != Branch::Plus1 {
+        fields.push(format!("ADDR={:04X}H", micro_code.addr));
+    }
+    if micro_code.hlt {
+        fields.push("HLT".to_owned());
+    }
+    if fields.is_empty() {
+        "NOP".to_owned()
+    } else {
+        fields.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembly() {
+        assert_eq!(disassemble(&MicroCode::default()), "NOP");
+        let micro_code = MicroCode {
+            x_bus: RegisterOrSwitch::Register(Register::Pc),
+            alu: AluOp::XPlus1,
+            z_bus: Register::Pc,
+            mem: MemOp::R,
+            ..MicroCode::default()
+        };
+        assert_eq!(disassemble(&micro_code), "X=PC ALU=X+1 Z=PC MEM=R");
+        let micro_code = MicroCode {
+            y_bus: RegisterOrSwitch::Sw2,
+            fl: true,
+            branch: Branch::J,
+            hlt: true,
+            ..MicroCode::default()
+        };
+        assert_eq!(disassemble(&micro_code), "Y=Sw2 FL BR=J ADDR=0000H HLT");
+    }
+
+    fn messages(text: &str) -> Vec<(usize, Severity, String)> {
+        parse(text, &Architecture::default())
+            .diagnostics
+            .into_iter()
+            .map(|d| (d.span.line + 1, d.severity, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn labels_and_org() {
+        let text = "\
+; fetch
+fetch:  X=PC Z=MAR
+        x=pc alu=x+1 z=pc mem=r
+        X=MDR Z=IR BR=JI ADDR=dispatch
+
+        ORG 0100H
+dispatch:
+        BR=J ADDR=fetch  ; back
+        HLT
+";
+        let arch = Architecture::default();
+        let program = parse(text, &arch);
+        assert_eq!(program.diagnostics, vec![]);
+        assert_eq!(program.label("dispatch").unwrap().addr, 0x100);
+        assert_eq!(program.label("fetch").unwrap().span.line, 1);
+        assert_eq!(program.references.len(), 2);
+        let micro_program = assemble(text, &arch).unwrap();
+        assert_eq!(micro_program.len(), CONTROL_STORE);
+        assert_eq!(disassemble(&micro_program[1]), "X=PC ALU=X+1 Z=PC MEM=R");
+        assert_eq!(micro_program[2].addr, 0x100);
+        assert_eq!(disassemble(&micro_program[0x100]), "BR=J ADDR=0000H");
+        assert!(micro_program[0x101].hlt);
+        assert_eq!(program.rows[4].span.line, 8);
+    }
+
+    #[test]
+    fn reference_program_round_trips() {
+        let vm = crate::reference::project(&crate::reference::SAMPLES[1]);
+        let mut text = String::new();
+        let mut next = 0;
+        for (addr, micro_code) in vm.micro_program.iter().enumerate() {
+            if *micro_code != MicroCode::default() {
+                if addr != next {
+                    text += &format!("ORG {:04X}H\n", addr);
+                }
+                text += &disassemble(micro_code);
+                text += "\n";
+                next = addr + 1;
+            }
+        }
+        let arch = Architecture::default();
+        assert_eq!(messages(&text), vec![]);
+        assert!(assemble(&text, &arch).unwrap() == vm.micro_program);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let text = "\
+start:  X=R7 Z=Sw1 ALU=X*Y
+        X=PC X=PC FOO HLT=1 ADDR
+        BR=JZ
+        MEM=R Z=MDR
+        SFT=SLL SIN Y=R1 ALU=X+1
+start:  BR=J ADDR=nowhere
+        BR=J ADDR=10000H
+        ORG FFFFH
+        ORG 0FFFFH
+        NOP
+        NOP FL
+";
+        use Severity::*;
+        let expected = [
+            (1, Error, "unknown register R7"),
+            (1, Error, "Sw1 is a switch, it can not be written"),
+            (1, Error, "unknown ALU operation X*Y"),
+            (2, Error, "X is given twice"),
+            (2, Error, "unknown field FOO"),
+            (2, Error, "HLT takes no value"),
+            (2, Error, "ADDR needs a value, ADDR=..."),
+            (3, Error, "BR=JZ needs ADDR"),
+            (4, Error, "Z=MDR is overwritten by MEM=R"),
+            (5, Warning, "Y is not used by ALU=X+1"),
+            (6, Error, "label start is already defined on line 1"),
+            (6, Error, "undefined label nowhere"),
+            (7, Error, "10000H is outside the 10000H word control store"),
+            (8, Error, "invalid micro address FFFFH"),
+            (11, Error, "NOP can not be combined with other fields"),
+            (11, Error, "10000H is outside the 10000H word control store"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(line, severity, message)| (*line, *severity, message.to_string()))
+            .collect();
+        assert_eq!(messages(text), expected);
+        assert_eq!(
+            messages("SIN\nX=R1 Z=R1 SIN SFT=SRA")[0].2,
+            "SIN only affects SFT=SRL, SLL and SLA"
+        );
+        assert_eq!(
+            messages("Z=CNT BR=LDCT ADDR=3")[0].2,
+            "Z=CNT conflicts with BR=LDCT, both write CNT"
+        );
+        assert_eq!(
+            messages("NOP X=R1")[0].2,
+            "NOP can not be combined with other fields"
+        );
+
+        let span = parse("  X=R9", &Architecture::default()).diagnostics[0].span;
+        assert_eq!((span.start, span.end), (4, 6));
+        let error = assemble("NOP\nBR=J", &Architecture::default()).unwrap_err();
+        assert_eq!(error, "line 2: BR=J needs ADDR");
+    }
+
+    #[test]
+    fn architecture_limits() {
+        let mut arch = Architecture::default();
+        arch.fields.addr = 8;
+        arch.registers
+            .retain(|register| register.register != Register::R6);
+        arch.branches.retain(|branch| branch.op != Branch::Rpct);
+        let program = parse(
+            "X=R6 BR=RPCT ADDR=100H\nBR=J ADDR=far\nORG 100H\nfar:",
+            &arch,
+        );
+        let messages: Vec<_> = program.errors().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "R6 can not drive the X bus in default",
+                "RPCT is not available in default",
+                "0100H does not fit the 8 bit address field",
+                "0100H does not fit the 8 bit address field",
+            ]
+        );
+    }
+
+    #[test]
+    fn description_and_encoding() {
+        let arch = Architecture::default();
+        let micro_code = assemble("X=PC ALU=X+1 Z=PC MEM=R", &arch).unwrap()[0];
+        assert_eq!(describe(&micro_code), "PC ← PC + 1; MDR ← [MAR]");
+        let micro_code =
+            assemble("X=R1 Y=Sw1 SFT=SLL SIN FL BR=JC ADDR=12H HLT", &arch).unwrap()[0];
+        assert_eq!(
+            describe(&micro_code),
+            "shift in 1; update flags; jump to 0012H if carry; halt"
+        );
+        assert_eq!(describe(&MicroCode::default()), "nothing");
+        let encoding = encoding(&arch, &micro_code).unwrap();
+        let lines: Vec<_> = encoding.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!("{:011X}H", arch.encode(&micro_code).unwrap())
+        );
+        assert_eq!(lines[1], "X    Y    ALU SFT SIN FL Z    MEM BR   HLT ADDR");
+        assert_eq!(
+            lines[2],
+            "0010 1101 000 100 1   1  0000 00  0100 1   0000000000010010"
+        );
+        assert!(values(&arch, "x")
+            .iter()
+            .any(|(name, code)| name == "Sw2" && *code == 14));
+        assert_eq!(values(&arch, "BR")[0], ("+1".to_owned(), 0));
+        assert_eq!(values(&arch, "ADDR"), vec![]);
+    }
+}
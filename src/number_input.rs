@@ -0,0 +1,202 @@
+use eframe::egui::{Response, TextEdit, Ui};
+
+/// how register values are shown and typed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberFormat {
+    Hex,
+    Unsigned,
+    Signed,
+    Binary,
+    Ascii,
+}
+
+impl NumberFormat {
+    pub const ALL: [NumberFormat; 5] = [
+        NumberFormat::Hex,
+        NumberFormat::Unsigned,
+        NumberFormat::Signed,
+        NumberFormat::Binary,
+        NumberFormat::Ascii,
+    ];
+
+    /// `value` of a `bits` wide register, 8 or 16.
+    pub fn format(self, value: u16, bits: u32) -> String {
+        match self {
+            NumberFormat::Hex => format!("{:0width$X}H", value, width = bits as usize / 4),
+            NumberFormat::Unsigned => value.to_string(),
+            NumberFormat::Signed => {
+                let shift = 16 - bits;
+                ((value << shift) as i16 >> shift).to_string()
+            }
+            NumberFormat::Binary => format!("{:0width$b}", value, width = bits as usize),
+            NumberFormat::Ascii => {
+                let chars: String = (0..bits / 8)
+                    .rev()
+                    .map(|byte| match (value >> (byte * 8)) as u8 {
+                        c @ 0x20..=0x7e if c != b'\\' && c != b'\'' => (c as char).to_string(),
+                        c => format!("\\x{:02X}", c),
+                    })
+                    .collect();
+                format!("'{}'", chars)
+            }
+        }
+    }
+
+    /// typed text as a `bits` wide value, None until it is one.
+    pub fn parse(self, text: &str, bits: u32) -> Option<u16> {
+        let text = text.trim();
+        let max = (1i64 << bits) - 1;
+        let (value, range) = match self {
+            NumberFormat::Hex => {
+                let digits = text.strip_suffix(['H', 'h']).unwrap_or(text);
+                (i64::from_str_radix(digits, 16).ok()?, 0..=max)
+            }
+            NumberFormat::Unsigned => (text.parse().ok()?, 0..=max),
+            NumberFormat::Signed => (text.parse().ok()?, -(max + 1) / 2..=max / 2),
+            NumberFormat::Binary => (i64::from_str_radix(text, 2).ok()?, 0..=max),
+            NumberFormat::Ascii => {
+                let text = text
+                    .strip_prefix('\'')
+                    .and_then(|text| text.strip_suffix('\''))
+                    .unwrap_or(text);
+                let mut bytes = vec![];
+                let mut rest = text;
+                while let Some(c) = rest.chars().next() {
+                    if let Some(hex) = rest.strip_prefix("\\x") {
+                        bytes.push(u8::from_str_radix(hex.get(..2)?, 16).ok()?);
+                        rest = &hex[2..];
+                    } else if c.is_ascii() {
+                        bytes.push(c as u8);
+                        rest = &rest[1..];
+                    } else {
+                        return None;
+                    }
+                }
+                if bytes.is_empty() || bytes.len() > bits as usize / 8 {
+                    return None;
+                }
+                let value = bytes
+                    .iter()
+                    .fold(0, |value, byte| value << 8 | *byte as i64);
+                (value, 0..=max)
+            }
+        };
+        range.contains(&value).then_some((value & max) as u16)
+    }
+
+    /// characters of the longest value.
+    fn width(self, bits: u32) -> usize {
+        let max = ((1u32 << bits) - 1) as u16;
+        [0, max, 1 << (bits - 1), 0x5c]
+            .iter()
+            .map(|value| self.format(*value, bits).len())
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for NumberFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NumberFormat::Hex => "hex",
+            NumberFormat::Unsigned => "unsigned",
+            NumberFormat::Signed => "signed",
+            NumberFormat::Binary => "binary",
+            NumberFormat::Ascii => "ASCII",
+        })
+    }
+}
+
+/// Register input in any `NumberFormat`.
+///
+/// The text being typed is kept while the input has focus and written to the
+/// register whenever it parses.
+pub struct NumberInput<'a> {
+    value: &'a mut u16,
+    bits: u32,
+    format: NumberFormat,
+    id_source: &'a str,
+}
+
+impl<'a> NumberInput<'a> {
+    pub fn new(value: &'a mut u16, bits: u32, format: NumberFormat, id_source: &'a str) -> Self {
+        Self {
+            value,
+            bits,
+            format,
+            id_source,
+        }
+    }
+}
+
+impl<'a> eframe::egui::Widget for NumberInput<'a> {
+    fn ui(self, ui: &mut Ui) -> Response {
+        let id = ui.make_persistent_id(self.id_source);
+        let mut text = ui
+            .memory()
+            .data
+            .get_temp::<String>(id)
+            .unwrap_or_else(|| self.format.format(*self.value, self.bits));
+        let text_edit = TextEdit::singleline(&mut text)
+            .id(id)
+            .desired_width(self.format.width(self.bits) as f32 * 8.0);
+        let response = ui.add(text_edit);
+        if response.has_focus() {
+            if let Some(value) = self.format.parse(&text, self.bits) {
+                *self.value = value;
+            }
+            ui.memory().data.insert_temp(id, text);
+        } else {
+            ui.memory().data.remove::<String>(id);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for format in NumberFormat::ALL {
+            for value in 0..=0xff {
+                let text = format.format(value, 8);
+                assert_eq!(format.parse(&text, 8), Some(value), "{} {}", format, text);
+            }
+            for value in [0, 0x41, 0x7fff, 0x8000, 0xabcd, 0xffff] {
+                let text = format.format(value, 16);
+                assert_eq!(format.parse(&text, 16), Some(value), "{} {}", format, text);
+            }
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let format = |format: NumberFormat, value| format.format(value, 8);
+        assert_eq!(format(NumberFormat::Hex, 0x0a), "0AH");
+        assert_eq!(format(NumberFormat::Unsigned, 0xff), "255");
+        assert_eq!(format(NumberFormat::Signed, 0xff), "-1");
+        assert_eq!(format(NumberFormat::Signed, 0x7f), "127");
+        assert_eq!(format(NumberFormat::Binary, 5), "00000101");
+        assert_eq!(format(NumberFormat::Ascii, 0x41), "'A'");
+        assert_eq!(format(NumberFormat::Ascii, 0x0a), "'\\x0A'");
+        assert_eq!(NumberFormat::Ascii.format(0x4142, 16), "'AB'");
+        assert_eq!(NumberFormat::Signed.format(0x8000, 16), "-32768");
+    }
+
+    #[test]
+    fn typed_text() {
+        assert_eq!(NumberFormat::Hex.parse("ff", 8), Some(0xff));
+        assert_eq!(NumberFormat::Hex.parse("100", 8), None);
+        assert_eq!(NumberFormat::Unsigned.parse(" 42 ", 8), Some(42));
+        assert_eq!(NumberFormat::Unsigned.parse("256", 8), None);
+        assert_eq!(NumberFormat::Signed.parse("-128", 8), Some(0x80));
+        assert_eq!(NumberFormat::Signed.parse("128", 8), None);
+        assert_eq!(NumberFormat::Binary.parse("1_0", 8), None);
+        assert_eq!(NumberFormat::Ascii.parse("z", 8), Some(b'z' as u16));
+        assert_eq!(NumberFormat::Ascii.parse("'ab'", 8), None);
+        assert_eq!(NumberFormat::Ascii.parse("''", 8), None);
+        assert_eq!(NumberFormat::Ascii.parse("é", 8), None);
+    }
+}
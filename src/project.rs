@@ -0,0 +1,418 @@
+//! Project files (`.cpu_memory`).
+//!
+//! `save` writes a header followed by a sparse body: only micro codes differing from
+//! `MicroCode::default()` are stored with their address, followed by the labels and
+//! comments of micro code addresses, optionally deflate compressed.
+//!
+//! ```text
+//! "MPRJ" | version (u16 LE) | compression (0 none, 1 deflate) | bincode(Annotated)
+//! ```
+//!
+//! `load` accepts the current version, every older one listed in `versions`, and
+//! the text formats of `save_text`. Older files are migrated one version at a time.
+//! Whatever the version, the loaded control store is padded to `CONTROL_STORE`
+//! words and main memory to `MEMORY` bytes, and larger ones are refused.
+use crate::microasm::CONTROL_STORE;
+use crate::vm::{MicroArch, MicroCode};
+use bincode::Options;
+use std::io::{Read, Write};
+
+mod text;
+mod versions;
+pub(crate) use text::dump_memory;
+pub use text::{save_text, TextFormat};
+use versions::{Annotated, Sparse, V0, V1, V2};
+
+const MAGIC: &[u8; 4] = b"MPRJ";
+/// version 3 files had no version field.
+const V3_MAGIC: &[u8; 4] = b"UPRJ";
+/// version written by `save`.
+pub const VERSION: u16 = 5;
+/// main memory bytes MAR addresses.
+const MEMORY: usize = 1 << 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+}
+
+/// bincode as `bincode::serialize` writes it, but refusing leftover bytes so one
+/// layout is not mistaken for another.
+fn strict() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+/// encode the machine in the current format.
+pub fn save(vm: &MicroArch, compression: Compression) -> Result<Vec<u8>, String> {
+    let body = bincode::serialize(&Annotated::from(vm)).map_err(|e| e.to_string())?;
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.push(compression.tag());
+    match compression {
+        Compression::None => bytes.extend_from_slice(&body),
+        Compression::Deflate => {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(bytes, flate2::Compression::best());
+            encoder.write_all(&body).map_err(|e| e.to_string())?;
+            bytes = encoder.finish().map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// decode a project file written by `save`, `save_text` or by older versions.
+pub fn load(bytes: &[u8]) -> Result<MicroArch, String> {
+    decode(bytes).and_then(checked)
+}
+
+/// default control store of `len` words, refused before allocating when the
+/// sequencer cannot address it.
+fn control_store<T: From<MicroCode> + Clone>(len: u32) -> Result<Vec<T>, String> {
+    if len as usize > CONTROL_STORE {
+        return Err(format!(
+            "control store of {} words, at most {}",
+            len, CONTROL_STORE
+        ));
+    }
+    Ok(vec![T::from(MicroCode::default()); len as usize])
+}
+
+/// refuse a control store or memory larger than the machine addresses and pad
+/// smaller ones, so execution never indexes past them, and check the architecture.
+fn checked(mut vm: MicroArch) -> Result<MicroArch, String> {
+    if vm.micro_program.len() > CONTROL_STORE {
+        return Err(format!(
+            "control store of {} words, at most {}",
+            vm.micro_program.len(),
+            CONTROL_STORE
+        ));
+    }
+    if vm.memory.len() > MEMORY {
+        return Err(format!(
+            "main memory of {} bytes, at most {}",
+            vm.memory.len(),
+            MEMORY
+        ));
+    }
+    vm.micro_program.resize(CONTROL_STORE, MicroCode::default());
+    vm.memory.resize(MEMORY, 0);
+    vm.arch.validate()?;
+    Ok(vm)
+}
+
+fn decode(bytes: &[u8]) -> Result<MicroArch, String> {
+    if let Some(rest) = bytes.strip_prefix(MAGIC) {
+        let (version, rest) = match rest {
+            [low, high, rest @ ..] => (u16::from_le_bytes([*low, *high]), rest),
+            _ => return Err("truncated project file".to_string()),
+        };
+        return match version {
+            4 => inflate(rest).and_then(|body| sparse(&body)),
+            5 => {
+                let body = inflate(rest)?;
+                let annotated: Annotated = strict()
+                    .deserialize(&body)
+                    .map_err(|e| format!("broken project file : {}", e))?;
+                MicroArch::try_from(annotated)
+            }
+            _ if version > VERSION => Err(format!(
+                "project file version {} is newer than this program (version {})",
+                version, VERSION
+            )),
+            _ => Err(format!("unknown project file version {}", version)),
+        };
+    }
+    if let Some(rest) = bytes.strip_prefix(V3_MAGIC) {
+        return inflate(rest).and_then(|body| sparse(&body));
+    }
+    let text = std::str::from_utf8(bytes).map(text::load_text);
+    if let Ok(Ok(vm)) = text {
+        return Ok(vm);
+    }
+    strict()
+        .deserialize::<V2>(bytes)
+        .or_else(|_| strict().deserialize::<V1>(bytes).map(V2::from))
+        .or_else(|_| {
+            strict()
+                .deserialize::<V0>(bytes)
+                .map(|v0| V2::from(V1::from(v0)))
+        })
+        .map(MicroArch::from)
+        .map_err(|_| match text {
+            // bincode rarely is valid UTF-8, so this was meant to be text.
+            Ok(Err(e)) => e,
+            _ => "not a project file".to_string(),
+        })
+}
+
+/// body of version 3 and later files, decompressed as their compression tag says.
+fn inflate(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let (tag, body) = bytes.split_first().ok_or("truncated project file")?;
+    let body = match tag {
+        0 => body.to_vec(),
+        1 => {
+            let mut inflated = vec![];
+            flate2::read::DeflateDecoder::new(body)
+                .read_to_end(&mut inflated)
+                .map_err(|e| e.to_string())?;
+            inflated
+        }
+        _ => return Err(format!("unknown compression {}", tag)),
+    };
+    Ok(body)
+}
+
+/// `Sparse` body of version 3 and 4 files.
+fn sparse(body: &[u8]) -> Result<MicroArch, String> {
+    let sparse: Sparse = strict()
+        .deserialize(body)
+        .map_err(|e| format!("broken project file : {}", e))?;
+    V2::try_from(sparse).map(MicroArch::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::{project, SAMPLES};
+    use crate::vm::MicroCode;
+
+    pub(super) fn assert_same(a: &MicroArch, b: &MicroArch) {
+        assert!(a.micro_program == b.micro_program);
+        assert_eq!(a.memory, b.memory);
+        assert_eq!(a.micro_program_counter, b.micro_program_counter);
+        assert_eq!(a.counter, b.counter);
+        assert_eq!(a.gpr, b.gpr);
+        assert_eq!(
+            [a.pc, a.ir, a.mdr, a.mar, a.str, a.sw1, a.sw2],
+            [b.pc, b.ir, b.mdr, b.mar, b.str, b.sw1, b.sw2]
+        );
+        assert_eq!(a.hlt, b.hlt);
+        assert!(a.arch == b.arch);
+        assert_eq!(a.annotations, b.annotations);
+    }
+
+    pub(super) fn busy_machine() -> MicroArch {
+        let mut vm = project(&SAMPLES[1]);
+        vm.sw1 = 6;
+        vm.sw2 = 7;
+        for _ in 0..50 {
+            vm.exec();
+        }
+        vm
+    }
+
+    #[test]
+    fn round_trip() {
+        let vm = busy_machine();
+        for compression in [Compression::None, Compression::Deflate] {
+            let bytes = save(&vm, compression).unwrap();
+            assert_same(&load(&bytes).unwrap(), &vm);
+        }
+    }
+
+    #[test]
+    fn version_4_has_no_annotations() {
+        let mut vm = busy_machine();
+        vm.annotations.insert(
+            2,
+            crate::vm::Annotation {
+                label: "multiply".to_owned(),
+                comment: String::new(),
+            },
+        );
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&4u16.to_le_bytes());
+        bytes.push(0);
+        bytes.extend(bincode::serialize(&Sparse::from(&vm)).unwrap());
+        assert!(load(&bytes).unwrap().annotations.is_empty());
+        let saved = save(&vm, Compression::Deflate).unwrap();
+        assert_eq!(load(&saved).unwrap().annotations, vm.annotations);
+    }
+
+    #[test]
+    fn sparse_is_small() {
+        let vm = busy_machine();
+        let full = bincode::serialize(&vm).unwrap().len();
+        let sparse = save(&vm, Compression::None).unwrap().len();
+        let deflated = save(&vm, Compression::Deflate).unwrap().len();
+        assert!(sparse * 20 < full, "{} vs {}", sparse, full);
+        assert!(deflated < sparse);
+    }
+
+    #[test]
+    fn empty_program_stores_no_rows() {
+        let vm = MicroArch::construct(vec![MicroCode::default(); 1 << 16]);
+        let bytes = save(&vm, Compression::None).unwrap();
+        let annotated: Annotated = strict().deserialize(&bytes[7..]).unwrap();
+        assert!(annotated.sparse.micro_program.is_empty());
+        assert_eq!(load(&bytes).unwrap().micro_program.len(), 1 << 16);
+    }
+
+    #[test]
+    fn sizes_are_bounded_and_padded() {
+        let mut vm = busy_machine();
+        vm.micro_program.truncate(0x10);
+        vm.memory.truncate(0x10);
+        let loaded = load(&save(&vm, Compression::None).unwrap()).unwrap();
+        assert_eq!(loaded.micro_program.len(), 1 << 16);
+        assert_eq!(loaded.micro_program[..0x10], vm.micro_program[..]);
+        assert_eq!(loaded.memory.len(), 1 << 8);
+
+        let mut annotated = Annotated::from(&vm);
+        annotated.sparse.micro_program_len = u32::MAX;
+        let mut bytes = save(&vm, Compression::None).unwrap()[..7].to_vec();
+        bytes.extend(bincode::serialize(&annotated).unwrap());
+        assert!(load(&bytes).err().unwrap().contains("control store"));
+
+        vm.memory.resize(0x101, 0);
+        let bytes = save(&vm, Compression::None).unwrap();
+        assert!(load(&bytes).err().unwrap().contains("main memory"));
+        let text = save_text(&vm, TextFormat::Json).unwrap();
+        assert!(load(text.as_bytes()).err().unwrap().contains("main memory"));
+    }
+
+    #[test]
+    fn every_version_validates_the_architecture() {
+        let mut vm = busy_machine();
+        vm.arch.flags.carry = 9;
+        let bytes = save(&vm, Compression::Deflate).unwrap();
+        assert!(load(&bytes).err().unwrap().contains("outside STR"));
+        let v2 = bincode::serialize(&Sparse::from(&vm).machine).unwrap();
+        assert!(load(&v2).is_err());
+    }
+
+    #[test]
+    fn loads_text() {
+        let vm = busy_machine();
+        for format in TextFormat::ALL {
+            let text = save_text(&vm, format).unwrap();
+            assert_same(&load(text.as_bytes()).unwrap(), &vm);
+        }
+    }
+
+    /// files saved by every version, loaded as `(name, bytes)`.
+    const SAMPLES_BY_VERSION: [(&str, &[u8]); 15] = [
+        ("v0", include_bytes!("../testdata/projects/v0.cpu_memory")),
+        ("v1", include_bytes!("../testdata/projects/v1.cpu_memory")),
+        ("v2", include_bytes!("../testdata/projects/v2.cpu_memory")),
+        ("v3", include_bytes!("../testdata/projects/v3.cpu_memory")),
+        (
+            "v3_deflate",
+            include_bytes!("../testdata/projects/v3_deflate.cpu_memory"),
+        ),
+        ("v4", include_bytes!("../testdata/projects/v4.cpu_memory")),
+        (
+            "v4_deflate",
+            include_bytes!("../testdata/projects/v4_deflate.cpu_memory"),
+        ),
+        ("v5", include_bytes!("../testdata/projects/v5.cpu_memory")),
+        (
+            "v5_deflate",
+            include_bytes!("../testdata/projects/v5_deflate.cpu_memory"),
+        ),
+        (
+            "text_v1.ron",
+            include_bytes!("../testdata/projects/text_v1.ron"),
+        ),
+        (
+            "text_v1.json",
+            include_bytes!("../testdata/projects/text_v1.json"),
+        ),
+        (
+            "text_v1.toml",
+            include_bytes!("../testdata/projects/text_v1.toml"),
+        ),
+        (
+            "text_v2.ron",
+            include_bytes!("../testdata/projects/text_v2.ron"),
+        ),
+        (
+            "text_v2.json",
+            include_bytes!("../testdata/projects/text_v2.json"),
+        ),
+        (
+            "text_v2.toml",
+            include_bytes!("../testdata/projects/text_v2.toml"),
+        ),
+    ];
+
+    #[test]
+    fn samples_of_every_version_load() {
+        // all samples hold the same machine: "SW1 x SW2" 50 micro codes in with
+        // SW1 = 6, SW2 = 7, CNT = 0123H and the control store cut to 140H rows,
+        // padded back to full size on load.
+        // from version 5 on, and in text_v2, 0000H is labelled "start" and 000CH "loop".
+        let newest = load(SAMPLES_BY_VERSION[7].1).unwrap();
+        assert_eq!(newest.micro_program.len(), 1 << 16);
+        assert_eq!([newest.sw1, newest.sw2], [6, 7]);
+        assert_eq!(newest.memory[0x80..0x82], [6, 7]);
+        assert_eq!(newest.counter, 0x0123);
+        assert_eq!(newest.arch.name, "sample");
+        assert_eq!(newest.annotations[&0].label, "start");
+        assert_eq!(newest.annotations[&0x0c].label, "loop");
+        for (name, bytes) in SAMPLES_BY_VERSION {
+            let mut loaded = load(bytes).unwrap_or_else(|e| panic!("{} : {}", name, e));
+            match name {
+                "v0" => {
+                    assert_eq!(loaded.counter, 0);
+                    loaded.counter = newest.counter;
+                    assert!(loaded.arch == crate::arch::Architecture::default());
+                    loaded.arch = newest.arch.clone();
+                }
+                "v1" => {
+                    assert!(loaded.arch == crate::arch::Architecture::default());
+                    loaded.arch = newest.arch.clone();
+                }
+                _ => {}
+            }
+            if !name.starts_with("v5") && !name.starts_with("text_v2") {
+                assert!(loaded.annotations.is_empty(), "{}", name);
+                loaded.annotations = newest.annotations.clone();
+            }
+            assert_same(&loaded, &newest);
+        }
+    }
+
+    #[test]
+    fn header() {
+        let bytes = save(&busy_machine(), Compression::Deflate).unwrap();
+        assert_eq!(bytes[..4], *b"MPRJ");
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), VERSION);
+        assert_eq!(bytes[6], 1);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut bytes = save(&busy_machine(), Compression::None).unwrap();
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        match load(&bytes) {
+            Err(e) => assert!(e.contains("newer"), "{}", e),
+            Ok(_) => panic!("newer version accepted"),
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(load(b"").is_err());
+        assert!(load(b"hello world").is_err());
+        assert!(load(b"UPRJ").is_err());
+        assert!(load(b"UPRJ\x07").is_err());
+        assert!(load(b"MPRJ").is_err());
+        assert!(load(b"MPRJ\x02\x00\x00").is_err());
+        let mut bytes = save(&busy_machine(), Compression::None).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        assert!(load(&bytes).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+//! Human readable project files.
+//!
+//! The same document is written as RON, JSON or TOML. Micro codes are keyed by
+//! their hex address and only non default rows are written, memory is a hex dump
+//! of 16 bytes per line, and labels and comments are keyed by hex address like
+//! the micro codes. Fields always come out in the same order so saved files
+//! diff cleanly.
+//!
+//! Like the binary layouts in `versions`, the document is made of frozen copies of
+//! the live types, which convert with `From`.
+use super::versions::{AluOpV1, ArchV2, BranchV1, MemOpV1, RegisterV1, ShiftOpV1};
+use crate::arch::Architecture;
+use crate::vm::{Annotation, MicroArch, MicroCode, RegisterOrSwitch};
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const BYTES_PER_LINE: usize = 16;
+/// layout written by `save_text`. bump it with a migration in `Document::machine`
+/// whenever the document changes.
+const VERSION: u32 = 2;
+
+/// documents from before the version field.
+fn first_version() -> u32 {
+    1
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextFormat {
+    Ron,
+    Json,
+    Toml,
+}
+
+impl TextFormat {
+    pub const ALL: [TextFormat; 3] = [TextFormat::Ron, TextFormat::Json, TextFormat::Toml];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TextFormat::Ron => "ron",
+            TextFormat::Json => "json",
+            TextFormat::Toml => "toml",
+        }
+    }
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+}
+
+impl std::fmt::Display for TextFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TextFormat::Ron => "RON",
+            TextFormat::Json => "JSON",
+            TextFormat::Toml => "TOML",
+        })
+    }
+}
+
+// TOML wants plain values before tables, so scalars and arrays come first.
+#[derive(Deserialize, Serialize)]
+struct Document {
+    #[serde(default = "first_version")]
+    version: u32,
+    micro_program_len: u32,
+    /// "ADDR: XX XX ..." lines.
+    memory: Vec<String>,
+    registers: Registers,
+    switches: Switches,
+    arch: ArchV2,
+    /// non default micro codes keyed by "ADDR" in hex.
+    micro_program: BTreeMap<String, Row>,
+    /// labels and comments keyed like `micro_program`, missing before version 2.
+    #[serde(default)]
+    annotations: BTreeMap<String, AnnotationV2>,
+}
+
+/// `Annotation` as version 2 documents store it.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct AnnotationV2 {
+    label: String,
+    comment: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Registers {
+    micro_program_counter: u16,
+    counter: u16,
+    gpr: [u8; 7],
+    pc: u8,
+    ir: u8,
+    mdr: u8,
+    mar: u8,
+    str: u8,
+    hlt: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Switches {
+    sw1: u8,
+    sw2: u8,
+}
+
+/// `MicroCode` with the buses spelled as plain names, which TOML can hold.
+#[derive(Deserialize, Serialize)]
+struct Row {
+    x_bus: String,
+    y_bus: String,
+    alu: AluOpV1,
+    sft: ShiftOpV1,
+    sin: bool,
+    fl: bool,
+    z_bus: RegisterV1,
+    mem: MemOpV1,
+    branch: BranchV1,
+    hlt: bool,
+    addr: u16,
+}
+
+fn bus_name(bus: RegisterOrSwitch) -> String {
+    match bus {
+        RegisterOrSwitch::Sw1 => "Sw1".to_owned(),
+        RegisterOrSwitch::Sw2 => "Sw2".to_owned(),
+        RegisterOrSwitch::Register(register) => format!("{:?}", RegisterV1::from(register)),
+    }
+}
+
+fn parse_bus(name: &str) -> Result<RegisterOrSwitch, String> {
+    match name {
+        "Sw1" => Ok(RegisterOrSwitch::Sw1),
+        "Sw2" => Ok(RegisterOrSwitch::Sw2),
+        _ => RegisterV1::deserialize(name.into_deserializer())
+            .map(|register| RegisterOrSwitch::Register(register.into()))
+            .map_err(|e: serde::de::value::Error| e.to_string()),
+    }
+}
+
+impl From<&MicroCode> for Row {
+    fn from(micro_code: &MicroCode) -> Self {
+        Self {
+            x_bus: bus_name(micro_code.x_bus),
+            y_bus: bus_name(micro_code.y_bus),
+            alu: micro_code.alu.into(),
+            sft: micro_code.sft.into(),
+            sin: micro_code.sin,
+            fl: micro_code.fl,
+            z_bus: micro_code.z_bus.into(),
+            mem: micro_code.mem.into(),
+            branch: micro_code.branch.into(),
+            hlt: micro_code.hlt,
+            addr: micro_code.addr,
+        }
+    }
+}
+
+impl Row {
+    fn micro_code(&self) -> Result<MicroCode, String> {
+        Ok(MicroCode {
+            x_bus: parse_bus(&self.x_bus)?,
+            y_bus: parse_bus(&self.y_bus)?,
+            alu: self.alu.into(),
+            sft: self.sft.into(),
+            sin: self.sin,
+            fl: self.fl,
+            z_bus: self.z_bus.into(),
+            mem: self.mem.into(),
+            branch: self.branch.into(),
+            hlt: self.hlt,
+            addr: self.addr,
+        })
+    }
+}
+
+/// "ADDR: XX XX ..." lines of 16 bytes.
+pub(crate) fn dump_memory(memory: &[u8]) -> Vec<String> {
+    memory
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(line, bytes)| {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("{:04X}: {}", line * BYTES_PER_LINE, bytes.join(" "))
+        })
+        .collect()
+}
+
+fn parse_memory(lines: &[String]) -> Result<Vec<u8>, String> {
+    let mut memory = vec![];
+    for line in lines {
+        let (addr, bytes) = line
+            .split_once(':')
+            .ok_or_else(|| format!("memory line without address : {}", line))?;
+        let addr = usize::from_str_radix(addr.trim(), 16)
+            .map_err(|_| format!("bad memory address : {}", line))?;
+        if addr != memory.len() {
+            return Err(format!("memory line out of order : {}", line));
+        }
+        for byte in bytes.split_whitespace() {
+            memory.push(
+                u8::from_str_radix(byte, 16)
+                    .map_err(|_| format!("bad memory byte {} : {}", byte, line))?,
+            );
+        }
+    }
+    Ok(memory)
+}
+
+impl From<&MicroArch> for Document {
+    fn from(vm: &MicroArch) -> Self {
+        let default = MicroCode::default();
+        Self {
+            version: VERSION,
+            micro_program_len: vm.micro_program.len() as u32,
+            memory: dump_memory(&vm.memory),
+            registers: Registers {
+                micro_program_counter: vm.micro_program_counter,
+                counter: vm.counter,
+                gpr: vm.gpr,
+                pc: vm.pc,
+                ir: vm.ir,
+                mdr: vm.mdr,
+                mar: vm.mar,
+                str: vm.str,
+                hlt: vm.hlt,
+            },
+            switches: Switches {
+                sw1: vm.sw1,
+                sw2: vm.sw2,
+            },
+            arch: ArchV2::from(&vm.arch),
+            micro_program: vm
+                .micro_program
+                .iter()
+                .enumerate()
+                .filter(|(_, micro_code)| **micro_code != default)
+                .map(|(addr, micro_code)| (format!("{:04X}", addr), Row::from(micro_code)))
+                .collect(),
+            annotations: vm
+                .annotations
+                .iter()
+                .filter(|(_, annotation)| !annotation.is_empty())
+                .map(|(addr, annotation)| {
+                    let annotation = AnnotationV2 {
+                        label: annotation.label.clone(),
+                        comment: annotation.comment.clone(),
+                    };
+                    (format!("{:04X}", addr), annotation)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Document {
+    fn machine(self) -> Result<MicroArch, String> {
+        if self.version > VERSION {
+            return Err(format!(
+                "project file version {} is newer than this program (version {})",
+                self.version, VERSION
+            ));
+        }
+        let mut micro_program = super::control_store(self.micro_program_len)?;
+        for (addr, row) in &self.micro_program {
+            let index = usize::from_str_radix(addr, 16)
+                .map_err(|_| format!("bad micro code address {}", addr))?;
+            *micro_program
+                .get_mut(index)
+                .ok_or_else(|| format!("micro code address {}H out of range", addr))? = row
+                .micro_code()
+                .map_err(|e| format!("micro code {}H : {}", addr, e))?;
+        }
+        let mut annotations = BTreeMap::new();
+        for (addr, annotation) in self.annotations {
+            let index = u16::from_str_radix(&addr, 16)
+                .map_err(|_| format!("bad annotation address {}", addr))?;
+            let annotation = Annotation {
+                label: annotation.label,
+                comment: annotation.comment,
+            };
+            annotations.insert(index, annotation);
+        }
+        let arch = Architecture::from(self.arch);
+        arch.validate()?;
+        Ok(MicroArch {
+            micro_program_counter: self.registers.micro_program_counter,
+            counter: self.registers.counter,
+            micro_program,
+            memory: parse_memory(&self.memory)?,
+            gpr: self.registers.gpr,
+            pc: self.registers.pc,
+            ir: self.registers.ir,
+            mdr: self.registers.mdr,
+            mar: self.registers.mar,
+            str: self.registers.str,
+            sw1: self.switches.sw1,
+            sw2: self.switches.sw2,
+            hlt: self.registers.hlt,
+            arch,
+            annotations,
+        })
+    }
+}
+
+/// write the machine as text.
+pub fn save_text(vm: &MicroArch, format: TextFormat) -> Result<String, String> {
+    let document = Document::from(vm);
+    match format {
+        TextFormat::Ron => ron::ser::to_string_pretty(&document, ron::ser::PrettyConfig::new())
+            .map_err(|e| e.to_string()),
+        TextFormat::Json => serde_json::to_string_pretty(&document).map_err(|e| e.to_string()),
+        TextFormat::Toml => toml::to_string_pretty(&document).map_err(|e| e.to_string()),
+    }
+}
+
+/// guess the format from the first meaningful character.
+pub fn detect(text: &str) -> TextFormat {
+    match text.trim_start().chars().next() {
+        Some('{') => TextFormat::Json,
+        Some('(') => TextFormat::Ron,
+        _ => TextFormat::Toml,
+    }
+}
+
+/// read a machine written by `save_text` in any of the formats.
+pub fn load_text(text: &str) -> Result<MicroArch, String> {
+    let document: Document = match detect(text) {
+        TextFormat::Ron => ron::from_str(text).map_err(|e| e.to_string())?,
+        TextFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string())?,
+        TextFormat::Toml => toml::from_str(text).map_err(|e| e.to_string())?,
+    };
+    document.machine()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::tests::{assert_same, busy_machine};
+    use crate::vm::Register;
+
+    #[test]
+    fn round_trip_every_format() {
+        let vm = busy_machine();
+        for format in TextFormat::ALL {
+            let text = save_text(&vm, format).unwrap();
+            assert_eq!(detect(&text), format);
+            assert_same(&load_text(&text).unwrap(), &vm);
+        }
+    }
+
+    #[test]
+    fn output_is_stable() {
+        let vm = busy_machine();
+        for format in TextFormat::ALL {
+            let text = save_text(&vm, format).unwrap();
+            let again = save_text(&load_text(&text).unwrap(), format).unwrap();
+            assert_eq!(text, again);
+        }
+    }
+
+    #[test]
+    fn switch_and_register_buses() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 16]);
+        vm.micro_program[3].x_bus = RegisterOrSwitch::Sw2;
+        vm.micro_program[3].y_bus = RegisterOrSwitch::Register(Register::Cnt);
+        let text = save_text(&vm, TextFormat::Toml).unwrap();
+        assert!(text.contains("[micro_program.0003]"), "{}", text);
+        assert!(text.contains("x_bus = 'Sw2'"), "{}", text);
+        assert!(text.contains("y_bus = 'Cnt'"), "{}", text);
+        assert_same(&load_text(&text).unwrap(), &vm);
+    }
+
+    #[test]
+    fn version_field() {
+        let text = save_text(&busy_machine(), TextFormat::Toml).unwrap();
+        assert!(text.starts_with("version = 2\n"), "{}", text);
+        let without = text.replacen("version = 2\n", "", 1);
+        assert!(load_text(&without).is_ok());
+        let newer = text.replacen("version = 2\n", "version = 3\n", 1);
+        assert!(load_text(&newer).is_err());
+    }
+
+    #[test]
+    fn annotations() {
+        let mut vm = MicroArch::construct(vec![MicroCode::default(); 16]);
+        vm.annotations.insert(
+            3,
+            Annotation {
+                label: "fetch".to_owned(),
+                comment: "read the next instruction".to_owned(),
+            },
+        );
+        vm.annotations.insert(5, Annotation::default());
+        for format in TextFormat::ALL {
+            let text = save_text(&vm, format).unwrap();
+            let loaded = load_text(&text).unwrap();
+            assert_eq!(loaded.annotations.len(), 1, "{}", text);
+            assert_eq!(loaded.annotations[&3], vm.annotations[&3]);
+        }
+        let text = save_text(&vm, TextFormat::Toml).unwrap();
+        assert!(text.contains("[annotations.0003]"), "{}", text);
+    }
+
+    #[test]
+    fn memory_dump() {
+        let lines = dump_memory(&[0x02, 0x41, 0x80]);
+        assert_eq!(lines, ["0000: 02 41 80"]);
+        assert_eq!(parse_memory(&lines).unwrap(), [0x02, 0x41, 0x80]);
+        assert!(parse_memory(&["0010: 00".to_owned()]).is_err());
+        assert!(parse_memory(&["0000: 0G".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn reports_bad_rows() {
+        let vm = busy_machine();
+        let text = save_text(&vm, TextFormat::Json).unwrap().replacen(
+            "\"x_bus\": \"",
+            "\"x_bus\": \"Q",
+            1,
+        );
+        match load_text(&text) {
+            Err(e) => assert!(e.contains("micro code"), "{}", e),
+            Ok(_) => panic!("unknown bus accepted"),
+        }
+    }
+}
@@ -0,0 +1,580 @@
+//! Every layout binary project files have had.
+//!
+//! | version | file |
+//! |---------|------|
+//! | 0 | bincode of `V0`, the machine before the loop counter |
+//! | 1 | bincode of `V1`, with the loop counter |
+//! | 2 | bincode of `V2`, with the architecture |
+//! | 3 | `"UPRJ"`, compression, bincode of `Sparse` |
+//! | 4 | `"MPRJ"`, version, compression, bincode of `Sparse` |
+//! | 5 | `"MPRJ"`, version, compression, bincode of `Annotated` |
+//!
+//! These structs are frozen: when `MicroArch` changes, add a struct for the new
+//! layout and a `From` from the previous one instead of editing them. The same goes
+//! for `MicroCodeV1` and `ArchV2`, the on-disk copies of `MicroCode` and
+//! `Architecture`, which convert to and from the live types with `From`. Their enums
+//! may only grow by appending variants, as bincode stores the variant index. The
+//! text formats store the same types by name, so nothing is ever renamed either.
+use crate::arch::{Architecture, FieldWidths, FlagBits, OpSpec, RegisterSpec, SwitchSpec};
+use crate::vm::{
+    AluOp, Annotation, Branch, MemOp, MicroArch, MicroCode, Register, RegisterOrSwitch, ShiftOp,
+};
+use serde::{Deserialize, Serialize};
+
+/// frozen copy of a fieldless live enum, converting both ways by variant name.
+macro_rules! frozen_enum {
+    ($(#[$doc:meta])* $frozen:ident = $live:ident { $($variant:ident),* $(,)? }) => {
+        $(#[$doc])*
+        #[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+        pub(super) enum $frozen {
+            $($variant),*
+        }
+        impl From<$frozen> for $live {
+            fn from(frozen: $frozen) -> Self {
+                match frozen {
+                    $($frozen::$variant => $live::$variant),*
+                }
+            }
+        }
+        impl From<$live> for $frozen {
+            fn from(live: $live) -> Self {
+                match live {
+                    $($live::$variant => $frozen::$variant),*
+                }
+            }
+        }
+    };
+}
+
+frozen_enum!(
+    RegisterV1 = Register {
+        Nop,
+        R0,
+        R1,
+        R2,
+        R3,
+        R4,
+        R5,
+        R6,
+        Pc,
+        Ir,
+        Mdr,
+        Mar,
+        Str,
+        Cnt,
+    }
+);
+frozen_enum!(
+    AluOpV1 = AluOp {
+        XPlusY,
+        XMinusY,
+        XAndY,
+        XorY,
+        XxorY,
+        XPlus1,
+        XMinus1,
+    }
+);
+frozen_enum!(
+    ShiftOpV1 = ShiftOp {
+        Nop,
+        RRwC,
+        RlwC,
+        Srl,
+        Sll,
+        Sra,
+        Sla,
+    }
+);
+frozen_enum!(MemOpV1 = MemOp { Nop, R, W });
+frozen_enum!(
+    /// version 0 files use the first seven.
+    BranchV1 = Branch { Plus1, J, JM, JZ, JC, JV, JI, Ldct, Rpct }
+);
+
+#[derive(Copy, Clone, Deserialize, Serialize)]
+enum RegisterOrSwitchV1 {
+    Sw1,
+    Sw2,
+    Register(RegisterV1),
+}
+
+impl From<RegisterOrSwitchV1> for RegisterOrSwitch {
+    fn from(frozen: RegisterOrSwitchV1) -> Self {
+        match frozen {
+            RegisterOrSwitchV1::Sw1 => RegisterOrSwitch::Sw1,
+            RegisterOrSwitchV1::Sw2 => RegisterOrSwitch::Sw2,
+            RegisterOrSwitchV1::Register(register) => RegisterOrSwitch::Register(register.into()),
+        }
+    }
+}
+
+impl From<RegisterOrSwitch> for RegisterOrSwitchV1 {
+    fn from(live: RegisterOrSwitch) -> Self {
+        match live {
+            RegisterOrSwitch::Sw1 => RegisterOrSwitchV1::Sw1,
+            RegisterOrSwitch::Sw2 => RegisterOrSwitchV1::Sw2,
+            RegisterOrSwitch::Register(register) => RegisterOrSwitchV1::Register(register.into()),
+        }
+    }
+}
+
+/// `MicroCode` as every version stores it.
+#[derive(Copy, Clone, Deserialize, Serialize)]
+pub(super) struct MicroCodeV1 {
+    x_bus: RegisterOrSwitchV1,
+    y_bus: RegisterOrSwitchV1,
+    alu: AluOpV1,
+    sft: ShiftOpV1,
+    sin: bool,
+    fl: bool,
+    z_bus: RegisterV1,
+    mem: MemOpV1,
+    branch: BranchV1,
+    hlt: bool,
+    addr: u16,
+}
+
+impl From<MicroCodeV1> for MicroCode {
+    fn from(frozen: MicroCodeV1) -> Self {
+        Self {
+            x_bus: frozen.x_bus.into(),
+            y_bus: frozen.y_bus.into(),
+            alu: frozen.alu.into(),
+            sft: frozen.sft.into(),
+            sin: frozen.sin,
+            fl: frozen.fl,
+            z_bus: frozen.z_bus.into(),
+            mem: frozen.mem.into(),
+            branch: frozen.branch.into(),
+            hlt: frozen.hlt,
+            addr: frozen.addr,
+        }
+    }
+}
+
+impl From<MicroCode> for MicroCodeV1 {
+    fn from(live: MicroCode) -> Self {
+        Self {
+            x_bus: live.x_bus.into(),
+            y_bus: live.y_bus.into(),
+            alu: live.alu.into(),
+            sft: live.sft.into(),
+            sin: live.sin,
+            fl: live.fl,
+            z_bus: live.z_bus.into(),
+            mem: live.mem.into(),
+            branch: live.branch.into(),
+            hlt: live.hlt,
+            addr: live.addr,
+        }
+    }
+}
+
+/// `Architecture` as version 2 and later store it.
+#[derive(Deserialize, Serialize)]
+pub(super) struct ArchV2 {
+    name: String,
+    fields: FieldWidthsV2,
+    flags: FlagBitsV2,
+    registers: Vec<RegisterSpecV2>,
+    switches: Vec<SwitchSpecV2>,
+    alu_ops: Vec<OpSpecV2<AluOpV1>>,
+    shift_ops: Vec<OpSpecV2<ShiftOpV1>>,
+    mem_ops: Vec<OpSpecV2<MemOpV1>>,
+    branches: Vec<OpSpecV2<BranchV1>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct FieldWidthsV2 {
+    x_bus: u32,
+    y_bus: u32,
+    alu: u32,
+    sft: u32,
+    sin: u32,
+    fl: u32,
+    z_bus: u32,
+    mem: u32,
+    branch: u32,
+    hlt: u32,
+    addr: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+struct FlagBitsV2 {
+    minus: u8,
+    zero: u8,
+    carry: u8,
+    overflow: u8,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RegisterSpecV2 {
+    register: RegisterV1,
+    name: String,
+    code: u64,
+    source: bool,
+    destination: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SwitchSpecV2 {
+    switch: RegisterOrSwitchV1,
+    name: String,
+    code: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct OpSpecV2<T> {
+    op: T,
+    name: String,
+    code: u64,
+}
+
+/// convert every element, frozen to live or back.
+fn convert<T: Into<U>, U>(items: Vec<T>) -> Vec<U> {
+    items.into_iter().map(Into::into).collect()
+}
+
+impl From<ArchV2> for Architecture {
+    fn from(frozen: ArchV2) -> Self {
+        let fields = frozen.fields;
+        let flags = frozen.flags;
+        Self {
+            name: frozen.name,
+            fields: FieldWidths {
+                x_bus: fields.x_bus,
+                y_bus: fields.y_bus,
+                alu: fields.alu,
+                sft: fields.sft,
+                sin: fields.sin,
+                fl: fields.fl,
+                z_bus: fields.z_bus,
+                mem: fields.mem,
+                branch: fields.branch,
+                hlt: fields.hlt,
+                addr: fields.addr,
+            },
+            flags: FlagBits {
+                minus: flags.minus,
+                zero: flags.zero,
+                carry: flags.carry,
+                overflow: flags.overflow,
+            },
+            registers: convert(frozen.registers),
+            switches: convert(frozen.switches),
+            alu_ops: convert(frozen.alu_ops),
+            shift_ops: convert(frozen.shift_ops),
+            mem_ops: convert(frozen.mem_ops),
+            branches: convert(frozen.branches),
+        }
+    }
+}
+
+impl From<&Architecture> for ArchV2 {
+    fn from(live: &Architecture) -> Self {
+        let fields = live.fields;
+        let flags = live.flags;
+        Self {
+            name: live.name.clone(),
+            fields: FieldWidthsV2 {
+                x_bus: fields.x_bus,
+                y_bus: fields.y_bus,
+                alu: fields.alu,
+                sft: fields.sft,
+                sin: fields.sin,
+                fl: fields.fl,
+                z_bus: fields.z_bus,
+                mem: fields.mem,
+                branch: fields.branch,
+                hlt: fields.hlt,
+                addr: fields.addr,
+            },
+            flags: FlagBitsV2 {
+                minus: flags.minus,
+                zero: flags.zero,
+                carry: flags.carry,
+                overflow: flags.overflow,
+            },
+            registers: convert(live.registers.clone()),
+            switches: convert(live.switches.clone()),
+            alu_ops: convert(live.alu_ops.clone()),
+            shift_ops: convert(live.shift_ops.clone()),
+            mem_ops: convert(live.mem_ops.clone()),
+            branches: convert(live.branches.clone()),
+        }
+    }
+}
+
+impl From<RegisterSpecV2> for RegisterSpec {
+    fn from(frozen: RegisterSpecV2) -> Self {
+        Self {
+            register: frozen.register.into(),
+            name: frozen.name,
+            code: frozen.code,
+            source: frozen.source,
+            destination: frozen.destination,
+        }
+    }
+}
+
+impl From<RegisterSpec> for RegisterSpecV2 {
+    fn from(live: RegisterSpec) -> Self {
+        Self {
+            register: live.register.into(),
+            name: live.name,
+            code: live.code,
+            source: live.source,
+            destination: live.destination,
+        }
+    }
+}
+
+impl From<SwitchSpecV2> for SwitchSpec {
+    fn from(frozen: SwitchSpecV2) -> Self {
+        Self {
+            switch: frozen.switch.into(),
+            name: frozen.name,
+            code: frozen.code,
+        }
+    }
+}
+
+impl From<SwitchSpec> for SwitchSpecV2 {
+    fn from(live: SwitchSpec) -> Self {
+        Self {
+            switch: live.switch.into(),
+            name: live.name,
+            code: live.code,
+        }
+    }
+}
+
+impl<T: Into<U>, U> From<OpSpecV2<T>> for OpSpec<U> {
+    fn from(frozen: OpSpecV2<T>) -> Self {
+        Self {
+            op: frozen.op.into(),
+            name: frozen.name,
+            code: frozen.code,
+        }
+    }
+}
+
+impl<T: Into<U>, U> From<OpSpec<T>> for OpSpecV2<U> {
+    fn from(live: OpSpec<T>) -> Self {
+        Self {
+            op: live.op.into(),
+            name: live.name,
+            code: live.code,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub(super) struct V0 {
+    pub(super) micro_program_counter: u16,
+    pub(super) micro_program: Vec<MicroCodeV1>,
+    pub(super) memory: Vec<u8>,
+    pub(super) gpr: [u8; 7],
+    pub(super) pc: u8,
+    pub(super) ir: u8,
+    pub(super) mdr: u8,
+    pub(super) mar: u8,
+    pub(super) str: u8,
+    pub(super) sw1: u8,
+    pub(super) sw2: u8,
+    pub(super) hlt: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(super) struct V1 {
+    pub(super) micro_program_counter: u16,
+    pub(super) counter: u16,
+    pub(super) micro_program: Vec<MicroCodeV1>,
+    pub(super) memory: Vec<u8>,
+    pub(super) gpr: [u8; 7],
+    pub(super) pc: u8,
+    pub(super) ir: u8,
+    pub(super) mdr: u8,
+    pub(super) mar: u8,
+    pub(super) str: u8,
+    pub(super) sw1: u8,
+    pub(super) sw2: u8,
+    pub(super) hlt: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(super) struct V2 {
+    pub(super) micro_program_counter: u16,
+    pub(super) counter: u16,
+    pub(super) micro_program: Vec<MicroCodeV1>,
+    pub(super) memory: Vec<u8>,
+    pub(super) gpr: [u8; 7],
+    pub(super) pc: u8,
+    pub(super) ir: u8,
+    pub(super) mdr: u8,
+    pub(super) mar: u8,
+    pub(super) str: u8,
+    pub(super) sw1: u8,
+    pub(super) sw2: u8,
+    pub(super) hlt: bool,
+    pub(super) arch: ArchV2,
+}
+
+/// body of version 3 and 4 files.
+#[derive(Deserialize, Serialize)]
+pub(super) struct Sparse {
+    /// control store size.
+    pub(super) micro_program_len: u32,
+    /// (address, micro code) for every non default row in address order.
+    pub(super) micro_program: Vec<(u16, MicroCodeV1)>,
+    /// everything else. its micro_program is empty.
+    pub(super) machine: V2,
+}
+
+/// body of version 5 files.
+#[derive(Deserialize, Serialize)]
+pub(super) struct Annotated {
+    pub(super) sparse: Sparse,
+    /// (address, label, comment) for every annotated row in address order.
+    pub(super) annotations: Vec<(u16, String, String)>,
+}
+
+impl From<V0> for V1 {
+    fn from(v0: V0) -> Self {
+        Self {
+            micro_program_counter: v0.micro_program_counter,
+            counter: 0,
+            micro_program: v0.micro_program,
+            memory: v0.memory,
+            gpr: v0.gpr,
+            pc: v0.pc,
+            ir: v0.ir,
+            mdr: v0.mdr,
+            mar: v0.mar,
+            str: v0.str,
+            sw1: v0.sw1,
+            sw2: v0.sw2,
+            hlt: v0.hlt,
+        }
+    }
+}
+
+impl From<V1> for V2 {
+    fn from(v1: V1) -> Self {
+        Self {
+            micro_program_counter: v1.micro_program_counter,
+            counter: v1.counter,
+            micro_program: v1.micro_program,
+            memory: v1.memory,
+            gpr: v1.gpr,
+            pc: v1.pc,
+            ir: v1.ir,
+            mdr: v1.mdr,
+            mar: v1.mar,
+            str: v1.str,
+            sw1: v1.sw1,
+            sw2: v1.sw2,
+            hlt: v1.hlt,
+            arch: ArchV2::from(&Architecture::default()),
+        }
+    }
+}
+
+impl TryFrom<Sparse> for V2 {
+    type Error = String;
+    fn try_from(sparse: Sparse) -> Result<Self, String> {
+        let mut v2 = sparse.machine;
+        v2.micro_program = super::control_store(sparse.micro_program_len)?;
+        for (addr, micro_code) in sparse.micro_program {
+            *v2.micro_program
+                .get_mut(addr as usize)
+                .ok_or_else(|| format!("micro code address {:04X}H out of range", addr))? =
+                micro_code;
+        }
+        Ok(v2)
+    }
+}
+
+impl From<V2> for MicroArch {
+    fn from(v2: V2) -> Self {
+        Self {
+            micro_program_counter: v2.micro_program_counter,
+            counter: v2.counter,
+            micro_program: convert(v2.micro_program),
+            memory: v2.memory,
+            gpr: v2.gpr,
+            pc: v2.pc,
+            ir: v2.ir,
+            mdr: v2.mdr,
+            mar: v2.mar,
+            str: v2.str,
+            sw1: v2.sw1,
+            sw2: v2.sw2,
+            hlt: v2.hlt,
+            arch: v2.arch.into(),
+            annotations: Default::default(),
+        }
+    }
+}
+
+impl TryFrom<Annotated> for MicroArch {
+    type Error = String;
+    fn try_from(annotated: Annotated) -> Result<Self, String> {
+        let mut vm = MicroArch::from(V2::try_from(annotated.sparse)?);
+        vm.annotations = annotated
+            .annotations
+            .into_iter()
+            .map(|(addr, label, comment)| (addr, Annotation { label, comment }))
+            .collect();
+        Ok(vm)
+    }
+}
+
+impl From<&MicroArch> for Annotated {
+    fn from(vm: &MicroArch) -> Self {
+        Self {
+            sparse: Sparse::from(vm),
+            annotations: vm
+                .annotations
+                .iter()
+                .filter(|(_, annotation)| !annotation.is_empty())
+                .map(|(addr, annotation)| {
+                    (*addr, annotation.label.clone(), annotation.comment.clone())
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&MicroArch> for Sparse {
+    fn from(vm: &MicroArch) -> Self {
+        let default = MicroCode::default();
+        Self {
+            micro_program_len: vm.micro_program.len() as u32,
+            micro_program: vm
+                .micro_program
+                .iter()
+                .enumerate()
+                .filter(|(_, micro_code)| **micro_code != default)
+                .map(|(addr, micro_code)| (addr as u16, (*micro_code).into()))
+                .collect(),
+            machine: V2 {
+                micro_program_counter: vm.micro_program_counter,
+                counter: vm.counter,
+                micro_program: Vec::new(),
+                memory: vm.memory.clone(),
+                gpr: vm.gpr,
+                pc: vm.pc,
+                ir: vm.ir,
+                mdr: vm.mdr,
+                mar: vm.mar,
+                str: vm.str,
+                sw1: vm.sw1,
+                sw2: vm.sw2,
+                hlt: vm.hlt,
+                arch: ArchV2::from(&vm.arch),
+            },
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use eframe::egui::Label;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn ram_view(ui: &mut eframe::egui::Ui, memory: &mut [u8]) {
+/// memory table, returns the addresses edited with the bytes they held before.
+pub fn ram_view(ui: &mut eframe::egui::Ui, memory: &mut [u8]) -> Vec<(usize, u8)> {
+    let mut edited = vec![];
     static COLUMNS: once_cell::sync::Lazy<AtomicUsize> =
         once_cell::sync::Lazy::new(|| AtomicUsize::new(2));
     let columns = COLUMNS.load(Ordering::Relaxed);
@@ -31,12 +33,17 @@ pub fn ram_view(ui: &mut eframe::egui::Ui, memory: &mut [u8]) {
                                 .zip(columns_ui.iter_mut().skip(1))
                                 .enumerate()
                             {
-                                cell.1
-                                    .add(crate::hex_input::HexInput::new(cell.0, y * columns + x));
+                                let addr = y * columns + x;
+                                let before = *cell.0;
+                                cell.1.add(crate::hex_input::HexInput::new(cell.0, addr));
+                                if *cell.0 != before {
+                                    edited.push((addr, before));
+                                }
                             }
                         }
                     });
                 });
             });
     });
+    edited
 }
@@ -0,0 +1,659 @@
+//! Reference microprogram for a small KUE-CHIP2 style instruction set.
+//!
+//! The macro machine uses R0 as the accumulator (ACC), R1 as the index register (IX)
+//! and R6 as the output buffer (OBUF). Instructions are one byte, followed by an
+//! operand byte when the instruction needs one. Main memory holds program and data.
+//!
+//! | opcode              | mnemonic        | operation                                  |
+//! |---------------------|-----------------|--------------------------------------------|
+//! | `00`                | NOP             |                                            |
+//! | `01`                | HLT             | stop, Wakeup resumes at next instruction   |
+//! | `02`                | IN1             | ACC ← SW1                                  |
+//! | `03`                | IN2             | ACC ← SW2                                  |
+//! | `04`                | OUT             | OBUF ← ACC                                 |
+//! | `10` `r` `s`        | SRA/SLA/SRL/SLL | shift register, shifted out bit to C       |
+//! | `20` `n`            | BA n            | PC ← n                                     |
+//! | `21`~`28` `n`       | BZ BNZ BC BNC BN BP BV BNV | PC ← n if condition holds       |
+//! | `30` `r` `m` `n`    | LD r, op        | r ← op                                     |
+//! | `40` `r` `m` `n`    | ST r, op        | op ← r (immediate mode is illegal)         |
+//! | `50` `r` `m` `n`    | ADD r, op       | r ← r + op, flags updated                  |
+//! | `60` `r` `m` `n`    | SUB r, op       | r ← r - op, flags updated                  |
+//! | `70` `r` `m` `n`    | CMP r, op       | flags updated by r - op                    |
+//! | `80` `r` `m` `n`    | AND r, op       | r ← r & op, flags updated                  |
+//! | `90` `r` `m` `n`    | OR r, op        | r ← r \| op, flags updated                 |
+//! | `A0` `r` `m` `n`    | EOR r, op       | r ← r ^ op, flags updated                  |
+//!
+//! `r` is `00` for ACC or `08` for IX, `s` is `0` SRA, `1` SLA, `2` SRL, `3` SLL and `m`
+//! selects the operand: `0` immediate `n`, `1` memory `[n]`, `2` memory `[IX+n]`.
+//! Flags follow the VM's rules, so C and V are computed from X+Y even for SUB and CMP
+//! and shifts set N and Z from the value before shifting.
+//! Any other opcode halts the machine at the illegal instruction handler.
+use crate::vm::{AluOp, Branch, MemOp, MicroArch, MicroCode, Register, RegisterOrSwitch, ShiftOp};
+
+/// instruction opcodes. operand register and addressing mode are or'ed in.
+pub mod op {
+    pub const NOP: u8 = 0x00;
+    pub const HLT: u8 = 0x01;
+    pub const IN1: u8 = 0x02;
+    pub const IN2: u8 = 0x03;
+    pub const OUT: u8 = 0x04;
+    pub const SRA: u8 = 0x10;
+    pub const SLA: u8 = 0x11;
+    pub const SRL: u8 = 0x12;
+    pub const SLL: u8 = 0x13;
+    pub const BA: u8 = 0x20;
+    pub const BZ: u8 = 0x21;
+    pub const BNZ: u8 = 0x22;
+    pub const BC: u8 = 0x23;
+    pub const BNC: u8 = 0x24;
+    pub const BN: u8 = 0x25;
+    pub const BP: u8 = 0x26;
+    pub const BV: u8 = 0x27;
+    pub const BNV: u8 = 0x28;
+    pub const LD: u8 = 0x30;
+    pub const ST: u8 = 0x40;
+    pub const ADD: u8 = 0x50;
+    pub const SUB: u8 = 0x60;
+    pub const CMP: u8 = 0x70;
+    pub const AND: u8 = 0x80;
+    pub const OR: u8 = 0x90;
+    pub const EOR: u8 = 0xA0;
+    /// operate on IX instead of ACC.
+    pub const IX: u8 = 0x08;
+    /// operand is the byte following the instruction.
+    pub const IMM: u8 = 0x00;
+    /// operand is memory addressed by the following byte.
+    pub const DIR: u8 = 0x01;
+    /// operand is memory addressed by the following byte plus IX.
+    pub const IDX: u8 = 0x02;
+}
+
+/// micro address of instruction fetch.
+pub const FETCH: u16 = 0x0000;
+/// JI base of the opcode dispatch table.
+pub const DISPATCH: u16 = 0x0100;
+const ROUTINES: u16 = 0x0200;
+
+const ACC: Register = Register::R0;
+const IX: Register = Register::R1;
+const OBUF: Register = Register::R6;
+
+/// sample macro program with its initial data.
+pub struct Sample {
+    pub name: &'static str,
+    pub program: &'static [u8],
+    /// (address, bytes) stored in addition to the program.
+    pub data: &'static [(usize, &'static [u8])],
+}
+
+pub const SAMPLES: [Sample; 3] = [
+    Sample {
+        name: "sum 1..SW1",
+        program: &[
+            0x02, // 00 IN1
+            0x41, 0x80, // 01 ST ACC,[80]
+            0x30, 0x00, // 03 LD ACC,0
+            0x41, 0x81, // 05 ST ACC,[81]
+            0x31, 0x80, // 07 LD ACC,[80]
+            0x70, 0x00, // 09 CMP ACC,0
+            0x21, 0x19, // 0B BZ 19
+            0x51, 0x81, // 0D ADD ACC,[81]
+            0x41, 0x81, // 0F ST ACC,[81]
+            0x31, 0x80, // 11 LD ACC,[80]
+            0x60, 0x01, // 13 SUB ACC,1
+            0x41, 0x80, // 15 ST ACC,[80]
+            0x20, 0x07, // 17 BA 07
+            0x31, 0x81, // 19 LD ACC,[81]
+            0x04, // 1B OUT
+            0x01, // 1C HLT
+        ],
+        data: &[],
+    },
+    Sample {
+        name: "SW1 x SW2",
+        program: &[
+            0x02, // 00 IN1
+            0x41, 0x80, // 01 ST ACC,[80]
+            0x03, // 03 IN2
+            0x41, 0x81, // 04 ST ACC,[81]
+            0x30, 0x00, // 06 LD ACC,0
+            0x41, 0x82, // 08 ST ACC,[82]
+            0x31, 0x81, // 0A LD ACC,[81]
+            0x70, 0x00, // 0C CMP ACC,0
+            0x21, 0x22, // 0E BZ 22
+            0x12, // 10 SRL ACC
+            0x41, 0x81, // 11 ST ACC,[81]
+            0x24, 0x1B, // 13 BNC 1B
+            0x31, 0x82, // 15 LD ACC,[82]
+            0x51, 0x80, // 17 ADD ACC,[80]
+            0x41, 0x82, // 19 ST ACC,[82]
+            0x31, 0x80, // 1B LD ACC,[80]
+            0x13, // 1D SLL ACC
+            0x41, 0x80, // 1E ST ACC,[80]
+            0x20, 0x0A, // 20 BA 0A
+            0x31, 0x82, // 22 LD ACC,[82]
+            0x04, // 24 OUT
+            0x01, // 25 HLT
+        ],
+        data: &[],
+    },
+    Sample {
+        name: "array sum",
+        program: &[
+            0x38, 0x00, // 00 LD IX,0
+            0x30, 0x00, // 02 LD ACC,0
+            0x41, 0x81, // 04 ST ACC,[81]
+            0x31, 0x80, // 06 LD ACC,[80]
+            0x70, 0x00, // 08 CMP ACC,0
+            0x21, 0x1A, // 0A BZ 1A
+            0x60, 0x01, // 0C SUB ACC,1
+            0x41, 0x80, // 0E ST ACC,[80]
+            0x31, 0x81, // 10 LD ACC,[81]
+            0x52, 0x90, // 12 ADD ACC,[IX+90]
+            0x41, 0x81, // 14 ST ACC,[81]
+            0x58, 0x01, // 16 ADD IX,1
+            0x20, 0x06, // 18 BA 06
+            0x31, 0x81, // 1A LD ACC,[81]
+            0x04, // 1C OUT
+            0x01, // 1D HLT
+        ],
+        data: &[(0x80, &[4]), (0x90, &[1, 2, 3, 4])],
+    },
+];
+
+/// VM loaded with the reference microprogram and a macro program.
+pub fn project(sample: &Sample) -> MicroArch {
+    let mut vm = MicroArch::construct(micro_program());
+    vm.memory[..sample.program.len()].copy_from_slice(sample.program);
+    for (addr, bytes) in sample.data {
+        vm.memory[*addr..*addr + bytes.len()].copy_from_slice(bytes);
+    }
+    vm
+}
+
+/// the full 64K control store of the reference machine.
+pub fn micro_program() -> Vec<MicroCode> {
+    let mut builder = Builder {
+        micro_program: vec![MicroCode::default(); 1 << 16],
+        next: FETCH,
+    };
+    // MAR ← PC, MDR ← (MAR), PC ← PC+1, IR ← MDR then dispatch.
+    builder.emit(transfer(reg(Register::Pc), Register::Mar));
+    builder.emit(MicroCode {
+        mem: MemOp::R,
+        ..increment(Register::Pc)
+    });
+    builder.emit(MicroCode {
+        branch: Branch::JI,
+        addr: DISPATCH,
+        ..transfer(reg(Register::Mdr), Register::Ir)
+    });
+
+    builder.next = ROUTINES;
+    let illegal = builder.here();
+    builder.emit(MicroCode {
+        hlt: true,
+        ..jump(illegal)
+    });
+    for opcode in 0..=0xff {
+        builder.micro_program[(DISPATCH + opcode) as usize] = jump(illegal);
+    }
+    // PC ← MDR, shared by every taken branch.
+    let take = builder.emit(MicroCode {
+        branch: Branch::J,
+        addr: FETCH,
+        ..transfer(reg(Register::Mdr), Register::Pc)
+    });
+
+    builder.routine(op::NOP, &[jump(FETCH)]);
+    builder.routine(
+        op::HLT,
+        &[MicroCode {
+            hlt: true,
+            ..jump(FETCH)
+        }],
+    );
+    builder.routine(op::IN1, &[done(transfer(RegisterOrSwitch::Sw1, ACC))]);
+    builder.routine(op::IN2, &[done(transfer(RegisterOrSwitch::Sw2, ACC))]);
+    builder.routine(op::OUT, &[done(transfer(reg(ACC), OBUF))]);
+
+    for (register_bit, register) in [(0, ACC), (op::IX, IX)] {
+        for (opcode, sft) in [
+            (op::SRA, ShiftOp::Sra),
+            (op::SLA, ShiftOp::Sla),
+            (op::SRL, ShiftOp::Srl),
+            (op::SLL, ShiftOp::Sll),
+        ] {
+            builder.routine(
+                opcode | register_bit,
+                &[done(MicroCode {
+                    sft,
+                    fl: true,
+                    ..transfer(reg(register), register)
+                })],
+            );
+        }
+    }
+
+    builder.routine(
+        op::BA,
+        &[fetch_operand()[0], fetch_operand()[1], jump(take)],
+    );
+    for (opcode, branch, taken_when_set) in [
+        (op::BZ, Branch::JZ, true),
+        (op::BNZ, Branch::JZ, false),
+        (op::BC, Branch::JC, true),
+        (op::BNC, Branch::JC, false),
+        (op::BN, Branch::JM, true),
+        (op::BP, Branch::JM, false),
+        (op::BV, Branch::JV, true),
+        (op::BNV, Branch::JV, false),
+    ] {
+        let (when_set, otherwise) = if taken_when_set {
+            (take, FETCH)
+        } else {
+            (FETCH, take)
+        };
+        // flags are untouched while fetching the operand, so test them on the read.
+        builder.routine(
+            opcode,
+            &[
+                fetch_operand()[0],
+                MicroCode {
+                    branch,
+                    addr: when_set,
+                    ..fetch_operand()[1]
+                },
+                jump(otherwise),
+            ],
+        );
+    }
+
+    for (register_bit, register) in [(0, ACC), (op::IX, IX)] {
+        for (mode, address) in [(op::DIR, direct()), (op::IDX, indexed())] {
+            builder.routine(
+                op::ST | register_bit | mode,
+                &[
+                    fetch_operand()[0],
+                    fetch_operand()[1],
+                    address,
+                    done(MicroCode {
+                        mem: MemOp::W,
+                        ..transfer(reg(register), Register::Mdr)
+                    }),
+                ],
+            );
+        }
+        for (opcode, alu, write_back) in [
+            (op::LD, None, true),
+            (op::ADD, Some(AluOp::XPlusY), true),
+            (op::SUB, Some(AluOp::XMinusY), true),
+            (op::CMP, Some(AluOp::XMinusY), false),
+            (op::AND, Some(AluOp::XAndY), true),
+            (op::OR, Some(AluOp::XorY), true),
+            (op::EOR, Some(AluOp::XxorY), true),
+        ] {
+            let execute = match alu {
+                None => transfer(reg(Register::Mdr), register),
+                Some(alu) => MicroCode {
+                    x_bus: reg(register),
+                    y_bus: reg(Register::Mdr),
+                    alu,
+                    fl: true,
+                    z_bus: if write_back { register } else { Register::Nop },
+                    ..MicroCode::default()
+                },
+            };
+            builder.routine(
+                opcode | register_bit | op::IMM,
+                &[fetch_operand()[0], fetch_operand()[1], done(execute)],
+            );
+            for (mode, mut address) in [(op::DIR, direct()), (op::IDX, indexed())] {
+                address.mem = MemOp::R;
+                builder.routine(
+                    opcode | register_bit | mode,
+                    &[
+                        fetch_operand()[0],
+                        fetch_operand()[1],
+                        address,
+                        done(execute),
+                    ],
+                );
+            }
+        }
+    }
+    builder.micro_program
+}
+
+/// sequentially places micro code and fills the dispatch table.
+struct Builder {
+    micro_program: Vec<MicroCode>,
+    next: u16,
+}
+impl Builder {
+    fn here(&self) -> u16 {
+        self.next
+    }
+    fn emit(&mut self, micro_code: MicroCode) -> u16 {
+        let addr = self.next;
+        self.micro_program[addr as usize] = micro_code;
+        self.next += 1;
+        addr
+    }
+    /// place an instruction handler and register it for `opcode`.
+    fn routine(&mut self, opcode: u8, micro_codes: &[MicroCode]) {
+        let start = self.here();
+        for micro_code in micro_codes {
+            self.emit(*micro_code);
+        }
+        self.micro_program[(DISPATCH + opcode as u16) as usize] = jump(start);
+    }
+}
+
+fn reg(register: Register) -> RegisterOrSwitch {
+    RegisterOrSwitch::Register(register)
+}
+/// Z ← X
+fn transfer(from: RegisterOrSwitch, to: Register) -> MicroCode {
+    MicroCode {
+        x_bus: from,
+        z_bus: to,
+        ..MicroCode::default()
+    }
+}
+/// register ← register + 1
+fn increment(register: Register) -> MicroCode {
+    MicroCode {
+        alu: AluOp::XPlus1,
+        ..transfer(reg(register), register)
+    }
+}
+fn jump(addr: u16) -> MicroCode {
+    MicroCode {
+        branch: Branch::J,
+        addr,
+        ..MicroCode::default()
+    }
+}
+/// last micro code of an instruction.
+fn done(micro_code: MicroCode) -> MicroCode {
+    MicroCode {
+        branch: Branch::J,
+        addr: FETCH,
+        ..micro_code
+    }
+}
+/// MDR ← (PC), PC ← PC+1
+fn fetch_operand() -> [MicroCode; 2] {
+    [
+        transfer(reg(Register::Pc), Register::Mar),
+        MicroCode {
+            mem: MemOp::R,
+            ..increment(Register::Pc)
+        },
+    ]
+}
+/// MAR ← MDR
+fn direct() -> MicroCode {
+    transfer(reg(Register::Mdr), Register::Mar)
+}
+/// MAR ← MDR + IX
+fn indexed() -> MicroCode {
+    MicroCode {
+        y_bus: reg(IX),
+        ..direct()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::op::*;
+    use super::{micro_program, project, FETCH, SAMPLES};
+    use crate::vm::MicroArch;
+
+    const MINUS: u8 = 0x01;
+    const ZERO: u8 = 0x02;
+    const CARRY: u8 = 0x04;
+    const OVERFLOW: u8 = 0x08;
+
+    fn run_with(program: &[u8], data: &[(usize, &[u8])], sw1: u8, sw2: u8) -> MicroArch {
+        let mut vm = MicroArch::construct(micro_program());
+        vm.memory[..program.len()].copy_from_slice(program);
+        for (addr, bytes) in data {
+            vm.memory[*addr..*addr + bytes.len()].copy_from_slice(bytes);
+        }
+        vm.sw1 = sw1;
+        vm.sw2 = sw2;
+        for _ in 0..100_000 {
+            if vm.exec() {
+                return vm;
+            }
+        }
+        panic!("program did not halt");
+    }
+    fn run(program: &[u8]) -> MicroArch {
+        run_with(program, &[], 0, 0)
+    }
+    fn acc(vm: &MicroArch) -> u8 {
+        vm.gpr[0]
+    }
+    fn ix(vm: &MicroArch) -> u8 {
+        vm.gpr[1]
+    }
+
+    #[test]
+    fn nop_and_hlt() {
+        let vm = run(&[NOP, NOP, HLT]);
+        assert_eq!(vm.pc, 3);
+        assert!(vm.hlt);
+        assert_eq!(vm.micro_program_counter, FETCH);
+    }
+
+    #[test]
+    fn hlt_resumes_at_next_instruction() {
+        let mut vm = run(&[HLT, IN1, HLT]);
+        vm.sw1 = 0x5a;
+        vm.start();
+        while !vm.exec() {}
+        assert_eq!(acc(&vm), 0x5a);
+        assert_eq!(vm.pc, 3);
+    }
+
+    #[test]
+    fn illegal_opcode_halts() {
+        let mut vm = run(&[0xff]);
+        assert_eq!(vm.pc, 1);
+        vm.start();
+        assert!(vm.exec());
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn store_immediate_is_illegal() {
+        let vm = run(&[ST | IMM, 0x10, IN1, HLT]);
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn input_and_output() {
+        let vm = run_with(&[IN1, OUT, IN2, HLT], &[], 0x12, 0x34);
+        assert_eq!(vm.gpr[6], 0x12);
+        assert_eq!(acc(&vm), 0x34);
+    }
+
+    #[test]
+    fn load_addressing_modes() {
+        let vm = run_with(&[LD | IMM, 0x11, HLT], &[], 0, 0);
+        assert_eq!(acc(&vm), 0x11);
+        let vm = run_with(&[LD | DIR, 0x80, HLT], &[(0x80, &[0x22])], 0, 0);
+        assert_eq!(acc(&vm), 0x22);
+        let vm = run_with(
+            &[LD | IX | IMM, 0x03, LD | IDX, 0x80, HLT],
+            &[(0x83, &[0x33])],
+            0,
+            0,
+        );
+        assert_eq!(ix(&vm), 0x03);
+        assert_eq!(acc(&vm), 0x33);
+        let vm = run_with(&[LD | IX | DIR, 0x80, HLT], &[(0x80, &[0x44])], 0, 0);
+        assert_eq!(ix(&vm), 0x44);
+    }
+
+    #[test]
+    fn load_keeps_flags() {
+        let vm = run(&[LD | IMM, 0x00, CMP | IMM, 0x01, LD | IMM, 0x00, HLT]);
+        assert_eq!(vm.str & ZERO, 0);
+        assert_eq!(vm.str & MINUS, MINUS);
+    }
+
+    #[test]
+    fn store_addressing_modes() {
+        let vm = run(&[LD | IMM, 0x55, ST | DIR, 0x80, HLT]);
+        assert_eq!(vm.memory[0x80], 0x55);
+        let vm = run(&[
+            LD | IMM,
+            0x66,
+            LD | IX | IMM,
+            0x05,
+            ST | IDX,
+            0x80,
+            ST | IX | DIR,
+            0x81,
+            HLT,
+        ]);
+        assert_eq!(vm.memory[0x85], 0x66);
+        assert_eq!(vm.memory[0x81], 0x05);
+    }
+
+    #[test]
+    fn add_and_sub() {
+        let vm = run(&[LD | IMM, 0x12, ADD | IMM, 0x34, HLT]);
+        assert_eq!(acc(&vm), 0x46);
+        assert_eq!(vm.str & 0x0f, 0);
+        let vm = run(&[LD | IMM, 0x80, ADD | IMM, 0x80, HLT]);
+        assert_eq!(acc(&vm), 0x00);
+        assert_eq!(vm.str & (ZERO | CARRY), ZERO | CARRY);
+        let vm = run(&[LD | IMM, 0x40, ADD | IMM, 0x40, HLT]);
+        assert_eq!(vm.str & (MINUS | OVERFLOW), MINUS | OVERFLOW);
+        let vm = run(&[LD | IMM, 0x05, SUB | IMM, 0x05, HLT]);
+        assert_eq!(acc(&vm), 0);
+        assert_eq!(vm.str & ZERO, ZERO);
+        let vm = run(&[LD | IMM, 0x03, SUB | IMM, 0x05, HLT]);
+        assert_eq!(acc(&vm), 0xfe);
+        assert_eq!(vm.str & MINUS, MINUS);
+        let vm = run(&[LD | IX | IMM, 0x10, ADD | IX | DIR, 0x80, HLT]);
+        assert_eq!(ix(&vm), 0x10);
+    }
+
+    #[test]
+    fn compare_keeps_register() {
+        let vm = run(&[LD | IMM, 0x07, CMP | IMM, 0x07, HLT]);
+        assert_eq!(acc(&vm), 0x07);
+        assert_eq!(vm.str & ZERO, ZERO);
+        let vm = run(&[LD | IX | IMM, 0x01, CMP | IX | IMM, 0x02, HLT]);
+        assert_eq!(ix(&vm), 0x01);
+        assert_eq!(vm.str & MINUS, MINUS);
+    }
+
+    #[test]
+    fn logical_operations() {
+        let vm = run(&[LD | IMM, 0b1100, AND | IMM, 0b1010, HLT]);
+        assert_eq!(acc(&vm), 0b1000);
+        let vm = run(&[LD | IMM, 0b1100, OR | IMM, 0b1010, HLT]);
+        assert_eq!(acc(&vm), 0b1110);
+        let vm = run(&[LD | IMM, 0b1100, EOR | IMM, 0b1100, HLT]);
+        assert_eq!(acc(&vm), 0);
+        assert_eq!(vm.str & ZERO, ZERO);
+        let vm = run_with(
+            &[LD | IX | IMM, 0x01, LD | IMM, 0xff, AND | IDX, 0x80, HLT],
+            &[(0x81, &[0x0f])],
+            0,
+            0,
+        );
+        assert_eq!(acc(&vm), 0x0f);
+    }
+
+    #[test]
+    fn shifts() {
+        let vm = run(&[LD | IMM, 0x81, SRA, HLT]);
+        assert_eq!(acc(&vm), 0xc0);
+        assert_eq!(vm.str & CARRY, CARRY);
+        let vm = run(&[LD | IMM, 0x81, SLA, HLT]);
+        assert_eq!(acc(&vm), 0x02);
+        assert_eq!(vm.str & CARRY, CARRY);
+        let vm = run(&[LD | IMM, 0x02, SRL, HLT]);
+        assert_eq!(acc(&vm), 0x01);
+        assert_eq!(vm.str & CARRY, 0);
+        // N and Z come from the ALU, before the shifter.
+        let vm = run(&[LD | IMM, 0x01, SRL, HLT]);
+        assert_eq!(acc(&vm), 0x00);
+        assert_eq!(vm.str & (ZERO | CARRY), CARRY);
+        let vm = run(&[LD | IX | IMM, 0x41, SLL | IX, HLT]);
+        assert_eq!(ix(&vm), 0x82);
+    }
+
+    #[test]
+    fn branches() {
+        // (setup, branch, taken)
+        let cases: [(&[u8], u8, bool); 16] = [
+            (&[CMP | IMM, 0x00], BZ, true),
+            (&[CMP | IMM, 0x01], BZ, false),
+            (&[CMP | IMM, 0x01], BNZ, true),
+            (&[CMP | IMM, 0x00], BNZ, false),
+            (&[LD | IMM, 0xff, ADD | IMM, 0x01], BC, true),
+            (&[ADD | IMM, 0x01], BC, false),
+            (&[ADD | IMM, 0x01], BNC, true),
+            (&[LD | IMM, 0xff, ADD | IMM, 0x01], BNC, false),
+            (&[CMP | IMM, 0x01], BN, true),
+            (&[CMP | IMM, 0x00], BN, false),
+            (&[CMP | IMM, 0x00], BP, true),
+            (&[CMP | IMM, 0x01], BP, false),
+            (&[LD | IMM, 0x40, ADD | IMM, 0x40], BV, true),
+            (&[ADD | IMM, 0x01], BV, false),
+            (&[ADD | IMM, 0x01], BNV, true),
+            (&[LD | IMM, 0x40, ADD | IMM, 0x40], BNV, false),
+        ];
+        for (setup, branch, taken) in cases {
+            let mut program = setup.to_vec();
+            // not taken falls into HLT, taken skips over it to IN1.
+            let target = program.len() as u8 + 3;
+            program.extend_from_slice(&[branch, target, HLT, IN1, HLT]);
+            let vm = run_with(&program, &[], 0x77, 0);
+            assert_eq!(
+                acc(&vm) == 0x77,
+                taken,
+                "branch {:02X} after {:02X?}",
+                branch,
+                setup
+            );
+        }
+        let vm = run(&[BA, 0x04, HLT, HLT, LD | IMM, 0x01, HLT]);
+        assert_eq!(acc(&vm), 0x01);
+    }
+
+    #[test]
+    fn sample_sum() {
+        let mut vm = project(&SAMPLES[0]);
+        vm.sw1 = 10;
+        while !vm.exec() {}
+        assert_eq!(vm.gpr[6], 55);
+    }
+
+    #[test]
+    fn sample_multiply() {
+        for (a, b) in [(3, 5), (0, 9), (7, 0), (15, 17)] {
+            let mut vm = project(&SAMPLES[1]);
+            vm.sw1 = a;
+            vm.sw2 = b;
+            while !vm.exec() {}
+            assert_eq!(vm.gpr[6], a.wrapping_mul(b), "{} x {}", a, b);
+        }
+    }
+
+    #[test]
+    fn sample_array_sum() {
+        let mut vm = project(&SAMPLES[2]);
+        while !vm.exec() {}
+        assert_eq!(vm.gpr[6], 10);
+    }
+}
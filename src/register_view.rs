@@ -1,78 +1,159 @@
+use crate::history::{Edit, History, Registers};
+use crate::number_input::{NumberFormat, NumberInput};
+use crate::vm::{MicroArch, Register};
+
+/// micro codes executed by one press of the fast run button.
+const FAST_RUN_BUDGET: u64 = 10_000_000;
+
+/// record what the widgets edited since `before` and take `vm` as the new state.
+fn record(history: &mut History, before: &mut Registers, vm: &MicroArch, time: f64) {
+    let after = Registers::of(vm);
+    let mut edit = Edit::default();
+    edit.registers(*before, after);
+    history.record(edit, time);
+    *before = after;
+}
+
 pub fn register_view(
     ui: &mut eframe::egui::Ui,
-    vm: &mut crate::vm::MicroArch,
+    vm: &mut MicroArch,
+    engine: &mut crate::engine::Engine,
     auto_exec: &mut bool,
+    format: &mut NumberFormat,
+    transfer: &mut Option<crate::datapath::Transfer>,
+    history: &mut History,
 ) {
+    let time = ui.input().time;
+    let mut before = Registers::of(vm);
+    let format_now = *format;
+    // edit a register through a 16 bit copy.
+    let input = |ui: &mut eframe::egui::Ui, value: &mut u16, bits, id: &str| {
+        ui.add(NumberInput::new(value, bits, format_now, id));
+    };
+    let register = |ui: &mut eframe::egui::Ui, vm: &mut MicroArch, register| {
+        let mut value = vm.load_register(register) as u16;
+        input(ui, &mut value, 8, &format!("{:?}", register));
+        if value != vm.load_register(register) as u16 {
+            vm.store_register(register, value as u8);
+        }
+    };
     ui.vertical(|ui| {
+        ui.horizontal(|ui| {
+            ui.label("format");
+            for number_format in NumberFormat::ALL {
+                ui.radio_value(format, number_format, number_format.to_string());
+            }
+        });
         ui.horizontal(|ui| {
             ui.horizontal(|ui| {
                 ui.vertical(|ui| {
-                    ui.label("R0~R6");
-                    for (name, x) in vm.gpr.iter().enumerate() {
-                        ui.label(format!("R{} {:02X}H", name, x));
+                    ui.label("GPR");
+                    let gprs: Vec<_> = vm.arch.gprs().cloned().collect();
+                    for gpr in gprs {
+                        ui.horizontal(|ui| {
+                            ui.label(&gpr.name);
+                            register(ui, vm, gpr.register);
+                        });
                     }
                 });
                 ui.horizontal_top(|ui| {
+                    const SPECIAL_REGISTERS: [Register; 5] = [
+                        Register::Ir,
+                        Register::Pc,
+                        Register::Mar,
+                        Register::Mdr,
+                        Register::Str,
+                    ];
+                    let specials: Vec<_> = SPECIAL_REGISTERS
+                        .iter()
+                        .filter_map(|register| vm.arch.register(*register))
+                        .cloned()
+                        .collect();
+                    let counter = vm.arch.register(Register::Cnt).cloned();
                     ui.vertical(|ui| {
-                        ui.label("IR");
-                        ui.label("PC");
-                        ui.label("MAR");
-                        ui.label("MDR");
-                        ui.label("STR");
-                    });
-                    ui.vertical(|ui| {
-                        ui.label(format!("{:02X}H", vm.ir));
-                        ui.label(format!("{:02X}H", vm.pc));
-                        ui.label(format!("{:02X}H", vm.mar));
-                        ui.label(format!("{:02X}H", vm.mdr));
-                        ui.label(format!("{:02X}H", vm.str));
+                        for special in &specials {
+                            ui.label(&special.name);
+                        }
+                        if let Some(counter) = &counter {
+                            ui.label(&counter.name);
+                        }
                     });
                     ui.vertical(|ui| {
-                        ui.label("Minus flag");
-                        ui.label("Zero flag");
-                        ui.label("Carry flag");
-                        ui.label("Overflow flag");
+                        for special in &specials {
+                            register(ui, vm, special.register);
+                        }
+                        if counter.is_some() {
+                            input(ui, &mut vm.counter, 16, "counter");
+                        }
                     });
                     ui.vertical(|ui| {
-                        ui.label(format!("{}", vm.str & 0x01));
-                        ui.label(format!("{}", vm.str & 0x02 >> 1));
-                        ui.label(format!("{}", vm.str & 0x04 >> 2));
-                        ui.label(format!("{}", vm.str & 0x08 >> 3));
+                        let flags = vm.arch.flags;
+                        for (name, bit) in [
+                            ("Minus flag", flags.minus),
+                            ("Zero flag", flags.zero),
+                            ("Carry flag", flags.carry),
+                            ("Overflow flag", flags.overflow),
+                        ] {
+                            let mut flag = vm.flag(bit);
+                            if ui.checkbox(&mut flag, name).changed() {
+                                vm.set_flag(bit, flag);
+                            }
+                        }
                     });
                 });
                 ui.vertical(|ui| {
-                    ui.horizontal(|ui| {
-                        ui.label("sw1");
-                        ui.add(crate::hex_input::HexInput::new(&mut vm.sw1, 0xfffff));
-                    });
-                    ui.horizontal(|ui| {
-                        ui.label("sw2");
-                        ui.add(crate::hex_input::HexInput::new(&mut vm.sw2, 0xffffff));
-                    });
+                    for (name, switch) in [("sw1", &mut vm.sw1), ("sw2", &mut vm.sw2)] {
+                        ui.horizontal(|ui| {
+                            ui.label(name);
+                            let mut value = *switch as u16;
+                            input(ui, &mut value, 8, name);
+                            *switch = value as u8;
+                        });
+                    }
                 })
             })
         });
         ui.horizontal(|ui| {
-            ui.label(format!(
-                "micro code address : {:04X}H",
-                vm.micro_program_counter
-            ));
+            ui.label("micro code address");
+            input(ui, &mut vm.micro_program_counter, 16, "micro code address");
             if ui.button("Wakeup").clicked() {
                 vm.start();
             }
             if ui.button("Step execution").clicked() {
                 *auto_exec = false;
-                vm.exec();
+                record(history, &mut before, vm, time);
+                *transfer = vm.step();
+                before = Registers::of(vm);
             }
             if ui.button("Exec until Halt").clicked() {
                 *auto_exec = true;
             }
+            if ui
+                .button("Run to Halt (fast)")
+                .on_hover_text(format!(
+                    "run without redrawing, at most {} micro codes",
+                    FAST_RUN_BUDGET
+                ))
+                .clicked()
+            {
+                *auto_exec = false;
+                *transfer = None;
+                record(history, &mut before, vm, time);
+                engine.run(vm, FAST_RUN_BUDGET);
+                before = Registers::of(vm);
+            }
             if ui.button("Reset micro code address").clicked() {
+                *transfer = None;
                 vm.micro_program_counter = 0;
             }
             if ui.button("Reset registers").clicked() {
+                *transfer = None;
                 vm.reset_register();
             }
         });
+        if let Some(fault) = vm.fault() {
+            ui.colored_label(eframe::egui::Color32::RED, fault);
+        }
     });
+    record(history, &mut before, vm, time);
 }
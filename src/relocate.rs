@@ -0,0 +1,408 @@
+//! Row operations on the control store that keep branches pointing at the same
+//! micro codes.
+//!
+//! `preview` works out the control store after a `RowOp` together with every
+//! branch address it rewrites, so the GUI can show the changes before they are
+//! applied. Labels and comments move with their rows. Rows only move inside the
+//! fixed size control store: inserting pushes the last rows out, which must be
+//! empty, and deleting fills the end with empty rows.
+use crate::vm::{Annotation, Branch, MicroCode};
+use std::collections::BTreeMap;
+
+/// entries of a JI dispatch table, one per IR value.
+const DISPATCH_TABLE: usize = 0x100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowOp {
+    /// `count` empty rows before `at`.
+    Insert {
+        at: usize,
+        count: usize,
+    },
+    Delete {
+        start: usize,
+        count: usize,
+    },
+    /// `count` rows from `start` so that the block begins at `to` afterwards.
+    Move {
+        start: usize,
+        count: usize,
+        to: usize,
+    },
+    /// copy of `count` rows from `start` inserted before `to`.
+    Duplicate {
+        start: usize,
+        count: usize,
+        to: usize,
+    },
+}
+
+impl RowOp {
+    /// address after the operation of the row at `addr`, None when it is deleted or
+    /// pushed out of a control store of `len` rows.
+    pub fn relocate(&self, addr: usize, len: usize) -> Option<usize> {
+        let new = match *self {
+            RowOp::Insert { at, count } | RowOp::Duplicate { to: at, count, .. } => {
+                if addr < at {
+                    addr
+                } else {
+                    addr + count
+                }
+            }
+            RowOp::Delete { start, count } => {
+                if addr < start {
+                    addr
+                } else if addr < start + count {
+                    return None;
+                } else {
+                    addr - count
+                }
+            }
+            RowOp::Move { start, count, to } => {
+                if (start..start + count).contains(&addr) {
+                    to + addr - start
+                } else {
+                    let rest = if addr < start { addr } else { addr - count };
+                    if rest < to {
+                        rest
+                    } else {
+                        rest + count
+                    }
+                }
+            }
+        };
+        (new < len).then_some(new)
+    }
+
+    fn check(&self, len: usize) -> Result<(), String> {
+        let rows = |start: usize, count: usize| {
+            if count == 0 {
+                Err("no rows selected".to_owned())
+            } else if start + count > len {
+                Err(format!(
+                    "rows {:04X}H..{:04X}H are outside the control store",
+                    start,
+                    start + count - 1
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        match *self {
+            RowOp::Insert { at, count } => rows(at, count),
+            RowOp::Delete { start, count } => rows(start, count),
+            RowOp::Move { start, count, to } | RowOp::Duplicate { start, count, to } => {
+                rows(start, count)?;
+                rows(to, count)
+                    .map_err(|_| format!("destination {:04X}H is outside the control store", to))
+            }
+        }
+    }
+}
+
+/// branch address rewritten by a row operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    /// address of the branching micro code after the operation.
+    pub addr: usize,
+    pub branch: Branch,
+    pub from: u16,
+    pub to: u16,
+}
+
+pub struct Preview {
+    /// the whole control store after the operation.
+    pub program: Vec<MicroCode>,
+    pub relocations: Vec<Relocation>,
+    /// branches the operation could not keep on their target.
+    pub warnings: Vec<String>,
+}
+
+/// branches whose address field is a micro code address.
+pub fn branches_to_address(branch: Branch) -> bool {
+    matches!(
+        branch,
+        Branch::J | Branch::JM | Branch::JZ | Branch::JC | Branch::JV | Branch::JI | Branch::Rpct
+    )
+}
+
+/// control store after `op` with every branch relocated.
+pub fn preview(program: &[MicroCode], op: RowOp) -> Result<Preview, String> {
+    let len = program.len();
+    op.check(len)?;
+    let mut result = vec![MicroCode::default(); len];
+    // old address of every row in `result`, and whether it is a duplicated copy.
+    let mut origin = vec![None; len];
+    for (addr, micro_code) in program.iter().enumerate() {
+        match op.relocate(addr, len) {
+            Some(new) => {
+                result[new] = *micro_code;
+                origin[new] = Some((addr, false));
+            }
+            None if matches!(op, RowOp::Delete { .. }) => {}
+            None if *micro_code == MicroCode::default() => {}
+            None => {
+                return Err(format!(
+                    "{:04X}H would be pushed out of the control store",
+                    addr
+                ))
+            }
+        }
+    }
+    if let RowOp::Duplicate { start, count, to } = op {
+        for offset in 0..count {
+            result[to + offset] = program[start + offset];
+            origin[to + offset] = Some((start + offset, true));
+        }
+    }
+
+    let mut relocations = vec![];
+    let mut warnings = vec![];
+    for (addr, micro_code) in result.iter_mut().enumerate() {
+        let (old, copy) = match origin[addr] {
+            Some(origin) if branches_to_address(micro_code.branch) => origin,
+            _ => continue,
+        };
+        let from = micro_code.addr;
+        let target = from as usize;
+        let to = match op {
+            // branches inside a duplicated block follow the copy.
+            RowOp::Duplicate { start, count, to }
+                if copy && (start..start + count).contains(&target) =>
+            {
+                Some(to + target - start)
+            }
+            op => op.relocate(target, len),
+        };
+        let to = match to {
+            Some(to) => to as u16,
+            None => {
+                warnings.push(format!(
+                    "{:04X}H: {} target {:04X}H is no longer there",
+                    old,
+                    micro_code.branch.to_string(),
+                    from
+                ));
+                continue;
+            }
+        };
+        if micro_code.branch == Branch::JI && !table_intact(op, target, to as usize, len) {
+            warnings.push(format!(
+                "{:04X}H: JI table at {:04X}H does not move as a whole",
+                old, from
+            ));
+        }
+        if to != from {
+            micro_code.addr = to;
+            relocations.push(Relocation {
+                addr,
+                branch: micro_code.branch,
+                from,
+                to,
+            });
+        }
+    }
+    Ok(Preview {
+        program: result,
+        relocations,
+        warnings,
+    })
+}
+
+/// labels and comments after `op` on a control store of `len` rows. those of deleted
+/// rows are dropped, and duplicated rows are left unlabelled so labels stay unique.
+pub fn relocate_annotations(
+    annotations: &BTreeMap<u16, Annotation>,
+    op: RowOp,
+    len: usize,
+) -> BTreeMap<u16, Annotation> {
+    annotations
+        .iter()
+        .filter_map(|(addr, annotation)| {
+            let new = op.relocate(*addr as usize, len)?;
+            Some((new as u16, annotation.clone()))
+        })
+        .collect()
+}
+
+/// whether every entry of the JI table at `base` lands after the one before it.
+fn table_intact(op: RowOp, base: usize, new_base: usize, len: usize) -> bool {
+    (0..DISPATCH_TABLE)
+        .take_while(|offset| base + offset < len)
+        .all(|offset| op.relocate(base + offset, len) == Some(new_base + offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Register, RegisterOrSwitch};
+
+    fn jump(branch: Branch, addr: u16) -> MicroCode {
+        MicroCode {
+            branch,
+            addr,
+            ..MicroCode::default()
+        }
+    }
+
+    fn marker(register: Register) -> MicroCode {
+        MicroCode {
+            x_bus: RegisterOrSwitch::Register(register),
+            ..MicroCode::default()
+        }
+    }
+
+    /// 0: J 3, 1: R0, 2: R1, 3: JZ 1, 4: LDCT 2, then empty rows.
+    fn program() -> Vec<MicroCode> {
+        let mut program = vec![MicroCode::default(); 16];
+        program[0] = jump(Branch::J, 3);
+        program[1] = marker(Register::R0);
+        program[2] = marker(Register::R1);
+        program[3] = jump(Branch::JZ, 1);
+        program[4] = jump(Branch::Ldct, 2);
+        program
+    }
+
+    #[test]
+    fn insert_relocates_targets_after_it() {
+        let preview = preview(&program(), RowOp::Insert { at: 2, count: 2 }).unwrap();
+        assert_eq!(preview.program[0], jump(Branch::J, 5));
+        assert_eq!(preview.program[1], marker(Register::R0));
+        assert_eq!(preview.program[2], MicroCode::default());
+        assert_eq!(preview.program[4], marker(Register::R1));
+        assert_eq!(preview.program[5], jump(Branch::JZ, 1));
+        // LDCT loads a count, not an address.
+        assert_eq!(preview.program[6], jump(Branch::Ldct, 2));
+        assert_eq!(
+            preview.relocations,
+            vec![Relocation {
+                addr: 0,
+                branch: Branch::J,
+                from: 3,
+                to: 5
+            }]
+        );
+        assert!(preview.warnings.is_empty());
+    }
+
+    #[test]
+    fn delete_warns_about_lost_targets() {
+        let preview = preview(&program(), RowOp::Delete { start: 1, count: 1 }).unwrap();
+        assert_eq!(preview.program[0], jump(Branch::J, 2));
+        assert_eq!(preview.program[1], marker(Register::R1));
+        assert_eq!(preview.program[2], jump(Branch::JZ, 1));
+        assert_eq!(preview.program[15], MicroCode::default());
+        assert_eq!(
+            preview.warnings,
+            vec!["0003H: JZ target 0001H is no longer there"]
+        );
+    }
+
+    #[test]
+    fn move_and_duplicate() {
+        // rows 1 and 2 to the end of the used rows.
+        let moved = preview(
+            &program(),
+            RowOp::Move {
+                start: 1,
+                count: 2,
+                to: 3,
+            },
+        )
+        .unwrap();
+        assert_eq!(moved.program[0], jump(Branch::J, 1));
+        assert_eq!(moved.program[1], jump(Branch::JZ, 3));
+        assert_eq!(moved.program[3], marker(Register::R0));
+        assert_eq!(moved.program[4], marker(Register::R1));
+        for addr in 0..16 {
+            let back = RowOp::Move {
+                start: 3,
+                count: 2,
+                to: 1,
+            };
+            let there = RowOp::Move {
+                start: 1,
+                count: 2,
+                to: 3,
+            }
+            .relocate(addr, 16)
+            .unwrap();
+            assert_eq!(back.relocate(there, 16), Some(addr));
+        }
+
+        // a copy of the loop keeps branching into itself.
+        let duplicated = preview(
+            &program(),
+            RowOp::Duplicate {
+                start: 1,
+                count: 3,
+                to: 8,
+            },
+        )
+        .unwrap();
+        assert_eq!(duplicated.program[3], jump(Branch::JZ, 1));
+        assert_eq!(duplicated.program[8], marker(Register::R0));
+        assert_eq!(duplicated.program[10], jump(Branch::JZ, 8));
+    }
+
+    #[test]
+    fn annotations_follow_their_rows() {
+        let label = |label: &str| Annotation {
+            label: label.to_owned(),
+            comment: String::new(),
+        };
+        let annotations = BTreeMap::from([(1, label("first")), (3, label("test"))]);
+        let moved = relocate_annotations(&annotations, RowOp::Insert { at: 2, count: 2 }, 16);
+        assert_eq!(
+            moved,
+            BTreeMap::from([(1, label("first")), (5, label("test"))])
+        );
+        let deleted = relocate_annotations(&annotations, RowOp::Delete { start: 1, count: 1 }, 16);
+        assert_eq!(deleted, BTreeMap::from([(2, label("test"))]));
+    }
+
+    #[test]
+    fn rejects_lost_rows() {
+        let mut program = program();
+        program[15] = marker(Register::R6);
+        let error = preview(&program, RowOp::Insert { at: 0, count: 1 }).err();
+        assert_eq!(
+            error.as_deref(),
+            Some("000FH would be pushed out of the control store")
+        );
+        let error = preview(
+            &program,
+            RowOp::Delete {
+                start: 10,
+                count: 7,
+            },
+        )
+        .err();
+        assert_eq!(
+            error.as_deref(),
+            Some("rows 000AH..0010H are outside the control store")
+        );
+        assert!(preview(&program, RowOp::Delete { start: 0, count: 0 }).is_err());
+    }
+
+    #[test]
+    fn split_dispatch_table() {
+        let mut program = vec![MicroCode::default(); 0x400];
+        program[0] = jump(Branch::JI, 0x100);
+        let inside = preview(
+            &program,
+            RowOp::Insert {
+                at: 0x180,
+                count: 1,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            inside.warnings,
+            vec!["0000H: JI table at 0100H does not move as a whole"]
+        );
+        let before = preview(&program, RowOp::Insert { at: 0x80, count: 1 }).unwrap();
+        assert!(before.warnings.is_empty());
+        assert_eq!(before.program[0], jump(Branch::JI, 0x101));
+    }
+}
@@ -0,0 +1,285 @@
+//! Control store ROM images.
+//!
+//! The micro program is assembled with `Architecture::encode`, laid out as
+//! `arch::FieldWidths` describes, and written for hardware
+//! tools: raw binary, Intel HEX, Verilog `$readmemh` / `$readmemb` and
+//! Logisim-evolution ROM contents. The word can be split into byte wide chips, chip
+//! 0 holding bits 7..0.
+use crate::arch::Architecture;
+use crate::memory_image::ImageFormat;
+use crate::vm::MicroCode;
+
+/// widest ROM Logisim can hold.
+const LOGISIM_MAX_WIDTH: u32 = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RomFormat {
+    Binary,
+    IntelHex,
+    ReadMemH,
+    ReadMemB,
+    Logisim,
+}
+
+impl RomFormat {
+    pub const ALL: [RomFormat; 5] = [
+        RomFormat::Binary,
+        RomFormat::IntelHex,
+        RomFormat::ReadMemH,
+        RomFormat::ReadMemB,
+        RomFormat::Logisim,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            RomFormat::Binary => "bin",
+            RomFormat::IntelHex => "hex",
+            RomFormat::ReadMemH => "memh",
+            RomFormat::ReadMemB => "memb",
+            RomFormat::Logisim => "rom",
+        }
+    }
+}
+
+impl std::fmt::Display for RomFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RomFormat::Binary => "raw binary",
+            RomFormat::IntelHex => "Intel HEX",
+            RomFormat::ReadMemH => "Verilog $readmemh",
+            RomFormat::ReadMemB => "Verilog $readmemb",
+            RomFormat::Logisim => "Logisim-evolution ROM",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct RomOptions {
+    /// stop after the last micro code differing from `MicroCode::default()`.
+    pub trim: bool,
+    /// one file per 8 bit chip instead of one file of whole words.
+    pub byte_chips: bool,
+}
+
+/// one ROM file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomFile {
+    /// "" for a whole word ROM, "_chip0".. for byte chips.
+    pub suffix: String,
+    /// bits of the micro code word held by this ROM.
+    pub width: u32,
+    pub contents: Vec<u8>,
+}
+
+/// control store words, trimmed as requested.
+pub fn assemble(
+    micro_program: &[MicroCode],
+    arch: &Architecture,
+    trim: bool,
+) -> Result<Vec<u64>, String> {
+    let len = if trim {
+        let default = MicroCode::default();
+        micro_program
+            .iter()
+            .rposition(|micro_code| *micro_code != default)
+            .map_or(0, |last| last + 1)
+    } else {
+        micro_program.len()
+    };
+    micro_program[..len]
+        .iter()
+        .enumerate()
+        .map(|(addr, micro_code)| {
+            arch.encode(micro_code)
+                .map_err(|e| format!("{:04X}H : {}", addr, e))
+        })
+        .collect()
+}
+
+/// write the control store in the format, one file per chip when split.
+pub fn export(
+    micro_program: &[MicroCode],
+    arch: &Architecture,
+    format: RomFormat,
+    options: RomOptions,
+) -> Result<Vec<RomFile>, String> {
+    let words = assemble(micro_program, arch, options.trim)?;
+    let width = arch.word_width();
+    if !options.byte_chips {
+        return Ok(vec![RomFile {
+            suffix: String::new(),
+            width,
+            contents: rom(&words, width, format)?,
+        }]);
+    }
+    (0..width.div_ceil(8))
+        .map(|chip| {
+            let bytes: Vec<u64> = words.iter().map(|word| word >> (chip * 8) & 0xff).collect();
+            Ok(RomFile {
+                suffix: format!("_chip{}", chip),
+                width: 8.min(width - chip * 8),
+                contents: rom(&bytes, 8, format)?,
+            })
+        })
+        .collect()
+}
+
+fn rom(words: &[u64], width: u32, format: RomFormat) -> Result<Vec<u8>, String> {
+    let bytes_per_word = width.div_ceil(8) as usize;
+    let big_endian = || -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|word| word.to_be_bytes()[8 - bytes_per_word..].to_vec())
+            .collect()
+    };
+    Ok(match format {
+        RomFormat::Binary => big_endian(),
+        RomFormat::IntelHex => {
+            let bytes = big_endian();
+            crate::memory_image::export(&bytes, 0..bytes.len(), ImageFormat::IntelHex)?
+        }
+        RomFormat::ReadMemH => read_mem(words, width, 16).into_bytes(),
+        RomFormat::ReadMemB => read_mem(words, width, 2).into_bytes(),
+        RomFormat::Logisim => {
+            if width > LOGISIM_MAX_WIDTH {
+                return Err(format!(
+                    "Logisim ROMs are at most {} bits wide, split the {} bit word into byte chips",
+                    LOGISIM_MAX_WIDTH, width
+                ));
+            }
+            logisim(words).into_bytes()
+        }
+    })
+}
+
+fn read_mem(words: &[u64], width: u32, radix: u32) -> String {
+    let digits = if radix == 16 {
+        width.div_ceil(4)
+    } else {
+        width
+    } as usize;
+    let mut text = format!(
+        "// micro_programming control store, {} words x {} bits\n",
+        words.len(),
+        width
+    );
+    for word in words {
+        text += &match radix {
+            16 => format!("{:0digits$x}\n", word, digits = digits),
+            _ => format!("{:0digits$b}\n", word, digits = digits),
+        };
+    }
+    text
+}
+
+/// "v2.0 raw" image, runs of 4 or more equal words written as `count*word`.
+fn logisim(words: &[u64]) -> String {
+    let mut items = vec![];
+    let mut rest = words;
+    while let Some(first) = rest.first() {
+        let run = rest.iter().take_while(|word| *word == first).count();
+        if run >= 4 {
+            items.push(format!("{}*{:x}", run, first));
+        } else {
+            items.extend(rest[..run].iter().map(|word| format!("{:x}", word)));
+        }
+        rest = &rest[run..];
+    }
+    let lines: Vec<String> = items.chunks(8).map(|line| line.join(" ")).collect();
+    format!("v2.0 raw\n{}\n", lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Assemble, Branch};
+
+    fn program() -> Vec<MicroCode> {
+        let mut program = vec![MicroCode::default(); 16];
+        program[0].branch = Branch::J;
+        program[0].addr = 0x1234;
+        program[1].hlt = true;
+        program[2].addr = 0xffff;
+        program
+    }
+
+    fn text(file: &RomFile) -> String {
+        String::from_utf8(file.contents.clone()).unwrap()
+    }
+
+    #[test]
+    fn assemble_and_trim() {
+        let arch = Architecture::default();
+        let words = assemble(&program(), &arch, false).unwrap();
+        assert_eq!(words.len(), 16);
+        assert_eq!(words[0], program()[0].assemble());
+        assert_eq!(assemble(&program(), &arch, true).unwrap().len(), 3);
+        assert!(assemble(&[MicroCode::default(); 4], &arch, true)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn read_mem_files() {
+        let arch = Architecture::default();
+        let options = RomOptions {
+            trim: true,
+            byte_chips: false,
+        };
+        let memh = export(&program(), &arch, RomFormat::ReadMemH, options).unwrap();
+        let lines: Vec<String> = text(&memh[0]).lines().map(str::to_owned).collect();
+        assert!(lines[0].starts_with("//"));
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], format!("{:011x}", program()[0].assemble()));
+        let memb = export(&program(), &arch, RomFormat::ReadMemB, options).unwrap();
+        let line = text(&memb[0]).lines().nth(2).unwrap().to_owned();
+        assert_eq!(line.len(), 43);
+        assert_eq!(u64::from_str_radix(&line, 2).unwrap(), 1 << 16);
+    }
+
+    #[test]
+    fn byte_chips_recombine() {
+        let arch = Architecture::default();
+        let options = RomOptions {
+            trim: false,
+            byte_chips: true,
+        };
+        let chips = export(&program(), &arch, RomFormat::Binary, options).unwrap();
+        assert_eq!(chips.len(), 6);
+        assert_eq!(chips[5].width, 3);
+        assert_eq!(chips[2].suffix, "_chip2");
+        for (addr, micro_code) in program().iter().enumerate() {
+            let word = chips.iter().enumerate().fold(0, |word, (i, chip)| {
+                word | (chip.contents[addr] as u64) << (i * 8)
+            });
+            assert_eq!(word, micro_code.assemble());
+        }
+    }
+
+    #[test]
+    fn binary_and_intel_hex_are_big_endian() {
+        let arch = Architecture::default();
+        let options = RomOptions::default();
+        let binary = export(&program(), &arch, RomFormat::Binary, options).unwrap();
+        assert_eq!(binary[0].contents.len(), 16 * 6);
+        let word = program()[0].assemble();
+        assert_eq!(binary[0].contents[..6], word.to_be_bytes()[2..]);
+        let hex = export(&program(), &arch, RomFormat::IntelHex, options).unwrap();
+        let segments = crate::memory_image::parse(&hex[0].contents, ImageFormat::IntelHex).unwrap();
+        assert_eq!(segments[0].bytes, binary[0].contents);
+    }
+
+    #[test]
+    fn logisim_needs_narrow_words() {
+        let arch = Architecture::default();
+        assert!(export(&program(), &arch, RomFormat::Logisim, RomOptions::default()).is_err());
+        let options = RomOptions {
+            trim: false,
+            byte_chips: true,
+        };
+        let chips = export(&program(), &arch, RomFormat::Logisim, options).unwrap();
+        let chip0 = text(&chips[0]);
+        assert!(chip0.starts_with("v2.0 raw\n"));
+        assert_eq!(chip0.lines().nth(1).unwrap(), "34 0 ff 13*0");
+    }
+}
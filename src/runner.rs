@@ -0,0 +1,255 @@
+//! Headless runs for scripts and CI.
+//!
+//! `run` executes a machine until HLT, a cycle limit or a fault and `Report` is the
+//! final state in a stable shape for text or JSON output. A fault is anything
+//! `MicroArch::fault` reports: an op the architecture lacks, or the sequencer
+//! leaving the control store or a memory access past main memory, which only
+//! happens with a truncated control store or memory, never with a loaded project.
+use crate::engine::Engine;
+use crate::vm::MicroArch;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// micro codes between two looks at the cancel flag.
+const SLICE: u64 = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Halted,
+    CycleLimit,
+    Fault(String),
+}
+
+impl Stop {
+    /// process exit code, 0 only for HLT.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Stop::Halted => 0,
+            Stop::Fault(_) => 2,
+            Stop::CycleLimit => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// micro codes executed.
+    pub cycles: u64,
+    pub stop: Stop,
+}
+
+/// run from the current state until HLT, `max_cycles` micro codes or a fault.
+pub fn run(vm: &mut MicroArch, max_cycles: u64) -> Outcome {
+    run_cancellable(vm, max_cycles, &AtomicBool::new(false))
+}
+
+/// `run`, also stopping as at the cycle limit within `SLICE` micro codes of
+/// `cancel` being set.
+pub fn run_cancellable(vm: &mut MicroArch, max_cycles: u64, cancel: &AtomicBool) -> Outcome {
+    let mut engine = Engine::new();
+    let mut cycles = 0;
+    let result = loop {
+        let result = engine.run(vm, (max_cycles - cycles).min(SLICE));
+        cycles += result.cycles;
+        if result.halted || result.faulted || cycles == max_cycles || cancel.load(Ordering::Relaxed)
+        {
+            break result;
+        }
+    };
+    let stop = match vm.fault() {
+        Some(fault) if result.faulted => Stop::Fault(fault),
+        _ if result.halted => Stop::Halted,
+        _ => Stop::CycleLimit,
+    };
+    Outcome { cycles, stop }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Registers {
+    pub r0: u8,
+    pub r1: u8,
+    pub r2: u8,
+    pub r3: u8,
+    pub r4: u8,
+    pub r5: u8,
+    pub r6: u8,
+    pub pc: u8,
+    pub ir: u8,
+    pub mdr: u8,
+    pub mar: u8,
+    pub str: u8,
+    pub cnt: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Flags {
+    pub minus: bool,
+    pub zero: bool,
+    pub carry: bool,
+    pub overflow: bool,
+}
+
+/// final state of a run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    /// "halted", "cycle limit" or "fault".
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fault: Option<String>,
+    pub cycles: u64,
+    pub micro_program_counter: u16,
+    pub registers: Registers,
+    pub flags: Flags,
+    pub sw1: u8,
+    pub sw2: u8,
+    pub memory: Vec<u8>,
+}
+
+impl Report {
+    pub fn new(vm: &MicroArch, outcome: &Outcome) -> Self {
+        let flags = vm.arch.flags;
+        Self {
+            status: match outcome.stop {
+                Stop::Halted => "halted",
+                Stop::CycleLimit => "cycle limit",
+                Stop::Fault(_) => "fault",
+            }
+            .to_owned(),
+            fault: match &outcome.stop {
+                Stop::Fault(fault) => Some(fault.clone()),
+                _ => None,
+            },
+            cycles: outcome.cycles,
+            micro_program_counter: vm.micro_program_counter,
+            registers: Registers {
+                r0: vm.gpr[0],
+                r1: vm.gpr[1],
+                r2: vm.gpr[2],
+                r3: vm.gpr[3],
+                r4: vm.gpr[4],
+                r5: vm.gpr[5],
+                r6: vm.gpr[6],
+                pc: vm.pc,
+                ir: vm.ir,
+                mdr: vm.mdr,
+                mar: vm.mar,
+                str: vm.str,
+                cnt: vm.counter,
+            },
+            flags: Flags {
+                minus: vm.flag(flags.minus),
+                zero: vm.flag(flags.zero),
+                carry: vm.flag(flags.carry),
+                overflow: vm.flag(flags.overflow),
+            },
+            sw1: vm.sw1,
+            sw2: vm.sw2,
+            memory: vm.memory.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report serializes")
+    }
+
+    pub fn to_text(&self) -> String {
+        let r = &self.registers;
+        let mut text = format!("status  {}\n", self.status);
+        if let Some(fault) = &self.fault {
+            text += &format!("fault   {}\n", fault);
+        }
+        text += &format!("cycles  {}\n", self.cycles);
+        text += &format!("uPC     {:04X}H\n", self.micro_program_counter);
+        text += &format!(
+            "R0-R6   {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X}\n",
+            r.r0, r.r1, r.r2, r.r3, r.r4, r.r5, r.r6
+        );
+        text += &format!(
+            "PC {:02X}  IR {:02X}  MDR {:02X}  MAR {:02X}  STR {:02X}  CNT {:04X}\n",
+            r.pc, r.ir, r.mdr, r.mar, r.str, r.cnt
+        );
+        let f = &self.flags;
+        text += &format!(
+            "flags   M={} Z={} C={} V={}\n",
+            f.minus as u8, f.zero as u8, f.carry as u8, f.overflow as u8
+        );
+        text += &format!("SW1 {:02X}  SW2 {:02X}\n", self.sw1, self.sw2);
+        text += "memory\n";
+        for line in crate::project::dump_memory(&self.memory) {
+            text += &line;
+            text += "\n";
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::{project, SAMPLES};
+    use crate::vm::MicroCode;
+
+    #[test]
+    fn runs_to_halt() {
+        let mut vm = project(&SAMPLES[1]);
+        vm.sw1 = 6;
+        vm.sw2 = 7;
+        let outcome = run(&mut vm, 100_000);
+        assert_eq!(outcome.stop, Stop::Halted);
+        assert_eq!(outcome.stop.exit_code(), 0);
+        let report = Report::new(&vm, &outcome);
+        assert_eq!(report.memory[0x82], 42);
+        assert_eq!(report.status, "halted");
+        assert!(report.to_text().contains("status  halted\n"));
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["registers"]["r0"], 42);
+        assert_eq!(json["cycles"], outcome.cycles);
+        assert!(json.get("fault").is_none());
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let mut vm = project(&SAMPLES[1]);
+        let outcome = run(&mut vm, 10);
+        assert_eq!(outcome.cycles, 10);
+        assert_eq!(outcome.stop, Stop::CycleLimit);
+        assert_eq!(outcome.stop.exit_code(), 3);
+    }
+
+    #[test]
+    fn cancel_stops_an_endless_run() {
+        let mut vm = project(&SAMPLES[1]);
+        // +1/Nop everywhere, the sequencer wraps around forever.
+        vm.micro_program = vec![MicroCode::default(); vm.micro_program.len()];
+        let outcome = run(&mut vm.clone(), 3 * SLICE + 5);
+        assert_eq!(outcome.cycles, 3 * SLICE + 5);
+        assert_eq!(outcome.stop, Stop::CycleLimit);
+        let outcome = run_cancellable(&mut vm, u64::MAX, &AtomicBool::new(true));
+        assert_eq!(outcome.cycles, SLICE);
+        assert_eq!(outcome.stop, Stop::CycleLimit);
+    }
+
+    #[test]
+    fn truncated_machine_faults() {
+        let mut vm = project(&SAMPLES[1]);
+        vm.sw1 = 6;
+        vm.sw2 = 7;
+        let mut reference = vm.clone();
+        let expected = run(&mut reference, 100_000);
+        // the same run fits in a truncated control store.
+        vm.micro_program.truncate(0x400);
+        assert_eq!(run(&mut vm.clone(), 100_000), expected);
+
+        vm.micro_program.truncate(2);
+        let outcome = run(&mut vm, 100_000);
+        assert_eq!(outcome.cycles, 2);
+        assert!(matches!(outcome.stop, Stop::Fault(ref fault) if fault.contains("0002H")));
+        assert_eq!(outcome.stop.exit_code(), 2);
+
+        let mut vm = project(&SAMPLES[1]);
+        vm.memory.truncate(0x10);
+        vm.micro_program.truncate(0x400);
+        let outcome = run(&mut vm, 100_000);
+        assert!(matches!(outcome.stop, Stop::Fault(ref fault) if fault.contains("MAR")));
+    }
+}
@@ -0,0 +1,341 @@
+//! Self-checking Verilog testbenches from recorded traces.
+//!
+//! `record` runs a copy of the machine from reset and keeps the register file and
+//! the memory writes after every micro code, up to HLT, a fault or a cycle limit.
+//! `generate` turns the trace into a testbench for any design with the port list
+//! of the `<prefix>_cpu` module from `verilog`: it models main memory, resets the
+//! design, and compares every register and memory write after each clock. Run it
+//! with Icarus Verilog:
+//!
+//! ```text
+//! iverilog -o tb design.v design_tb.v && vvp tb
+//! ```
+use crate::vm::{MicroArch, Register, REGISTER_FILE};
+use std::fmt::Write;
+
+/// machine state after one micro code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub upc: u16,
+    pub hlt: bool,
+    /// values in `vm::REGISTER_FILE` order.
+    pub registers: Vec<u16>,
+    /// (address, value) stored to main memory by this micro code.
+    pub writes: Vec<(u8, u8)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub sw1: u8,
+    pub sw2: u8,
+    /// main memory before the first micro code.
+    pub memory: Vec<u8>,
+    /// state right after reset.
+    pub reset: Step,
+    pub steps: Vec<Step>,
+    /// `MicroArch::fault` that stopped the run after the last step.
+    pub fault: Option<String>,
+}
+
+fn snapshot(vm: &MicroArch, writes: Vec<(u8, u8)>) -> Step {
+    Step {
+        upc: vm.micro_program_counter,
+        hlt: vm.hlt,
+        registers: REGISTER_FILE
+            .iter()
+            .map(|(register, _, _)| match register {
+                Register::Cnt => vm.counter,
+                register => vm.load_register(*register) as u16,
+            })
+            .collect(),
+        writes,
+    }
+}
+
+/// run a copy of `vm` from reset, keeping its switches and memory, until HLT, a
+/// fault or `max_cycles` micro codes.
+pub fn record(vm: &MicroArch, max_cycles: usize) -> Trace {
+    let mut vm = vm.clone();
+    let (sw1, sw2) = (vm.sw1, vm.sw2);
+    vm.reset_register();
+    // the hardware reset clears MAR too.
+    vm.mar = 0;
+    vm.sw1 = sw1;
+    vm.sw2 = sw2;
+    let memory = vm.memory.clone();
+    let reset = snapshot(&vm, vec![]);
+    let mut steps = vec![];
+    let mut fault = None;
+    while !vm.hlt && steps.len() < max_cycles {
+        fault = vm.fault();
+        if fault.is_some() {
+            break;
+        }
+        let before = vm.memory.clone();
+        vm.exec();
+        let writes = vm
+            .memory
+            .iter()
+            .zip(&before)
+            .enumerate()
+            .filter(|(_, (after, before))| after != before)
+            .map(|(addr, (after, _))| (addr as u8, *after))
+            .collect();
+        steps.push(snapshot(&vm, writes));
+    }
+    Trace {
+        sw1,
+        sw2,
+        memory,
+        reset,
+        steps,
+        fault,
+    }
+}
+
+impl Trace {
+    /// stopped by HLT rather than by the cycle limit.
+    pub fn halted(&self) -> bool {
+        self.steps.last().map_or(self.reset.hlt, |step| step.hlt)
+    }
+}
+
+fn check(v: &mut String, cycle: usize, step: &Step) {
+    let values: Vec<String> = REGISTER_FILE
+        .iter()
+        .zip(&step.registers)
+        .map(|((_, _, bits), value)| format!("{}'h{:x}", bits, value))
+        .collect();
+    let _ = writeln!(
+        v,
+        "        check({}, 16'h{:x}, 1'b{}, {});",
+        cycle,
+        step.upc,
+        step.hlt as u8,
+        values.join(", ")
+    );
+    for (addr, value) in &step.writes {
+        let _ = writeln!(
+            v,
+            "        check_memory({}, 8'h{:x}, 8'h{:x});",
+            cycle, addr, value
+        );
+    }
+}
+
+/// Verilog testbench `<prefix>_tb` checking a `<prefix>_cpu` against the trace.
+pub fn generate(trace: &Trace, prefix: &str) -> String {
+    let mut v = String::new();
+    let _ = writeln!(
+        v,
+        "// generated by micro_programming. {} micro codes, {}.",
+        trace.steps.len(),
+        match &trace.fault {
+            _ if trace.halted() => "ends with HLT".to_owned(),
+            Some(fault) => format!("stopped by a fault: {}", fault),
+            None => "cut at the cycle limit".to_owned(),
+        }
+    );
+    let _ = writeln!(v, "`timescale 1ns / 1ps");
+    let _ = writeln!(v, "`default_nettype none\n");
+    let _ = writeln!(v, "module {}_tb;", prefix);
+    let _ = writeln!(v, "    reg clk = 1'b0;");
+    let _ = writeln!(v, "    reg reset = 1'b0;");
+    let _ = writeln!(v, "    reg start = 1'b0;");
+    let _ = writeln!(v, "    reg [7:0] sw1 = 8'h{:x};", trace.sw1);
+    let _ = writeln!(v, "    reg [7:0] sw2 = 8'h{:x};", trace.sw2);
+    let _ = writeln!(v, "    wire [7:0] mem_addr, mem_wdata, mem_rdata;");
+    let _ = writeln!(v, "    wire mem_we, hlt;");
+    let _ = writeln!(v, "    wire [15:0] upc;");
+    for (_, name, bits) in REGISTER_FILE {
+        let _ = writeln!(v, "    wire [{}:0] {};", bits - 1, name);
+    }
+    let _ = writeln!(v, "\n    // main memory model, read combinationally");
+    let _ = writeln!(
+        v,
+        "    reg [7:0] memory [0:{}];",
+        trace.memory.len().max(1) - 1
+    );
+    let _ = writeln!(v, "    assign mem_rdata = memory[mem_addr];");
+    let _ = writeln!(
+        v,
+        "    always @(posedge clk) if (mem_we) memory[mem_addr] <= mem_wdata;\n"
+    );
+    let ports: Vec<String> = REGISTER_FILE
+        .iter()
+        .map(|(_, name, _)| format!(".{}({})", name, name))
+        .collect();
+    let _ = writeln!(v, "    {}_cpu dut (", prefix);
+    let _ = writeln!(
+        v,
+        "        .clk(clk), .reset(reset), .start(start), .sw1(sw1), .sw2(sw2),"
+    );
+    let _ = writeln!(v, "        .mem_addr(mem_addr), .mem_wdata(mem_wdata), .mem_we(mem_we), .mem_rdata(mem_rdata),");
+    let _ = writeln!(v, "        .hlt(hlt), .upc(upc), {}", ports.join(", "));
+    let _ = writeln!(v, "    );\n");
+    let _ = writeln!(v, "    always #5 clk = ~clk;\n");
+
+    let _ = writeln!(v, "    integer errors = 0;");
+    let _ = writeln!(v, "    task report(input integer cycle, input [8*8-1:0] name, input [15:0] actual, input [15:0] expected);");
+    let _ = writeln!(v, "        begin");
+    let _ = writeln!(v, "            $display(\"cycle %0d: %0s is %h, expected %h\", cycle, name, actual, expected);");
+    let _ = writeln!(v, "            errors = errors + 1;");
+    let _ = writeln!(v, "        end");
+    let _ = writeln!(v, "    endtask");
+    let inputs: Vec<String> = REGISTER_FILE
+        .iter()
+        .map(|(_, name, bits)| format!("input [{}:0] e_{}", bits - 1, name))
+        .collect();
+    let _ = writeln!(
+        v,
+        "    task check(input integer cycle, input [15:0] e_upc, input e_hlt, {});",
+        inputs.join(", ")
+    );
+    let _ = writeln!(v, "        begin");
+    let _ = writeln!(
+        v,
+        "            if (upc !== e_upc) report(cycle, \"upc\", upc, e_upc);"
+    );
+    let _ = writeln!(
+        v,
+        "            if (hlt !== e_hlt) report(cycle, \"hlt\", hlt, e_hlt);"
+    );
+    for (_, name, _) in REGISTER_FILE {
+        let _ = writeln!(
+            v,
+            "            if ({0} !== e_{0}) report(cycle, \"{0}\", {0}, e_{0});",
+            name
+        );
+    }
+    let _ = writeln!(v, "        end");
+    let _ = writeln!(v, "    endtask");
+    let _ = writeln!(
+        v,
+        "    task check_memory(input integer cycle, input [7:0] addr, input [7:0] value);"
+    );
+    let _ = writeln!(v, "        if (memory[addr] !== value) begin");
+    let _ = writeln!(v, "            $display(\"cycle %0d: memory[%h] is %h, expected %h\", cycle, addr, memory[addr], value);");
+    let _ = writeln!(v, "            errors = errors + 1;");
+    let _ = writeln!(v, "        end");
+    let _ = writeln!(v, "    endtask\n");
+
+    let _ = writeln!(v, "    integer i;");
+    let _ = writeln!(v, "    initial begin");
+    let _ = writeln!(
+        v,
+        "        for (i = 0; i < {}; i = i + 1) memory[i] = 8'h00;",
+        trace.memory.len()
+    );
+    for (addr, byte) in trace.memory.iter().enumerate() {
+        if *byte != 0 {
+            let _ = writeln!(v, "        memory[{}] = 8'h{:x};", addr, byte);
+        }
+    }
+    let _ = writeln!(v, "        reset = 1'b1;");
+    let _ = writeln!(v, "        @(posedge clk);");
+    let _ = writeln!(v, "        #1 reset = 1'b0;");
+    check(&mut v, 0, &trace.reset);
+    for (cycle, step) in trace.steps.iter().enumerate() {
+        let _ = writeln!(v, "        @(posedge clk); #1;");
+        check(&mut v, cycle + 1, step);
+    }
+    let _ = writeln!(
+        v,
+        "        if (errors == 0) $display(\"PASS {} cycles\");",
+        trace.steps.len()
+    );
+    let _ = writeln!(v, "        else $display(\"FAIL %0d mismatches\", errors);");
+    let _ = writeln!(v, "        $finish;");
+    let _ = writeln!(v, "    end");
+    let _ = writeln!(v, "endmodule");
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::{project, SAMPLES};
+
+    fn multiply() -> MicroArch {
+        let mut vm = project(&SAMPLES[1]);
+        vm.sw1 = 6;
+        vm.sw2 = 7;
+        vm.gpr[3] = 0x55;
+        vm
+    }
+
+    #[test]
+    fn record_runs_from_reset_to_halt() {
+        let vm = multiply();
+        let trace = record(&vm, 100_000);
+        assert!(trace.halted());
+        assert_eq!(trace.reset.upc, 0);
+        assert!(trace.reset.registers.iter().all(|value| *value == 0));
+        assert_eq!([trace.sw1, trace.sw2], [6, 7]);
+        // the original machine is untouched.
+        assert_eq!(vm.gpr[3], 0x55);
+
+        let mut replay = vm.clone();
+        replay.reset_register();
+        replay.mar = 0;
+        replay.sw1 = 6;
+        replay.sw2 = 7;
+        for step in &trace.steps {
+            replay.exec();
+            assert_eq!(step.upc, replay.micro_program_counter);
+            assert_eq!(step.registers[0], replay.gpr[0] as u16);
+        }
+        let writes: Vec<(u8, u8)> = trace.steps.iter().flat_map(|s| s.writes.clone()).collect();
+        assert!(writes.contains(&(0x82, 42)), "{:?}", writes);
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let trace = record(&multiply(), 10);
+        assert_eq!(trace.steps.len(), 10);
+        assert!(!trace.halted());
+        assert!(trace.fault.is_none());
+        assert!(generate(&trace, "m").contains("cut at the cycle limit"));
+    }
+
+    #[test]
+    fn fault_stops_recording() {
+        let mut vm = multiply();
+        // fetch runs off a 4 word control store.
+        vm.micro_program.truncate(4);
+        let trace = record(&vm, 100);
+        assert!(!trace.halted());
+        assert!(trace.steps.len() < 100);
+        let fault = trace.fault.clone().unwrap();
+        assert!(
+            fault.contains("outside the 4 word control store"),
+            "{}",
+            fault
+        );
+        let v = generate(&trace, "m");
+        assert!(v.contains(&format!("stopped by a fault: {}", fault)));
+        assert!(!v.contains("cut at the cycle limit"));
+        assert!(v.contains(&format!("PASS {} cycles", trace.steps.len())));
+    }
+
+    #[test]
+    fn testbench_checks_every_cycle() {
+        let trace = record(&multiply(), 100_000);
+        let v = generate(&trace, "kue");
+        assert!(v.contains("module kue_tb;"));
+        assert!(v.contains("kue_cpu dut ("));
+        assert_eq!(v.matches("        check(").count(), trace.steps.len() + 1);
+        assert_eq!(
+            v.matches("        check_memory(").count(),
+            trace.steps.iter().map(|s| s.writes.len()).sum::<usize>()
+        );
+        assert!(v.contains(&format!("PASS {} cycles", trace.steps.len())));
+        let last = trace.steps.last().unwrap();
+        assert!(v.contains(&format!(
+            "check({}, 16'h{:x}, 1'b1, 8'h{:x},",
+            trace.steps.len(),
+            last.upc,
+            last.registers[0]
+        )));
+    }
+}
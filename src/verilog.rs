@@ -0,0 +1,632 @@
+//! Verilog generator.
+//!
+//! Emits a synthesizable Verilog-2001 model of `MicroArch::exec` for the current
+//! architecture: register file, X/Y bus multiplexers, ALU, shifter, flags, memory
+//! interface and sequencer, with the control store initialised from the micro
+//! program. One clock edge executes one micro code, in the same order as `exec`:
+//! flags, shifter, Z bus write, memory, then branch.
+//!
+//! Three modules are generated, `<prefix>_cpu`, `<prefix>_memory` (asynchronous
+//! read, synchronous write, initialised from main memory) and `<prefix>_system`
+//! connecting them.
+use crate::arch::Architecture;
+use crate::vm::{
+    AluOp, Branch, MemOp, MicroArch, MicroCode, Register, RegisterOrSwitch, ShiftOp, REGISTER_FILE,
+};
+use std::fmt::Write;
+
+/// `[hi:lo]` of each micro code field, None for zero width fields.
+struct Fields {
+    x_bus: Option<(u32, u32)>,
+    y_bus: Option<(u32, u32)>,
+    alu: Option<(u32, u32)>,
+    sft: Option<(u32, u32)>,
+    sin: Option<(u32, u32)>,
+    fl: Option<(u32, u32)>,
+    z_bus: Option<(u32, u32)>,
+    mem: Option<(u32, u32)>,
+    branch: Option<(u32, u32)>,
+    hlt: Option<(u32, u32)>,
+    addr: Option<(u32, u32)>,
+}
+
+impl Fields {
+    fn new(arch: &Architecture) -> Self {
+        let f = &arch.fields;
+        let mut offset = 0;
+        // packed low to high as in `Architecture::encode`.
+        let mut next = |width: u32| {
+            let field = (width > 0).then(|| (offset + width - 1, offset));
+            offset += width;
+            field
+        };
+        let addr = next(f.addr);
+        let hlt = next(f.hlt);
+        let branch = next(f.branch);
+        let mem = next(f.mem);
+        let z_bus = next(f.z_bus);
+        let fl = next(f.fl);
+        let sin = next(f.sin);
+        let sft = next(f.sft);
+        let alu = next(f.alu);
+        let y_bus = next(f.y_bus);
+        let x_bus = next(f.x_bus);
+        Self {
+            x_bus,
+            y_bus,
+            alu,
+            sft,
+            sin,
+            fl,
+            z_bus,
+            mem,
+            branch,
+            hlt,
+            addr,
+        }
+    }
+}
+
+fn port(register: Register) -> &'static str {
+    REGISTER_FILE
+        .iter()
+        .find(|(r, _, _)| *r == register)
+        .map_or("8'h00", |(_, name, _)| name)
+}
+
+fn field_wire(name: &str, field: Option<(u32, u32)>) -> String {
+    match field {
+        Some((hi, lo)) => format!(
+            "    wire [{}:0] f_{} = word[{}:{}];\n",
+            hi - lo,
+            name,
+            hi,
+            lo
+        ),
+        None => format!("    wire [0:0] f_{} = 1'b0;\n", name),
+    }
+}
+
+fn hex(width: u32, value: u64) -> String {
+    format!("{}'h{:x}", width, value)
+}
+
+/// `case` arm label for an operation, None when the architecture lacks it.
+fn code_of<T: PartialEq>(ops: &[crate::arch::OpSpec<T>], op: T, width: u32) -> Option<String> {
+    ops.iter()
+        .find(|spec| spec.op == op)
+        .map(|spec| hex(width.max(1), spec.code))
+}
+
+fn bus_mux(text: &mut String, bus: &str, field: &str, width: u32, arch: &Architecture) {
+    let _ = writeln!(text, "        case (f_{})", field);
+    for spec in arch.registers.iter().filter(|r| r.source) {
+        if spec.register != Register::Nop {
+            let value = match spec.register {
+                Register::Cnt => "cnt[7:0]",
+                register => port(register),
+            };
+            let _ = writeln!(
+                text,
+                "            {}: {} = {}; // {}",
+                hex(width.max(1), spec.code),
+                bus,
+                value,
+                spec.name
+            );
+        }
+    }
+    for spec in &arch.switches {
+        let value = match spec.switch {
+            RegisterOrSwitch::Sw1 => "sw1",
+            RegisterOrSwitch::Sw2 => "sw2",
+            RegisterOrSwitch::Register(register) => port(register),
+        };
+        let _ = writeln!(
+            text,
+            "            {}: {} = {}; // {}",
+            hex(width.max(1), spec.code),
+            bus,
+            value,
+            spec.name
+        );
+    }
+    let _ = writeln!(text, "            default: {} = 8'h00;", bus);
+    let _ = writeln!(text, "        endcase");
+}
+
+/// Verilog source of the machine. fails when a micro code cannot be encoded.
+pub fn generate(vm: &MicroArch, prefix: &str) -> Result<String, String> {
+    let arch = &vm.arch;
+    let width = arch.word_width();
+    let fields = Fields::new(arch);
+    let f = &arch.fields;
+    let flags = arch.flags;
+    let default_word = arch
+        .encode(&MicroCode::default())
+        .map_err(|e| format!("default micro code : {}", e))?;
+    let words = crate::rom_image::assemble(&vm.micro_program, arch, false)?;
+    let alu = |op| code_of(&arch.alu_ops, op, f.alu);
+    let sft = |op| code_of(&arch.shift_ops, op, f.sft);
+    let mem = |op| code_of(&arch.mem_ops, op, f.mem);
+    let branch = |op| code_of(&arch.branches, op, f.branch);
+
+    let mut v = String::new();
+    let _ = writeln!(
+        v,
+        "// generated by micro_programming from architecture \"{}\".",
+        arch.name
+    );
+    let _ = writeln!(v, "`default_nettype none\n");
+
+    // cpu
+    let _ = writeln!(v, "module {}_cpu (", prefix);
+    let _ = writeln!(v, "    input  wire        clk,");
+    let _ = writeln!(
+        v,
+        "    input  wire        reset, // clears registers as \"Reset registers\", and MAR"
+    );
+    let _ = writeln!(
+        v,
+        "    input  wire        start, // clears hlt as \"Wakeup\""
+    );
+    let _ = writeln!(v, "    input  wire [7:0]  sw1,");
+    let _ = writeln!(v, "    input  wire [7:0]  sw2,");
+    let _ = writeln!(v, "    output reg  [7:0]  mem_addr,");
+    let _ = writeln!(v, "    output reg  [7:0]  mem_wdata,");
+    let _ = writeln!(v, "    output wire        mem_we,");
+    let _ = writeln!(
+        v,
+        "    input  wire [7:0]  mem_rdata, // mem_addr read combinationally"
+    );
+    let _ = writeln!(v, "    output reg         hlt,");
+    let _ = writeln!(v, "    output reg  [15:0] upc,");
+    for (i, (_, name, bits)) in REGISTER_FILE.iter().enumerate() {
+        let _ = writeln!(
+            v,
+            "    output reg  {:<6} {}{}",
+            format!("[{}:0]", bits - 1),
+            name,
+            if i + 1 == REGISTER_FILE.len() {
+                ""
+            } else {
+                ","
+            }
+        );
+    }
+    let _ = writeln!(v, ");");
+    let _ = writeln!(v, "    localparam WORDS = {};", vm.micro_program.len());
+    let _ = writeln!(v, "    reg [{}:0] control_store [0:WORDS-1];", width - 1);
+    let _ = writeln!(v, "    integer i;");
+    let _ = writeln!(v, "    initial begin");
+    let _ = writeln!(
+        v,
+        "        for (i = 0; i < WORDS; i = i + 1) control_store[i] = {};",
+        hex(width, default_word)
+    );
+    for (addr, word) in words.iter().enumerate() {
+        if *word != default_word {
+            let _ = writeln!(
+                v,
+                "        control_store[{}] = {};",
+                hex(16, addr as u64),
+                hex(width, *word)
+            );
+        }
+    }
+    let _ = writeln!(v, "        hlt = 1'b{};", vm.hlt as u8);
+    let _ = writeln!(
+        v,
+        "        upc = {};",
+        hex(16, vm.micro_program_counter as u64)
+    );
+    for (register, name, bits) in REGISTER_FILE {
+        let value = match register {
+            Register::Cnt => vm.counter as u64,
+            register => vm.load_register(register) as u64,
+        };
+        let _ = writeln!(v, "        {} = {};", name, hex(bits, value));
+    }
+    let _ = writeln!(v, "    end\n");
+
+    let _ = writeln!(v, "    wire [{}:0] word = control_store[upc];", width - 1);
+    for (name, field) in [
+        ("x_bus", fields.x_bus),
+        ("y_bus", fields.y_bus),
+        ("alu", fields.alu),
+        ("sft", fields.sft),
+        ("sin", fields.sin),
+        ("fl", fields.fl),
+        ("z_bus", fields.z_bus),
+        ("mem", fields.mem),
+        ("branch", fields.branch),
+        ("hlt", fields.hlt),
+        ("addr", fields.addr),
+    ] {
+        v += &field_wire(name, field);
+    }
+    let _ = writeln!(v);
+    let _ = writeln!(v, "    reg [7:0] x_bus, y_bus, alu_out, z_bus;");
+    let _ = writeln!(v, "    reg [8:0] sum;");
+    let _ = writeln!(v, "    reg cf, we;");
+    let _ = writeln!(v, "    reg [7:0] str_fl;");
+    for (_, name, bits) in REGISTER_FILE {
+        let _ = writeln!(v, "    reg [{}:0] {}_n;", bits - 1, name);
+    }
+    let _ = writeln!(v, "    reg [15:0] upc_n, next;\n");
+    let _ = writeln!(v, "    always @* begin");
+    let _ = writeln!(v, "        // X and Y bus");
+    bus_mux(&mut v, "x_bus", "x_bus", f.x_bus, arch);
+    bus_mux(&mut v, "y_bus", "y_bus", f.y_bus, arch);
+
+    let _ = writeln!(v, "        // ALU");
+    let _ = writeln!(v, "        case (f_alu)");
+    for (op, expression) in [
+        (AluOp::XPlusY, "x_bus + y_bus"),
+        (AluOp::XMinusY, "x_bus - y_bus"),
+        (AluOp::XAndY, "x_bus & y_bus"),
+        (AluOp::XorY, "x_bus | y_bus"),
+        (AluOp::XxorY, "x_bus ^ y_bus"),
+        (AluOp::XPlus1, "x_bus + 8'h01"),
+        (AluOp::XMinus1, "x_bus - 8'h01"),
+    ] {
+        if let Some(code) = alu(op) {
+            let _ = writeln!(v, "            {}: alu_out = {};", code, expression);
+        }
+    }
+    let _ = writeln!(v, "            default: alu_out = 8'h00;");
+    let _ = writeln!(v, "        endcase\n");
+
+    let _ = writeln!(
+        v,
+        "        // flags, carry and overflow come from X + Y whatever the operation"
+    );
+    let _ = writeln!(v, "        sum = {{1'b0, x_bus}} + {{1'b0, y_bus}};");
+    let _ = writeln!(v, "        str_fl = str;");
+    let _ = writeln!(v, "        if (f_fl) begin");
+    let _ = writeln!(v, "            str_fl[{}] = alu_out[7];", flags.minus);
+    let _ = writeln!(v, "            str_fl[{}] = alu_out == 8'h00;", flags.zero);
+    let _ = writeln!(v, "            str_fl[{}] = sum[8];", flags.carry);
+    let _ = writeln!(v, "            str_fl[{}] = sum > 9'd127;", flags.overflow);
+    let _ = writeln!(v, "        end");
+    let _ = writeln!(v, "        cf = str_fl[{}];\n", flags.carry);
+
+    let _ = writeln!(v, "        // shifter, the shifted out bit replaces carry");
+    let _ = writeln!(v, "        str_n = str_fl;");
+    let _ = writeln!(v, "        z_bus = alu_out;");
+    let _ = writeln!(v, "        case (f_sft)");
+    let c = flags.carry;
+    for (op, carry_in, value) in [
+        (ShiftOp::RRwC, "alu_out[0]", "{cf, alu_out[7:1]}"),
+        (ShiftOp::RlwC, "alu_out[7]", "{alu_out[6:0], cf}"),
+        (ShiftOp::Srl, "alu_out[0]", "{f_sin[0], alu_out[7:1]}"),
+        (ShiftOp::Sll, "alu_out[7]", "{alu_out[6:0], f_sin[0]}"),
+        (ShiftOp::Sla, "alu_out[7]", "{alu_out[6:0], f_sin[0]}"),
+        (ShiftOp::Sra, "alu_out[0]", "{alu_out[7], alu_out[7:1]}"),
+    ] {
+        if let Some(code) = sft(op) {
+            let _ = writeln!(
+                v,
+                "            {}: begin str_n[{}] = {}; z_bus = {}; end",
+                code, c, carry_in, value
+            );
+        }
+    }
+    let _ = writeln!(v, "            default: ;");
+    let _ = writeln!(v, "        endcase\n");
+
+    let _ = writeln!(v, "        // Z bus");
+    for (register, name, _) in REGISTER_FILE {
+        if register != Register::Str {
+            let _ = writeln!(v, "        {}_n = {};", name, name);
+        }
+    }
+    let _ = writeln!(v, "        case (f_z_bus)");
+    for spec in arch.registers.iter().filter(|r| r.destination) {
+        if spec.register != Register::Nop {
+            let value = match spec.register {
+                Register::Cnt => "{8'h00, z_bus}",
+                _ => "z_bus",
+            };
+            let _ = writeln!(
+                v,
+                "            {}: {}_n = {}; // {}",
+                hex(f.z_bus.max(1), spec.code),
+                port(spec.register),
+                value,
+                spec.name
+            );
+        }
+    }
+    let _ = writeln!(v, "            default: ;");
+    let _ = writeln!(v, "        endcase\n");
+
+    let _ = writeln!(v, "        // memory, addressed by the MAR just written");
+    let _ = writeln!(v, "        mem_addr = mar_n;");
+    let _ = writeln!(v, "        mem_wdata = mdr_n;");
+    let _ = writeln!(v, "        we = 1'b0;");
+    if let Some(code) = mem(MemOp::R) {
+        let _ = writeln!(v, "        if (f_mem == {}) mdr_n = mem_rdata;", code);
+    }
+    if let Some(code) = mem(MemOp::W) {
+        let _ = writeln!(v, "        if (f_mem == {}) we = 1'b1;", code);
+    }
+    let _ = writeln!(v);
+
+    let _ = writeln!(
+        v,
+        "        // sequencer, conditions see STR and IR after the Z bus write"
+    );
+    let _ = writeln!(v, "        next = upc + 16'h0001;");
+    let _ = writeln!(v, "        upc_n = next;");
+    let _ = writeln!(v, "        case (f_branch)");
+    for (op, statement) in [
+        (Branch::J, "upc_n = f_addr;".to_owned()),
+        (
+            Branch::JM,
+            format!("if (str_n[{}]) upc_n = f_addr;", flags.minus),
+        ),
+        (
+            Branch::JZ,
+            format!("if (str_n[{}]) upc_n = f_addr;", flags.zero),
+        ),
+        (
+            Branch::JC,
+            format!("if (str_n[{}]) upc_n = f_addr;", flags.carry),
+        ),
+        (
+            Branch::JV,
+            format!("if (str_n[{}]) upc_n = f_addr;", flags.overflow),
+        ),
+        (Branch::JI, "upc_n = f_addr + {8'h00, ir_n};".to_owned()),
+        (Branch::Ldct, "cnt_n = f_addr;".to_owned()),
+        (
+            Branch::Rpct,
+            "if (cnt_n != 16'h0000) begin cnt_n = cnt_n - 16'h0001; upc_n = f_addr; end".to_owned(),
+        ),
+    ] {
+        if let Some(code) = branch(op) {
+            let _ = writeln!(v, "            {}: {}", code, statement);
+        }
+    }
+    let _ = writeln!(v, "            default: ;");
+    let _ = writeln!(v, "        endcase");
+    let _ = writeln!(v, "    end\n");
+
+    let _ = writeln!(v, "    assign mem_we = we & ~hlt & ~reset & ~start;\n");
+    let _ = writeln!(v, "    always @(posedge clk) begin");
+    let _ = writeln!(v, "        if (reset) begin");
+    let _ = writeln!(v, "            hlt <= 1'b0;");
+    let _ = writeln!(v, "            upc <= 16'h0000;");
+    for (_, name, bits) in REGISTER_FILE {
+        let _ = writeln!(v, "            {} <= {};", name, hex(bits, 0));
+    }
+    let _ = writeln!(v, "        end else if (start) begin");
+    let _ = writeln!(v, "            hlt <= 1'b0;");
+    let _ = writeln!(v, "        end else if (!hlt) begin");
+    for (_, name, _) in REGISTER_FILE {
+        let _ = writeln!(v, "            {} <= {}_n;", name, name);
+    }
+    let _ = writeln!(v, "            upc <= upc_n;");
+    let _ = writeln!(v, "            hlt <= f_hlt[0];");
+    let _ = writeln!(v, "        end");
+    let _ = writeln!(v, "    end");
+    let _ = writeln!(v, "endmodule\n");
+
+    // memory
+    let _ = writeln!(v, "module {}_memory (", prefix);
+    let _ = writeln!(v, "    input  wire       clk,");
+    let _ = writeln!(v, "    input  wire [7:0] addr,");
+    let _ = writeln!(v, "    input  wire [7:0] wdata,");
+    let _ = writeln!(v, "    input  wire       we,");
+    let _ = writeln!(v, "    output wire [7:0] rdata");
+    let _ = writeln!(v, ");");
+    let _ = writeln!(v, "    reg [7:0] cells [0:{}];", vm.memory.len().max(1) - 1);
+    let _ = writeln!(v, "    integer i;");
+    let _ = writeln!(v, "    initial begin");
+    let _ = writeln!(
+        v,
+        "        for (i = 0; i < {}; i = i + 1) cells[i] = 8'h00;",
+        vm.memory.len()
+    );
+    for (addr, byte) in vm.memory.iter().enumerate() {
+        if *byte != 0 {
+            let _ = writeln!(
+                v,
+                "        cells[{}] = {};",
+                hex(8, addr as u64),
+                hex(8, *byte as u64)
+            );
+        }
+    }
+    let _ = writeln!(v, "    end");
+    let _ = writeln!(v, "    assign rdata = cells[addr];");
+    let _ = writeln!(v, "    always @(posedge clk) if (we) cells[addr] <= wdata;");
+    let _ = writeln!(v, "endmodule\n");
+
+    // system
+    let _ = writeln!(v, "module {}_system (", prefix);
+    let _ = writeln!(v, "    input  wire       clk,");
+    let _ = writeln!(v, "    input  wire       reset,");
+    let _ = writeln!(v, "    input  wire       start,");
+    let _ = writeln!(v, "    input  wire [7:0] sw1,");
+    let _ = writeln!(v, "    input  wire [7:0] sw2,");
+    let _ = writeln!(v, "    output wire       hlt");
+    let _ = writeln!(v, ");");
+    let _ = writeln!(v, "    wire [7:0] mem_addr, mem_wdata, mem_rdata;");
+    let _ = writeln!(v, "    wire mem_we;");
+    let _ = writeln!(v, "    wire [15:0] upc;");
+    for (_, name, bits) in REGISTER_FILE {
+        let _ = writeln!(v, "    wire [{}:0] {};", bits - 1, name);
+    }
+    let _ = writeln!(v, "    {}_cpu cpu (", prefix);
+    let _ = writeln!(
+        v,
+        "        .clk(clk), .reset(reset), .start(start), .sw1(sw1), .sw2(sw2),"
+    );
+    let _ = writeln!(v, "        .mem_addr(mem_addr), .mem_wdata(mem_wdata), .mem_we(mem_we), .mem_rdata(mem_rdata),");
+    let ports: Vec<String> = REGISTER_FILE
+        .iter()
+        .map(|(_, name, _)| format!(".{}({})", name, name))
+        .collect();
+    let _ = writeln!(v, "        .hlt(hlt), .upc(upc), {}", ports.join(", "));
+    let _ = writeln!(v, "    );");
+    let _ = writeln!(v, "    {}_memory memory (", prefix);
+    let _ = writeln!(
+        v,
+        "        .clk(clk), .addr(mem_addr), .wdata(mem_wdata), .we(mem_we), .rdata(mem_rdata)"
+    );
+    let _ = writeln!(v, "    );");
+    let _ = writeln!(v, "endmodule");
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::{project, SAMPLES};
+
+    #[test]
+    fn modules_and_ports() {
+        let v = generate(&project(&SAMPLES[0]), "kue").unwrap();
+        for module in [
+            "module kue_cpu (",
+            "module kue_memory (",
+            "module kue_system (",
+        ] {
+            assert!(v.contains(module), "{}", module);
+        }
+        assert_eq!(v.matches("\nmodule ").count(), 3);
+        assert_eq!(v.matches("endmodule").count(), 3);
+        assert!(v.contains("reg [42:0] control_store [0:WORDS-1];"));
+        assert!(v.contains("localparam WORDS = 65536;"));
+    }
+
+    #[test]
+    fn field_slices_follow_encoding() {
+        let v = generate(&project(&SAMPLES[0]), "m").unwrap();
+        for wire in [
+            "wire [15:0] f_addr = word[15:0];",
+            "wire [0:0] f_hlt = word[16:16];",
+            "wire [3:0] f_branch = word[20:17];",
+            "wire [1:0] f_mem = word[22:21];",
+            "wire [3:0] f_z_bus = word[26:23];",
+            "wire [0:0] f_fl = word[27:27];",
+            "wire [0:0] f_sin = word[28:28];",
+            "wire [2:0] f_sft = word[31:29];",
+            "wire [2:0] f_alu = word[34:32];",
+            "wire [3:0] f_y_bus = word[38:35];",
+            "wire [3:0] f_x_bus = word[42:39];",
+        ] {
+            assert!(v.contains(wire), "{}", wire);
+        }
+    }
+
+    #[test]
+    fn control_store_and_memory_initialised() {
+        let vm = project(&SAMPLES[0]);
+        let v = generate(&vm, "m").unwrap();
+        let arch = &vm.arch;
+        let default = arch.encode(&MicroCode::default()).unwrap();
+        let rows = vm
+            .micro_program
+            .iter()
+            .filter(|micro_code| arch.encode(micro_code).unwrap() != default)
+            .count();
+        assert!(rows > 0);
+        assert_eq!(v.matches("        control_store[16'h").count(), rows);
+        let fetch = arch.encode(&vm.micro_program[0]).unwrap();
+        assert!(v.contains(&format!("control_store[16'h0] = 43'h{:x};", fetch)));
+        let bytes = vm.memory.iter().filter(|byte| **byte != 0).count();
+        assert_eq!(v.matches("        cells[8'h").count(), bytes);
+    }
+
+    #[test]
+    fn wiring_follows_architecture() {
+        let mut vm = project(&SAMPLES[0]);
+        let v = generate(&vm, "m").unwrap();
+        assert!(v.contains("4'hd: x_bus = sw1; // Sw1"));
+        assert!(v.contains("4'hf: cnt_n = {8'h00, z_bus}; // CNT"));
+        assert!(v.contains("str_fl[2] = sum[8];"));
+
+        vm.arch.flags.carry = 5;
+        vm.arch.flags.zero = 2;
+        vm.arch.registers.retain(|r| r.register != Register::Cnt);
+        for spec in &mut vm.arch.registers {
+            if spec.register == Register::Mar {
+                spec.source = false;
+            }
+        }
+        let v = generate(&vm, "m").unwrap();
+        assert!(v.contains("str_fl[5] = sum[8];"));
+        assert!(v.contains("cf = str_fl[5];"));
+        assert!(!v.contains("// CNT"));
+        assert!(!v.contains("x_bus = mar;"));
+        assert!(v.contains("mar_n = z_bus; // MAR"));
+    }
+
+    #[test]
+    fn unencodable_program_is_rejected() {
+        let mut vm = project(&SAMPLES[0]);
+        vm.arch.registers.retain(|r| r.register != Register::R6);
+        assert!(generate(&vm, "m").is_err());
+    }
+
+    /// `prefix_tb` output under Icarus Verilog, or None when it is not installed.
+    fn simulate(design: &str, testbench: &str, prefix: &str) -> Option<String> {
+        use std::process::Command;
+        Command::new("iverilog").arg("-V").output().ok()?;
+        let dir =
+            std::env::temp_dir().join(format!("micro_verilog_{}_{}", prefix, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("design.v"), design).unwrap();
+        std::fs::write(dir.join("design_tb.v"), testbench).unwrap();
+        let compile = Command::new("iverilog")
+            .current_dir(&dir)
+            .args([
+                "-o",
+                "tb",
+                "-s",
+                &format!("{}_tb", prefix),
+                "design.v",
+                "design_tb.v",
+            ])
+            .output()
+            .unwrap();
+        assert!(
+            compile.status.success(),
+            "{}",
+            String::from_utf8_lossy(&compile.stderr)
+        );
+        let run = Command::new("vvp")
+            .current_dir(&dir)
+            .arg("tb")
+            .output()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        Some(String::from_utf8_lossy(&run.stdout).into_owned())
+    }
+
+    #[test]
+    fn design_matches_recorded_trace() {
+        for (index, sample) in SAMPLES.iter().enumerate() {
+            let mut vm = project(sample);
+            vm.sw1 = 6;
+            vm.sw2 = 7;
+            let prefix = format!("s{}", index);
+            let trace = crate::testbench::record(&vm, 100_000);
+            assert!(trace.halted(), "{}", sample.name);
+            let design = generate(&vm, &prefix).unwrap();
+            let testbench = crate::testbench::generate(&trace, &prefix);
+            let output = match simulate(&design, &testbench, &prefix) {
+                Some(output) => output,
+                None => {
+                    eprintln!("iverilog is not installed, skipping the simulation");
+                    return;
+                }
+            };
+            let pass = format!("PASS {} cycles", trace.steps.len());
+            assert!(output.contains(&pass), "{}: {}", sample.name, output);
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use crate::vm::{AluOp, Branch, MemOp, MicroArch, Register, RegisterOrSwitch, ShiftOp};
-use eframe::egui::CtxRef;
-use eframe::epi::Frame;
+use crate::file_io::{FileIo, Files, Filter, Opened, Purpose};
+use crate::history::{Edit, History, Registers};
+use crate::vm::{MicroArch, MicroCode};
+use eframe::egui::{CtxRef, Key};
+use eframe::epi::{Frame, Storage};
 
 pub struct VMView {
     ///VM
@@ -8,130 +10,487 @@ pub struct VMView {
     open_register_view: bool,
     open_micro_code_view: bool,
     open_memory_view: bool,
-    /// where the current viewing micro code page.
-    current_viewing_page: u8,
+    open_datapath_view: bool,
+    /// scroll position of the micro code table.
+    micro_code_view: crate::micro_code_view::MicroCodeView,
     /// inter frame data tracking
     auto_exec: bool,
+    /// last file error shown to user.
+    error: Option<String>,
+    /// pre-decoded micro program for fast runs.
+    engine: crate::engine::Engine,
+    /// deflate project files on save.
+    compress_project: bool,
+    /// first and last main memory address for image export, raw binary loads at the first.
+    memory_range: (u8, u8),
+    /// trimming and chip splitting for control store export.
+    rom_options: crate::rom_image::RomOptions,
+    /// most micro codes recorded into an exported testbench.
+    testbench_cycles: usize,
+    /// dialogs and files, or uploads and downloads in the browser.
+    file_io: FileIo,
+    /// offered when saving, binary unless a text format.
+    project_format: Option<crate::project::TextFormat>,
+    /// offered when exporting main memory.
+    memory_format: crate::memory_image::ImageFormat,
+    /// how the register view shows values.
+    register_format: crate::number_input::NumberFormat,
+    /// what the last stepped micro code did, None once the machine changed otherwise.
+    transfer: Option<crate::datapath::Transfer>,
+    /// undo and redo of edits to the machine.
+    history: History,
 }
 impl VMView {
     pub fn init() -> Self {
+        let vm = MicroArch::construct(vec![MicroCode::default(); 1 << 16]);
         Self {
-            vm: MicroArch::construct(vec![
-                crate::vm::MicroCode {
-                    x_bus: RegisterOrSwitch::Register(Register::Nop),
-                    y_bus: RegisterOrSwitch::Register(Register::Nop),
-                    alu: AluOp::XPlusY,
-                    sft: ShiftOp::Nop,
-                    sin: false,
-                    fl: false,
-                    z_bus: Register::Nop,
-                    mem: MemOp::Nop,
-                    branch: Branch::Plus1,
-                    hlt: false,
-                    addr: 0
-                };
-                1 << 16
-            ]),
+            history: History::default(),
+            vm,
             open_register_view: true,
             open_micro_code_view: true,
             open_memory_view: false,
+            open_datapath_view: false,
             auto_exec: false,
-            current_viewing_page: 0,
+            micro_code_view: crate::micro_code_view::MicroCodeView::default(),
+            error: None,
+            engine: crate::engine::Engine::new(),
+            compress_project: true,
+            memory_range: (0x00, 0xff),
+            rom_options: crate::rom_image::RomOptions::default(),
+            testbench_cycles: 10_000,
+            file_io: FileIo::default(),
+            project_format: None,
+            memory_format: crate::memory_image::ImageFormat::IntelHex,
+            register_format: crate::number_input::NumberFormat::Hex,
+            transfer: None,
+        }
+    }
+    /// ROM files named after `name`, chips get their suffix before the extension.
+    fn rom_files(&self, name: &str, format: crate::rom_image::RomFormat) -> Result<Files, String> {
+        let files = crate::rom_image::export(
+            &self.vm.micro_program,
+            &self.vm.arch,
+            format,
+            self.rom_options,
+        )?;
+        let stem = std::path::Path::new(name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(files
+            .into_iter()
+            .map(|file| {
+                let name = format!("{}{}.{}", stem, file.suffix, format.extension());
+                (name, file.contents)
+            })
+            .collect())
+    }
+    /// every memory image format.
+    fn memory_image_filters() -> Vec<Filter> {
+        crate::memory_image::ImageFormat::ALL
+            .iter()
+            .map(|format| Filter::new(format.to_string(), format.extensions()))
+            .collect()
+    }
+    fn memory_image_format(name: &str) -> Result<crate::memory_image::ImageFormat, String> {
+        std::path::Path::new(name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(crate::memory_image::ImageFormat::from_extension)
+            .ok_or_else(|| format!("unknown memory image format : {}", name))
+    }
+    fn import_memory(&mut self, name: &str, image: &[u8], time: f64) -> Result<(), String> {
+        let format = Self::memory_image_format(name)?;
+        let segments = crate::memory_image::parse(image, format)?;
+        let offset = if format == crate::memory_image::ImageFormat::Binary {
+            self.memory_range.0 as usize
+        } else {
+            0
+        };
+        let before = self.vm.memory.clone();
+        crate::memory_image::load(&mut self.vm.memory, &segments, offset)?;
+        let mut edit = Edit::default();
+        for (addr, (before, after)) in before.into_iter().zip(&self.vm.memory).enumerate() {
+            edit.memory(addr, before, *after);
+        }
+        self.history.record(edit, time);
+        Ok(())
+    }
+    fn export_memory(&self, name: &str) -> Result<Files, String> {
+        let format = Self::memory_image_format(name)?;
+        let (first, last) = self.memory_range;
+        if first > last {
+            return Err(format!("empty memory range {:02X}H..={:02X}H", first, last));
+        }
+        let image = crate::memory_image::export(
+            &self.vm.memory,
+            first as usize..last as usize + 1,
+            format,
+        )?;
+        Ok(vec![(name.to_owned(), image)])
+    }
+    /// the binary project and every text format.
+    fn project_filters(binary_name: &str) -> Vec<Filter> {
+        std::iter::once(Filter::new(binary_name, &["cpu_memory"]))
+            .chain(
+                crate::project::TextFormat::ALL.iter().map(|format| {
+                    Filter::new(format!("project ({})", format), &[format.extension()])
+                }),
+            )
+            .collect()
+    }
+    /// file name offered when saving the project.
+    fn project_name(&self) -> String {
+        let extension = self
+            .project_format
+            .map_or("cpu_memory", |format| format.extension());
+        format!("project.{}", extension)
+    }
+    /// save in the format chosen by the file extension.
+    fn save_project(&self, name: &str) -> Result<Files, String> {
+        let text_format = std::path::Path::new(name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(crate::project::TextFormat::from_extension);
+        let bytes = match text_format {
+            Some(format) => crate::project::save_text(&self.vm, format)?.into_bytes(),
+            None => {
+                let compression = if self.compress_project {
+                    crate::project::Compression::Deflate
+                } else {
+                    crate::project::Compression::None
+                };
+                crate::project::save(&self.vm, compression)?
+            }
+        };
+        Ok(vec![(name.to_owned(), bytes)])
+    }
+    /// a file picked from the File menu has arrived at `time`.
+    fn opened(&mut self, opened: Opened, time: f64) -> Result<(), String> {
+        match opened.purpose {
+            Purpose::Project => {
+                self.vm = crate::project::load(&opened.bytes)?;
+                self.engine.invalidate_all();
+                self.micro_code_view.invalidate();
+                self.transfer = None;
+                self.history.clear();
+            }
+            Purpose::MemoryImage => self.import_memory(&opened.name, &opened.bytes, time)?,
+            Purpose::Architecture => {
+                let text = String::from_utf8(opened.bytes).map_err(|e| e.to_string())?;
+                self.vm.arch = crate::arch::Architecture::from_toml(&text)?;
+                self.engine.invalidate_all();
+            }
+        }
+        Ok(())
+    }
+    /// Ctrl+Z undoes, Ctrl+Y or Ctrl+Shift+Z redoes unless a text field takes the keys.
+    fn undo_shortcuts(&mut self, ctx: &CtxRef) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        let input = ctx.input();
+        let command = input.modifiers.command;
+        let redo = command
+            && (input.key_pressed(Key::Y) || input.modifiers.shift && input.key_pressed(Key::Z));
+        let undo = command && !redo && input.key_pressed(Key::Z);
+        if undo {
+            self.undo();
+        } else if redo {
+            self.redo();
+        }
+    }
+    fn undo(&mut self) {
+        if self.history.undo(&mut self.vm) {
+            self.engine.invalidate_all();
+            self.micro_code_view.invalidate();
+            self.transfer = None;
+        }
+    }
+    fn redo(&mut self) {
+        if self.history.redo(&mut self.vm) {
+            self.engine.invalidate_all();
+            self.micro_code_view.invalidate();
+            self.transfer = None;
+        }
+    }
+    /// show errors of file operations.
+    fn report(&mut self, result: Result<(), String>) {
+        if let Err(e) = result {
+            self.error = Some(e);
         }
     }
 }
 impl eframe::epi::App for VMView {
-    fn update(&mut self, ctx: &CtxRef, _frame: &Frame) {
+    fn update(&mut self, ctx: &CtxRef, frame: &Frame) {
+        let time = ctx.input().time;
+        for opened in self.file_io.take() {
+            let result = opened.and_then(|opened| self.opened(opened, time));
+            self.report(result);
+        }
+        self.undo_shortcuts(ctx);
         let panel = eframe::egui::TopBottomPanel::top("windows");
         panel.show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.menu_button("File", |ui| {
                     if ui.button("Read CPU config & main memory").clicked() {
-                        let cpu_and_memory: Option<crate::vm::MicroArch> = rfd::FileDialog::new()
-                            .add_filter("cpu with main memory", &["cpu_memory"])
-                            .pick_file()
-                            .map(|path| {
-                                std::fs::read(path).ok().map(|cpu_and_memory| {
-                                    bincode::deserialize(&cpu_and_memory).ok()
-                                })
-                            })
-                            .flatten()
-                            .flatten();
-                        if let Some(cpu_and_memory) = cpu_and_memory {
-                            self.vm = cpu_and_memory;
-                        }
+                        self.file_io.open(
+                            frame,
+                            Purpose::Project,
+                            &Self::project_filters("cpu with main memory"),
+                        );
                     }
                     if ui.button("Save CPU config & main memory").clicked() {
-                        if let Some((path, Some(vm))) = rfd::FileDialog::new()
-                            .add_filter("マイクロコードとメインメモリ", &["cpu_memory"])
-                            .save_file()
-                            .map(|x| (x, bincode::serialize(&self.vm).ok()))
-                        {
-                            std::fs::write(path, vm).ok();
+                        let result = self.file_io.save(
+                            &self.project_name(),
+                            &Self::project_filters("マイクロコードとメインメモリ"),
+                            |name| self.save_project(name),
+                        );
+                        self.report(result);
+                    }
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.project_format, None, "binary");
+                        for format in crate::project::TextFormat::ALL {
+                            ui.radio_value(
+                                &mut self.project_format,
+                                Some(format),
+                                format.to_string(),
+                            );
                         }
+                    })
+                    .response
+                    .on_hover_text("format of saved projects, a file name overrides it");
+                    ui.checkbox(
+                        &mut self.compress_project,
+                        "Compress saved project (binary only)",
+                    );
+                    ui.menu_button("Load reference project", |ui| {
+                        for sample in &crate::reference::SAMPLES {
+                            if ui.button(sample.name).clicked() {
+                                self.vm = crate::reference::project(sample);
+                                self.engine.invalidate_all();
+                                self.micro_code_view.invalidate();
+                                self.transfer = None;
+                                self.history.clear();
+                            }
+                        }
+                    });
+                    ui.menu_button("Main memory image", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("from");
+                            ui.add(crate::hex_input::HexInput::new(
+                                &mut self.memory_range.0,
+                                0x3e3e0000,
+                            ));
+                            ui.label("to");
+                            ui.add(crate::hex_input::HexInput::new(
+                                &mut self.memory_range.1,
+                                0x3e3e0001,
+                            ));
+                        })
+                        .response
+                        .on_hover_text("export range. raw binary is imported at \"from\"");
+                        for format in crate::memory_image::ImageFormat::ALL {
+                            ui.radio_value(&mut self.memory_format, format, format.to_string());
+                        }
+                        if ui.button("Import").clicked() {
+                            self.file_io.open(
+                                frame,
+                                Purpose::MemoryImage,
+                                &Self::memory_image_filters(),
+                            );
+                        }
+                        if ui.button("Export").clicked() {
+                            let name = format!("memory.{}", self.memory_format.extensions()[0]);
+                            let result =
+                                self.file_io
+                                    .save(&name, &Self::memory_image_filters(), |name| {
+                                        self.export_memory(name)
+                                    });
+                            self.report(result);
+                        }
+                    });
+                    ui.menu_button("Export control store ROM", |ui| {
+                        ui.checkbox(&mut self.rom_options.trim, "Trim to used addresses");
+                        ui.checkbox(&mut self.rom_options.byte_chips, "Split into byte chips");
+                        for format in crate::rom_image::RomFormat::ALL {
+                            if ui.button(format.to_string()).clicked() {
+                                let result = self.file_io.save(
+                                    &format!("control_store.{}", format.extension()),
+                                    &[Filter::new(format.to_string(), &[format.extension()])],
+                                    |name| self.rom_files(name, format),
+                                );
+                                self.report(result);
+                            }
+                        }
+                    });
+                    if ui.button("Export Verilog").clicked() {
+                        let result = self.file_io.save(
+                            "micro_arch.v",
+                            &[Filter::new("Verilog", &["v"])],
+                            |name| {
+                                let v = crate::verilog::generate(&self.vm, "micro_arch")?;
+                                Ok(vec![(name.to_owned(), v.into_bytes())])
+                            },
+                        );
+                        self.report(result);
+                    }
+                    ui.menu_button("Export testbench", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("cycle limit");
+                            ui.add(
+                                eframe::egui::DragValue::new(&mut self.testbench_cycles)
+                                    .clamp_range(1..=1_000_000),
+                            );
+                        });
+                        if ui.button("Record and export").clicked() {
+                            let result = self.file_io.save(
+                                "micro_arch_tb.v",
+                                &[Filter::new("Verilog", &["v"])],
+                                |name| {
+                                    let trace =
+                                        crate::testbench::record(&self.vm, self.testbench_cycles);
+                                    let v = crate::testbench::generate(&trace, "micro_arch");
+                                    Ok(vec![(name.to_owned(), v.into_bytes())])
+                                },
+                            );
+                            self.report(result);
+                        }
+                    });
+                    ui.separator();
+                    if ui.button("Load architecture").clicked() {
+                        self.file_io.open(
+                            frame,
+                            Purpose::Architecture,
+                            &[Filter::new("architecture", &["toml"])],
+                        );
+                    }
+                    if ui.button("Save architecture").clicked() {
+                        let result = self.file_io.save(
+                            "architecture.toml",
+                            &[Filter::new("architecture", &["toml"])],
+                            |name| Ok(vec![(name.to_owned(), self.vm.arch.to_toml().into_bytes())]),
+                        );
+                        self.report(result);
                     }
                 });
+                ui.menu_button("Edit", |ui| {
+                    let undo = eframe::egui::Button::new("Undo (Ctrl+Z)");
+                    if ui.add_enabled(self.history.can_undo(), undo).clicked() {
+                        self.undo();
+                    }
+                    let redo = eframe::egui::Button::new("Redo (Ctrl+Y)");
+                    if ui.add_enabled(self.history.can_redo(), redo).clicked() {
+                        self.redo();
+                    }
+                });
+                ui.label(format!("architecture : {}", self.vm.arch.name));
                 ui.checkbox(&mut self.open_register_view, "Register View");
                 ui.checkbox(&mut self.open_micro_code_view, "Microcode View");
                 ui.checkbox(&mut self.open_memory_view, "Memory View");
+                ui.checkbox(&mut self.open_datapath_view, "Datapath");
             });
         });
         let register_view =
             eframe::egui::Window::new("RegisterView").open(&mut self.open_register_view);
         register_view.show(ctx, |ui| {
-            crate::register_view::register_view(ui, &mut self.vm, &mut self.auto_exec)
+            crate::register_view::register_view(
+                ui,
+                &mut self.vm,
+                &mut self.engine,
+                &mut self.auto_exec,
+                &mut self.register_format,
+                &mut self.transfer,
+                &mut self.history,
+            )
         });
 
-        let micro_code_base_addr = (self.current_viewing_page as u16) << 8;
-
         let micro_code_view =
             eframe::egui::Window::new("MicroCodeView").open(&mut self.open_micro_code_view);
 
         micro_code_view.show(ctx, |ui| {
-            ui.vertical(|ui| {
-                ui.horizontal(|ui| {
-                    if ui.button("<").clicked() & (self.current_viewing_page > 0) {
-                        self.current_viewing_page -= 1;
-                    }
-                    ui.add(crate::hex_input::HexInput::new(
-                        &mut self.current_viewing_page,
-                        0xcafebabe,
-                    ));
-                    if ui.button(">").clicked() & (self.current_viewing_page < 0xff) {
-                        self.current_viewing_page += 1;
-                    }
-                });
-                crate::micro_code_view::micro_code_view(
-                    ui,
-                    micro_code_base_addr as usize,
-                    self.vm.micro_program_counter as usize,
-                    &mut self.vm.micro_program
-                        [micro_code_base_addr as usize..(micro_code_base_addr + 0x100) as usize],
-                )
-            });
+            let changes = self.micro_code_view.show(
+                ui,
+                self.vm.micro_program_counter as usize,
+                &mut self.vm.micro_program,
+                &mut self.vm.annotations,
+                &self.vm.arch,
+            );
+            let mut edit = Edit::default();
+            for (addr, before) in changes.edited {
+                self.engine.invalidate(addr);
+                edit.micro_code(addr, before, self.vm.micro_program[addr]);
+            }
+            for (addr, before) in changes.annotated {
+                edit.annotation(addr, before, self.vm.annotations.get(&addr).cloned());
+            }
+            if let Some(op) = changes.row_op {
+                let registers = Registers::of(&self.vm);
+                let upc = self.vm.micro_program_counter as usize;
+                if let Some(upc) = op.relocate(upc, self.vm.micro_program.len()) {
+                    self.vm.micro_program_counter = upc as u16;
+                }
+                edit.registers(registers, Registers::of(&self.vm));
+                let annotations = crate::relocate::relocate_annotations(
+                    &self.vm.annotations,
+                    op,
+                    self.vm.micro_program.len(),
+                );
+                edit.annotations(&self.vm.annotations, &annotations);
+                self.vm.annotations = annotations;
+                self.engine.invalidate_all();
+                self.transfer = None;
+            }
+            self.history.record(edit, time);
         });
         eframe::egui::Window::new("Ram View")
             .open(&mut self.open_memory_view)
             .show(ctx, |ui| {
-                crate::ram_view::ram_view(ui, &mut self.vm.memory);
+                let mut edit = Edit::default();
+                for (addr, before) in crate::ram_view::ram_view(ui, &mut self.vm.memory) {
+                    edit.memory(addr, before, self.vm.memory[addr]);
+                }
+                self.history.record(edit, time);
+            });
+        eframe::egui::Window::new("Datapath")
+            .open(&mut self.open_datapath_view)
+            .show(ctx, |ui| {
+                crate::datapath_view::datapath_view(ui, &self.vm, self.transfer.as_ref());
+            });
+        if let Some(error) = self.error.clone() {
+            eframe::egui::Window::new("Error").show(ctx, |ui| {
+                ui.label(error);
+                if ui.button("OK").clicked() {
+                    self.error = None;
+                }
             });
+        }
         if self.auto_exec {
-            self.auto_exec = !self.vm.exec();
-            _frame.request_repaint();
+            self.transfer = self.vm.step();
+            self.auto_exec = !self.vm.hlt && self.vm.fault().is_none();
+            frame.request_repaint();
         }
     }
-    fn on_exit(&mut self) {
-        let path = rfd::FileDialog::new()
-            .add_filter("cpu and main memory", &["cpu_memory"])
-            .save_file();
-        if let Some(path) = path {
-            if let Ok(vm_persistence) = bincode::serialize(&self.vm) {
-                std::fs::write(path, vm_persistence).ok();
-            }
-        } else {
-            std::process::exit(0);
+    fn setup(&mut self, _ctx: &CtxRef, _frame: &Frame, storage: Option<&dyn Storage>) {
+        if let Some(vm) = FileIo::restore(storage) {
+            self.vm = vm;
+            self.micro_code_view.invalidate();
+            self.transfer = None;
+            self.history.clear();
         }
     }
+    fn save(&mut self, storage: &mut dyn Storage) {
+        FileIo::store(storage, &self.vm);
+    }
+    fn on_exit(&mut self) {
+        self.file_io
+            .save(
+                &self.project_name(),
+                &Self::project_filters("cpu and main memory"),
+                |name| self.save_project(name),
+            )
+            .ok();
+    }
     fn name(&self) -> &str {
         "MicroProgramming"
     }
@@ -1,13 +1,21 @@
+use crate::arch::Architecture;
+use crate::datapath::{Access, Transfer};
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+mod tests;
 
 /// Small 8 bit micro code driven architecture.
 ///
 ///
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct MicroArch {
     /// next micro code execution address
     pub micro_program_counter: u16,
+    /// sequencer loop counter. loaded by LDCT or Z bus, decremented by RPCT.
+    pub counter: u16,
     ///
     pub micro_program: Vec<MicroCode>,
     pub memory: Vec<u8>,
@@ -20,12 +28,32 @@ pub struct MicroArch {
     pub sw1: u8,
     pub sw2: u8,
     pub hlt: bool,
+    /// machine description driving bus, flag and encoder behaviour.
+    pub arch: Architecture,
+    /// labels and comments of micro code addresses, for the reader only.
+    pub annotations: BTreeMap<u16, Annotation>,
+}
+
+/// label and free text comment of a micro code address.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Annotation {
+    pub label: String,
+    pub comment: String,
 }
+
+impl Annotation {
+    pub fn is_empty(&self) -> bool {
+        self.label.is_empty() && self.comment.is_empty()
+    }
+}
+
 impl MicroArch {
     /// initialize registers and load micro code.
     pub fn construct(micro_codes: Vec<MicroCode>) -> Self {
         Self {
             micro_program_counter: 0,
+            counter: 0,
             micro_program: micro_codes,
             memory: vec![0; 256],
             gpr: [0; 7],
@@ -37,11 +65,16 @@ impl MicroArch {
             sw1: 0,
             sw2: 0,
             hlt: false,
+            arch: Architecture::default(),
+            annotations: BTreeMap::new(),
         }
     }
+    /// clear the registers but MAR, the counter, the switches and HLT and put the
+    /// sequencer back to 0. memory and micro program are kept.
     pub fn reset_register(&mut self) {
         self.hlt = false;
         self.micro_program_counter = 0;
+        self.counter = 0;
         self.gpr[0] = 0;
         self.gpr[1] = 0;
         self.gpr[2] = 0;
@@ -60,186 +93,121 @@ impl MicroArch {
     pub fn start(&mut self) {
         self.hlt = false;
     }
-    /// execute 1 microcode .
+    /// what stops the next micro code from executing, if anything: the sequencer
+    /// outside the control store, an op the architecture lacks or a memory access
+    /// past main memory.
+    pub fn fault(&self) -> Option<String> {
+        self.next_transfer().err()
+    }
+    /// `datapath::trace` of the next micro code unless it faults.
+    fn next_transfer(&self) -> Result<Transfer, String> {
+        let upc = self.micro_program_counter;
+        let transfer = crate::datapath::trace(self).ok_or_else(|| {
+            format!(
+                "micro program counter {:04X}H is outside the {} word control store",
+                upc,
+                self.micro_program.len()
+            )
+        })?;
+        self.arch
+            .ops_available(&transfer.micro_code)
+            .map_err(|e| format!("{:04X}H : {}", upc, e))?;
+        // the access sees MAR as this micro code leaves it.
+        match transfer.memory {
+            Some(access) if access.addr as usize >= self.memory.len() => Err(format!(
+                "{:04X}H : MAR {:02X}H is outside the {} byte main memory",
+                upc,
+                access.addr,
+                self.memory.len()
+            )),
+            _ => Ok(transfer),
+        }
+    }
+    /// execute 1 microcode. true once the machine stopped, by HLT or by a `fault`
+    /// which leaves the machine as it was. the ALU wraps at 8 bits and the
+    /// sequencer at 16 bits, in debug builds as in release builds.
     pub fn exec(&mut self) -> bool {
-        if !self.hlt {
-            // fetch micro code .
-            let micro_code = self.micro_program[self.micro_program_counter as usize];
-
-            let x_bus = self.data_load(micro_code.x_bus);
-            let y_bus = self.data_load(micro_code.y_bus);
-            let alu_out = match micro_code.alu {
-                AluOp::XPlusY => x_bus.wrapping_add(y_bus),
-                AluOp::XMinusY => x_bus.wrapping_sub(y_bus),
-                AluOp::XAndY => x_bus & y_bus,
-                AluOp::XorY => x_bus | y_bus,
-                AluOp::XxorY => x_bus ^ y_bus,
-                AluOp::XPlus1 => x_bus + 1,
-                AluOp::XMinus1 => x_bus - 1,
-            };
-
-            if micro_code.fl {
-                //minus flag
-                if (alu_out & MSB) == MSB {
-                    self.str |= 0x01;
-                } else {
-                    self.str &= 0b11111110;
-                }
-                //zero flag
-                if alu_out == 0 {
-                    self.str |= 0x02;
-                } else {
-                    self.str &= 0b11111101;
-                }
-                //carry flag
-                if (x_bus as usize) + (y_bus as usize) > 255 {
-                    self.str |= 0x04;
-                } else {
-                    self.str &= 0b11111011;
-                }
-                //overflow flag
-                let test = (x_bus as isize) + (y_bus as isize);
-                if (127 < test) | (test < -128) {
-                    self.str |= 0x08;
-                } else {
-                    self.str &= 0b11110111;
-                }
-            }
-            //extract carry flag
-            let cf = (self.str & 0b00000100) >> 2;
-
-            const MSB: u8 = 0b10000000;
-            const LSB: u8 = 0b00000001;
-
-            let z_bus = match micro_code.sft {
-                ShiftOp::Nop => alu_out,
-                ShiftOp::RRwC => {
-                    //
-                    self.str |= (alu_out & LSB) << 2;
-                    let x = alu_out >> 1;
-                    if cf == 1 {
-                        x | MSB
-                    } else {
-                        x
-                    }
-                }
-                ShiftOp::RlwC => {
-                    self.str |= (alu_out & MSB) >> 4;
-                    let x = alu_out << 1;
-                    if cf == 1 {
-                        x | LSB
-                    } else {
-                        x
-                    }
-                }
-                ShiftOp::Srl => {
-                    self.str |= (alu_out & LSB) << 2;
-                    let x = alu_out >> 1;
-                    if micro_code.sin {
-                        x | MSB
-                    } else {
-                        x
-                    }
-                }
-                ShiftOp::Sll | ShiftOp::Sla => {
-                    self.str |= (alu_out & MSB) >> 4;
-                    let x = alu_out << 1;
-                    if micro_code.sin {
-                        x | LSB
-                    } else {
-                        x
-                    }
-                }
-                ShiftOp::Sra => {
-                    let msb = alu_out & MSB;
-                    self.str |= (alu_out >> 1) << 2;
-                    msb | (alu_out >> 1)
-                }
-            };
-            match micro_code.z_bus {
-                Register::Nop => {}
-                Register::R0 => self.gpr[0] = z_bus,
-                Register::R1 => self.gpr[1] = z_bus,
-                Register::R2 => self.gpr[2] = z_bus,
-                Register::R3 => self.gpr[3] = z_bus,
-                Register::R4 => self.gpr[4] = z_bus,
-                Register::R5 => self.gpr[5] = z_bus,
-                Register::R6 => self.gpr[6] = z_bus,
-                Register::Pc => self.pc = z_bus,
-                Register::Ir => self.ir = z_bus,
-                Register::Mdr => self.mdr = z_bus,
-                Register::Mar => self.mar = z_bus,
-                Register::Str => self.str = z_bus,
-            }
-            match micro_code.mem {
-                MemOp::Nop => {}
-                MemOp::R => self.mdr = self.memory[self.mar as usize],
-                MemOp::W => self.memory[self.mar as usize] = self.mdr,
-            }
-            match micro_code.branch {
-                Branch::Plus1 => self.micro_program_counter += 1,
-                Branch::J => self.micro_program_counter = micro_code.addr,
-                Branch::JM => {
-                    if (self.str & 0x01) == 0x01 {
-                        self.micro_program_counter = micro_code.addr;
-                    } else {
-                        self.micro_program_counter += 1;
-                    }
-                }
-                Branch::JZ => {
-                    if (self.str & 0x02) == 0x02 {
-                        self.micro_program_counter = micro_code.addr;
-                    } else {
-                        self.micro_program_counter += 1;
-                    }
-                }
-                Branch::JC => {
-                    if (self.str & 0x04) == 0x04 {
-                        self.micro_program_counter = micro_code.addr;
-                    } else {
-                        self.micro_program_counter += 1;
-                    }
-                }
-                Branch::JV => {
-                    if (self.str & 0x08) == 0x08 {
-                        self.micro_program_counter = micro_code.addr;
-                    } else {
-                        self.micro_program_counter += 1;
-                    }
-                }
-                Branch::JI => self.micro_program_counter = micro_code.addr + self.ir as u16,
-            }
-            if micro_code.hlt {
-                println!("HLT detected sequencer stop ");
-                self.hlt = micro_code.hlt;
-            }
-            return micro_code.hlt;
+        match self.step() {
+            Some(transfer) => transfer.micro_code.hlt,
+            None => true,
+        }
+    }
+    /// execute 1 microcode like `exec` and return what it did, None when halted
+    /// or faulted.
+    pub fn step(&mut self) -> Option<Transfer> {
+        if self.hlt {
+            return None;
+        }
+        let transfer = self.next_transfer().ok()?;
+        // flags and shift carry, then the Z bus, memory and the sequencer.
+        self.str = transfer.flags;
+        if let Some(register) = transfer.destination {
+            self.store_register(register, transfer.z);
+        }
+        match transfer.memory {
+            Some(Access {
+                op: MemOp::R, data, ..
+            }) => self.mdr = data,
+            Some(Access { addr, data, .. }) => self.memory[addr as usize] = data,
+            None => {}
         }
-        true
+        self.counter = transfer.counter;
+        self.micro_program_counter = transfer.next;
+        self.hlt = transfer.micro_code.hlt;
+        Some(transfer)
     }
-    fn data_load(&self, from: RegisterOrSwitch) -> u8 {
-        match from {
-            RegisterOrSwitch::Sw1 => self.sw1,
-            RegisterOrSwitch::Sw2 => self.sw2,
-            RegisterOrSwitch::Register(register) => match register {
-                Register::Nop => 0,
-                Register::R0 => self.gpr[0],
-                Register::R1 => self.gpr[1],
-                Register::R2 => self.gpr[2],
-                Register::R3 => self.gpr[3],
-                Register::R4 => self.gpr[4],
-                Register::R5 => self.gpr[5],
-                Register::R6 => self.gpr[6],
-                Register::Pc => self.pc,
-                Register::Ir => self.ir,
-                Register::Mdr => self.mdr,
-                Register::Mar => self.mar,
-                Register::Str => self.str,
-            },
+    /// read register value regardless of bus wiring.
+    pub fn load_register(&self, register: Register) -> u8 {
+        match register {
+            Register::Nop => 0,
+            Register::R0 => self.gpr[0],
+            Register::R1 => self.gpr[1],
+            Register::R2 => self.gpr[2],
+            Register::R3 => self.gpr[3],
+            Register::R4 => self.gpr[4],
+            Register::R5 => self.gpr[5],
+            Register::R6 => self.gpr[6],
+            Register::Pc => self.pc,
+            Register::Ir => self.ir,
+            Register::Mdr => self.mdr,
+            Register::Mar => self.mar,
+            Register::Str => self.str,
+            Register::Cnt => self.counter as u8,
+        }
+    }
+    /// write register value regardless of bus wiring.
+    pub fn store_register(&mut self, register: Register, value: u8) {
+        match register {
+            Register::Nop => {}
+            Register::R0 => self.gpr[0] = value,
+            Register::R1 => self.gpr[1] = value,
+            Register::R2 => self.gpr[2] = value,
+            Register::R3 => self.gpr[3] = value,
+            Register::R4 => self.gpr[4] = value,
+            Register::R5 => self.gpr[5] = value,
+            Register::R6 => self.gpr[6] = value,
+            Register::Pc => self.pc = value,
+            Register::Ir => self.ir = value,
+            Register::Mdr => self.mdr = value,
+            Register::Mar => self.mar = value,
+            Register::Str => self.str = value,
+            Register::Cnt => self.counter = value as u16,
+        }
+    }
+    /// read STR bit.
+    pub fn flag(&self, bit: u8) -> bool {
+        (self.str >> bit) & 1 == 1
+    }
+    /// write STR bit.
+    pub fn set_flag(&mut self, bit: u8, value: bool) {
+        if value {
+            self.str |= 1 << bit;
+        } else {
+            self.str &= !(1 << bit);
         }
     }
 }
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct MicroCode {
     pub x_bus: RegisterOrSwitch,
     pub y_bus: RegisterOrSwitch,
@@ -253,27 +221,34 @@ pub struct MicroCode {
     pub hlt: bool,
     pub addr: u16,
 }
-///This architecture use 42bit micro code
+/// `+1/Nop` row which fills an empty control store.
+impl Default for MicroCode {
+    fn default() -> Self {
+        Self {
+            x_bus: RegisterOrSwitch::Register(Register::Nop),
+            y_bus: RegisterOrSwitch::Register(Register::Nop),
+            alu: AluOp::XPlusY,
+            sft: ShiftOp::Nop,
+            sin: false,
+            fl: false,
+            z_bus: Register::Nop,
+            mem: MemOp::Nop,
+            branch: Branch::Plus1,
+            hlt: false,
+            addr: 0,
+        }
+    }
+}
+/// 43 bit word of the default architecture, laid out as `FieldWidths` describes.
 impl Assemble for MicroCode {
     fn assemble(&self) -> u64 {
-        // high                                  low
-        //  4 4  3   3   1  1  4  2     3    1   16
-        //  x|y|alu|sft|sin|fl|z|mem|branch|hlt|addr
-        self.addr.assemble()
-            | self.hlt.assemble() << 16
-            | self.branch.assemble() << 17
-            | self.mem.assemble() << 20
-            | self.z_bus.assemble() << 22
-            | self.fl.assemble() << 26
-            | self.sin.assemble() << 27
-            | self.sft.assemble() << 28
-            | self.alu.assemble() << 31
-            | self.y_bus.assemble() << 33
-            | self.x_bus.assemble() << 38
+        Architecture::default()
+            .encode(self)
+            .expect("the default architecture encodes every micro code")
     }
 }
 
-trait Assemble {
+pub(crate) trait Assemble {
     ///Assemble Microcode.
     fn assemble(&self) -> u64;
 }
@@ -300,6 +275,10 @@ pub enum Branch {
     JC,
     JV,
     JI,
+    /// load sequencer counter from B.Addr.
+    Ldct,
+    /// repeat B.Addr while sequencer counter is not zero.
+    Rpct,
 }
 impl ToString for Branch {
     fn to_string(&self) -> String {
@@ -311,6 +290,8 @@ impl ToString for Branch {
             Branch::JC => "JC",
             Branch::JV => "JV",
             Branch::JI => "JI",
+            Branch::Ldct => "LDCT",
+            Branch::Rpct => "RPCT",
         }
         .to_owned()
     }
@@ -325,6 +306,8 @@ impl Assemble for Branch {
             Branch::JC => 4,
             Branch::JV => 5,
             Branch::JI => 6,
+            Branch::Ldct => 7,
+            Branch::Rpct => 8,
         }
     }
 }
@@ -429,7 +412,40 @@ pub enum Register {
     Mdr,
     Mar,
     Str,
+    Cnt,
 }
+impl Register {
+    /// index into `MicroArch::gpr` for R0~R6.
+    pub fn gpr_index(&self) -> Option<usize> {
+        match self {
+            Register::R0 => Some(0),
+            Register::R1 => Some(1),
+            Register::R2 => Some(2),
+            Register::R3 => Some(3),
+            Register::R4 => Some(4),
+            Register::R5 => Some(5),
+            Register::R6 => Some(6),
+            _ => None,
+        }
+    }
+}
+/// the fixed register file by lowercase name and width in bits, as the Verilog
+/// ports, testbenches and grading specs name it.
+pub const REGISTER_FILE: [(Register, &str, u32); 13] = [
+    (Register::R0, "r0", 8),
+    (Register::R1, "r1", 8),
+    (Register::R2, "r2", 8),
+    (Register::R3, "r3", 8),
+    (Register::R4, "r4", 8),
+    (Register::R5, "r5", 8),
+    (Register::R6, "r6", 8),
+    (Register::Pc, "pc", 8),
+    (Register::Ir, "ir", 8),
+    (Register::Mdr, "mdr", 8),
+    (Register::Mar, "mar", 8),
+    (Register::Str, "str", 8),
+    (Register::Cnt, "cnt", 16),
+];
 impl ToString for Register {
     fn to_string(&self) -> String {
         match self {
@@ -446,6 +462,7 @@ impl ToString for Register {
             Register::Mdr => "MDR",
             Register::Mar => "MAR",
             Register::Str => "STR",
+            Register::Cnt => "CNT",
         }
         .to_owned()
     }
@@ -466,10 +483,11 @@ impl Assemble for Register {
             Register::Mdr => 10,
             Register::Mar => 11,
             Register::Str => 12,
+            Register::Cnt => 15,
         }
     }
 }
-#[derive(Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum AluOp {
     XPlusY,
     XMinusY,
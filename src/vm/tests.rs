@@ -0,0 +1,836 @@
+use super::*;
+
+const MINUS: u8 = 0x01;
+const ZERO: u8 = 0x02;
+const CARRY: u8 = 0x04;
+const OVERFLOW: u8 = 0x08;
+
+const REGISTERS: [Register; 14] = [
+    Register::Nop,
+    Register::R0,
+    Register::R1,
+    Register::R2,
+    Register::R3,
+    Register::R4,
+    Register::R5,
+    Register::R6,
+    Register::Pc,
+    Register::Ir,
+    Register::Mdr,
+    Register::Mar,
+    Register::Str,
+    Register::Cnt,
+];
+
+fn reg(register: Register) -> RegisterOrSwitch {
+    RegisterOrSwitch::Register(register)
+}
+
+/// VM whose control store starts with `micro_codes`.
+fn vm_with(micro_codes: &[MicroCode]) -> MicroArch {
+    let mut micro_program = vec![MicroCode::default(); 0x100];
+    micro_program[..micro_codes.len()].copy_from_slice(micro_codes);
+    MicroArch::construct(micro_program)
+}
+
+/// execute a single micro code.
+fn step(vm: &mut MicroArch, micro_code: MicroCode) -> bool {
+    let addr = vm.micro_program_counter as usize;
+    vm.micro_program[addr] = micro_code;
+    vm.exec()
+}
+
+/// Z ← X op Y without flag update.
+fn alu(alu: AluOp, x: u8, y: u8) -> u8 {
+    let mut vm = vm_with(&[]);
+    vm.gpr[0] = x;
+    vm.gpr[1] = y;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::R0),
+            y_bus: reg(Register::R1),
+            alu,
+            z_bus: Register::R2,
+            ..MicroCode::default()
+        },
+    );
+    vm.gpr[2]
+}
+
+/// shift `value` with STR preset to `str`, returns (Z bus, STR).
+fn shift(sft: ShiftOp, sin: bool, fl: bool, value: u8, str: u8) -> (u8, u8) {
+    let mut vm = vm_with(&[]);
+    vm.gpr[0] = value;
+    vm.str = str;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::R0),
+            sft,
+            sin,
+            fl,
+            z_bus: Register::R1,
+            ..MicroCode::default()
+        },
+    );
+    (vm.gpr[1], vm.str)
+}
+
+/// STR after X+Y with FL = 1.
+fn flags_after(alu: AluOp, x: u8, y: u8) -> u8 {
+    let mut vm = vm_with(&[]);
+    vm.gpr[0] = x;
+    vm.gpr[1] = y;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::R0),
+            y_bus: reg(Register::R1),
+            alu,
+            fl: true,
+            ..MicroCode::default()
+        },
+    );
+    vm.str
+}
+
+/// micro program counter after executing `branch` at address 0x10 with STR preset.
+fn branch_from(branch: Branch, str: u8) -> u16 {
+    let mut vm = vm_with(&[]);
+    vm.micro_program_counter = 0x10;
+    vm.str = str;
+    step(
+        &mut vm,
+        MicroCode {
+            branch,
+            addr: 0x80,
+            ..MicroCode::default()
+        },
+    );
+    vm.micro_program_counter
+}
+
+#[test]
+fn every_register_is_a_bus_source() {
+    for (i, register) in REGISTERS.iter().enumerate().skip(1) {
+        let value = 0x10 + i as u8;
+        for y_bus in [false, true] {
+            let mut vm = vm_with(&[]);
+            vm.store_register(*register, value);
+            let source = reg(*register);
+            step(
+                &mut vm,
+                MicroCode {
+                    x_bus: if y_bus { reg(Register::Nop) } else { source },
+                    y_bus: if y_bus { source } else { reg(Register::Nop) },
+                    z_bus: Register::R0,
+                    ..MicroCode::default()
+                },
+            );
+            assert_eq!(vm.gpr[0], value, "{:?} on y bus {}", register, y_bus);
+        }
+    }
+}
+
+#[test]
+fn nop_and_switches_on_bus() {
+    let mut vm = vm_with(&[]);
+    vm.sw1 = 0x12;
+    vm.sw2 = 0x34;
+    vm.gpr[0] = 0xff;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::Nop),
+            y_bus: reg(Register::Nop),
+            z_bus: Register::R0,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.gpr[0], 0);
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            y_bus: RegisterOrSwitch::Sw2,
+            z_bus: Register::R0,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.gpr[0], 0x46);
+}
+
+#[test]
+fn counter_on_bus_is_low_byte() {
+    let mut vm = vm_with(&[]);
+    vm.counter = 0x1234;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::Cnt),
+            z_bus: Register::R0,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.gpr[0], 0x34);
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::R0),
+            alu: AluOp::XPlus1,
+            z_bus: Register::Cnt,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.counter, 0x35);
+}
+
+#[test]
+fn every_register_is_a_bus_destination() {
+    for register in REGISTERS.iter().skip(1) {
+        let mut vm = vm_with(&[]);
+        vm.sw1 = 0x5a;
+        step(
+            &mut vm,
+            MicroCode {
+                x_bus: RegisterOrSwitch::Sw1,
+                z_bus: *register,
+                ..MicroCode::default()
+            },
+        );
+        assert_eq!(vm.load_register(*register), 0x5a, "{:?}", register);
+    }
+}
+
+#[test]
+fn nop_destination_changes_nothing() {
+    let mut vm = vm_with(&[]);
+    vm.sw1 = 0x5a;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::Nop,
+            ..MicroCode::default()
+        },
+    );
+    for register in REGISTERS {
+        assert_eq!(vm.load_register(register), 0, "{:?}", register);
+    }
+}
+
+#[test]
+fn architecture_limits_bus_wiring() {
+    let mut vm = vm_with(&[]);
+    vm.arch.registers.retain(|r| r.register != Register::R6);
+    vm.arch
+        .registers
+        .iter_mut()
+        .find(|r| r.register == Register::R5)
+        .unwrap()
+        .destination = false;
+    vm.arch.switches.clear();
+    vm.gpr[6] = 0x66;
+    vm.sw1 = 0x11;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::R6),
+            y_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::R0,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.gpr[0], 0);
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: reg(Register::Pc),
+            alu: AluOp::XPlus1,
+            z_bus: Register::R5,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.gpr[5], 0);
+}
+
+#[test]
+fn alu_operations() {
+    assert_eq!(alu(AluOp::XPlusY, 0x12, 0x34), 0x46);
+    assert_eq!(alu(AluOp::XPlusY, 0xff, 0x02), 0x01);
+    assert_eq!(alu(AluOp::XMinusY, 0x34, 0x12), 0x22);
+    assert_eq!(alu(AluOp::XMinusY, 0x00, 0x01), 0xff);
+    assert_eq!(alu(AluOp::XAndY, 0b1100, 0b1010), 0b1000);
+    assert_eq!(alu(AluOp::XorY, 0b1100, 0b1010), 0b1110);
+    assert_eq!(alu(AluOp::XxorY, 0b1100, 0b1010), 0b0110);
+    assert_eq!(alu(AluOp::XPlus1, 0x41, 0x99), 0x42);
+    assert_eq!(alu(AluOp::XMinus1, 0x41, 0x99), 0x40);
+}
+
+#[test]
+fn flags_follow_alu_result() {
+    assert_eq!(flags_after(AluOp::XPlusY, 0x01, 0x02), 0);
+    assert_eq!(flags_after(AluOp::XMinusY, 0x02, 0x02), ZERO);
+    assert_eq!(flags_after(AluOp::XMinusY, 0x01, 0x02), MINUS);
+    assert_eq!(
+        flags_after(AluOp::XPlusY, 0x80, 0x80),
+        ZERO | CARRY | OVERFLOW
+    );
+    assert_eq!(flags_after(AluOp::XPlusY, 0x40, 0x40), MINUS | OVERFLOW);
+    assert_eq!(flags_after(AluOp::XAndY, 0x0f, 0xf0), ZERO | OVERFLOW);
+}
+
+#[test]
+fn carry_and_overflow_come_from_x_plus_y() {
+    // whatever the ALU op is, C is X+Y > 255 and V is X+Y > 127.
+    assert_eq!(flags_after(AluOp::XMinusY, 0xff, 0x01) & CARRY, CARRY);
+    assert_eq!(flags_after(AluOp::XxorY, 0x70, 0x10) & OVERFLOW, OVERFLOW);
+    assert_eq!(flags_after(AluOp::XPlus1, 0xff, 0x00) & CARRY, 0);
+}
+
+#[test]
+fn flags_kept_without_fl() {
+    let mut vm = vm_with(&[]);
+    vm.str = 0xf0 | CARRY;
+    step(
+        &mut vm,
+        MicroCode {
+            alu: AluOp::XMinus1,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.str, 0xf0 | CARRY);
+}
+
+#[test]
+fn flag_update_keeps_upper_str_bits() {
+    let mut vm = vm_with(&[]);
+    vm.str = 0xf0;
+    step(
+        &mut vm,
+        MicroCode {
+            fl: true,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.str, 0xf0 | ZERO);
+}
+
+#[test]
+fn flags_use_architecture_bits() {
+    let mut vm = vm_with(&[]);
+    vm.arch.flags.zero = 7;
+    step(
+        &mut vm,
+        MicroCode {
+            fl: true,
+            branch: Branch::JZ,
+            addr: 0x40,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.str, 0x80);
+    assert_eq!(vm.micro_program_counter, 0x40);
+}
+
+#[test]
+fn shift_nop() {
+    assert_eq!(shift(ShiftOp::Nop, true, false, 0x81, 0), (0x81, 0));
+}
+
+#[test]
+fn shift_right_logical() {
+    assert_eq!(shift(ShiftOp::Srl, false, false, 0x81, 0), (0x40, CARRY));
+    assert_eq!(shift(ShiftOp::Srl, true, false, 0x80, 0), (0xc0, 0));
+}
+
+#[test]
+fn shift_left_logical() {
+    assert_eq!(shift(ShiftOp::Sll, false, false, 0x81, 0), (0x02, CARRY));
+    assert_eq!(shift(ShiftOp::Sll, true, false, 0x01, 0), (0x03, 0));
+}
+
+#[test]
+fn shift_arithmetic() {
+    assert_eq!(shift(ShiftOp::Sra, false, false, 0x81, 0), (0xc0, CARRY));
+    assert_eq!(shift(ShiftOp::Sra, true, false, 0x42, 0), (0x21, 0));
+    assert_eq!(shift(ShiftOp::Sla, false, false, 0x81, 0), (0x02, CARRY));
+    assert_eq!(shift(ShiftOp::Sla, true, false, 0x40, 0), (0x81, 0));
+}
+
+#[test]
+fn rotate_through_carry() {
+    // the carry moved in is replaced by the bit moved out.
+    assert_eq!(shift(ShiftOp::RRwC, false, false, 0x02, CARRY), (0x81, 0));
+    assert_eq!(shift(ShiftOp::RRwC, false, false, 0x01, 0), (0x00, CARRY));
+    assert_eq!(shift(ShiftOp::RlwC, false, false, 0x40, CARRY), (0x81, 0));
+    assert_eq!(shift(ShiftOp::RlwC, false, false, 0x80, 0), (0x00, CARRY));
+}
+
+#[test]
+fn shifter_replaces_carry() {
+    // C is the shifted out bit. it used to be or'ed into C, so shifting out a
+    // zero left a stale C = 1 and these gave (0x01, CARRY) and (0x04, CARRY).
+    assert_eq!(shift(ShiftOp::Srl, false, false, 0x02, CARRY), (0x01, 0));
+    assert_eq!(shift(ShiftOp::Sll, false, false, 0x02, CARRY), (0x04, 0));
+    // other STR bits are kept.
+    assert_eq!(shift(ShiftOp::Srl, false, false, 0x02, 0xf0), (0x01, 0xf0));
+    // Nop leaves C alone.
+    assert_eq!(
+        shift(ShiftOp::Nop, false, false, 0x02, CARRY),
+        (0x02, CARRY)
+    );
+    // with FL the ALU clears C first and the shifter sees the new value.
+    assert_eq!(shift(ShiftOp::Srl, false, true, 0x02, CARRY), (0x01, 0));
+    assert_eq!(shift(ShiftOp::RRwC, false, true, 0x02, CARRY), (0x01, 0));
+}
+
+#[test]
+fn shift_flags_use_alu_output() {
+    let (z, str) = shift(ShiftOp::Srl, false, true, 0x01, 0);
+    assert_eq!(z, 0);
+    assert_eq!(str, CARRY);
+}
+
+#[test]
+fn sequential_branch() {
+    assert_eq!(branch_from(Branch::Plus1, 0xff), 0x11);
+}
+
+#[test]
+fn unconditional_branch() {
+    assert_eq!(branch_from(Branch::J, 0), 0x80);
+}
+
+#[test]
+fn conditional_branches() {
+    for (branch, flag) in [
+        (Branch::JM, MINUS),
+        (Branch::JZ, ZERO),
+        (Branch::JC, CARRY),
+        (Branch::JV, OVERFLOW),
+    ] {
+        assert_eq!(branch_from(branch, flag), 0x80, "{:?} taken", branch);
+        assert_eq!(branch_from(branch, !flag), 0x11, "{:?} not taken", branch);
+    }
+}
+
+#[test]
+fn branch_sees_flags_of_same_micro_code() {
+    let mut vm = vm_with(&[]);
+    step(
+        &mut vm,
+        MicroCode {
+            fl: true,
+            branch: Branch::JZ,
+            addr: 0x80,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.micro_program_counter, 0x80);
+}
+
+#[test]
+fn indexed_branch() {
+    let mut vm = vm_with(&[]);
+    vm.ir = 0x12;
+    step(
+        &mut vm,
+        MicroCode {
+            branch: Branch::JI,
+            addr: 0x40,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.micro_program_counter, 0x52);
+    // IR written in the same micro code is used.
+    let mut vm = vm_with(&[]);
+    vm.sw1 = 0x03;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::Ir,
+            branch: Branch::JI,
+            addr: 0x40,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.micro_program_counter, 0x43);
+    assert_eq!(branch_from(Branch::JI, 0), 0x80);
+}
+
+#[test]
+fn counter_loop() {
+    let mut vm = vm_with(&[
+        MicroCode {
+            branch: Branch::Ldct,
+            addr: 3,
+            ..MicroCode::default()
+        },
+        MicroCode {
+            x_bus: reg(Register::R0),
+            alu: AluOp::XPlus1,
+            z_bus: Register::R0,
+            branch: Branch::Rpct,
+            addr: 1,
+            ..MicroCode::default()
+        },
+        MicroCode {
+            hlt: true,
+            ..MicroCode::default()
+        },
+    ]);
+    vm.exec();
+    assert_eq!(vm.counter, 3);
+    assert_eq!(vm.micro_program_counter, 1);
+    while !vm.exec() {}
+    // RPCT repeats while CNT != 0, so the body runs CNT + 1 times.
+    assert_eq!(vm.gpr[0], 4);
+    assert_eq!(vm.counter, 0);
+}
+
+#[test]
+fn memory_read_uses_mar_written_on_z_bus() {
+    let mut vm = vm_with(&[]);
+    vm.memory[0x20] = 0x99;
+    vm.sw1 = 0x20;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::Mar,
+            mem: MemOp::R,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.mar, 0x20);
+    assert_eq!(vm.mdr, 0x99);
+}
+
+#[test]
+fn memory_read_overrides_mdr_written_on_z_bus() {
+    let mut vm = vm_with(&[]);
+    vm.memory[0] = 0x99;
+    vm.sw1 = 0x11;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::Mdr,
+            mem: MemOp::R,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.mdr, 0x99);
+}
+
+#[test]
+fn memory_write_uses_mdr_written_on_z_bus() {
+    let mut vm = vm_with(&[]);
+    vm.mar = 0x30;
+    vm.sw1 = 0x77;
+    step(
+        &mut vm,
+        MicroCode {
+            x_bus: RegisterOrSwitch::Sw1,
+            z_bus: Register::Mdr,
+            mem: MemOp::W,
+            ..MicroCode::default()
+        },
+    );
+    assert_eq!(vm.memory[0x30], 0x77);
+}
+
+#[test]
+fn memory_nop_leaves_memory() {
+    let mut vm = vm_with(&[]);
+    vm.mdr = 0x77;
+    step(&mut vm, MicroCode::default());
+    assert!(vm.memory.iter().all(|byte| *byte == 0));
+    assert_eq!(vm.mdr, 0x77);
+}
+
+#[test]
+fn halt_stops_sequencer() {
+    let mut vm = vm_with(&[
+        MicroCode {
+            x_bus: reg(Register::R0),
+            alu: AluOp::XPlus1,
+            z_bus: Register::R0,
+            hlt: true,
+            branch: Branch::J,
+            addr: 0x20,
+            ..MicroCode::default()
+        },
+        MicroCode::default(),
+    ]);
+    assert!(vm.exec());
+    assert!(vm.hlt);
+    // the halting micro code still completes.
+    assert_eq!(vm.gpr[0], 1);
+    assert_eq!(vm.micro_program_counter, 0x20);
+    // further exec does nothing until woken up.
+    assert!(vm.exec());
+    assert_eq!(vm.micro_program_counter, 0x20);
+    vm.start();
+    assert!(!vm.exec());
+    assert_eq!(vm.micro_program_counter, 0x21);
+}
+
+#[test]
+fn reset_register_clears_state() {
+    let mut vm = vm_with(&[]);
+    for register in REGISTERS {
+        vm.store_register(register, 0xaa);
+    }
+    vm.counter = 0x1234;
+    vm.sw1 = 1;
+    vm.sw2 = 2;
+    vm.hlt = true;
+    vm.micro_program_counter = 0x40;
+    vm.memory[0x10] = 0x55;
+    vm.micro_program[5].hlt = true;
+    vm.reset_register();
+    for register in REGISTERS
+        .iter()
+        .filter(|register| **register != Register::Mar)
+    {
+        assert_eq!(vm.load_register(*register), 0, "{:?}", register);
+    }
+    assert_eq!(vm.counter, 0);
+    assert_eq!((vm.sw1, vm.sw2), (0, 0));
+    assert!(!vm.hlt);
+    assert_eq!(vm.micro_program_counter, 0);
+    // MAR, memory and micro program are kept.
+    assert_eq!(vm.mar, 0xaa);
+    assert_eq!(vm.memory[0x10], 0x55);
+    assert!(vm.micro_program[5].hlt);
+}
+
+/// micro code with every field zero encoded.
+#[test]
+fn flags_read_and_write_their_own_bit() {
+    // the register view once showed `str & 0x02 >> 1`, 2 for a set zero flag.
+    let mut vm = vm_with(&[]);
+    vm.str = ZERO | OVERFLOW;
+    let flags = vm.arch.flags;
+    let read = |vm: &MicroArch| {
+        [flags.minus, flags.zero, flags.carry, flags.overflow].map(|bit| vm.flag(bit) as u8)
+    };
+    assert_eq!(read(&vm), [0, 1, 0, 1]);
+    vm.set_flag(flags.carry, true);
+    vm.set_flag(flags.zero, false);
+    assert_eq!(read(&vm), [0, 0, 1, 1]);
+    assert_eq!(vm.str, CARRY | OVERFLOW);
+    vm.str |= 0xf0;
+    vm.set_flag(flags.overflow, false);
+    assert_eq!(vm.str, 0xf0 | CARRY);
+}
+
+fn zero_code() -> MicroCode {
+    MicroCode::default()
+}
+
+#[test]
+fn assemble_default_is_zero() {
+    assert_eq!(zero_code().assemble(), 0);
+}
+
+#[test]
+fn assemble_field_positions() {
+    assert_eq!(
+        MicroCode {
+            addr: 0xffff,
+            ..zero_code()
+        }
+        .assemble(),
+        0xffff
+    );
+    assert_eq!(
+        MicroCode {
+            hlt: true,
+            ..zero_code()
+        }
+        .assemble(),
+        1 << 16
+    );
+    assert_eq!(
+        MicroCode {
+            branch: Branch::Rpct,
+            ..zero_code()
+        }
+        .assemble(),
+        8 << 17
+    );
+    assert_eq!(
+        MicroCode {
+            mem: MemOp::W,
+            ..zero_code()
+        }
+        .assemble(),
+        2 << 21
+    );
+    assert_eq!(
+        MicroCode {
+            z_bus: Register::Cnt,
+            ..zero_code()
+        }
+        .assemble(),
+        15 << 23
+    );
+    assert_eq!(
+        MicroCode {
+            fl: true,
+            ..zero_code()
+        }
+        .assemble(),
+        1 << 27
+    );
+    assert_eq!(
+        MicroCode {
+            sin: true,
+            ..zero_code()
+        }
+        .assemble(),
+        1 << 28
+    );
+    assert_eq!(
+        MicroCode {
+            sft: ShiftOp::Sla,
+            ..zero_code()
+        }
+        .assemble(),
+        6 << 29
+    );
+    assert_eq!(
+        MicroCode {
+            alu: AluOp::XMinus1,
+            ..zero_code()
+        }
+        .assemble(),
+        6 << 32
+    );
+    assert_eq!(
+        MicroCode {
+            y_bus: RegisterOrSwitch::Sw2,
+            ..zero_code()
+        }
+        .assemble(),
+        14 << 35
+    );
+    assert_eq!(
+        MicroCode {
+            x_bus: RegisterOrSwitch::Sw2,
+            ..zero_code()
+        }
+        .assemble(),
+        14 << 39
+    );
+}
+
+#[test]
+fn assemble_fits_43_bits() {
+    let widest = MicroCode {
+        x_bus: reg(Register::Cnt),
+        y_bus: reg(Register::Cnt),
+        alu: AluOp::XMinus1,
+        sft: ShiftOp::Sla,
+        sin: true,
+        fl: true,
+        z_bus: Register::Cnt,
+        mem: MemOp::W,
+        branch: Branch::Rpct,
+        hlt: true,
+        addr: 0xffff,
+    };
+    assert!(widest.assemble() < 1 << 43);
+    assert_eq!(Architecture::default().word_width(), 43);
+}
+
+#[test]
+fn assemble_codes() {
+    assert_eq!(
+        REGISTERS.iter().map(|r| r.assemble()).collect::<Vec<_>>(),
+        vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 15]
+    );
+    assert_eq!(RegisterOrSwitch::Sw1.assemble(), 13);
+    assert_eq!(RegisterOrSwitch::Sw2.assemble(), 14);
+    let arch = Architecture::default();
+    assert_eq!(
+        arch.alu_ops
+            .iter()
+            .map(|op| op.op.assemble())
+            .collect::<Vec<_>>(),
+        vec![0, 1, 2, 3, 4, 5, 6]
+    );
+    assert_eq!(
+        arch.shift_ops
+            .iter()
+            .map(|op| op.op.assemble())
+            .collect::<Vec<_>>(),
+        vec![0, 1, 2, 3, 4, 5, 6]
+    );
+    assert_eq!(
+        arch.mem_ops
+            .iter()
+            .map(|op| op.op.assemble())
+            .collect::<Vec<_>>(),
+        vec![0, 1, 2]
+    );
+    assert_eq!(
+        arch.branches
+            .iter()
+            .map(|op| op.op.assemble())
+            .collect::<Vec<_>>(),
+        vec![0, 1, 2, 3, 4, 5, 6, 7, 8]
+    );
+}
+
+#[test]
+fn default_architecture_encodes_like_assemble() {
+    let arch = Architecture::default();
+    let sources = arch
+        .switches
+        .iter()
+        .map(|s| s.switch)
+        .chain(REGISTERS.iter().map(|r| reg(*r)));
+    for (i, x_bus) in sources.enumerate() {
+        let micro_code = MicroCode {
+            x_bus,
+            y_bus: reg(REGISTERS[i % REGISTERS.len()]),
+            alu: arch.alu_ops[i % arch.alu_ops.len()].op,
+            sft: arch.shift_ops[i % arch.shift_ops.len()].op,
+            sin: i % 2 == 0,
+            fl: i % 3 == 0,
+            z_bus: REGISTERS[(i + 5) % REGISTERS.len()],
+            mem: arch.mem_ops[i % arch.mem_ops.len()].op,
+            branch: arch.branches[i % arch.branches.len()].op,
+            hlt: i % 5 == 0,
+            addr: (i as u16).wrapping_mul(0x1357),
+        };
+        assert_eq!(arch.encode(&micro_code), Ok(micro_code.assemble()));
+    }
+}
+
+#[test]
+fn encode_rejects_missing_and_overflowing_values() {
+    let mut arch = Architecture::default();
+    arch.registers.retain(|r| r.register != Register::R6);
+    arch.fields.addr = 8;
+    assert!(matches!(
+        arch.encode(&MicroCode {
+            z_bus: Register::R6,
+            ..MicroCode::default()
+        }),
+        Err(crate::arch::EncodeError::Unsupported { .. })
+    ));
+    assert!(matches!(
+        arch.encode(&MicroCode {
+            addr: 0x100,
+            ..MicroCode::default()
+        }),
+        Err(crate::arch::EncodeError::Overflow { .. })
+    ));
+}
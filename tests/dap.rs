@@ -0,0 +1,202 @@
+//! Drive `micro_dap` over stdio the way an editor does.
+use micro_programming::dap::{read_message, write_message};
+use micro_programming::project::{save, Compression};
+use micro_programming::reference::{project, SAMPLES};
+use micro_programming::vm::{MicroArch, MicroCode};
+use serde_json::{json, Value};
+use std::io::BufReader;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+struct Adapter {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: i64,
+    /// events received while waiting for responses.
+    events: Vec<Value>,
+}
+
+impl Adapter {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_micro_dap"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            seq: 0,
+            events: vec![],
+        }
+    }
+
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let request = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        write_message(&mut self.stdin, &request).unwrap();
+        loop {
+            let message = read_message(&mut self.stdout).unwrap().unwrap();
+            if message["type"] == "response" {
+                assert_eq!(message["request_seq"], self.seq);
+                assert_eq!(message["command"], command);
+                return message;
+            }
+            self.events.push(message);
+        }
+    }
+
+    fn event(&mut self, name: &str) -> Value {
+        if let Some(index) = self.events.iter().position(|event| event["event"] == name) {
+            return self.events.remove(index);
+        }
+        loop {
+            let message = read_message(&mut self.stdout).unwrap().unwrap();
+            if message["event"] == name {
+                return message;
+            }
+            self.events.push(message);
+        }
+    }
+
+    fn line(&mut self) -> Value {
+        let trace = self.request("stackTrace", json!({ "threadId": 1 }));
+        trace["body"]["stackFrames"][0]["line"].clone()
+    }
+
+    fn variable(&mut self, reference: i64, name: &str) -> String {
+        let variables = self.request("variables", json!({ "variablesReference": reference }));
+        variables["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|variable| variable["name"] == name)
+            .unwrap()["value"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    fn finish(mut self) {
+        let response = self.request("disconnect", json!({}));
+        assert_eq!(response["success"], true);
+        self.event("terminated");
+        assert!(self.child.wait().unwrap().success());
+    }
+}
+
+fn project_file(name: &str, vm: &MicroArch) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "micro_dap_{}_{}.cpu_memory",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&path, save(vm, Compression::Deflate).unwrap()).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+fn launch(adapter: &mut Adapter, program: &str, stop_on_entry: bool) {
+    let response = adapter.request("initialize", json!({ "adapterID": "micro_programming" }));
+    assert_eq!(response["body"]["supportsConfigurationDoneRequest"], true);
+    let response = adapter.request(
+        "launch",
+        json!({ "program": program, "sw1": 6, "sw2": 7, "reset": true, "stopOnEntry": stop_on_entry }),
+    );
+    assert_eq!(response["success"], true, "{}", response);
+    adapter.event("initialized");
+}
+
+#[test]
+fn step_break_and_halt() {
+    let program = project_file("multiply", &project(&SAMPLES[1]));
+    let mut adapter = Adapter::start();
+    launch(&mut adapter, &program, true);
+
+    // the third listing line is the dispatch at 0002H.
+    let response = adapter.request(
+        "setBreakpoints",
+        json!({ "source": { "sourceReference": 1 }, "breakpoints": [{ "line": 3 }, { "line": 100000 }] }),
+    );
+    let breakpoints = &response["body"]["breakpoints"];
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[1]["verified"], false);
+    adapter.request("configurationDone", json!({}));
+    assert_eq!(adapter.event("stopped")["body"]["reason"], "entry");
+
+    let trace = adapter.request("stackTrace", json!({ "threadId": 1 }));
+    let frame = &trace["body"]["stackFrames"][0];
+    assert_eq!(frame["line"], 1);
+    assert_eq!(frame["name"], "0000H X=PC Z=MAR");
+    let source = adapter.request("source", json!({ "sourceReference": 1 }));
+    let listing = source["body"]["content"].as_str().unwrap().to_owned();
+    assert!(listing
+        .lines()
+        .nth(2)
+        .unwrap()
+        .starts_with("0002H  X=MDR Z=IR BR=JI"));
+
+    adapter.request("next", json!({ "threadId": 1 }));
+    assert_eq!(adapter.event("stopped")["body"]["reason"], "step");
+    assert_eq!(adapter.line(), 2);
+
+    adapter.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(adapter.event("stopped")["body"]["reason"], "breakpoint");
+    assert_eq!(adapter.line(), 3);
+    assert_eq!(adapter.variable(1, "uPC"), "0002H");
+
+    adapter.request(
+        "setBreakpoints",
+        json!({ "source": { "sourceReference": 1 }, "breakpoints": [] }),
+    );
+    adapter.request("continue", json!({ "threadId": 1 }));
+    let stopped = adapter.event("stopped");
+    assert_eq!(stopped["body"]["reason"], "halt");
+    assert_eq!(stopped["body"]["description"], "HLT");
+    assert_eq!(adapter.variable(1, "R6"), "2AH");
+    assert_eq!(adapter.variable(1, "HLT"), "1");
+    assert_eq!(adapter.variable(2, "Z"), "1");
+    assert!(adapter.variable(3, "0080H").starts_with("30 00 2A"));
+
+    let scopes = adapter.request("scopes", json!({ "frameId": 0 }));
+    assert_eq!(scopes["body"]["scopes"].as_array().unwrap().len(), 3);
+    adapter.finish();
+    std::fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn faults_and_pause() {
+    let mut vm = project(&SAMPLES[1]);
+    let alu = vm.micro_program[0].alu;
+    vm.arch.alu_ops.retain(|spec| spec.op != alu);
+    let program = project_file("restricted", &vm);
+    let mut adapter = Adapter::start();
+    launch(&mut adapter, &program, false);
+    adapter.request("configurationDone", json!({}));
+    let stopped = adapter.event("stopped");
+    assert_eq!(stopped["body"]["reason"], "exception");
+    assert!(stopped["body"]["text"]
+        .as_str()
+        .unwrap()
+        .contains("is not available in ALU field"));
+    assert_eq!(adapter.event("output")["body"]["category"], "console");
+    adapter.finish();
+    std::fs::remove_file(program).unwrap();
+
+    // counts up the control store forever.
+    let vm = MicroArch::construct(vec![MicroCode::default(); 1 << 16]);
+    let program = project_file("forever", &vm);
+    let mut adapter = Adapter::start();
+    launch(&mut adapter, &program, false);
+    adapter.request("configurationDone", json!({}));
+    let response = adapter.request("pause", json!({ "threadId": 1 }));
+    assert_eq!(response["success"], true);
+    assert_eq!(adapter.event("stopped")["body"]["reason"], "pause");
+    adapter.finish();
+    std::fs::remove_file(program).unwrap();
+}